@@ -3,13 +3,19 @@ fn work() {
     println!("{:?}", &v);
 }
 
-fn demo() {
+async fn demo() {
     // Using a period of 1 to catch all allocations.
-    let heap_profiler_guard = heappy::HeapProfilerGuard::new(1).unwrap();
+    let heap_profiler_guard = heappy::HeapProfilerGuard::new(1).await.unwrap();
 
+    // the first print lazily initializes the print subsystem, which is expensive and only happens once: leave it out
+    // of the profile.
     work();
+    let baseline = heap_profiler_guard.snapshot().await;
 
-    let report = heap_profiler_guard.report();
+    work();
+
+    let mut report = heap_profiler_guard.report().await;
+    report.subtract_baseline(&baseline);
 
     let filename = "/tmp/memflame.svg";
     println!("Writing to {}", filename);
@@ -22,9 +28,8 @@ fn demo() {
     report.write_pprof(&mut file).unwrap();
 }
 
-fn main() {
-    // cause some print before the demo or the memory profiler will show also the (expensive) lazy initialization of the print subsystem
-    println!("starting demo");
-    demo();
+#[tokio::main]
+async fn main() {
+    demo().await;
     println!("bye");
 }
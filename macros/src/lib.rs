@@ -0,0 +1,143 @@
+//! The attribute macros of heappy, re-exported by heappy with its `macros` feature: see `heappy::instrument` and
+//! `heappy::main`.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, ItemFn, LitInt, LitStr};
+
+/// Labels the allocations of a function, sync or async, with its name (the path of its module and its own, e.g.
+/// `my_crate::index::load`) as their `function` label, for as long as it runs (or, async, whenever it's polled):
+///
+/// ```ignore
+/// #[heappy::instrument]
+/// async fn load(path: &Path) -> io::Result<Index> {
+///     ...
+/// }
+/// ```
+///
+/// `#[heappy::instrument(name = "load_index")]` labels them with another name, and `#[heappy::instrument(focus)]`
+/// makes the function a [focus](https://docs.rs/heappy/latest/heappy/fn.focus.html) too, for the sessions that only
+/// record the allocations in focus to record its.
+#[proc_macro_attribute]
+pub fn instrument(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name = None;
+    let mut focus = false;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else if meta.path.is_ident("focus") {
+            focus = true;
+            Ok(())
+        } else {
+            Err(meta.error("expected `name = \"...\"` or `focus`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    if let Some(constness) = sig.constness {
+        return syn::Error::new_spanned(constness, "a const fn can't be instrumented")
+            .to_compile_error()
+            .into();
+    }
+
+    let ident = sig.ident.to_string();
+    let name = match name {
+        Some(name) => quote!(#name),
+        None => quote!(::core::concat!(::core::module_path!(), "::", #ident)),
+    };
+    let body = if sig.asyncness.is_some() {
+        let focused =
+            focus.then(|| quote!(let __heappy_future = ::heappy::focused(__heappy_future);));
+        quote!({
+            let __heappy_future = async move #block;
+            #focused
+            ::heappy::in_function(#name, __heappy_future).await
+        })
+    } else {
+        let focused = focus.then(|| quote!(let __heappy_focus = ::heappy::focus();));
+        // spliced rather than nested, for the lints not to take the braces of the function for unnecessary ones.
+        let stmts = &block.stmts;
+        quote!({
+            let __heappy_function = ::heappy::function_scope(#name);
+            #focused
+            #(#stmts)*
+        })
+    };
+    quote!(#(#attrs)* #vis #sig #body).into()
+}
+
+/// Profiles the whole process, from the start of `main` until it returns or panics, with the session of
+/// `heappy::profile_main`, set up from the environment (see `HeapProfilerGuardBuilder::from_env`):
+///
+/// ```ignore
+/// #[heappy::main(period = 65536, report = "heap-{pid}.pb")]
+/// #[tokio::main]
+/// async fn main() {
+///     ...
+/// }
+/// ```
+///
+/// `period` is the sampling period of the default session, `report` the file the pprof report is written to
+/// (`heap-{pid}.pb` if left out), both unless the environment says otherwise. The runtime's attribute of an async
+/// `main` can come before or after, the session starts before the runtime either way.
+#[proc_macro_attribute]
+pub fn main(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut period = None;
+    let mut report = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("period") {
+            period = Some(meta.value()?.parse::<LitInt>()?);
+            Ok(())
+        } else if meta.path.is_ident("report") {
+            report = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `period = ...` or `report = \"...\"`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    if sig.asyncness.is_some() && attrs.is_empty() {
+        return syn::Error::new_spanned(
+            sig.asyncness,
+            "an async main needs the attribute of a runtime, like #[tokio::main]",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let period = period.map(|period| quote!(.period(#period)));
+    let report = match report {
+        Some(report) => quote!(#report),
+        None => quote!("heap-{pid}.pb"),
+    };
+    // the attributes of the runtime go to an inner function, so that the session starts before the runtime does and
+    // ends after it shut down.
+    let mut inner = sig.clone();
+    inner.ident = syn::Ident::new("__heappy_main", sig.ident.span());
+    let mut outer = sig;
+    outer.asyncness = None;
+    quote!(
+        #vis #outer {
+            let __heappy_session = ::heappy::profile_main(
+                ::heappy::HeapProfilerGuardBuilder::default() #period,
+                ::core::option::Option::Some(#report),
+            );
+            #(#attrs)*
+            #inner #block
+            __heappy_main()
+        }
+    )
+    .into()
+}
@@ -0,0 +1,154 @@
+//! Early warnings for leaks: a callback fired when the sampled in-use bytes grow past a threshold.
+//!
+//! ```ignore
+//! let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+//! let thresholds = heappy::GrowthThresholds {
+//!     max_in_use_bytes: Some(2 << 30),
+//!     ..Default::default()
+//! };
+//! let _guard = heappy::HeapProfilerGuardBuilder::default()
+//!     .period(4096)
+//!     .on_growth(thresholds, move |alert| {
+//!         let _ = tx.send(alert);
+//!     })
+//!     .build()
+//!     .await?;
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::collector::MemProfileRecord;
+use crate::labels::Labels;
+use crate::profiler::{Profiler, StackKey, HEAP_PROFILER_STATE, MAX_DEPTH};
+use crate::task::{self, Task};
+
+pub(crate) type GrowthCallback = Arc<dyn Fn(GrowthAlert) + Send + Sync>;
+
+/// When to fire a [`GrowthAlert`]. An alert fires when one of the thresholds that are set starts being exceeded, and
+/// not again until none of them is.
+#[derive(Clone, Debug)]
+pub struct GrowthThresholds {
+    /// Sampled in-use bytes.
+    pub max_in_use_bytes: Option<i64>,
+    /// Growth of the sampled in-use bytes between two checks, in bytes per second.
+    pub max_growth_rate: Option<f64>,
+    /// How often to check.
+    pub interval: Duration,
+    /// How many of the top growing stacks to include in an alert.
+    pub top_stacks: usize,
+}
+
+impl Default for GrowthThresholds {
+    fn default() -> Self {
+        Self {
+            max_in_use_bytes: None,
+            max_growth_rate: None,
+            interval: Duration::from_secs(10),
+            top_stacks: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GrowthAlert {
+    pub in_use_bytes: i64,
+    /// Bytes per second since the previous check.
+    pub growth_rate: f64,
+    /// The stacks that grew the most since the previous check, most first.
+    pub top_growing: Vec<GrowingStack>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GrowingStack {
+    pub frames: pprof::Frames,
+    pub labels: Labels,
+    pub in_use_bytes: i64,
+    /// Since the previous check.
+    pub growth_bytes: i64,
+}
+
+// Without measure_free nothing is ever freed as far as the profiler knows.
+#[cfg(feature = "measure_free")]
+fn in_use(rec: &MemProfileRecord) -> i64 {
+    rec.in_use_bytes()
+}
+
+#[cfg(not(feature = "measure_free"))]
+fn in_use(rec: &MemProfileRecord) -> i64 {
+    rec.alloc_bytes
+}
+
+// Checks the running session every `thresholds.interval` until aborted.
+pub(crate) fn spawn(thresholds: GrowthThresholds, callback: GrowthCallback) -> Task<()> {
+    let mut previous: Option<(Instant, HashMap<StackKey<MAX_DEPTH>, i64>)> = None;
+    let mut firing = false;
+    task::every(thresholds.interval, move || {
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        // the watcher's own allocations would be attributed to whatever it interrupted.
+        let stacks: HashMap<_, _> = Profiler::untracked(|| {
+            profiler
+                .collector
+                .iter()
+                .map(|(key, rec)| (key.clone(), in_use(rec)))
+                .collect()
+        })
+        .unwrap_or_default();
+        std::mem::drop(profiler);
+        let now = crate::clock::now();
+
+        let Some((then, before)) = previous.replace((now, stacks)) else {
+                return;
+            };
+        let (_, stacks) = previous.as_ref().unwrap();
+        let in_use_bytes: i64 = stacks.values().sum();
+        let elapsed = now.duration_since(then).as_secs_f64();
+        let growth_rate = if elapsed > 0.0 {
+            (in_use_bytes - before.values().sum::<i64>()) as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let exceeded = thresholds
+            .max_in_use_bytes
+            .map_or(false, |max| in_use_bytes > max)
+            || thresholds
+                .max_growth_rate
+                .map_or(false, |max| growth_rate > max);
+        let fire = exceeded && !firing;
+        firing = exceeded;
+        if !fire {
+            return;
+        }
+
+        let mut growing: Vec<_> = stacks
+            .iter()
+            .map(|(key, &bytes)| (key, bytes, bytes - before.get(key).copied().unwrap_or(0)))
+            .filter(|(_, _, growth)| *growth > 0)
+            .collect();
+        growing.sort_by_key(|(_, _, growth)| std::cmp::Reverse(*growth));
+        let top: Vec<_> = growing
+            .into_iter()
+            .take(thresholds.top_stacks)
+            .map(|(key, bytes, growth)| (key.clone(), bytes, growth))
+            .collect();
+
+        let top_growing = Profiler::untracked(|| {
+            top.into_iter()
+                .map(|(key, in_use_bytes, growth_bytes)| GrowingStack {
+                    frames: key.frames.into(),
+                    labels: key.labels,
+                    in_use_bytes,
+                    growth_bytes,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+        callback(GrowthAlert {
+            in_use_bytes,
+            growth_rate,
+            top_growing,
+        });
+    })
+}
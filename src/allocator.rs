@@ -0,0 +1,80 @@
+//! A [`GlobalAlloc`] wrapper feeding the profiler, for where the libc hooks can't intercept `malloc`: e.g. Rust
+//! components built as a shared library and loaded into an Android app, where Bionic's allocator is already bound.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: heappy::ProfilingAllocator = heappy::ProfilingAllocator::new(std::alloc::System);
+//! ```
+//!
+//! Only the allocations of Rust code are seen, and the allocator doesn't report what it granted on top of what was
+//! asked for. Don't combine it with the `enable_heap_profiler` hooks, allocations would be counted twice.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+
+use crate::profiler::Profiler;
+
+/// Records the allocations of the wrapped allocator during a session.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProfilingAllocator<A = System> {
+    inner: A,
+}
+
+impl<A> ProfilingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ProfilingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        crate::testing::allocating(layout.size());
+        let res = self.inner.alloc(layout);
+        if !res.is_null() {
+            let size = layout.size() as i64;
+            Profiler::track_allocated(res.cast(), size, size);
+        }
+        res
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        crate::testing::allocating(layout.size());
+        let res = self.inner.alloc_zeroed(layout);
+        if !res.is_null() {
+            let size = layout.size() as i64;
+            Profiler::track_allocated(res.cast(), size, size);
+        }
+        res
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "measure_free")]
+        {
+            let size = layout.size() as i64;
+            Profiler::track_allocated(ptr.cast(), -size, -size);
+        }
+        Profiler::track_freed(ptr.cast());
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        crate::testing::allocating(new_size);
+        let old_size = layout.size() as i64;
+        #[cfg(feature = "measure_free")]
+        let foreign = crate::foreign::is_foreign(ptr as usize);
+        let res = self.inner.realloc(ptr, layout, new_size);
+        if res.is_null() {
+            return res;
+        }
+        // an allocation from before the session is gone, and one of the session takes its place.
+        #[cfg(feature = "measure_free")]
+        if foreign {
+            Profiler::track_allocated(ptr.cast(), -old_size, -old_size);
+            Profiler::track_allocated(res.cast(), new_size as i64, new_size as i64);
+            return res;
+        }
+        Profiler::track_freed(ptr.cast());
+        let grown = new_size as i64 - old_size;
+        Profiler::track_allocated(res.cast(), grown, grown);
+        res
+    }
+}
@@ -0,0 +1,275 @@
+//! Arrow IPC files (also known as Feather v2) of the reports, see
+//! [`HeapReport::to_arrow`](crate::HeapReport::to_arrow), encoded by heappy itself: a single record batch of
+//! non-nullable `Utf8`, `Int64` and `Timestamp(ns, UTC)` columns, whose flatbuffers are written front to back, each
+//! object after the ones pointing to it, so that the offsets are forward ones as the format wants.
+
+pub(crate) enum Column {
+    Utf8(Vec<String>),
+    Int64(Vec<i64>),
+    /// In nanoseconds since the Unix epoch.
+    Timestamp(Vec<i64>),
+}
+
+impl Column {
+    fn len(&self) -> usize {
+        match self {
+            Self::Utf8(values) => values.len(),
+            Self::Int64(values) | Self::Timestamp(values) => values.len(),
+        }
+    }
+}
+
+const MAGIC: &[u8] = b"ARROW1";
+// MetadataVersion.V5
+const VERSION: i16 = 4;
+
+// The file with the named `columns`, of the same length.
+pub(crate) fn file(columns: &[(&str, Column)]) -> Vec<u8> {
+    let rows = columns.first().map_or(0, |(_, column)| column.len());
+    let mut out = MAGIC.to_vec();
+    pad(&mut out, 8);
+    message(&mut out, 1, schema(columns), &[]);
+    let (batch, body) = record_batch(rows, columns);
+    let block = message(&mut out, 3, batch, &body);
+    // the end of the stream.
+    out.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+    let footer = build(&table(vec![
+        (0, Field::I16(VERSION)),
+        (1, Field::Offset(schema(columns))),
+        (2, Field::Offset(Object::Structs(8, 0, vec![]))),
+        (3, Field::Offset(Object::Structs(8, 1, block))),
+    ]));
+    out.extend_from_slice(&footer);
+    out.extend_from_slice(&(footer.len() as i32).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+    out
+}
+
+fn schema(columns: &[(&str, Column)]) -> Object {
+    let fields = columns
+        .iter()
+        .map(|(name, column)| {
+            let (type_id, ty) = match column {
+                Column::Utf8(_) => (5, table(vec![])),
+                Column::Int64(_) => (2, table(vec![(0, Field::I32(64)), (1, Field::Bool(true))])),
+                Column::Timestamp(_) => (
+                    10,
+                    table(vec![
+                        (0, Field::I16(3)),
+                        (1, Field::Offset(Object::String("UTC".to_string()))),
+                    ]),
+                ),
+            };
+            table(vec![
+                (0, Field::Offset(Object::String(name.to_string()))),
+                (1, Field::Bool(false)),
+                (2, Field::U8(type_id)),
+                (3, Field::Offset(ty)),
+                // readers want the children, even if there are none.
+                (5, Field::Offset(Object::Tables(vec![]))),
+            ])
+        })
+        .collect();
+    table(vec![(1, Field::Offset(Object::Tables(fields)))])
+}
+
+// The metadata of the batch and its body: a validity buffer (empty, nothing is null) and the values of each column,
+// with the offsets of the strings before them.
+fn record_batch(rows: usize, columns: &[(&str, Column)]) -> (Object, Vec<u8>) {
+    let mut body = vec![];
+    let mut buffers = vec![];
+    let mut add = |body: &mut Vec<u8>, bytes: &[u8]| {
+        buffers.extend_from_slice(&(body.len() as i64).to_le_bytes());
+        buffers.extend_from_slice(&(bytes.len() as i64).to_le_bytes());
+        body.extend_from_slice(bytes);
+        pad(body, 8);
+    };
+    let mut nodes = vec![];
+    for (_, column) in columns {
+        nodes.extend_from_slice(&(column.len() as i64).to_le_bytes());
+        nodes.extend_from_slice(&0i64.to_le_bytes());
+        add(&mut body, &[]);
+        match column {
+            Column::Utf8(values) => {
+                let mut offsets = 0i32.to_le_bytes().to_vec();
+                let mut data = vec![];
+                for value in values {
+                    data.extend_from_slice(value.as_bytes());
+                    offsets.extend_from_slice(&(data.len() as i32).to_le_bytes());
+                }
+                add(&mut body, &offsets);
+                add(&mut body, &data);
+            }
+            Column::Int64(values) | Column::Timestamp(values) => {
+                let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                add(&mut body, &data);
+            }
+        }
+    }
+    let batch = table(vec![
+        (0, Field::I64(rows as i64)),
+        (1, Field::Offset(Object::Structs(8, columns.len(), nodes))),
+        (
+            2,
+            Field::Offset(Object::Structs(8, buffers.len() / 16, buffers)),
+        ),
+    ]);
+    (batch, body)
+}
+
+// Writes an encapsulated message (`header` of type `header_type`, then `body`) and returns its block in the footer.
+fn message(out: &mut Vec<u8>, header_type: u8, header: Object, body: &[u8]) -> Vec<u8> {
+    let mut metadata = build(&table(vec![
+        (0, Field::I16(VERSION)),
+        (1, Field::U8(header_type)),
+        (2, Field::Offset(header)),
+        (3, Field::I64(body.len() as i64)),
+    ]));
+    pad(&mut metadata, 8);
+    let offset = out.len();
+    out.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    out.extend_from_slice(&(metadata.len() as i32).to_le_bytes());
+    out.extend_from_slice(&metadata);
+    out.extend_from_slice(body);
+    let mut block = (offset as i64).to_le_bytes().to_vec();
+    block.extend_from_slice(&(8 + metadata.len() as i32).to_le_bytes());
+    block.extend_from_slice(&[0; 4]);
+    block.extend_from_slice(&(body.len() as i64).to_le_bytes());
+    block
+}
+
+enum Object {
+    Table(Vec<(u16, Field)>),
+    String(String),
+    Tables(Vec<Object>),
+    /// Their alignment, count and bytes.
+    Structs(usize, usize, Vec<u8>),
+}
+
+enum Field {
+    U8(u8),
+    Bool(bool),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Offset(Object),
+}
+
+impl Field {
+    fn size(&self) -> usize {
+        match self {
+            Self::U8(_) | Self::Bool(_) => 1,
+            Self::I16(_) => 2,
+            Self::I32(_) | Self::Offset(_) => 4,
+            Self::I64(_) => 8,
+        }
+    }
+}
+
+fn table(fields: Vec<(u16, Field)>) -> Object {
+    Object::Table(fields)
+}
+
+// The flatbuffer of `root`.
+fn build(root: &Object) -> Vec<u8> {
+    let mut buf = vec![0; 4];
+    let root = write(&mut buf, root);
+    patch(&mut buf, 0, root);
+    buf
+}
+
+// Writes `object` and what it points to, returning where its offsets should point.
+fn write(buf: &mut Vec<u8>, object: &Object) -> usize {
+    match object {
+        Object::Table(fields) => {
+            let slots = fields
+                .iter()
+                .map(|(slot, _)| *slot as usize + 1)
+                .max()
+                .unwrap_or(0);
+            pad(buf, 2);
+            let vtable = buf.len();
+            let start = align(vtable + 4 + 2 * slots, 4);
+            // the largest fields first, for the least padding.
+            let mut order: Vec<&(u16, Field)> = fields.iter().collect();
+            order.sort_by_key(|(_, field)| std::cmp::Reverse(field.size()));
+            let mut cursor = start + 4;
+            let mut positions = vec![0; slots];
+            for (slot, field) in &order {
+                cursor = align(cursor, field.size());
+                positions[*slot as usize] = cursor;
+                cursor += field.size();
+            }
+            buf.extend_from_slice(&(4 + 2 * slots as u16).to_le_bytes());
+            buf.extend_from_slice(&((cursor - start) as u16).to_le_bytes());
+            for position in &positions {
+                let offset = if *position == 0 { 0 } else { position - start };
+                buf.extend_from_slice(&(offset as u16).to_le_bytes());
+            }
+            buf.resize(cursor, 0);
+            buf[start..start + 4].copy_from_slice(&((start - vtable) as i32).to_le_bytes());
+            for (slot, field) in fields {
+                let at = positions[*slot as usize];
+                let bytes = match field {
+                    Field::U8(v) => vec![*v],
+                    Field::Bool(v) => vec![*v as u8],
+                    Field::I16(v) => v.to_le_bytes().to_vec(),
+                    Field::I32(v) => v.to_le_bytes().to_vec(),
+                    Field::I64(v) => v.to_le_bytes().to_vec(),
+                    Field::Offset(_) => continue,
+                };
+                buf[at..at + bytes.len()].copy_from_slice(&bytes);
+            }
+            for (slot, field) in fields {
+                if let Field::Offset(child) = field {
+                    let target = write(buf, child);
+                    patch(buf, positions[*slot as usize], target);
+                }
+            }
+            start
+        }
+        Object::String(value) => {
+            pad(buf, 4);
+            let start = buf.len();
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value.as_bytes());
+            buf.push(0);
+            start
+        }
+        Object::Tables(tables) => {
+            pad(buf, 4);
+            let start = buf.len();
+            buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+            buf.resize(start + 4 + 4 * tables.len(), 0);
+            for (i, table) in tables.iter().enumerate() {
+                let target = write(buf, table);
+                patch(buf, start + 4 + 4 * i, target);
+            }
+            start
+        }
+        Object::Structs(alignment, count, bytes) => {
+            // the length before the structs, which are aligned.
+            pad(buf, 4);
+            while (buf.len() + 4) % alignment != 0 {
+                buf.push(0);
+            }
+            let start = buf.len();
+            buf.extend_from_slice(&(*count as u32).to_le_bytes());
+            buf.extend_from_slice(bytes);
+            start
+        }
+    }
+}
+
+// Points the offset at `at` to `target`, further in the buffer.
+fn patch(buf: &mut [u8], at: usize, target: usize) {
+    buf[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+fn align(position: usize, alignment: usize) -> usize {
+    (position + alignment - 1) / alignment * alignment
+}
+
+fn pad(buf: &mut Vec<u8>, alignment: usize) {
+    buf.resize(align(buf.len(), alignment), 0);
+}
@@ -0,0 +1,117 @@
+//! What the hooks do with the samples of a thread when the collector thread can't keep up with them, see
+//! [`HeapProfilerGuardBuilder::backpressure`](crate::HeapProfilerGuardBuilder::backpressure), and how often it
+//! happened, see [`Profiler::backpressure`](crate::Profiler::backpressure).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::profiler::Profiler;
+
+/// What a thread flushing its samples does when the queue of the collector thread is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Waits for the collector to make room, spinning for a few microseconds at most without holding the queue: the
+    /// allocating thread mustn't sleep in the allocator, nor hold up the collector (which may itself allocate). The
+    /// samples still without room are dropped like with [`DropNewest`](Self::DropNewest).
+    Block,
+    /// Drops the samples being flushed: the stacks and the totals of the session miss them.
+    DropNewest,
+    /// Drops the oldest batch of the queue to make room.
+    DropOldest,
+    /// Flushes the samples in place if the session isn't busy, otherwise keeps them on the thread until its next
+    /// flush, merging the samples of the same stack so that a thread doesn't pile them up meanwhile.
+    #[default]
+    CoalesceInPlace,
+}
+
+/// How often the queue of the collector thread was full, and what the [`Backpressure`] policy did about it, since the
+/// start of the last session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackpressureCounters {
+    /// The flushes that found the queue full.
+    pub full: u64,
+    /// The ones that waited for room, and how long they waited altogether.
+    pub blocked: u64,
+    pub blocked_for: Duration,
+    /// The samples dropped, and the bytes they allocated.
+    pub dropped_samples: u64,
+    pub dropped_bytes: u64,
+    /// The samples merged into a later one of the same stack.
+    pub coalesced: u64,
+    /// The flushes the allocating threads did themselves.
+    pub flushed_in_place: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    full: AtomicU64,
+    blocked: AtomicU64,
+    blocked_ns: AtomicU64,
+    dropped_samples: AtomicU64,
+    dropped_bytes: AtomicU64,
+    coalesced: AtomicU64,
+    flushed_in_place: AtomicU64,
+}
+
+lazy_static::lazy_static! {
+    static ref COUNTERS: Counters = Default::default();
+}
+
+impl Profiler {
+    /// What the [`Backpressure`] policy of the running (or last) session did so far.
+    pub fn backpressure() -> BackpressureCounters {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        BackpressureCounters {
+            full: get(&COUNTERS.full),
+            blocked: get(&COUNTERS.blocked),
+            blocked_for: Duration::from_nanos(get(&COUNTERS.blocked_ns)),
+            dropped_samples: get(&COUNTERS.dropped_samples),
+            dropped_bytes: get(&COUNTERS.dropped_bytes),
+            coalesced: get(&COUNTERS.coalesced),
+            flushed_in_place: get(&COUNTERS.flushed_in_place),
+        }
+    }
+}
+
+pub(crate) fn reset() {
+    for counter in [
+        &COUNTERS.full,
+        &COUNTERS.blocked,
+        &COUNTERS.blocked_ns,
+        &COUNTERS.dropped_samples,
+        &COUNTERS.dropped_bytes,
+        &COUNTERS.coalesced,
+        &COUNTERS.flushed_in_place,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn full() {
+    COUNTERS.full.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn blocked(waited: Duration) {
+    COUNTERS.blocked.fetch_add(1, Ordering::Relaxed);
+    let waited = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+    COUNTERS.blocked_ns.fetch_add(waited, Ordering::Relaxed);
+}
+
+pub(crate) fn dropped(samples: usize, bytes: i64) {
+    COUNTERS
+        .dropped_samples
+        .fetch_add(samples as u64, Ordering::Relaxed);
+    COUNTERS
+        .dropped_bytes
+        .fetch_add(bytes.max(0) as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn coalesced(samples: usize) {
+    COUNTERS
+        .coalesced
+        .fetch_add(samples as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn flushed_in_place() {
+    COUNTERS.flushed_in_place.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,391 @@
+//! Post-processing of heap profiles written by heappy (or any other pprof producer), so consumers don't need
+//! Go's `pprof` installed.
+
+mod profile;
+mod render;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use heappy::pprof_io::Stacks;
+
+use profile::Result;
+
+const USAGE: &str = "\
+usage: heappy <command> [options] <profile>...
+
+commands:
+  flamegraph <profile>       render an SVG flamegraph
+  folded <profile>           print folded stacks
+  speedscope <profile>       print speedscope JSON
+  top <profile>              print the functions allocating the most
+  diff <base> <profile>      compare two profiles (see --format)
+  symbolize <profile>        resolve a profile recorded with raw addresses, writing a new .pb
+  check                      fail if --current grew more than --max-growth over --baseline
+  serve <profile>            explore a profile in the browser (see --addr)
+  html <profile>             write a standalone HTML page: flamegraph, top functions and their annotated source
+  merge <profile>...         merge profiles (e.g. of several hosts) into one .pb, compressed if -o ends with .gz or .zst
+  console <socket> [cmd]     send a command (stats, top [n], dump <path> [format]) to the console socket of a running
+                             session, or the lines of stdin without one
+
+options:
+  -o, --output <file>        write to <file> instead of stdout, compressed if it ends with .gz or .zst
+  -t, --sample-type <name>   sample type to use, e.g. inuse_space (default: the profile's default)
+  -n, --nodes <n>            number of functions in top reports (default: 20)
+      --format <format>      diff output: top, folded or flamegraph (default: top); check output: text, or
+                             annotations for the functions that grew as JSON lines, for CI (default: text)
+      --baseline <profile>   baseline profile for check
+      --current <profile>    profile to check against the baseline
+      --max-growth <growth>  allowed growth for check, relative (5%) or absolute (10MiB, 1000)
+      --addr <addr>          address for serve to listen on (default: 127.0.0.1:6060)
+      --components <file>    also show top's totals by component, from `<symbol prefix> = <component>` lines
+  -d, --debug-dir <dir>      extra directory with binaries and debuginfo for symbolize (repeatable)
+      --debuginfod           fetch the debuginfo symbolize can't find from the servers of DEBUGINFOD_URLS
+  -h, --help                 print this help
+";
+
+#[derive(Default)]
+struct Args {
+    command: String,
+    profiles: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    sample_type: Option<String>,
+    nodes: Option<usize>,
+    format: Option<String>,
+    debug_dirs: Vec<PathBuf>,
+    debuginfod: bool,
+    baseline: Option<PathBuf>,
+    addr: Option<String>,
+    current: Option<PathBuf>,
+    components: Option<PathBuf>,
+    thresholds: heappy::Thresholds,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "-o" | "--output" => parsed.output = Some(value(&arg)?.into()),
+                "-t" | "--sample-type" => parsed.sample_type = Some(value(&arg)?),
+                "-n" | "--nodes" => parsed.nodes = Some(value(&arg)?.parse()?),
+                "--format" => parsed.format = Some(value(&arg)?),
+                "-d" | "--debug-dir" => parsed.debug_dirs.push(value(&arg)?.into()),
+                "--debuginfod" => parsed.debuginfod = true,
+                "--addr" => parsed.addr = Some(value(&arg)?),
+                "--baseline" => parsed.baseline = Some(value(&arg)?.into()),
+                "--current" => parsed.current = Some(value(&arg)?.into()),
+                "--components" => parsed.components = Some(value(&arg)?.into()),
+                "--max-growth" => {
+                    let growth = value(&arg)?;
+                    match growth.strip_suffix('%') {
+                        Some(percent) => {
+                            parsed.thresholds.max_growth = Some(percent.parse::<f64>()? / 100.0)
+                        }
+                        None => {
+                            parsed.thresholds.max_growth_abs = Some(profile::parse_size(&growth)?)
+                        }
+                    }
+                }
+                flag if flag.starts_with('-') && flag != "-" => {
+                    return Err(format!("unknown option {}", flag).into())
+                }
+                _ if parsed.command.is_empty() => parsed.command = arg,
+                _ => parsed.profiles.push(arg.into()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn profile(&self, count: usize) -> Result<&[PathBuf]> {
+        if self.profiles.len() != count {
+            return Err(format!(
+                "{} expects {} profile(s), got {}",
+                self.command,
+                count,
+                self.profiles.len()
+            )
+            .into());
+        }
+        Ok(&self.profiles)
+    }
+
+    fn load(&self, path: &Path) -> Result<Stacks> {
+        profile::load_stacks(path, self.sample_type.as_deref())
+    }
+
+    fn write_profile(&self, profile: &heappy::protos::Profile) -> Result<()> {
+        match &self.output {
+            Some(path) => heappy::pprof_io::write(path, profile)?,
+            None => {
+                let mut w = std::io::stdout().lock();
+                w.write_all(&heappy::pprof_io::encode(profile, false)?)?;
+                w.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn output(&self) -> Result<Output> {
+        Ok(match &self.output {
+            Some(path) => Output::File(heappy::CompressedWriter::new(
+                std::io::BufWriter::new(std::fs::File::create(path)?),
+                heappy::Compression::from_path(path),
+            )),
+            None => Output::Stdout(std::io::stdout().lock()),
+        })
+    }
+}
+
+// Where a command writes, which it has to finish: writing the end of the compressed stream and flushing can fail,
+// e.g. of a full disk, and dropped, its errors would go unnoticed.
+enum Output {
+    File(heappy::CompressedWriter<std::io::BufWriter<std::fs::File>>),
+    Stdout(std::io::StdoutLock<'static>),
+}
+
+impl Output {
+    fn finish(self) -> Result<()> {
+        match self {
+            Output::File(w) => w.finish()?.flush()?,
+            Output::Stdout(mut w) => w.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::File(w) => w.write(buf),
+            Output::Stdout(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::File(w) => w.flush(),
+            Output::Stdout(w) => w.flush(),
+        }
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let nodes = args.nodes.unwrap_or(20);
+    match args.command.as_str() {
+        "flamegraph" => {
+            let stacks = args.load(&args.profile(1)?[0])?;
+            let mut w = args.output()?;
+            render::flamegraph(&stacks, &mut w)?;
+            w.finish()
+        }
+        "folded" => {
+            let stacks = args.load(&args.profile(1)?[0])?;
+            let mut w = args.output()?;
+            render::folded(&stacks, &mut w)?;
+            w.finish()
+        }
+        "speedscope" => {
+            let path = &args.profile(1)?[0];
+            let stacks = args.load(path)?;
+            let mut w = args.output()?;
+            render::speedscope(&stacks, &path.display().to_string(), &mut w)?;
+            w.finish()
+        }
+        "top" => {
+            let stacks = args.load(&args.profile(1)?[0])?;
+            let components = match &args.components {
+                Some(path) => Some(
+                    std::fs::read_to_string(path)?
+                        .parse::<heappy::Components>()
+                        .map_err(|e| format!("{}: {}", path.display(), e))?,
+                ),
+                None => None,
+            };
+            let mut w = args.output()?;
+            render::top(&stacks, nodes, components.as_ref(), &mut w)?;
+            w.finish()
+        }
+        "diff" => {
+            let paths = args.profile(2)?;
+            let base = args.load(&paths[0])?;
+            let current = args.load(&paths[1])?;
+            let format = args.format.as_deref().unwrap_or("top");
+            if !["top", "folded", "flamegraph"].contains(&format) {
+                return Err(format!("unknown diff format {:?}", format).into());
+            }
+            let mut w = args.output()?;
+            match format {
+                "top" => render::diff_top(&base, &current, nodes, &mut w)?,
+                "folded" => render::diff_folded(&base, &current, &mut w)?,
+                _ => render::diff_flamegraph(&base, &current, &mut w)?,
+            }
+            w.finish()
+        }
+        "symbolize" => {
+            let mut profile = profile::read_profile(&args.profile(1)?[0])?;
+            let symbolizer = args
+                .debug_dirs
+                .iter()
+                .fold(heappy::symbolize::Symbolizer::new(), |s, dir| {
+                    s.search_path(dir)
+                })
+                .debuginfod(args.debuginfod);
+            let summary = symbolizer.symbolize(&mut profile);
+            for missing in &summary.missing {
+                eprintln!("heappy: could not symbolize {}", missing);
+            }
+            args.write_profile(&profile)
+        }
+        "check" => {
+            let (Some(baseline), Some(current)) = (&args.baseline, &args.current) else {
+                return Err("check needs --baseline and --current".into());
+            };
+            if args.thresholds.max_growth.is_none() && args.thresholds.max_growth_abs.is_none() {
+                return Err("check needs --max-growth".into());
+            }
+            let annotate = match args.format.as_deref().unwrap_or("text") {
+                "text" => false,
+                "annotations" => true,
+                other => return Err(format!("unknown check format {:?}", other).into()),
+            };
+            let thresholds = heappy::Thresholds {
+                sample_type: args.sample_type.clone(),
+                ..args.thresholds.clone()
+            };
+            let (baseline_profile, current_profile) = (
+                profile::read_profile(baseline)?,
+                profile::read_profile(current)?,
+            );
+            let result =
+                heappy::check_regression(&baseline_profile, &current_profile, &thresholds)?;
+            let mut w = args.output()?;
+            if annotate {
+                // the output is for the CI to parse, the verdict goes to stderr.
+                eprintln!("heappy: {}", result);
+                let annotations =
+                    heappy::annotations(&baseline_profile, &current_profile, &thresholds)?;
+                heappy::write_annotations(&mut w, &annotations)?;
+                w.finish()?;
+                if result.regressed {
+                    return Err("memory regression".into());
+                }
+                return Ok(());
+            }
+            writeln!(w, "{}", result)?;
+            if !result.regressed {
+                return w.finish();
+            }
+            let sample_type = Some(result.sample_type.as_str());
+            render::diff_top(
+                &profile::load_stacks(baseline, sample_type)?,
+                &profile::load_stacks(current, sample_type)?,
+                nodes,
+                &mut w,
+            )?;
+            w.finish()?;
+            Err("memory regression".into())
+        }
+        "serve" => {
+            let profile = profile::read_profile(&args.profile(1)?[0])?;
+            let addr = args.addr.as_deref().unwrap_or("127.0.0.1:6060");
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            eprintln!("heappy: serving on http://{}", addr);
+            runtime.block_on(heappy::serve::serve(profile, addr))?;
+            Ok(())
+        }
+        "html" => {
+            let profile = profile::read_profile(&args.profile(1)?[0])?;
+            let html = heappy::serve::html(&profile, args.sample_type.as_deref())?;
+            let mut w = args.output()?;
+            w.write_all(html.as_bytes())?;
+            w.finish()
+        }
+        "merge" => {
+            if args.profiles.is_empty() {
+                return Err("merge expects at least one profile".into());
+            }
+            let profiles = args
+                .profiles
+                .iter()
+                .map(|path| profile::read_profile(path))
+                .collect::<Result<Vec<_>>>()?;
+            args.write_profile(&heappy::pprof_io::merge(&profiles)?)
+        }
+        "console" => {
+            let Some((socket, command)) = args.profiles.split_first() else {
+                return Err("console expects a socket".into());
+            };
+            let command: Vec<_> = command
+                .iter()
+                .map(|arg| arg.display().to_string())
+                .collect();
+            let mut w = args.output()?;
+            console(socket, &command.join(" "), &mut w)?;
+            w.finish()
+        }
+        "" => Err(format!("missing command\n\n{}", USAGE).into()),
+        other => Err(format!("unknown command {:?}\n\n{}", other, USAGE).into()),
+    }
+}
+
+// Sends `command` (or each line of stdin, if empty) to the console at `socket`, writing the answers to `w`.
+fn console(socket: &Path, command: &str, mut w: impl Write) -> Result<()> {
+    use std::io::BufRead;
+
+    let stream = std::os::unix::net::UnixStream::connect(socket)
+        .map_err(|e| format!("cannot connect to {}: {}", socket.display(), e))?;
+    let mut answers = std::io::BufReader::new(stream.try_clone()?);
+    let mut send = |command: &str| -> Result<()> {
+        writeln!(&stream, "{}", command)?;
+        // an answer ends with an empty line.
+        loop {
+            let mut line = String::new();
+            if answers.read_line(&mut line)? == 0 {
+                return Err("the session closed the console".into());
+            }
+            if line == "\n" {
+                break;
+            }
+            w.write_all(line.as_bytes())?;
+        }
+        w.flush()?;
+        Ok(())
+    };
+    if !command.is_empty() {
+        return send(command);
+    }
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            send(&line)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("heappy: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        let broken_pipe = e
+            .downcast_ref::<std::io::Error>()
+            .map_or(false, |e| e.kind() == std::io::ErrorKind::BrokenPipe);
+        if !broken_pipe {
+            eprintln!("heappy: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
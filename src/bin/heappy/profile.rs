@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use heappy::pprof_io::Stacks;
+use heappy::protos::Profile;
+
+pub type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
+
+pub fn load_stacks(path: &Path, sample_type: Option<&str>) -> Result<Stacks> {
+    let profile = read_profile(path)?;
+    Stacks::from_profile(&profile, sample_type)
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+pub fn read_profile(path: &Path) -> Result<Profile> {
+    heappy::pprof_io::read(path).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Parses a plain number or a size like `10MiB` (or `10M`, the units all powers of 1024).
+pub fn parse_size(size: &str) -> Result<i64> {
+    const UNITS: [(&str, i64); 10] = [
+        ("TiB", 1 << 40),
+        ("GiB", 1 << 30),
+        ("MiB", 1 << 20),
+        ("KiB", 1 << 10),
+        ("T", 1 << 40),
+        ("G", 1 << 30),
+        ("M", 1 << 20),
+        ("K", 1 << 10),
+        ("k", 1 << 10),
+        ("B", 1),
+    ];
+    let (number, scale) = UNITS
+        .iter()
+        .find_map(|(unit, scale)| size.strip_suffix(unit).map(|number| (number, *scale)))
+        .unwrap_or((size, 1));
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", size))?;
+    let bytes = number * scale as f64;
+    // the cast would silently clamp what doesn't fit.
+    if !(0.0..i64::MAX as f64).contains(&bytes) {
+        return Err(format!("invalid size {:?}: out of range", size).into());
+    }
+    Ok(bytes as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("12B").unwrap(), 12);
+        assert_eq!(parse_size("1.5KiB").unwrap(), 1536);
+        assert_eq!(parse_size("1k").unwrap(), 1 << 10);
+        assert_eq!(parse_size("2K").unwrap(), 2 << 10);
+        assert_eq!(parse_size("10M").unwrap(), 10 << 20);
+        assert_eq!(parse_size("10MiB").unwrap(), 10 << 20);
+        assert_eq!(parse_size("3G").unwrap(), 3 << 30);
+        assert_eq!(parse_size(" 1 TiB").unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn bad_sizes() {
+        for size in ["", "MiB", "ten", "10 MB", "10%", "-1KiB", "NaN", "inf"] {
+            assert!(parse_size(size).is_err(), "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn size_overflow() {
+        assert!(parse_size("8388608T").is_err());
+        assert!(parse_size("9223372036854775807").is_err());
+        assert!(parse_size("1e30").is_err());
+        assert_eq!(parse_size("8388607T").unwrap(), 8388607 << 40);
+    }
+}
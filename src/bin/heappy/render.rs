@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use heappy::pprof_io::Stacks;
+use heappy::Components;
+
+use crate::profile::Result;
+
+pub fn folded<W: Write>(stacks: &Stacks, mut w: W) -> Result<()> {
+    for line in stacks.folded() {
+        writeln!(w, "{}", line)?;
+    }
+    Ok(())
+}
+
+pub fn flamegraph<W: Write>(stacks: &Stacks, w: W) -> Result<()> {
+    // the flamegraph can't draw negative widths.
+    let lines: Vec<String> = stacks
+        .counts
+        .iter()
+        .filter(|(_, v)| **v > 0)
+        .map(|(stack, value)| format!("{} {}", stack.join(";"), value))
+        .collect();
+    render_flamegraph(&stacks.sample_type, &stacks.unit, &lines, w)
+}
+
+fn render_flamegraph<W: Write>(title: &str, unit: &str, lines: &[String], w: W) -> Result<()> {
+    let mut options = pprof::flamegraph::Options::default();
+    options.title = title.to_string();
+    options.count_name = unit.to_string();
+    options.colors =
+        pprof::flamegraph::color::Palette::Basic(pprof::flamegraph::color::BasicPalette::Mem);
+    pprof::flamegraph::from_lines(&mut options, lines.iter().map(String::as_str), w)?;
+    Ok(())
+}
+
+/// Writes the stacks in speedscope's "sampled" file format, see https://www.speedscope.app/file-format-schema.json.
+pub fn speedscope<W: Write>(stacks: &Stacks, name: &str, mut w: W) -> Result<()> {
+    let mut frames = vec![];
+    let mut frame_idx = HashMap::new();
+    let mut samples = vec![];
+    let mut weights = vec![];
+    let mut entries: Vec<_> = stacks.counts.iter().filter(|(_, v)| **v > 0).collect();
+    entries.sort();
+    for (stack, value) in entries {
+        let sample: Vec<String> = stack
+            .iter()
+            .map(|name| {
+                let idx = *frame_idx.entry(name.as_str()).or_insert_with(|| {
+                    frames.push(format!("{{\"name\":{}}}", json_string(name)));
+                    frames.len() - 1
+                });
+                idx.to_string()
+            })
+            .collect();
+        samples.push(format!("[{}]", sample.join(",")));
+        weights.push(value.to_string());
+    }
+    let unit = if stacks.unit == "bytes" {
+        "bytes"
+    } else {
+        "none"
+    };
+
+    write!(
+        w,
+        concat!(
+            "{{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",",
+            "\"exporter\":\"heappy\",\"name\":{name},",
+            "\"shared\":{{\"frames\":[{frames}]}},",
+            "\"profiles\":[{{\"type\":\"sampled\",\"name\":{profile},\"unit\":\"{unit}\",",
+            "\"startValue\":0,\"endValue\":{total},\"samples\":[{samples}],\"weights\":[{weights}]}}]}}\n"
+        ),
+        name = json_string(name),
+        frames = frames.join(","),
+        profile = json_string(&stacks.sample_type),
+        unit = unit,
+        total = weights.iter().map(|w| w.parse::<i64>().unwrap()).sum::<i64>(),
+        samples = samples.join(","),
+        weights = weights.join(","),
+    )?;
+    Ok(())
+}
+
+pub fn top<W: Write>(
+    stacks: &Stacks,
+    nodes: usize,
+    components: Option<&Components>,
+    mut w: W,
+) -> Result<()> {
+    let total = stacks.total();
+    let mut funcs: Vec<_> = stacks.flat_and_cum().into_iter().collect();
+    funcs.sort_by(|a, b| (b.1 .0, b.1 .1, a.0).cmp(&(a.1 .0, a.1 .1, b.0)));
+
+    let percent = |v: i64| {
+        if total == 0 {
+            0.0
+        } else {
+            v as f64 * 100.0 / total as f64
+        }
+    };
+
+    writeln!(
+        w,
+        "Showing top {} of {} functions, total {} ({})",
+        nodes.min(funcs.len()),
+        funcs.len(),
+        stacks.format_value(total),
+        stacks.sample_type
+    )?;
+    if let Some(components) = components {
+        let mut totals: HashMap<Option<&str>, i64> = HashMap::new();
+        for (stack, value) in &stacks.counts {
+            let component = components.attribute(stack.iter().rev().map(String::as_str));
+            *totals.entry(component).or_default() += value;
+        }
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+        for (component, value) in totals {
+            writeln!(
+                w,
+                "{:>12} {:>6.2}%  {}",
+                stacks.format_value(value),
+                percent(value),
+                component.unwrap_or("(other)")
+            )?;
+        }
+        writeln!(w)?;
+    }
+    writeln!(
+        w,
+        "{:>12} {:>7} {:>7} {:>12} {:>7}",
+        "flat", "flat%", "sum%", "cum", "cum%"
+    )?;
+    let mut sum = 0;
+    for (name, (flat, cum)) in funcs.into_iter().take(nodes) {
+        sum += flat;
+        writeln!(
+            w,
+            "{:>12} {:>6.2}% {:>6.2}% {:>12} {:>6.2}%  {}",
+            stacks.format_value(flat),
+            percent(flat),
+            percent(sum),
+            stacks.format_value(cum),
+            percent(cum),
+            name
+        )?;
+    }
+    Ok(())
+}
+
+pub fn diff_top<W: Write>(base: &Stacks, current: &Stacks, nodes: usize, mut w: W) -> Result<()> {
+    let base_funcs = base.flat_and_cum();
+    let current_funcs = current.flat_and_cum();
+    let names: HashSet<&str> = base_funcs
+        .keys()
+        .chain(current_funcs.keys())
+        .copied()
+        .collect();
+    let mut rows: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let (base_flat, base_cum) = base_funcs.get(name).copied().unwrap_or_default();
+            let (flat, cum) = current_funcs.get(name).copied().unwrap_or_default();
+            (name, flat - base_flat, cum - base_cum)
+        })
+        .filter(|(_, flat, cum)| *flat != 0 || *cum != 0)
+        .collect();
+    rows.sort_by(|a, b| (b.1.abs(), b.2.abs(), a.0).cmp(&(a.1.abs(), a.2.abs(), b.0)));
+
+    writeln!(
+        w,
+        "Showing top {} of {} changed functions, total {} -> {} ({})",
+        nodes.min(rows.len()),
+        rows.len(),
+        base.format_value(base.total()),
+        current.format_value(current.total()),
+        current.sample_type
+    )?;
+    writeln!(w, "{:>12} {:>12}", "flat delta", "cum delta")?;
+    for (name, flat, cum) in rows.into_iter().take(nodes) {
+        writeln!(
+            w,
+            "{:>12} {:>12}  {}",
+            current.format_value(flat),
+            current.format_value(cum),
+            name
+        )?;
+    }
+    Ok(())
+}
+
+// Differential folded lines (`stack base current`), as understood by flamegraph.pl and inferno.
+fn diff_lines(base: &Stacks, current: &Stacks) -> Vec<String> {
+    let stacks: HashSet<&Vec<String>> = base.counts.keys().chain(current.counts.keys()).collect();
+    let mut lines: Vec<String> = stacks
+        .into_iter()
+        .map(|stack| {
+            let before = base.counts.get(stack).copied().unwrap_or_default().max(0);
+            let after = current
+                .counts
+                .get(stack)
+                .copied()
+                .unwrap_or_default()
+                .max(0);
+            (stack, before, after)
+        })
+        .filter(|(_, before, after)| *before != 0 || *after != 0)
+        .map(|(stack, before, after)| format!("{} {} {}", stack.join(";"), before, after))
+        .collect();
+    lines.sort();
+    lines
+}
+
+pub fn diff_folded<W: Write>(base: &Stacks, current: &Stacks, mut w: W) -> Result<()> {
+    for line in diff_lines(base, current) {
+        writeln!(w, "{}", line)?;
+    }
+    Ok(())
+}
+
+pub fn diff_flamegraph<W: Write>(base: &Stacks, current: &Stacks, w: W) -> Result<()> {
+    let title = format!("{} (diff)", current.sample_type);
+    render_flamegraph(&title, &current.unit, &diff_lines(base, current), w)
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
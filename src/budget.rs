@@ -0,0 +1,106 @@
+//! The budget of the profiler's own memory, see
+//! [`HeapProfilerGuardBuilder::memory_budget`](crate::HeapProfilerGuardBuilder::memory_budget): the stacks of the
+//! session, the live allocations it tracks, the samples queued for the collector thread and the buffers of the
+//! threads, by an estimate made at most every 100ms, by the collector thread or a thread that just sampled, which
+//! [`Profiler::footprint`](crate::Profiler::footprint) tells.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// How often the footprint is estimated, at most.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the session does when the profiler's memory outgrows its budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Doubles the sampling period each time the footprint grows by another eighth of the budget past it, so that the
+    /// session finds new stacks more slowly, and stops like [`Stop`](Self::Stop) at twice the budget.
+    #[default]
+    Coarsen,
+    /// Stops recording: the session keeps what it recorded so far, and its reports, flagged (see
+    /// [`HeapReport::over_budget`](crate::HeapReport::over_budget)), miss what's allocated afterwards.
+    Stop,
+}
+
+// What the session has to do about its footprint.
+pub(crate) enum Step {
+    Coarsen,
+    Stop,
+}
+
+struct Budget {
+    bytes: usize,
+    policy: OverBudget,
+    // the footprint past which the period doubles next.
+    limit: usize,
+    checked: Option<Instant>,
+}
+
+static BUDGET: spin::Mutex<Option<Budget>> = spin::Mutex::new(None);
+static FOOTPRINT: AtomicUsize = AtomicUsize::new(0);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+// Sets the budget of a new session.
+pub(crate) fn start(budget: Option<(usize, OverBudget)>) {
+    *BUDGET.lock() = budget.map(|(bytes, policy)| Budget {
+        bytes,
+        policy,
+        limit: bytes,
+        checked: None,
+    });
+    FOOTPRINT.store(0, Ordering::Relaxed);
+    STOPPED.store(false, Ordering::Relaxed);
+}
+
+// Whether the session stopped recording over its budget.
+pub(crate) fn stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn footprint() -> usize {
+    FOOTPRINT.load(Ordering::Relaxed)
+}
+
+// Whether the footprint is due for an estimate.
+pub(crate) fn due() -> bool {
+    if stopped() {
+        return false;
+    }
+    let mut budget = BUDGET.lock();
+    let Some(budget) = budget.as_mut() else {
+        return false;
+    };
+    let now = Instant::now();
+    if budget
+        .checked
+        .map_or(false, |checked| now.duration_since(checked) < INTERVAL)
+    {
+        return false;
+    }
+    budget.checked = Some(now);
+    true
+}
+
+// What to do about the estimated `footprint`.
+pub(crate) fn step(footprint: usize) -> Option<Step> {
+    FOOTPRINT.store(footprint, Ordering::Relaxed);
+    let mut budget = BUDGET.lock();
+    let budget = budget.as_mut()?;
+    let step = match budget.policy {
+        OverBudget::Coarsen if footprint > budget.bytes.saturating_mul(2) => Step::Stop,
+        OverBudget::Coarsen if footprint > budget.limit => Step::Coarsen,
+        OverBudget::Stop if footprint > budget.bytes => Step::Stop,
+        _ => return None,
+    };
+    if let Step::Stop = step {
+        STOPPED.store(true, Ordering::Relaxed);
+    }
+    Some(step)
+}
+
+// The session coarsened its sampling at `footprint`, it does again another eighth of the budget past it.
+pub(crate) fn coarsened(footprint: usize) {
+    if let Some(budget) = BUDGET.lock().as_mut() {
+        budget.limit = footprint.saturating_add(budget.bytes / 8);
+    }
+}
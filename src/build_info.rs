@@ -0,0 +1,72 @@
+//! What built the profiled process, see
+//! [`HeapProfilerGuardBuilder::service_name`](crate::HeapProfilerGuardBuilder::service_name),
+//! [`version`](crate::HeapProfilerGuardBuilder::version) and [`git_sha`](crate::HeapProfilerGuardBuilder::git_sha):
+//! stamped into the pprof reports, as comments (`pprof -comments`) and as labels of every sample, so that a profile
+//! file tells which build it's of, and the merged profiles of several builds can be told apart
+//! (`pprof -tagfocus version=1.4.2`).
+
+use crate::protos;
+
+/// The label of the service name.
+pub const SERVICE_LABEL: &str = "service";
+/// The label of the version.
+pub const VERSION_LABEL: &str = "version";
+/// The label of the git commit.
+pub const GIT_SHA_LABEL: &str = "git_sha";
+
+/// The service, version and git commit of the profiled build, each left out of the reports if unset.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub service: Option<String>,
+    pub version: Option<String>,
+    pub git_sha: Option<String>,
+}
+
+impl BuildInfo {
+    /// The labels of the build, the set ones.
+    pub fn labels(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            (SERVICE_LABEL, &self.service),
+            (VERSION_LABEL, &self.version),
+            (GIT_SHA_LABEL, &self.git_sha),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.as_deref()?)))
+    }
+
+    // Adds the build to the comments of `profile`, and to the labels of its samples, but the ones that have a label of
+    // their own of the same key.
+    pub(crate) fn stamp(&self, profile: &mut protos::Profile) {
+        let mut intern = |s: &str| -> i64 {
+            match profile.string_table.iter().position(|t| t == s) {
+                Some(i) => i as i64,
+                None => {
+                    profile.string_table.push(s.to_owned());
+                    profile.string_table.len() as i64 - 1
+                }
+            }
+        };
+        let labels: Vec<_> = self
+            .labels()
+            .map(|(key, value)| {
+                (
+                    intern(key),
+                    intern(value),
+                    intern(&format!("{}={}", key, value)),
+                )
+            })
+            .collect();
+        for &(key, value, comment) in &labels {
+            profile.comment.push(comment);
+            for sample in &mut profile.sample {
+                if sample.label.iter().all(|label| label.key != key) {
+                    sample.label.push(protos::Label {
+                        key,
+                        str: value,
+                        ..protos::Label::default()
+                    });
+                }
+            }
+        }
+    }
+}
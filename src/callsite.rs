@@ -0,0 +1,140 @@
+//! The allocation call site, the first frame past the allocator: alone, for sessions cheap enough to leave always on
+//! (see [`HeapProfilerGuardBuilder::call_sites_only`](crate::HeapProfilerGuardBuilder::call_sites_only)) and as the
+//! leaf of a symbolized stack.
+
+#[cfg(feature = "enable_heap_profiler")]
+use std::collections::HashMap;
+
+#[cfg(feature = "enable_heap_profiler")]
+use backtrace::Frame;
+
+use crate::unwinder::StackFrame;
+
+// The hooks, as symbolized.
+pub(crate) const HOOKS: &[&str] = &["malloc", "calloc", "realloc", "aligned_alloc", "free"];
+
+// The physical functions between the hooks and the code asking for memory, e.g. `__rust_alloc` or the growth of a
+// `Vec` when it isn't inlined.
+const ALLOCATOR_FUNCTIONS: &[&str] = &[
+    "__rust_",
+    "__rdl_",
+    "__rg_",
+    "alloc::alloc::",
+    "alloc::raw_vec::",
+    "<alloc::alloc::Global as ",
+    "<std::alloc::System as ",
+    "std::alloc::",
+    "std::sys::",
+    "core::alloc::",
+    "operator new",
+];
+
+#[cfg(feature = "enable_heap_profiler")]
+lazy_static::lazy_static! {
+    // whether a function is one of the allocator's, by start address: each one is only symbolized once.
+    static ref ALLOCATOR: spin::Mutex<HashMap<usize, bool>> = Default::default();
+}
+
+// Where the stack being unwound is at.
+#[cfg(feature = "enable_heap_profiler")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Walk {
+    // in the profiler, up to the hook.
+    Profiler,
+    // past the hook, in the allocator.
+    Allocator,
+}
+
+#[cfg(feature = "enable_heap_profiler")]
+impl Walk {
+    // Folds `frame` in, returning whether it's the call site.
+    pub(crate) fn call_site(&mut self, frame: &Frame) -> bool {
+        let function = frame.symbol_address() as usize;
+        match self {
+            Walk::Profiler => {
+                if crate::hook::entry_points().contains(&function) {
+                    *self = Walk::Allocator;
+                }
+                false
+            }
+            Walk::Allocator => !is_allocator(frame, function),
+        }
+    }
+}
+
+// The start of the function of `frame`, by the executable's symbols if the unwinder doesn't tell and they're to be
+// `resolved` (loaded beforehand, the hooks can't), 0 if there's no telling.
+pub(crate) fn function(frame: &StackFrame, resolved: bool) -> usize {
+    match frame.function {
+        0 if resolved => crate::executable::resolve(frame.ip.saturating_sub(1))
+            .map_or(0, |(_, function)| function),
+        function => function,
+    }
+}
+
+fn is_allocator_function(name: &str) -> bool {
+    ALLOCATOR_FUNCTIONS
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+// The innermost function of a symbolized stack that isn't the profiler's or the allocator's, or the innermost of all
+// if the stack has no hook (e.g. a call site alone).
+pub(crate) fn leaf(frames: &pprof::Frames) -> Option<&pprof::Symbol> {
+    let names = |frame: &[pprof::Symbol]| frame.iter().map(|s| s.name()).collect::<Vec<_>>();
+    let past_hook = frames
+        .frames
+        .iter()
+        .rposition(|frame| {
+            names(frame)
+                .iter()
+                .any(|name| HOOKS.contains(&name.as_str()))
+        })
+        .map_or(0, |hook| hook + 1);
+    frames.frames[past_hook..]
+        .iter()
+        .find(|frame| {
+            // the physical function is the last one, the others are inlined into it.
+            frame
+                .last()
+                .map_or(false, |physical| !is_allocator_function(&physical.name()))
+        })
+        .and_then(|frame| frame.last())
+}
+
+// The functions of a symbolized stack, the innermost first, past the profiler's own (the hook, or `track_allocated`
+// when it's inlined into it, and what they call).
+pub(crate) fn past_hook(frames: &pprof::Frames) -> Vec<&pprof::Symbol> {
+    let symbols: Vec<_> = frames.frames.iter().flatten().collect();
+    let past_hook = symbols
+        .iter()
+        .rposition(|symbol| {
+            let name = symbol.name();
+            name.ends_with("::Profiler::track_allocated") || HOOKS.contains(&name.as_str())
+        })
+        .map_or(0, |hook| hook + 1);
+    symbols[past_hook..].to_vec()
+}
+
+// Must be called untracked: symbolizing allocates.
+#[cfg(feature = "enable_heap_profiler")]
+fn is_allocator(frame: &Frame, function: usize) -> bool {
+    if let Some(&allocator) = ALLOCATOR.lock().get(&function) {
+        return allocator;
+    }
+    // the lock isn't held while symbolizing, it takes long the first time.
+    let mut name = None;
+    unsafe {
+        // the inlined functions come first, the physical one last.
+        backtrace::resolve_frame_unsynchronized(frame, |symbol| {
+            name = symbol.name().map(|name| format!("{:#}", name));
+        });
+    }
+    let name = name.or_else(|| {
+        let (name, _) = crate::executable::resolve((frame.ip() as usize).saturating_sub(1))?;
+        Some(format!("{:#}", backtrace::SymbolName::new(name.as_bytes())))
+    });
+    let allocator = name.map_or(false, |name| is_allocator_function(&name));
+    ALLOCATOR.lock().insert(function, allocator);
+    allocator
+}
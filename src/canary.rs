@@ -0,0 +1,163 @@
+//! A debugging aid for buffer overflows: some allocations are padded with canary bytes, checked when they're freed
+//! or reallocated. A corrupted canary is reported right away with the stack that allocated the buffer, as an error
+//! through `tracing` with the `tracing` feature and on stderr otherwise.
+//!
+//! ```ignore
+//! let _guard = heappy::HeapProfilerGuardBuilder::default()
+//!     .canaries(16)
+//!     .build()
+//!     .await?;
+//! // ... exercise the code, then
+//! assert!(heappy::canary::corruptions().is_empty());
+//! ```
+//!
+//! Only writes right past the end of the requested size are caught, and only once the buffer is freed.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::{c_void, size_t};
+
+use crate::labels::Labels;
+use crate::profiler::{Profiler, StackKey, MAX_DEPTH};
+
+const CANARY: u8 = 0xca;
+const CANARY_LEN: usize = 16;
+
+// 0 when not padding new allocations.
+static EVERY: AtomicUsize = AtomicUsize::new(0);
+// Padded allocations not freed yet, so that frees don't need to take the lock when there are none.
+static PADDED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // requested size and allocating stack, by address.
+    static ref CANARIES: spin::Mutex<HashMap<usize, (usize, StackKey<MAX_DEPTH>)>> = Default::default();
+    static ref CORRUPTIONS: spin::Mutex<Vec<(StackKey<MAX_DEPTH>, usize, usize)>> = Default::default();
+}
+
+thread_local!(static COUNTDOWN: Cell<usize> = Cell::new(0));
+
+/// A buffer whose canary was overwritten.
+#[derive(Clone, Debug)]
+pub struct Corruption {
+    /// Where the buffer was allocated.
+    pub frames: pprof::Frames,
+    pub labels: Labels,
+    /// The size that was asked for.
+    pub size: usize,
+    /// Offset of the first overwritten byte past the end of the buffer.
+    pub offset: usize,
+}
+
+/// The corruptions detected since the start of the last session that pads allocations, in the order they were
+/// detected.
+pub fn corruptions() -> Vec<Corruption> {
+    let corruptions = Profiler::untracked(|| CORRUPTIONS.lock().clone()).unwrap_or_default();
+    corruptions
+        .into_iter()
+        .map(|(key, size, offset)| Corruption {
+            frames: key.frames.into(),
+            labels: key.labels,
+            size,
+            offset,
+        })
+        .collect()
+}
+
+// Pads one allocation every `every` of each thread from now on, or none if 0.
+pub(crate) fn configure(every: usize) {
+    if every > 0 {
+        let previous = Profiler::untracked(|| std::mem::take(&mut *CORRUPTIONS.lock()));
+        std::mem::drop(previous);
+    }
+    EVERY.store(every, Ordering::Relaxed);
+}
+
+// Whether the next allocation of this thread is due for a canary.
+fn due() -> bool {
+    let every = EVERY.load(Ordering::Relaxed);
+    if every == 0 {
+        return false;
+    }
+    COUNTDOWN
+        .try_with(|countdown| match countdown.get() {
+            0 => {
+                countdown.set(every - 1);
+                true
+            }
+            n => {
+                countdown.set(n - 1);
+                false
+            }
+        })
+        .unwrap_or(false)
+}
+
+// Allocates `size` bytes followed by a canary if this allocation is due for one. `alloc` is called with the padded
+// size.
+pub(crate) unsafe fn alloc(
+    size: size_t,
+    alloc: impl FnOnce(size_t) -> *mut c_void,
+) -> Option<*mut c_void> {
+    if !due() {
+        return None;
+    }
+    let padded = size.checked_add(CANARY_LEN)?;
+    // the profiler's own allocations (e.g. growing the map) must not be padded, nor recorded.
+    Profiler::untracked(|| {
+        let res = alloc(padded);
+        if res.is_null() {
+            return res;
+        }
+        std::ptr::write_bytes((res as *mut u8).add(size), CANARY, CANARY_LEN);
+        let key = StackKey::capture();
+        CANARIES.lock().insert(res as usize, (size, key));
+        PADDED.fetch_add(1, Ordering::Relaxed);
+        res
+    })
+}
+
+// The size that was asked for, if `ptr` is padded: the canary isn't usable.
+pub(crate) unsafe fn usable_size(ptr: *const c_void) -> Option<size_t> {
+    if PADDED.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    Profiler::untracked(|| CANARIES.lock().get(&(ptr as usize)).map(|(size, _)| *size)).flatten()
+}
+
+// Checks the canary of `ptr` if it has one, before it's freed or reallocated (a reallocated buffer isn't padded).
+pub(crate) unsafe fn check(ptr: *mut c_void) {
+    if PADDED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    Profiler::untracked(|| {
+        let Some((size, key)) = CANARIES.lock().remove(&(ptr as usize)) else {
+            return;
+        };
+        PADDED.fetch_sub(1, Ordering::Relaxed);
+        let canary = std::slice::from_raw_parts((ptr as *const u8).add(size), CANARY_LEN);
+        if let Some(offset) = canary.iter().position(|b| *b != CANARY) {
+            report(&key, size, offset);
+            CORRUPTIONS.lock().push((key, size, offset));
+        }
+    });
+}
+
+fn report(key: &StackKey<MAX_DEPTH>, size: usize, offset: usize) {
+    let frames: pprof::Frames = key.frames.clone().into();
+    let stack = crate::profiler::stack_names(&frames);
+    #[cfg(feature = "tracing")]
+    tracing::error!(
+        size,
+        offset,
+        labels = ?key.labels,
+        stack = %stack,
+        "heap buffer overflow"
+    );
+    #[cfg(not(feature = "tracing"))]
+    eprintln!(
+        "heappy: heap buffer overflow: byte {} past the end of a {} byte buffer overwritten, allocated at {}",
+        offset, size, stack
+    );
+}
@@ -0,0 +1,259 @@
+//! Checkpoints of the running session, see
+//! [`HeapProfilerGuardBuilder::checkpoint`](crate::HeapProfilerGuardBuilder::checkpoint): its totals and the raw
+//! records of its stacks, before any symbolization, written periodically so that a restarted process can
+//! [resume](crate::HeapProfilerGuardBuilder::resume_from) a long profiling window instead of starting it over.
+//!
+//! The file is the one of [`Collector::save`](crate::collector::Collector::save), with the session and the mappings
+//! the addresses are in ahead of the records:
+//!
+//! ```text
+//! heappy-checkpoint 1
+//! session <period> <elapsed ns> <samples> <allocated objects> <allocated bytes> <requested bytes> <freed objects>
+//!     <freed bytes> <foreign freed objects> <foreign freed bytes>
+//! mapping <id> <path> <build id>
+//! record <stack> ...
+//! ```
+//!
+//! A stack is its frames, separated by spaces, then its labels' keys and values, separated by tabs (and escaped): a
+//! frame is `<mapping id>:<offset>:<function offset>`, the offsets (in hex) in the file of the mapping, so that the
+//! addresses are relocated to wherever the file is loaded after the restart, or `0` ending a stack the unwinder found
+//! no return address in. Stacks with frames outside of the
+//! mappings of the file, or in a different build of a binary, are left out when resuming.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::collector::{self, Collector};
+use crate::events::{escape, unescape};
+use crate::labels::Labels;
+use crate::mappings::{self, Mapping};
+use crate::profiler::{Profiler, ProfilerBuffer, StackKey, HEAP_PROFILER_STATE, MAX_DEPTH};
+use crate::task::{self, Task};
+use crate::unwinder::StackFrame;
+
+const VERSION: &str = "1";
+
+// Writes a checkpoint to `path` every `interval` until aborted. With the `tracing` feature the checkpoints that can't
+// be written are logged as warnings.
+pub(crate) fn spawn(interval: Duration, path: PathBuf) -> Task<()> {
+    let mut started = false;
+    task::every(interval, move || {
+        // the first step is right away, with nothing to save yet.
+        if !std::mem::replace(&mut started, true) {
+            return;
+        }
+        if let Err(_err) = write(&path) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(path = %path.display(), error = %_err, "cannot write heap checkpoint");
+        }
+    })
+}
+
+fn write(path: &Path) -> io::Result<()> {
+    Profiler::flush_batches();
+    let profiler = HEAP_PROFILER_STATE.read().unwrap();
+    let (period, elapsed, samples) = (profiler.period(), profiler.elapsed(), profiler.samples);
+    let totals = profiler.totals();
+    // copied out, the flushes shouldn't wait for the file.
+    let stacks = Profiler::untracked(|| profiler.collector.clone());
+    std::mem::drop(profiler);
+    let Some(stacks) = stacks else {
+        return Ok(());
+    };
+    Profiler::untracked(|| {
+        let mappings = mappings::current()?;
+        // the mappings of the same file share an id.
+        let mut ids: HashMap<&str, usize> = HashMap::new();
+        for mapping in &mappings {
+            let next = ids.len();
+            ids.entry(&mapping.path).or_insert(next);
+        }
+        collector::save(path, |writer| {
+            writeln!(writer, "heappy-checkpoint\t{}", VERSION)?;
+            write!(
+                writer,
+                "session\t{}\t{}\t{}",
+                period,
+                elapsed.as_nanos(),
+                samples
+            )?;
+            for counter in [
+                totals.allocated_objects,
+                totals.allocated_bytes,
+                totals.requested_bytes,
+                totals.freed_objects,
+                totals.freed_bytes,
+                totals.foreign_freed_objects,
+                totals.foreign_freed_bytes,
+            ] {
+                write!(writer, "\t{}", counter)?;
+            }
+            writeln!(writer)?;
+            let mut paths: Vec<_> = ids.iter().collect();
+            paths.sort_by_key(|(_, id)| **id);
+            for (path, id) in paths {
+                let build_id = mappings
+                    .iter()
+                    .find(|mapping| mapping.path == *path)
+                    .and_then(|mapping| mapping.build_id.as_deref());
+                writeln!(
+                    writer,
+                    "mapping\t{}\t{}\t{}",
+                    id,
+                    escape(path),
+                    build_id.unwrap_or("")
+                )?;
+            }
+            stacks.write_records(writer, |key| encode(key, &mappings, &ids))
+        })
+    })
+    .unwrap_or(Ok(()))
+}
+
+fn encode(key: &StackKey<MAX_DEPTH>, mappings: &[Mapping], ids: &HashMap<&str, usize>) -> String {
+    let offset = |addr: usize| {
+        let mapping = mappings.iter().find(|m| m.contains(addr as u64))?;
+        Some((
+            ids[mapping.path.as_str()],
+            addr as u64 - mapping.start + mapping.offset,
+        ))
+    };
+    let frames: Vec<_> = key
+        .frames()
+        .map(|frame| match (offset(frame.ip), frame.function) {
+            // where the unwinder found no return address.
+            _ if frame.ip == 0 => "0".to_string(),
+            (Some((id, ip)), 0) => format!("{}:{:x}:0", id, ip),
+            (Some((id, ip)), function) => match offset(function) {
+                Some((_, function)) => format!("{}:{:x}:{:x}", id, ip, function),
+                None => format!("{}:{:x}:0", id, ip),
+            },
+            // not in a file, there's no telling where it is after a restart.
+            (None, _) => "-".to_string(),
+        })
+        .collect();
+    let mut out = frames.join(" ");
+    for (k, v) in key.labels.iter() {
+        out += &format!("\t{}\t{}", escape(k), escape(v));
+    }
+    out
+}
+
+// Adds the checkpoint at `path` to the session that just started, if there's one.
+pub(crate) fn resume(path: &Path) -> io::Result<()> {
+    // the profiler's own reads aren't the session's.
+    let lines = match Profiler::untracked(|| collector::read_lines(path)) {
+        None => return Ok(()),
+        Some(Err(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Some(lines) => lines?,
+    };
+    let current = Profiler::untracked(mappings::current).unwrap_or_else(|| Ok(vec![]))?;
+    let invalid = |n: usize, message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", n + 1, message),
+        )
+    };
+    // read into a state of its own: the session keeps running meanwhile.
+    let mut stacks: Collector<StackKey<MAX_DEPTH>> = Collector::new();
+    let mut session = None;
+    // the current mappings of each file of the checkpoint, none if it isn't loaded or is of another build.
+    let mut files: HashMap<usize, Vec<&Mapping>> = HashMap::new();
+    Profiler::untracked(|| {
+        for (n, line) in lines.enumerate() {
+            let fields: Vec<String> = line?.split('\t').map(unescape).collect();
+            let number = |i: usize| -> io::Result<i64> {
+                fields
+                    .get(i)
+                    .and_then(|field| field.parse().ok())
+                    .ok_or_else(|| invalid(n, &format!("expected a number in field {}", i + 1)))
+            };
+            match fields[0].as_str() {
+                "heappy-checkpoint"
+                    if n == 0 && fields.get(1).map(String::as_str) == Some(VERSION) => {}
+                _ if n == 0 => return Err(invalid(n, "not a heappy checkpoint")),
+                "session" => {
+                    let mut counters = [0; 7];
+                    for (i, counter) in counters.iter_mut().enumerate() {
+                        *counter = number(4 + i)?;
+                    }
+                    let elapsed = Duration::from_nanos(number(2)? as u64);
+                    session = Some((elapsed, number(3)? as u64, counters));
+                }
+                "mapping" => {
+                    let (Some(path), Some(build_id)) = (fields.get(2), fields.get(3)) else {
+                        return Err(invalid(n, "expected a path and a build id"));
+                    };
+                    let loaded = current
+                        .iter()
+                        .filter(|mapping| {
+                            mapping.path == *path
+                                && (build_id.is_empty()
+                                    || mapping.build_id.as_deref() == Some(build_id.as_str()))
+                        })
+                        .collect();
+                    files.insert(number(1)? as usize, loaded);
+                }
+                "record" => stacks
+                    .read_record(&fields, |key| decode(key, &files))
+                    .map_err(|message| invalid(n, message))?,
+                other => return Err(invalid(n, &format!("unknown record {:?}", other))),
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Ok(()))?;
+    let Some((elapsed, samples, counters)) = session else {
+        return Err(invalid(0, "no session"));
+    };
+    let mut profiler = HEAP_PROFILER_STATE.write().unwrap();
+    ProfilerBuffer::from_counters(counters, 0).add_totals(&mut profiler);
+    profiler.samples += samples;
+    profiler.started = profiler
+        .started
+        .checked_sub(elapsed)
+        .unwrap_or(profiler.started);
+    Profiler::untracked(|| profiler.collector.merge(stacks));
+    Ok(())
+}
+
+// The stack `key` encodes, relocated into the current `files`.
+fn decode(key: &str, files: &HashMap<usize, Vec<&Mapping>>) -> Option<StackKey<MAX_DEPTH>> {
+    let mut fields = key.split('\t');
+    let relocate = |id: usize, offset: u64| {
+        let mapping = files.get(&id)?.iter().find(|mapping| {
+            mapping.offset <= offset && offset < mapping.offset + (mapping.limit - mapping.start)
+        })?;
+        Some((mapping.start + offset - mapping.offset) as usize)
+    };
+    let frames = fields
+        .next()?
+        .split(' ')
+        .filter(|frame| !frame.is_empty())
+        .map(|frame| {
+            if frame == "0" {
+                return Some(StackFrame { ip: 0, function: 0 });
+            }
+            let mut parts = frame.split(':');
+            let id = parts.next()?.parse().ok()?;
+            let ip = u64::from_str_radix(parts.next()?, 16).ok()?;
+            let function = u64::from_str_radix(parts.next()?, 16).ok()?;
+            Some(StackFrame {
+                ip: relocate(id, ip)?,
+                function: match function {
+                    0 => 0,
+                    function => relocate(id, function)?,
+                },
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let rest: Vec<_> = fields.map(unescape).collect();
+    let labels: Labels = rest
+        .chunks(2)
+        .filter(|kv| kv.len() == 2)
+        .map(|kv| (kv[0].clone(), kv[1].clone()))
+        .collect();
+    Some(StackKey::from_frames(&frames, labels))
+}
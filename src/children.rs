@@ -0,0 +1,287 @@
+//! The reports of the subprocesses of a session, see
+//! [`HeapProfilerGuardBuilder::profile_children`](crate::HeapProfilerGuardBuilder::profile_children): the session
+//! listens on a unix socket and puts its path and sampling settings in the environment its children inherit, whose
+//! sessions of [`HeapProfilerGuardBuilder::from_parent`](crate::HeapProfilerGuardBuilder::from_parent) send their
+//! report to it when they end. The reports of the parent add them to its own, the stacks of each child labeled with
+//! its [`PID_LABEL`].
+//!
+//! A child sends its report whole and symbolized (the parent can't resolve the addresses of another process), as
+//! text:
+//!
+//! ```text
+//! heappy-child 1
+//! process <pid>
+//! totals <allocated objects> <allocated bytes> <requested bytes> <freed objects> <freed bytes>
+//!     <foreign freed objects> <foreign freed bytes>
+//! record <stack> ...
+//! ```
+//!
+//! The records are the ones of [`Collector::save`](crate::collector::Collector::save): a stack is its labels' count,
+//! keys and values, then the symbols of its frames (innermost first) as `<frame> <name> <file> <line>`, `<frame>` the
+//! index of the frame they're inlined into, all separated by tabs (and escaped). A child sending again replaces what
+//! it sent before. The socket is only the owner's (mode 0600).
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::collector::{Collector, MemProfileRecord};
+use crate::events::{empty_frames, escape, unescape};
+use crate::labels::Labels;
+use crate::profiler::{HeapTotals, Profiler};
+use crate::task::{self, Task};
+
+/// The variable of the path of the parent's socket.
+pub const SOCKET_ENV: &str = "HEAPPY_PARENT_SOCKET";
+/// The variable of the parent's sampling period.
+pub const PERIOD_ENV: &str = "HEAPPY_PARENT_PERIOD";
+/// The variable of the parent's maximum stack depth.
+pub const DEPTH_ENV: &str = "HEAPPY_PARENT_DEPTH";
+/// The label of the process id of a child, on its stacks in the parent's reports.
+pub const PID_LABEL: &str = "pid";
+
+const VERSION: &str = "1";
+// How often the socket is checked for reports.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How long a parent that doesn't read holds the end of a child up.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Child {
+    totals: HeapTotals,
+    stacks: Collector<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref CHILDREN: spin::Mutex<HashMap<u32, Child>> = Default::default();
+}
+
+/// The socket file, removed along with the variables when dropped.
+pub(crate) struct ChildrenSocket(PathBuf);
+
+impl Drop for ChildrenSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        for name in [SOCKET_ENV, PERIOD_ENV, DEPTH_ENV] {
+            std::env::remove_var(name);
+        }
+    }
+}
+
+// Listens on `path` for the reports of the children until the socket is dropped and the task aborted, telling the
+// children to sample every `period` bytes, `depth` frames deep.
+pub(crate) fn spawn(
+    path: &Path,
+    period: usize,
+    depth: usize,
+) -> io::Result<(ChildrenSocket, Task<()>)> {
+    // left over by a process that didn't get to remove it.
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    let socket = ChildrenSocket(path.to_path_buf());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    std::env::set_var(SOCKET_ENV, path);
+    std::env::set_var(PERIOD_ENV, period.to_string());
+    std::env::set_var(DEPTH_ENV, depth.to_string());
+    let mut clients: Vec<(UnixStream, Vec<u8>)> = vec![];
+    let task = task::every(POLL_INTERVAL, move || {
+        // the reports of the children aren't the session's allocations.
+        Profiler::untracked(|| {
+            while let Ok((stream, _)) = listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    clients.push((stream, vec![]));
+                }
+            }
+            clients.retain_mut(|(stream, message)| match read(stream, message) {
+                Ok(false) => true,
+                Ok(true) => {
+                    if let Err(_err) = receive(message) {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(error = %_err, "invalid heap report from a child process");
+                    }
+                    false
+                }
+                Err(_) => false,
+            });
+        });
+    });
+    Ok((socket, task))
+}
+
+/// Forgets the reports of the children of the previous session.
+pub(crate) fn clear() {
+    let children = std::mem::take(&mut *CHILDREN.lock());
+    // freed outside of the lock.
+    std::mem::drop(children);
+}
+
+// Reads what the child sent so far into `message`, true once it's all there.
+fn read(stream: &mut UnixStream, message: &mut Vec<u8>) -> io::Result<bool> {
+    let mut buf = [0; 16 * 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(read) => message.extend_from_slice(&buf[..read]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn receive(message: &[u8]) -> Result<(), String> {
+    let text = std::str::from_utf8(message).map_err(|_| "not utf-8".to_string())?;
+    let mut pid = None;
+    let mut totals = None;
+    let mut stacks = Collector::new();
+    for (n, line) in text.lines().enumerate() {
+        let invalid = |message: &str| format!("line {}: {}", n + 1, message);
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match fields[0].as_str() {
+            "heappy-child" if n == 0 && fields.get(1).map(String::as_str) == Some(VERSION) => {}
+            _ if n == 0 => return Err(invalid("not a heappy child report")),
+            "process" => {
+                let parsed = fields.get(1).and_then(|pid| pid.parse().ok());
+                pid = Some(parsed.ok_or_else(|| invalid("expected a pid"))?);
+            }
+            "totals" => {
+                let counters: Vec<i64> = fields[1..]
+                    .iter()
+                    .map(|counter| counter.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid("invalid number"))?;
+                let [objects, bytes, requested, freed_objects, freed_bytes, foreign_objects, foreign_bytes] =
+                    counters[..]
+                else {
+                    return Err(invalid("expected 7 counters"));
+                };
+                totals = Some(HeapTotals {
+                    allocated_objects: objects,
+                    allocated_bytes: bytes,
+                    requested_bytes: requested,
+                    freed_objects,
+                    freed_bytes,
+                    foreign_freed_objects: foreign_objects,
+                    foreign_freed_bytes: foreign_bytes,
+                });
+            }
+            "record" => stacks
+                .read_record(&fields, |key| Some(key.to_owned()))
+                .map_err(invalid)?,
+            other => return Err(invalid(&format!("unknown record {:?}", other))),
+        }
+    }
+    let (Some(pid), Some(totals)) = (pid, totals) else {
+        return Err("no process or totals".to_string());
+    };
+    let previous = CHILDREN.lock().insert(pid, Child { totals, stacks });
+    std::mem::drop(previous);
+    Ok(())
+}
+
+// Sends the report of `stacks` and `totals` to the parent listening on `path`.
+pub(crate) fn send<'a>(
+    path: &Path,
+    totals: HeapTotals,
+    stacks: impl IntoIterator<Item = (&'a (pprof::Frames, Labels), &'a MemProfileRecord)>,
+) -> io::Result<()> {
+    // the stacks of the report are told apart by their frames and labels, which encode the same.
+    let mut records: Collector<String> = Collector::new();
+    for ((frames, labels), rec) in stacks {
+        records.insert(encode(frames, labels), rec);
+    }
+    let mut message = vec![];
+    writeln!(message, "heappy-child\t{}", VERSION)?;
+    writeln!(message, "process\t{}", std::process::id())?;
+    write!(message, "totals")?;
+    for counter in [
+        totals.allocated_objects,
+        totals.allocated_bytes,
+        totals.requested_bytes,
+        totals.freed_objects,
+        totals.freed_bytes,
+        totals.foreign_freed_objects,
+        totals.foreign_freed_bytes,
+    ] {
+        write!(message, "\t{}", counter)?;
+    }
+    writeln!(message)?;
+    records.write_records(&mut message, String::clone)?;
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.write_all(&message)
+}
+
+fn encode(frames: &pprof::Frames, labels: &Labels) -> String {
+    let mut out = labels.iter().count().to_string();
+    for (k, v) in labels.iter() {
+        out += &format!("\t{}\t{}", escape(k), escape(v));
+    }
+    for (i, frame) in frames.frames.iter().enumerate() {
+        for symbol in frame {
+            let file = symbol
+                .filename
+                .as_ref()
+                .map(|f| f.to_string_lossy().into_owned());
+            out += &format!(
+                "\t{}\t{}\t{}\t{}",
+                i,
+                escape(&String::from_utf8_lossy(symbol.raw_name())),
+                escape(file.as_deref().unwrap_or("")),
+                symbol.lineno.map(|l| l.to_string()).unwrap_or_default(),
+            );
+        }
+    }
+    out
+}
+
+// The frames and labels of the stack `key` of the child `pid`.
+fn decode(key: &str, pid: u32) -> Option<(pprof::Frames, Labels)> {
+    let fields: Vec<String> = key.split('\t').map(unescape).collect();
+    let labels_end = 1 + 2 * fields.first()?.parse::<usize>().ok()?;
+    let labels: Labels = fields
+        .get(1..labels_end)?
+        .chunks(2)
+        .map(|kv| (kv[0].clone(), kv[1].clone()))
+        .collect();
+    let symbols = &fields[labels_end..];
+    if symbols.len() % 4 != 0 {
+        return None;
+    }
+    let mut frames = empty_frames();
+    for symbol in symbols.chunks(4) {
+        let frame: usize = symbol[0].parse().ok()?;
+        if frame >= frames.frames.len() {
+            frames.frames.resize(frame + 1, vec![]);
+        }
+        frames.frames[frame].push(pprof::Symbol {
+            name: Some(symbol[1].clone().into_bytes()),
+            addr: None,
+            lineno: symbol[3].parse().ok(),
+            filename: (!symbol[2].is_empty()).then(|| PathBuf::from(&symbol[2])),
+        });
+    }
+    Some((frames, labels.with(PID_LABEL, pid.to_string())))
+}
+
+// Adds the reports of the children to the `data` and `totals` of a report of the session.
+pub(crate) fn add(
+    data: &mut HashMap<(pprof::Frames, Labels), MemProfileRecord>,
+    totals: &mut HeapTotals,
+) {
+    let children = CHILDREN.lock();
+    for (pid, child) in children.iter() {
+        totals.add(&child.totals);
+        for (key, rec) in &child.stacks {
+            if let Some(stack) = decode(key, *pid) {
+                data.entry(stack).or_default().add(rec);
+            }
+        }
+    }
+}
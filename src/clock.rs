@@ -0,0 +1,111 @@
+//! Where the sessions get the time from, see
+//! [`HeapProfilerGuardBuilder::clock`](crate::HeapProfilerGuardBuilder::clock): the flush intervals, the timelines, the
+//! ages of the live allocations and the start and duration of the reports.
+//!
+//! ```ignore
+//! let clock = heappy::ManualClock::new();
+//! let guard = heappy::HeapProfilerGuardBuilder::default()
+//!     .deterministic(true)
+//!     .clock(clock.clone())
+//!     .flush_strategy(heappy::FlushStrategy::Interval(Duration::from_secs(1)))
+//!     .build()
+//!     .await?;
+//! work();
+//! clock.advance(Duration::from_secs(1));
+//! more_work();
+//! let report = guard.report().await;
+//! assert_eq!(report.duration(), Duration::from_secs(1));
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// The monotonic time, for durations.
+    fn now(&self) -> Instant;
+
+    /// The wall-clock time, for timestamps.
+    fn system_time(&self) -> SystemTime;
+}
+
+lazy_static::lazy_static! {
+    static ref CLOCK: spin::RwLock<Arc<dyn Clock>> = spin::RwLock::new(Arc::new(SystemClock));
+}
+
+/// The time of the OS, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests: its clones share the time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    origin: Instant,
+    start: SystemTime,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// A clock at the Unix epoch.
+    pub fn new() -> Self {
+        Self::starting_at(SystemTime::UNIX_EPOCH)
+    }
+
+    pub fn starting_at(start: SystemTime) -> Self {
+        Self {
+            origin: Instant::now(),
+            start,
+            elapsed_nanos: Default::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let nanos = by.as_nanos().min(u64::MAX as u128) as u64;
+        self.elapsed_nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+
+    /// How far the clock has been advanced.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+}
+
+// Replaces the clock of the sessions, returning the previous one.
+pub(crate) fn set(clock: Arc<dyn Clock>) -> Arc<dyn Clock> {
+    std::mem::replace(&mut *CLOCK.write(), clock)
+}
+
+pub(crate) fn now() -> Instant {
+    CLOCK.read().now()
+}
+
+pub(crate) fn system_time() -> SystemTime {
+    CLOCK.read().system_time()
+}
@@ -1,51 +1,347 @@
+//! The aggregation of the samples by key, see [`Collector`].
+//!
+//! [`Collector::save`] writes the records as text, one per line, their fields separated by tabs (escaped like in the
+//! [event recordings](crate::events)):
+//!
+//! ```text
+//! heappy-collector 1
+//! record <key> <alloc bytes> <alloc objects> <allocated objects> <requested bytes> <granted bytes> <free bytes>
+//!     <free objects> <min size> <max size> <estimated objects> <estimated bytes> [<step>:<granted bytes>,...]
+//! ```
+
 use core::cmp::Eq;
 use core::default::Default;
 use core::hash::Hash;
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::compress::{CompressedWriter, Compression};
+use crate::events::{escape, unescape};
+
+const VERSION: &str = "1";
+
+/// All the allocations a sample stands for, i.e. those made since the previous sample of the thread, whether they've
+/// been freed since or not.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Allocations {
+    pub objects: i64,
+    /// What the allocations asked for.
+    pub requested_bytes: i64,
+    /// What the allocator gave them.
+    pub granted_bytes: i64,
+    /// The sizes of the allocations the samples were taken at.
+    pub sizes: AllocationSizes,
+}
+
+/// The sizes of the sampled allocations, and the mean size of the allocations they stand for: "one 2GiB allocation"
+/// rather than "a million 2KiB ones".
+#[derive(Default, Debug, Clone, Copy)]
+pub struct AllocationSizes {
+    /// The smallest and the largest sampled allocation, 0 without any.
+    pub min_bytes: i64,
+    pub max_bytes: i64,
+    /// How many allocations, and how many bytes, the sampled ones stand for: an allocation smaller than the sampling
+    /// period is sampled with a probability of about its size over the period, and so stands for period / size of
+    /// them, a larger one is always sampled.
+    pub estimated_objects: f64,
+    pub estimated_bytes: f64,
+}
 
+impl AllocationSizes {
+    /// The sizes of an allocation of `size` bytes sampled every `period` bytes.
+    pub fn sampled(size: i64, period: usize) -> Self {
+        if size <= 0 {
+            return Self::default();
+        }
+        let weight = (period as f64 / size as f64).max(1.0);
+        Self {
+            min_bytes: size,
+            max_bytes: size,
+            estimated_objects: weight,
+            estimated_bytes: weight * size as f64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.estimated_objects == 0.0
+    }
+
+    /// The estimated mean size of the allocations, none without samples.
+    pub fn mean_bytes(&self) -> Option<f64> {
+        (!self.is_empty()).then_some(self.estimated_bytes / self.estimated_objects)
+    }
+
+    pub fn add(&mut self, other: &Self) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            *self = *other;
+            return;
+        }
+        self.min_bytes = self.min_bytes.min(other.min_bytes);
+        self.max_bytes = self.max_bytes.max(other.max_bytes);
+        self.estimated_objects += other.estimated_objects;
+        self.estimated_bytes += other.estimated_bytes;
+    }
+}
+
+/// The granularity of [`MemProfileRecord::timeline`].
+pub const TIMELINE_RESOLUTION: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// What the samples of a key counted.
 #[derive(Default, Debug, Clone)]
 pub struct MemProfileRecord {
-    pub alloc_bytes: isize,
-    pub alloc_objects: isize,
+    /// The net changes of the samples that grew the heap, and their number.
+    pub alloc_bytes: i64,
+    pub alloc_objects: i64,
+    pub allocated: Allocations,
+    /// The granted bytes by time since the start of the session, in steps of [`TIMELINE_RESOLUTION`].
+    pub timeline: Vec<(u32, i64)>,
+    /// The net changes of the samples that shrank it, and their number.
     #[cfg(feature = "measure_free")]
-    pub free_bytes: isize,
+    pub free_bytes: i64,
     #[cfg(feature = "measure_free")]
-    pub free_objects: isize,
+    pub free_objects: i64,
+}
+
+impl MemProfileRecord {
+    /// Takes out what `baseline` counted, e.g. an earlier snapshot of the same stack. Counters don't go below zero;
+    /// the sizes are left as they are, what's left of them can't be told apart.
+    pub fn subtract(&mut self, baseline: &Self) {
+        let sub = |value: &mut i64, baseline: i64| *value = value.saturating_sub(baseline).max(0);
+        sub(&mut self.alloc_bytes, baseline.alloc_bytes);
+        sub(&mut self.alloc_objects, baseline.alloc_objects);
+        sub(&mut self.allocated.objects, baseline.allocated.objects);
+        sub(
+            &mut self.allocated.requested_bytes,
+            baseline.allocated.requested_bytes,
+        );
+        sub(
+            &mut self.allocated.granted_bytes,
+            baseline.allocated.granted_bytes,
+        );
+        #[cfg(feature = "measure_free")]
+        {
+            sub(&mut self.free_bytes, baseline.free_bytes);
+            sub(&mut self.free_objects, baseline.free_objects);
+        }
+        for &(step, granted) in &baseline.timeline {
+            if let Some((_, bytes)) = self.timeline.iter_mut().find(|(s, _)| *s == step) {
+                sub(bytes, granted);
+            }
+        }
+        self.timeline.retain(|(_, bytes)| *bytes > 0);
+    }
+
+    /// Adds what `other` counted, e.g. the same stack in another session.
+    pub fn add(&mut self, other: &Self) {
+        let add = |value: &mut i64, other: i64| *value = value.saturating_add(other);
+        add(&mut self.alloc_bytes, other.alloc_bytes);
+        add(&mut self.alloc_objects, other.alloc_objects);
+        add(&mut self.allocated.objects, other.allocated.objects);
+        add(
+            &mut self.allocated.requested_bytes,
+            other.allocated.requested_bytes,
+        );
+        add(
+            &mut self.allocated.granted_bytes,
+            other.allocated.granted_bytes,
+        );
+        self.allocated.sizes.add(&other.allocated.sizes);
+        #[cfg(feature = "measure_free")]
+        {
+            add(&mut self.free_bytes, other.free_bytes);
+            add(&mut self.free_objects, other.free_objects);
+        }
+        for &(step, granted) in &other.timeline {
+            match self.timeline.iter_mut().find(|(s, _)| *s == step) {
+                Some((_, bytes)) => add(bytes, granted),
+                None => self.timeline.push((step, granted)),
+            }
+        }
+        self.timeline.sort_by_key(|(step, _)| *step);
+    }
+
+    /// Whether nothing is left to report.
+    pub fn is_empty(&self) -> bool {
+        #[cfg(feature = "measure_free")]
+        if self.free_objects > 0 {
+            return false;
+        }
+        self.alloc_objects == 0 && self.allocated.objects == 0
+    }
 }
 
 #[cfg(feature = "measure_free")]
 impl MemProfileRecord {
-    pub fn in_use_bytes(&self) -> isize {
-        self.alloc_bytes - self.free_bytes
+    pub fn in_use_bytes(&self) -> i64 {
+        self.alloc_bytes.saturating_sub(self.free_bytes)
     }
 
-    pub fn in_use_objects(&self) -> isize {
-        self.alloc_objects - self.free_objects
+    pub fn in_use_objects(&self) -> i64 {
+        self.alloc_objects.saturating_sub(self.free_objects)
     }
 }
 
+/// Which records a [`Collector`] drops first when it holds too many, see [`Collector::evict`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eviction {
+    /// The ones that went the longest without a sample.
+    #[default]
+    LeastRecentlyUsed,
+    /// The ones with the fewest samples.
+    LeastFrequentlyUsed,
+}
+
+// When a record was last used, by the count of uses of the collector, and how often.
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    last: u64,
+    count: u64,
+}
+
+/// The records of the keys samples were taken for (the stacks, for the profiler), which other keys, e.g. the logical
+/// operations of a crate, can aggregate into too. [`HeapReport::from_collector`](crate::HeapReport::from_collector)
+/// turns one into a report for the report formats.
+#[derive(Debug, Clone)]
 pub struct Collector<K: Hash + Eq + 'static> {
-    map: HashMap<K, MemProfileRecord>,
+    map: HashMap<K, (MemProfileRecord, Usage)>,
+    uses: u64,
 }
 
 impl<K: Hash + Eq + 'static> Collector<K> {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// The records, leaving them in the collector, e.g. to report on a collector that keeps recording.
+    pub fn iter(&self) -> Iter<'_, K> {
+        Iter(self.map.iter())
+    }
+
+    pub fn get(&self, key: &K) -> Option<&MemProfileRecord> {
+        self.map.get(key).map(|(rec, _)| rec)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // The memory the records take, roughly: the entries of the map, their timelines and the heap of each key, by
+    // `heap`.
+    pub(crate) fn footprint(&self, heap: impl Fn(&K) -> usize) -> usize {
+        let entries = self.map.capacity() * std::mem::size_of::<(K, (MemProfileRecord, Usage))>();
+        let owned: usize = self
+            .map
+            .iter()
+            .map(|(key, (rec, _))| {
+                heap(key) + rec.timeline.capacity() * std::mem::size_of::<(u32, i64)>()
+            })
+            .sum();
+        entries + owned
+    }
+
+    /// Adds `rec` to the record of `key`.
+    pub fn insert(&mut self, key: K, rec: &MemProfileRecord) {
+        self.entry(key).add(rec);
+    }
+
+    /// Adds the records of `other`, e.g. of another thread or host.
+    pub fn merge(&mut self, other: Collector<K>) {
+        for (key, rec) in other {
+            self.insert(key, &rec);
+        }
+    }
+
+    /// Like [`merge`](Self::merge), leaving `other` as it is.
+    pub fn merge_from(&mut self, other: &Collector<K>)
+    where
+        K: Clone,
+    {
+        for (key, rec) in other {
+            self.insert(key.clone(), rec);
+        }
+    }
+
+    /// Drops records, the first ones by `eviction`, until `max` are left; how many it dropped. What they counted is
+    /// gone from the reports, so that keys without bound (e.g. the stacks of heavily generic or deeply async code)
+    /// don't grow the collector without bound.
+    pub fn evict(&mut self, max: usize, eviction: Eviction) -> usize {
+        let excess = self.map.len().saturating_sub(max);
+        if excess == 0 {
+            return 0;
         }
+        let rank = |usage: &Usage| match eviction {
+            Eviction::LeastRecentlyUsed => (usage.last, 0),
+            Eviction::LeastFrequentlyUsed => (usage.count, usage.last),
+        };
+        let mut ranks: Vec<_> = self.map.values().map(|(_, usage)| rank(usage)).collect();
+        // the records ranked up to the excess one go, those tied with it as long as there are too many.
+        let (_, &mut cutoff, _) = ranks.select_nth_unstable(excess - 1);
+        let mut ties = excess - ranks.iter().filter(|r| **r < cutoff).count();
+        self.map.retain(|_, (_, usage)| {
+            let rank = rank(usage);
+            if rank == cutoff && ties > 0 {
+                ties -= 1;
+                return false;
+            }
+            rank >= cutoff
+        });
+        excess
+    }
+
+    fn entry(&mut self, key: K) -> &mut MemProfileRecord {
+        self.uses += 1;
+        let (rec, usage) = self.map.entry(key).or_default();
+        usage.last = self.uses;
+        usage.count += 1;
+        rec
     }
 
-    pub fn record(&mut self, key: K, bytes: isize) {
-        let rec = self.map.entry(key).or_insert_with(Default::default);
+    /// Records a sample of `key`: the net change of `bytes` it stands for (an allocation when positive, a free when
+    /// negative, which needs the `measure_free` feature), and the `allocated` ones, in the `step` of the timeline.
+    ///
+    /// # Panics
+    ///
+    /// On a free without the `measure_free` feature.
+    pub fn record(&mut self, key: K, bytes: i64, allocated: Allocations, step: u32) {
+        let rec = self.entry(key);
+        if allocated.granted_bytes > 0 {
+            match rec.timeline.last_mut() {
+                Some((last, granted)) if *last == step => *granted += allocated.granted_bytes,
+                _ => rec.timeline.push((step, allocated.granted_bytes)),
+            }
+        }
+        rec.allocated.objects = rec.allocated.objects.saturating_add(allocated.objects);
+        rec.allocated.requested_bytes = rec
+            .allocated
+            .requested_bytes
+            .saturating_add(allocated.requested_bytes);
+        rec.allocated.granted_bytes = rec
+            .allocated
+            .granted_bytes
+            .saturating_add(allocated.granted_bytes);
+        rec.allocated.sizes.add(&allocated.sizes);
         match bytes.cmp(&0) {
             std::cmp::Ordering::Greater => {
-                rec.alloc_bytes += bytes;
-                rec.alloc_objects += 1;
+                rec.alloc_bytes = rec.alloc_bytes.saturating_add(bytes);
+                rec.alloc_objects = rec.alloc_objects.saturating_add(1);
             }
             #[cfg(feature = "measure_free")]
             std::cmp::Ordering::Less => {
-                rec.free_bytes += -bytes;
-                rec.free_objects += 1;
+                rec.free_bytes = rec.free_bytes.saturating_add(-bytes);
+                rec.free_objects = rec.free_objects.saturating_add(1);
             }
             #[cfg(not(feature = "measure_free"))]
             std::cmp::Ordering::Less => {
@@ -58,12 +354,198 @@ impl<K: Hash + Eq + 'static> Collector<K> {
     }
 }
 
+impl<K: Hash + Eq + Display + 'static> Collector<K> {
+    /// Writes the records to `path`, compressed after its extension, with the keys as they display, for
+    /// [`load`](Self::load) to restore them, e.g. after a restart. The file is written whole or not at all: it's
+    /// written next to `path`, then renamed.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save(path.as_ref(), |writer| {
+            writeln!(writer, "heappy-collector\t{}", VERSION)?;
+            self.write_records(writer, |key| key.to_string())
+        })
+    }
+}
+
+impl<K: Hash + Eq + FromStr + 'static> Collector<K> {
+    /// Reads the records [`save`](Self::save) wrote, parsing the keys from their display.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut collector = Self::new();
+        for (n, line) in read_lines(path.as_ref())?.enumerate() {
+            let fields: Vec<String> = line?.split('\t').map(unescape).collect();
+            match fields[0].as_str() {
+                "heappy-collector"
+                    if n == 0 && fields.get(1).map(String::as_str) == Some(VERSION) => {}
+                _ if n == 0 => return Err(invalid(n, "not a heappy collector file")),
+                "record" => collector
+                    .read_record(&fields, |key| key.parse().ok())
+                    .map_err(|message| invalid(n, message))?,
+                other => return Err(invalid(n, &format!("unknown record {:?}", other))),
+            }
+        }
+        Ok(collector)
+    }
+}
+
+impl<K: Hash + Eq + 'static> Collector<K> {
+    // Writes a `record` line per record, with the keys as `key` encodes them.
+    pub(crate) fn write_records<W: Write>(
+        &self,
+        writer: &mut W,
+        key: impl Fn(&K) -> String,
+    ) -> io::Result<()> {
+        for (k, rec) in self.iter() {
+            #[cfg(feature = "measure_free")]
+            let (free_bytes, free_objects) = (rec.free_bytes, rec.free_objects);
+            #[cfg(not(feature = "measure_free"))]
+            let (free_bytes, free_objects) = (0, 0);
+            let sizes = &rec.allocated.sizes;
+            let timeline: Vec<_> = rec
+                .timeline
+                .iter()
+                .map(|(step, bytes)| format!("{}:{}", step, bytes))
+                .collect();
+            writeln!(
+                writer,
+                "record\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                escape(&key(k)),
+                rec.alloc_bytes,
+                rec.alloc_objects,
+                rec.allocated.objects,
+                rec.allocated.requested_bytes,
+                rec.allocated.granted_bytes,
+                free_bytes,
+                free_objects,
+                sizes.min_bytes,
+                sizes.max_bytes,
+                sizes.estimated_objects,
+                sizes.estimated_bytes,
+                timeline.join(","),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Adds the record of a `record` line, split into its `fields`, with its key as `key` decodes it: none drops
+    // the record.
+    pub(crate) fn read_record(
+        &mut self,
+        fields: &[String],
+        key: impl FnOnce(&str) -> Option<K>,
+    ) -> Result<(), &'static str> {
+        if fields.len() != 14 {
+            return Err("expected 13 fields");
+        }
+        let int = |i: usize| fields[i].parse::<i64>().map_err(|_| "invalid number");
+        let float = |i: usize| fields[i].parse::<f64>().map_err(|_| "invalid number");
+        let mut rec = MemProfileRecord {
+            alloc_bytes: int(2)?,
+            alloc_objects: int(3)?,
+            allocated: Allocations {
+                objects: int(4)?,
+                requested_bytes: int(5)?,
+                granted_bytes: int(6)?,
+                sizes: AllocationSizes {
+                    min_bytes: int(9)?,
+                    max_bytes: int(10)?,
+                    estimated_objects: float(11)?,
+                    estimated_bytes: float(12)?,
+                },
+            },
+            ..Default::default()
+        };
+        #[cfg(feature = "measure_free")]
+        {
+            rec.free_bytes = int(7)?;
+            rec.free_objects = int(8)?;
+        }
+        for step in fields[13].split(',').filter(|step| !step.is_empty()) {
+            let (step, bytes) = step
+                .split_once(':')
+                .and_then(|(step, bytes)| Some((step.parse().ok()?, bytes.parse().ok()?)))
+                .ok_or("invalid timeline")?;
+            rec.timeline.push((step, bytes));
+        }
+        if let Some(key) = key(&fields[1]) {
+            self.insert(key, &rec);
+        }
+        Ok(())
+    }
+}
+
+// Writes a file with `write`, next to `path` first so that a failure leaves what was at `path` as it was.
+pub(crate) fn save(
+    path: &Path,
+    write: impl FnOnce(&mut CompressedWriter<io::BufWriter<std::fs::File>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut writer = CompressedWriter::new(
+        io::BufWriter::new(std::fs::File::create(&partial)?),
+        Compression::from_path(path),
+    );
+    write(&mut writer)?;
+    writer.finish()?.into_inner()?.sync_all()?;
+    std::fs::rename(&partial, path)
+}
+
+// The lines of the file at `path`, decompressed.
+pub(crate) fn read_lines(path: &Path) -> io::Result<io::Lines<Box<dyn BufRead>>> {
+    let file = std::fs::File::open(path)?;
+    Ok(crate::events::decompress(io::BufReader::new(file))?.lines())
+}
+
+fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line + 1, message),
+    )
+}
+
 impl<K: Hash + Eq + 'static> IntoIterator for Collector<K> {
     type Item = (K, MemProfileRecord);
-    type IntoIter = std::collections::hash_map::IntoIter<K, MemProfileRecord>;
+    type IntoIter = IntoIter<K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter(self.map.into_iter())
+    }
+}
+
+/// The records of a [`Collector`], by value.
+pub struct IntoIter<K>(std::collections::hash_map::IntoIter<K, (MemProfileRecord, Usage)>);
+
+impl<K> Iterator for IntoIter<K> {
+    type Item = (K, MemProfileRecord);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, (rec, _))| (key, rec))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a, K: Hash + Eq + 'static> IntoIterator for &'a Collector<K> {
+    type Item = (&'a K, &'a MemProfileRecord);
+    type IntoIter = Iter<'a, K>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.map.into_iter()
+        self.iter()
+    }
+}
+
+/// The records of a [`Collector`], by reference.
+pub struct Iter<'a, K>(std::collections::hash_map::Iter<'a, K, (MemProfileRecord, Usage)>);
+
+impl<'a, K> Iterator for Iter<'a, K> {
+    type Item = (&'a K, &'a MemProfileRecord);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, (rec, _))| (key, rec))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
     }
 }
 
@@ -0,0 +1,75 @@
+//! Attribution of stacks to the logical components of a product ("storage engine", "query planner") rather than to
+//! raw crate paths, by symbol prefix.
+//!
+//! ```ignore
+//! let components: heappy::Components = "
+//!     my_db::storage:: = storage engine
+//!     my_db::sql::planner:: = query planner
+//!     tokio:: = runtime
+//! "
+//! .parse()?;
+//! for (component, totals) in report.by_component(&components) {
+//!     println!("{}: {} bytes", component.as_deref().unwrap_or("other"), totals.bytes);
+//! }
+//! ```
+
+use std::str::FromStr;
+
+use crate::profiler::Error;
+
+/// A mapping from symbol prefixes to components.
+#[derive(Clone, Debug, Default)]
+pub struct Components {
+    rules: Vec<(String, String)>,
+}
+
+impl Components {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Attributes the functions whose name starts with `prefix` (e.g. `my_db::storage::`) to `component`. The longest
+    /// matching prefix wins.
+    pub fn add(mut self, prefix: impl Into<String>, component: impl Into<String>) -> Self {
+        self.rules.push((prefix.into(), component.into()));
+        self
+    }
+
+    /// The component of a single function.
+    pub fn component_of(&self, name: &str) -> Option<&str> {
+        // trait impls, e.g. `<my_db::storage::Page as core::clone::Clone>::clone`.
+        let name = name.trim_start_matches('<');
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, component)| component.as_str())
+    }
+
+    /// The component of a stack: the one of the innermost function attributed to any.
+    pub fn attribute<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> Option<&str> {
+        names.into_iter().find_map(|name| self.component_of(name))
+    }
+}
+
+/// One `<symbol prefix> = <component>` per line, with `#` comments.
+impl FromStr for Components {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = Self::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((prefix, component)) if !prefix.trim().is_empty() => {
+                    components = components.add(prefix.trim(), component.trim());
+                }
+                _ => return Err(Error::InvalidComponentRule(i + 1, line.to_string())),
+            }
+        }
+        Ok(components)
+    }
+}
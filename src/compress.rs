@@ -0,0 +1,149 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::zstd;
+
+/// How [`CompressedWriter`] compresses what's written to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// gzip, like pprof's own `.pb.gz` files.
+    Gzip,
+    /// Zstandard, which is faster to decompress. The encoder is a simple one written in Rust, whose output is about
+    /// the size of gzip's.
+    Zstd,
+}
+
+impl Compression {
+    /// The compression a file name asks for: gzip for `.gz`, zstd for `.zst` and none otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// A writer compressing what's written to it with a [`Compression`], e.g. to pass to
+/// [`HeapReport::write_json`](crate::HeapReport::write_json) or the other export methods.
+///
+/// [`finish`](Self::finish) writes the end of the compressed stream and returns the inner writer; dropping the
+/// writer does the same but ignores the errors.
+pub struct CompressedWriter<W: Write> {
+    inner: Inner<W>,
+}
+
+enum Inner<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(Box<zstd::Encoder<W>>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> Self {
+        let inner = match compression {
+            Compression::None => Inner::None(writer),
+            Compression::Gzip => Inner::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Inner::Zstd(Box::new(zstd::Encoder::new(writer))),
+        };
+        Self { inner }
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self.inner {
+            Inner::None(mut writer) => writer.flush().map(|()| writer),
+            Inner::Gzip(encoder) => encoder.finish(),
+            Inner::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Inner::None(writer) => writer.write(buf),
+            Inner::Gzip(encoder) => encoder.write(buf),
+            Inner::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::None(writer) => writer.flush(),
+            Inner::Gzip(encoder) => encoder.flush(),
+            Inner::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const DATA: &[u8] =
+        b"main;alloc::vec::Vec<u8>::with_capacity 4096\nmain;worker 128\nmain;worker 128\n";
+
+    fn compressed(compression: Compression) -> Vec<u8> {
+        let mut writer = CompressedWriter::new(vec![], compression);
+        for _ in 0..100 {
+            writer.write_all(DATA).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn unzstd(mut frame: &[u8]) -> Vec<u8> {
+        let mut decoded = vec![];
+        ruzstd::StreamingDecoder::new(&mut frame)
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    fn expected() -> Vec<u8> {
+        DATA.repeat(100)
+    }
+
+    #[test]
+    fn none() {
+        assert_eq!(compressed(Compression::None), expected());
+    }
+
+    #[test]
+    fn gzip() {
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(compressed(Compression::Gzip).as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, expected());
+    }
+
+    #[test]
+    fn zstd() {
+        let frame = compressed(Compression::Zstd);
+        assert!(frame.len() < expected().len() / 4, "{} bytes", frame.len());
+        assert_eq!(unzstd(&frame), expected());
+    }
+
+    #[test]
+    fn finished_when_dropped() {
+        let mut frame = vec![];
+        let mut writer = CompressedWriter::new(&mut frame, Compression::Zstd);
+        writer.write_all(DATA).unwrap();
+        std::mem::drop(writer);
+        assert_eq!(unzstd(&frame), DATA);
+    }
+
+    #[test]
+    fn from_path() {
+        assert_eq!(Compression::from_path("heap.pb.gz"), Compression::Gzip);
+        assert_eq!(Compression::from_path("heap.pb.zst"), Compression::Zstd);
+        assert_eq!(Compression::from_path("heap.pb"), Compression::None);
+    }
+}
@@ -0,0 +1,1003 @@
+//! Setting up a session from a file, so that deployments can tune the profiling without changing the code, see
+//! [`HeapProfilerGuardBuilder::from_path`]. The file is JSON if it ends with `.json`, TOML otherwise (tables, keys,
+//! strings, numbers, booleans and arrays: no dates, inline tables nor arrays of tables). Every key is optional and
+//! maps to the builder method of the same name:
+//!
+//! ```toml
+//! period = "512KiB"          # bytes, or with a B, KiB, MiB or GiB suffix
+//! depth = 16
+//! skip_frames = 2
+//! service_name = "api"       # stamped into the pprof reports
+//! version = "1.4.2"
+//! git_sha = "3f2c9e1"
+//! max_stacks = 100000
+//! eviction = "lfu"           # "lru" (the default) or "lfu"
+//! memory_budget = "64MiB"    # the profiler's own memory
+//! over_budget = "stop"       # "coarsen" (the default) or "stop"
+//! track_live = true
+//! unwinder = "frame_pointers" # "backtrace", "frame_pointers" or "libunwind"
+//! warm_up = "30s"            # ns, us, ms, s, m or h; a bare number is in seconds
+//! baseline_after = "1m"
+//!
+//! [flush]
+//! strategy = "hybrid"        # "threshold", "interval", "hybrid" or "on_report"
+//! samples = 64
+//! interval = "1s"
+//! backpressure = "block"     # "block", "drop_newest", "drop_oldest" or "coalesce_in_place"
+//!
+//! [memory]
+//! interval = "1s"
+//! cgroup = true
+//!
+//! [peaks]
+//! dir = "/var/lib/heappy/peaks"
+//! keep = 5
+//! min_growth = 0.1
+//!
+//! [dumps]                    # snapshots of the running session
+//! dir = "/var/lib/heappy"
+//! template = "{service}-{trigger}-{ts}-{seq}.pb.gz"
+//! keep = 20
+//! every = "10m"
+//! signal = "SIGUSR2"         # "SIGUSR1", "SIGUSR2" or "SIGHUP"
+//!
+//! [checkpoint]               # of long sessions, resumed after a restart
+//! path = "/var/lib/heappy/session.ckpt.gz"
+//! every = "5m"
+//! resume = true
+//!
+//! [filters]
+//! drop_frames = ["tokio::runtime::.*"]
+//! include_threads = ["query-.*"] # only these threads' allocations
+//! exclude_threads = ["query-io"]
+//! only_focused = false           # only the allocations in a heappy::focus
+//! pprof_top = 1000               # the stacks the pprof reports keep
+//! sample_types = ["inuse_space", "alloc_space"] # the ones the pprof reports have
+//! default_sample_type = "inuse_space"
+//!
+//! [outputs]                  # written when the session ends
+//! pprof = "/var/lib/heappy/heap.pb"
+//! flamegraph = "/var/lib/heappy/heap.svg"
+//! flamegraph_palette = "by_crate" # or "memory" (the default)
+//! ```
+//!
+//! The other top-level keys are `call_sites_only`, `key_by_ip`, `separate_foreign_frees`, `record_addresses`,
+//! `churn_window`, `trace_large`, `canaries`, `cpu_profile`, `log_summary`, `console`, `profile_children` and
+//! `symbolize_in_background`, along with `keep_frames` in `[filters]`, `interval` and `template` in `[peaks]`,
+//! `on_drop`, `service` and `format` (one of the `[outputs]` keys) in `[dumps]` and `flame_chart`, `json`, `folded`,
+//! `normalized`, `dhat` and `markdown` in `[outputs]`. The file names can use the variables of
+//! [`DumpFiles`](crate::DumpFiles), and are compressed if they end with `.gz` or `.zst`. Unknown keys are errors, so
+//! that typos don't go unnoticed.
+//!
+//! [`HeapProfilerGuardBuilder::from_env`] sets a session up from the environment instead: the file of
+//! [`CONFIG_ENV`], the report file of [`REPORT_ENV`] (in [`REPORT_FORMAT_ENV`]), and [`PROFILE_ENV`] to turn the
+//! profiling off, so that a process is profiled (or not) without being rebuilt:
+//!
+//! ```text
+//! HEAPPY_CONFIG=heappy.toml HEAPPY_REPORT=/tmp/heap-{pid}.svg HEAPPY_REPORT_FORMAT=flamegraph ./server
+//! ```
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::backpressure::Backpressure;
+use crate::budget::OverBudget;
+use crate::collector::Eviction;
+use crate::dumps::DumpFiles;
+use crate::palette::FlamegraphPalette;
+use crate::profiler::{
+    Error, FlushStrategy, HeapProfilerGuardBuilder, ReportFormat, Result, SampleType,
+};
+use crate::unwinder::Backtrace;
+use crate::watermark::Watermarks;
+
+/// The variable of the configuration file of [`HeapProfilerGuardBuilder::from_env`].
+pub const CONFIG_ENV: &str = "HEAPPY_CONFIG";
+/// The variable of the file the report is written to when the session ends, see
+/// [`HeapProfilerGuardBuilder::write_report`].
+pub const REPORT_ENV: &str = "HEAPPY_REPORT";
+/// The variable of the format of the report file, one of the `[outputs]` keys: `pprof` if unset.
+pub const REPORT_FORMAT_ENV: &str = "HEAPPY_REPORT_FORMAT";
+/// The variable turning the profiling off, if `0`, `false` or `off`.
+pub const PROFILE_ENV: &str = "HEAPPY_PROFILE";
+
+impl HeapProfilerGuardBuilder {
+    /// A builder configured from the TOML or JSON file at `path`, see [`config`](crate::config) for the keys.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).map_err(|err| Error::ConfigFile(path.into(), err))?;
+        let json = path.extension().map_or(false, |ext| ext == "json");
+        let config = if json {
+            Parser::new(&text).json()
+        } else {
+            Parser::new(&text).toml()
+        };
+        config
+            .and_then(|config| configure(Self::default(), config))
+            .map_err(|message| Error::InvalidConfig(path.into(), message))
+    }
+
+    /// A builder configured from the environment, see [`config`](crate::config) for the variables: the parent's (see
+    /// [`from_parent`](Self::from_parent)) in a child of a profiled process, the one of the file of [`CONFIG_ENV`] if
+    /// it's set, `defaults` otherwise, writing its report to the file of [`REPORT_ENV`] if it's set. `None` if
+    /// [`PROFILE_ENV`] turns the profiling off.
+    pub fn from_env(defaults: Self) -> Result<Option<Self>> {
+        let var = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+        if let Some(profile) = var(PROFILE_ENV) {
+            match profile.to_string_lossy().as_ref() {
+                "0" | "false" | "off" => return Ok(None),
+                "1" | "true" | "on" => {}
+                other => return Err(Error::InvalidEnv(PROFILE_ENV, format!("{:?}", other))),
+            }
+        }
+        #[cfg(unix)]
+        let parent = Self::from_parent();
+        #[cfg(not(unix))]
+        let parent = None;
+        let mut builder = match (parent, var(CONFIG_ENV)) {
+            (Some(parent), _) => parent,
+            (None, Some(path)) => Self::from_path(path)?,
+            (None, None) => defaults,
+        };
+        if let Some(path) = var(REPORT_ENV) {
+            let format = match var(REPORT_FORMAT_ENV) {
+                Some(name) => {
+                    let name = name.to_string_lossy();
+                    report_format(&name).ok_or_else(|| {
+                        Error::InvalidEnv(REPORT_FORMAT_ENV, format!("unknown format {:?}", name))
+                    })?
+                }
+                None => ReportFormat::Pprof,
+            };
+            builder = builder.write_report(format, path);
+        }
+        Ok(Some(builder))
+    }
+}
+
+fn configure(
+    mut builder: HeapProfilerGuardBuilder,
+    config: Vec<(String, Value)>,
+) -> Result<HeapProfilerGuardBuilder, String> {
+    let mut top = Table::new("", config);
+    if let Some(period) = top.size("period")? {
+        builder = builder.period(period);
+    }
+    if let Some(depth) = top.integer("depth")? {
+        builder = builder.max_depth(depth);
+    }
+    if let Some(n) = top.integer("skip_frames")? {
+        builder = builder.skip_frames(n);
+    }
+    if let Some(name) = top.string("service_name")? {
+        builder = builder.service_name(name);
+    }
+    if let Some(version) = top.string("version")? {
+        builder = builder.version(version);
+    }
+    if let Some(sha) = top.string("git_sha")? {
+        builder = builder.git_sha(sha);
+    }
+    let eviction = match top.string("eviction")?.as_deref() {
+        None | Some("lru") => Eviction::LeastRecentlyUsed,
+        Some("lfu") => Eviction::LeastFrequentlyUsed,
+        Some(other) => return Err(format!("unknown eviction {:?}", other)),
+    };
+    if let Some(max) = top.integer("max_stacks")? {
+        builder = builder.max_stacks(max, eviction);
+    }
+    let policy = match top.string("over_budget")?.as_deref() {
+        None | Some("coarsen") => OverBudget::Coarsen,
+        Some("stop") => OverBudget::Stop,
+        Some(other) => return Err(format!("unknown over_budget {:?}", other)),
+    };
+    if let Some(budget) = top.size("memory_budget")? {
+        builder = builder.memory_budget(budget, policy);
+    }
+    if let Some(track_live) = top.bool("track_live")? {
+        builder = builder.track_live(track_live);
+    }
+    if let Some(call_sites_only) = top.bool("call_sites_only")? {
+        builder = builder.call_sites_only(call_sites_only);
+    }
+    if let Some(key_by_ip) = top.bool("key_by_ip")? {
+        builder = builder.key_by_ip(key_by_ip);
+    }
+    if let Some(unwinder) = top.string("unwinder")? {
+        builder = match unwinder.as_str() {
+            "backtrace" => builder.unwinder(Backtrace),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64"),
+                any(target_os = "linux", target_os = "android", target_os = "macos")
+            ))]
+            "frame_pointers" => builder.unwinder(crate::unwinder::FramePointers),
+            #[cfg(all(feature = "libunwind", target_os = "linux"))]
+            "libunwind" => builder.unwinder(crate::unwinder::LibUnwind),
+            other => return Err(format!("unwinder {:?} isn't available here", other)),
+        };
+    }
+    if let Some(separate) = top.bool("separate_foreign_frees")? {
+        builder = builder.separate_foreign_frees(separate);
+    }
+    if let Some(max) = top.duration("warm_up")? {
+        builder = builder.warm_up(max);
+    }
+    if let Some(startup) = top.duration("baseline_after")? {
+        builder = builder.baseline_after(startup);
+    }
+    if let Some(record) = top.bool("record_addresses")? {
+        builder = builder.record_addresses(record);
+    }
+    if let Some(window) = top.duration("churn_window")? {
+        builder = builder.churn_window(window);
+    }
+    if let Some(_path) = top.string("console")? {
+        #[cfg(unix)]
+        {
+            builder = builder.console(_path);
+        }
+        #[cfg(not(unix))]
+        return Err("console needs a unix platform".to_string());
+    }
+    if let Some(_path) = top.string("profile_children")? {
+        #[cfg(unix)]
+        {
+            builder = builder.profile_children(_path);
+        }
+        #[cfg(not(unix))]
+        return Err("profile_children needs a unix platform".to_string());
+    }
+    if let Some(interval) = top.duration("symbolize_in_background")? {
+        builder = builder.symbolize_in_background(interval);
+    }
+    if let Some(_interval) = top.duration("log_summary")? {
+        #[cfg(feature = "tracing")]
+        {
+            builder = builder.log_summary(_interval);
+        }
+        #[cfg(not(feature = "tracing"))]
+        return Err("log_summary needs the `tracing` feature".to_string());
+    }
+    if let Some(threshold) = top.size("trace_large")? {
+        builder = builder.trace_large(threshold);
+    }
+    if let Some(_every) = top.integer("canaries")? {
+        #[cfg(feature = "canary")]
+        {
+            builder = builder.canaries(_every);
+        }
+        #[cfg(not(feature = "canary"))]
+        return Err("canaries need the `canary` feature".to_string());
+    }
+    if let Some(frequency) = top.integer("cpu_profile")? {
+        let frequency = i32::try_from(frequency).map_err(|_| "cpu_profile is too large")?;
+        builder = builder.cpu_profile(frequency);
+    }
+
+    if let Some(mut flush) = top.table("flush")? {
+        let interval = flush.duration("interval")?;
+        let samples = flush.integer("samples")?;
+        let strategy = match flush.string("strategy")?.as_deref() {
+            None | Some("threshold") => FlushStrategy::Threshold,
+            Some("interval") => {
+                FlushStrategy::Interval(interval.ok_or("flush.interval is missing")?)
+            }
+            Some("hybrid") => FlushStrategy::Hybrid {
+                samples: samples.ok_or("flush.samples is missing")?,
+                interval: interval.ok_or("flush.interval is missing")?,
+            },
+            Some("on_report") => FlushStrategy::OnReport,
+            Some(other) => return Err(format!("unknown flush.strategy {:?}", other)),
+        };
+        builder = builder.flush_strategy(strategy);
+        if let Some(policy) = flush.string("backpressure")? {
+            builder = builder.backpressure(match policy.as_str() {
+                "block" => Backpressure::Block,
+                "drop_newest" => Backpressure::DropNewest,
+                "drop_oldest" => Backpressure::DropOldest,
+                "coalesce_in_place" => Backpressure::CoalesceInPlace,
+                other => return Err(format!("unknown flush.backpressure {:?}", other)),
+            });
+        }
+        flush.finish()?;
+    }
+    if let Some(mut memory) = top.table("memory")? {
+        let interval = memory
+            .duration("interval")?
+            .ok_or("memory.interval is missing")?;
+        let cgroup = memory.bool("cgroup")?.unwrap_or(false);
+        builder = builder.sample_memory(interval, cgroup);
+        memory.finish()?;
+    }
+    if let Some(mut peaks) = top.table("peaks")? {
+        let mut watermarks = Watermarks::default();
+        if let Some(interval) = peaks.duration("interval")? {
+            watermarks.interval = interval;
+        }
+        if let Some(min_growth) = peaks.float("min_growth")? {
+            watermarks.min_growth = min_growth;
+        }
+        let dir = peaks.string("dir")?.ok_or("peaks.dir is missing")?;
+        let keep = peaks.integer("keep")?.unwrap_or(0);
+        builder = match peaks.string("template")? {
+            Some(template) => {
+                let files = DumpFiles::new(dir).template(template).keep(keep);
+                builder.dump_peaks(watermarks, files)
+            }
+            None => builder.write_peaks(watermarks, dir, keep),
+        };
+        peaks.finish()?;
+    }
+    if let Some(mut dumps) = top.table("dumps")? {
+        let mut files = DumpFiles::new(dumps.string("dir")?.ok_or("dumps.dir is missing")?);
+        if let Some(template) = dumps.string("template")? {
+            files = files.template(template);
+        }
+        if let Some(keep) = dumps.integer("keep")? {
+            files = files.keep(keep);
+        }
+        if let Some(service) = dumps.string("service")? {
+            files = files.service(service);
+        }
+        if let Some(format) = dumps.string("format")? {
+            files = files.format(report_format(&format).ok_or("unknown dumps.format")?);
+        }
+        if dumps.bool("on_drop")?.unwrap_or(false) {
+            builder = builder.dump_on_drop(files.clone());
+        }
+        if let Some(interval) = dumps.duration("every")? {
+            builder = builder.dump_every(interval, files.clone());
+        }
+        if let Some(_signal) = dumps.string("signal")? {
+            #[cfg(unix)]
+            {
+                let signal = match _signal.as_str() {
+                    "SIGUSR1" => libc::SIGUSR1,
+                    "SIGUSR2" => libc::SIGUSR2,
+                    "SIGHUP" => libc::SIGHUP,
+                    other => return Err(format!("unknown dumps.signal {:?}", other)),
+                };
+                builder = builder.dump_on_signal(signal, files);
+            }
+            #[cfg(not(unix))]
+            return Err("dumps.signal needs a unix platform".to_string());
+        }
+        dumps.finish()?;
+    }
+    if let Some(mut checkpoint) = top.table("checkpoint")? {
+        let path = checkpoint
+            .string("path")?
+            .ok_or("checkpoint.path is missing")?;
+        if let Some(interval) = checkpoint.duration("every")? {
+            builder = builder.checkpoint(interval, path.clone());
+        }
+        if checkpoint.bool("resume")?.unwrap_or(false) {
+            builder = builder.resume_from(path);
+        }
+        checkpoint.finish()?;
+    }
+    if let Some(mut filters) = top.table("filters")? {
+        for regex in filters.strings("drop_frames")? {
+            builder = builder.drop_frames(regex);
+        }
+        for regex in filters.strings("keep_frames")? {
+            builder = builder.keep_frames(regex);
+        }
+        for regex in filters.strings("include_threads")? {
+            builder = builder.include_threads(regex);
+        }
+        for regex in filters.strings("exclude_threads")? {
+            builder = builder.exclude_threads(regex);
+        }
+        if let Some(only_focused) = filters.bool("only_focused")? {
+            builder = builder.only_focused(only_focused);
+        }
+        if let Some(n) = filters.integer("pprof_top")? {
+            builder = builder.pprof_top(n);
+        }
+        let sample_type = |name: String| {
+            SampleType::from_name(&name).ok_or_else(|| format!("unknown sample type {:?}", name))
+        };
+        let types = filters.strings("sample_types")?;
+        if !types.is_empty() {
+            let types = types
+                .into_iter()
+                .map(sample_type)
+                .collect::<Result<Vec<_>, _>>()?;
+            builder = builder.pprof_sample_types(types);
+        }
+        if let Some(ty) = filters.string("default_sample_type")? {
+            builder = builder.default_sample_type(sample_type(ty)?);
+        }
+        filters.finish()?;
+    }
+    if let Some(mut outputs) = top.table("outputs")? {
+        if let Some(palette) = outputs.string("flamegraph_palette")? {
+            builder = builder.flamegraph_palette(match palette.as_str() {
+                "memory" => FlamegraphPalette::Memory,
+                "by_crate" => FlamegraphPalette::ByCrate,
+                other => return Err(format!("unknown outputs.flamegraph_palette {:?}", other)),
+            });
+        }
+        for (key, format) in REPORT_FORMATS {
+            if let Some(path) = outputs.string(key)? {
+                builder = builder.write_report(format, path);
+            }
+        }
+        outputs.finish()?;
+    }
+    top.finish()?;
+    Ok(builder)
+}
+
+const REPORT_FORMATS: [(&str, ReportFormat); 8] = [
+    ("pprof", ReportFormat::Pprof),
+    ("flamegraph", ReportFormat::Flamegraph),
+    ("flame_chart", ReportFormat::FlameChart),
+    ("json", ReportFormat::Json),
+    ("folded", ReportFormat::Folded),
+    ("normalized", ReportFormat::Normalized),
+    ("dhat", ReportFormat::Dhat),
+    ("markdown", ReportFormat::Markdown),
+];
+
+pub(crate) fn report_format(name: &str) -> Option<ReportFormat> {
+    REPORT_FORMATS
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|&(_, format)| format)
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+// The keys of a table, taken one by one: whatever is left when it's finished is unknown.
+struct Table {
+    name: String,
+    entries: Vec<(String, Value)>,
+}
+
+impl Table {
+    fn new(name: &str, entries: Vec<(String, Value)>) -> Self {
+        Self {
+            name: name.to_string(),
+            entries,
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        if self.name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.name, key)
+        }
+    }
+
+    fn take(&mut self, key: &str) -> Option<Value> {
+        let i = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.remove(i).1)
+    }
+
+    fn expected<T>(&self, key: &str, what: &str, value: &Value) -> Result<T, String> {
+        Err(format!(
+            "{} should be {}, got {:?}",
+            self.path(key),
+            what,
+            value
+        ))
+    }
+
+    fn bool(&mut self, key: &str) -> Result<Option<bool>, String> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Bool(b)) => Ok(Some(b)),
+            Some(other) => self.expected(key, "a boolean", &other),
+        }
+    }
+
+    fn integer(&mut self, key: &str) -> Result<Option<usize>, String> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) if i >= 0 => Ok(Some(i as usize)),
+            Some(other) => self.expected(key, "a positive integer", &other),
+        }
+    }
+
+    fn float(&mut self, key: &str) -> Result<Option<f64>, String> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Float(f)) => Ok(Some(f)),
+            Some(Value::Integer(i)) => Ok(Some(i as f64)),
+            Some(other) => self.expected(key, "a number", &other),
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>, String> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(other) => self.expected(key, "a string", &other),
+        }
+    }
+
+    // A string or an array of them.
+    fn strings(&mut self, key: &str) -> Result<Vec<String>, String> {
+        match self.take(key) {
+            None => Ok(vec![]),
+            Some(Value::String(s)) => Ok(vec![s]),
+            Some(Value::Array(values)) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(s) => Ok(s),
+                    other => self.expected(key, "an array of strings", &other),
+                })
+                .collect(),
+            Some(other) => self.expected(key, "an array of strings", &other),
+        }
+    }
+
+    fn size(&mut self, key: &str) -> Result<Option<usize>, String> {
+        let (number, unit) = match self.take(key) {
+            None => return Ok(None),
+            Some(Value::Integer(i)) if i >= 0 => return Ok(Some(i as usize)),
+            Some(Value::String(s)) => match split_unit(&s) {
+                Some(split) => split,
+                None => return self.expected(key, "a size", &Value::String(s)),
+            },
+            Some(other) => return self.expected(key, "a size", &other),
+        };
+        let scale: u64 = match unit.as_str() {
+            "" | "B" => 1,
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            _ => return Err(format!("{}: unknown unit {:?}", self.path(key), unit)),
+        };
+        Ok(Some((number * scale as f64) as usize))
+    }
+
+    fn duration(&mut self, key: &str) -> Result<Option<Duration>, String> {
+        let (number, unit) = match self.take(key) {
+            None => return Ok(None),
+            Some(Value::Integer(i)) if i >= 0 => (i as f64, "s".to_string()),
+            Some(Value::Float(f)) if f >= 0.0 => (f, "s".to_string()),
+            Some(Value::String(s)) => match split_unit(&s) {
+                Some(split) => split,
+                None => return self.expected(key, "a duration", &Value::String(s)),
+            },
+            Some(other) => return self.expected(key, "a duration", &other),
+        };
+        let secs = match unit.as_str() {
+            "ns" => number / 1e9,
+            "us" => number / 1e6,
+            "ms" => number / 1e3,
+            "" | "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return Err(format!("{}: unknown unit {:?}", self.path(key), unit)),
+        };
+        Duration::try_from_secs_f64(secs)
+            .map(Some)
+            .map_err(|_| format!("{}: out of range", self.path(key)))
+    }
+
+    fn table(&mut self, key: &str) -> Result<Option<Table>, String> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Table(entries)) => Ok(Some(Table::new(&self.path(key), entries))),
+            Some(other) => self.expected(key, "a table", &other),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.entries.first() {
+            None => Ok(()),
+            Some((key, _)) => Err(format!("unknown key {}", self.path(key))),
+        }
+    }
+}
+
+// `1.5MiB` into `(1.5, "MiB")`.
+fn split_unit(s: &str) -> Option<(f64, String)> {
+    let s = s.trim();
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let number = s[..end].parse().ok()?;
+    Some((number, s[end..].trim().to_string()))
+}
+
+// Both formats, over the characters of the file.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Self {
+        Self {
+            chars: text.chars().collect(),
+            pos: 0,
+            line: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn error<T>(&self, message: impl std::fmt::Display) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line, message))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => self.error(format!("expected {:?}, got {:?}", expected, c)),
+            None => self.error(format!("expected {:?}, got the end of the file", expected)),
+        }
+    }
+
+    // Spaces within a line.
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+            self.next();
+        }
+    }
+
+    // Spaces, newlines and, in TOML, comments.
+    fn skip_blank(&mut self, comments: bool) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.next();
+                }
+                Some('#') if comments => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.next();
+        }
+    }
+
+    // The end of a TOML line: nothing but a comment until the newline.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(c) => self.error(format!("unexpected {:?}", c)),
+        }
+    }
+
+    fn toml(mut self) -> Result<Vec<(String, Value)>, String> {
+        let mut top = vec![];
+        // the entries of the table being read, and where they go.
+        let mut current: Option<(String, Vec<(String, Value)>)> = None;
+        loop {
+            self.skip_blank(true);
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    self.next();
+                    self.skip_spaces();
+                    let name = self.toml_key()?;
+                    self.skip_spaces();
+                    self.expect(']')?;
+                    self.end_of_line()?;
+                    if top.iter().any(|(k, _)| *k == name) {
+                        return self.error(format!("{} is defined twice", name));
+                    }
+                    if let Some((name, entries)) = current.replace((name, vec![])) {
+                        top.push((name, Value::Table(entries)));
+                    }
+                }
+                Some(_) => {
+                    let key = self.toml_key()?;
+                    self.skip_spaces();
+                    self.expect('=')?;
+                    self.skip_spaces();
+                    let value = self.value(true)?;
+                    self.end_of_line()?;
+                    let entries = current.as_mut().map_or(&mut top, |(_, entries)| entries);
+                    if entries.iter().any(|(k, _)| *k == key) {
+                        return self.error(format!("{} is defined twice", key));
+                    }
+                    entries.push((key, value));
+                }
+            }
+        }
+        if let Some((name, entries)) = current {
+            if top.iter().any(|(k, _)| *k == name) {
+                return self.error(format!("{} is defined twice", name));
+            }
+            top.push((name, Value::Table(entries)));
+        }
+        Ok(top)
+    }
+
+    fn toml_key(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some('"') => self.string('"'),
+            Some('\'') => self.string('\''),
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.next();
+                }
+                if start == self.pos {
+                    return self.error("expected a key");
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn json(mut self) -> Result<Vec<(String, Value)>, String> {
+        self.skip_blank(false);
+        let value = self.value(false)?;
+        self.skip_blank(false);
+        match (value, self.peek()) {
+            (Value::Table(entries), None) => Ok(entries),
+            (Value::Table(_), Some(c)) => self.error(format!("unexpected {:?}", c)),
+            _ => self.error("expected an object"),
+        }
+    }
+
+    fn value(&mut self, toml: bool) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.string('"').map(Value::String),
+            Some('\'') if toml => self.string('\'').map(Value::String),
+            Some('[') => {
+                self.next();
+                let mut values = vec![];
+                loop {
+                    self.skip_blank(toml);
+                    if self.peek() == Some(']') {
+                        self.next();
+                        break;
+                    }
+                    values.push(self.value(toml)?);
+                    self.skip_blank(toml);
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => break,
+                        _ => return self.error("expected ',' or ']' in the array"),
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            Some('{') if !toml => {
+                self.next();
+                let mut entries: Vec<(String, Value)> = vec![];
+                loop {
+                    self.skip_blank(false);
+                    if entries.is_empty() && self.peek() == Some('}') {
+                        self.next();
+                        break;
+                    }
+                    let key = self.string('"')?;
+                    self.skip_blank(false);
+                    self.expect(':')?;
+                    self.skip_blank(false);
+                    let value = self.value(false)?;
+                    if entries.iter().any(|(k, _)| *k == key) {
+                        return self.error(format!("{} is defined twice", key));
+                    }
+                    entries.push((key, value));
+                    self.skip_blank(false);
+                    match self.next() {
+                        Some(',') => {}
+                        Some('}') => break,
+                        _ => return self.error("expected ',' or '}' in the object"),
+                    }
+                }
+                Ok(Value::Table(entries))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphabetic()) {
+                    self.next();
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => self.error(format!("unexpected {:?}", word)),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || "+-._eE".contains(c)) {
+                    self.next();
+                }
+                let number: String = self.chars[start..self.pos]
+                    .iter()
+                    .filter(|&&c| !(toml && c == '_'))
+                    .collect();
+                if let Ok(i) = number.parse() {
+                    Ok(Value::Integer(i))
+                } else if let Ok(f) = number.parse() {
+                    Ok(Value::Float(f))
+                } else {
+                    self.error(format!("invalid number {:?}", number))
+                }
+            }
+            Some(c) => self.error(format!("unexpected {:?}", c)),
+            None => self.error("expected a value, got the end of the file"),
+        }
+    }
+
+    // A string quoted with `quote`: single quotes (TOML's literal strings) don't have escapes.
+    fn string(&mut self, quote: char) -> Result<String, String> {
+        self.expect(quote)?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some(c) if c == quote => return Ok(s),
+                Some('\\') if quote == '"' => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.next()).collect();
+                            match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                                Some(c) => c,
+                                None => return self.error(format!("invalid escape \\u{}", hex)),
+                            }
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        _ => return self.error("invalid escape"),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml(text: &str) -> Result<(), String> {
+        Parser::new(text)
+            .toml()
+            .and_then(|config| configure(HeapProfilerGuardBuilder::default(), config))
+            .map(|_| ())
+    }
+
+    fn json(text: &str) -> Result<(), String> {
+        Parser::new(text)
+            .json()
+            .and_then(|config| configure(HeapProfilerGuardBuilder::default(), config))
+            .map(|_| ())
+    }
+
+    #[test]
+    fn documented_example() {
+        let example: String = include_str!("config.rs")
+            .lines()
+            .skip_while(|line| *line != "//! ```toml")
+            .skip(1)
+            .take_while(|line| *line != "//! ```")
+            // the frame pointers are only walked on some targets.
+            .filter(|line| {
+                cfg!(all(
+                    any(target_arch = "x86_64", target_arch = "aarch64"),
+                    any(
+                        target_os = "linux",
+                        target_os = "android",
+                        target_os = "macos"
+                    )
+                )) || !line.contains("frame_pointers")
+            })
+            .map(|line| format!("{}\n", line.trim_start_matches("//!").trim_start()))
+            .collect();
+        assert!(example.contains("[flush]"));
+        toml(&example).unwrap();
+    }
+
+    #[test]
+    fn json_config() {
+        json(r#"{"period": "512KiB", "depth": 16, "flush": {"strategy": "on_report"}}"#).unwrap();
+    }
+
+    #[test]
+    fn duplicates() {
+        let err = toml("[flush]\nstrategy = \"on_report\"\n[flush]\n").unwrap_err();
+        assert!(err.contains("flush is defined twice"), "{}", err);
+        let err = toml("depth = 1\ndepth = 2\n").unwrap_err();
+        assert!(err.contains("depth is defined twice"), "{}", err);
+        let err = json(r#"{"depth": 1, "depth": 2}"#).unwrap_err();
+        assert!(err.contains("depth is defined twice"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert_eq!(toml("deph = 16\n").unwrap_err(), "unknown key deph");
+        assert_eq!(
+            toml("[flush]\nstrategy = \"on_report\"\nsample = 1\n").unwrap_err(),
+            "unknown key flush.sample"
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let config = Parser::new(
+            r#"a = 'C:\dir\n' # literal
+b = "\t\"\u00e9\\"
+"#,
+        )
+        .toml()
+        .unwrap();
+        assert_eq!(
+            config,
+            vec![
+                ("a".to_string(), Value::String("C:\\dir\\n".to_string())),
+                ("b".to_string(), Value::String("\t\"\u{e9}\\".to_string())),
+            ]
+        );
+        assert!(Parser::new("a = \"\\q\"\n").toml().is_err());
+        assert!(Parser::new("a = \"unterminated\n").toml().is_err());
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let config = Parser::new("a = [\n  \"x\",\n  \"y\",\n]\n")
+            .toml()
+            .unwrap();
+        assert_eq!(
+            config,
+            vec![(
+                "a".to_string(),
+                Value::Array(vec![
+                    Value::String("x".to_string()),
+                    Value::String("y".to_string())
+                ])
+            )]
+        );
+        // JSON has none.
+        assert!(Parser::new(r#"{"a": 1,}"#).json().is_err());
+    }
+
+    #[test]
+    fn out_of_range() {
+        let err = toml("warm_up = 1e20\n").unwrap_err();
+        assert_eq!(err, "warm_up: out of range");
+        let err = toml("warm_up = \"99999999999999999999999h\"\n").unwrap_err();
+        assert_eq!(err, "warm_up: out of range");
+        assert!(toml("depth = 99999999999999999999\n").is_err());
+        assert!(toml("depth = -1\n").is_err());
+    }
+}
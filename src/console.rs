@@ -0,0 +1,209 @@
+//! A control socket of the running session, see
+//! [`HeapProfilerGuardBuilder::console`](crate::HeapProfilerGuardBuilder::console), for looking into a process from
+//! the outside in the spirit of tokio-console: `heappy console /tmp/service.heappy` (or `socat - UNIX:...`) talks to
+//! it. The protocol is text, a command per line, each answered by lines ending with an empty one:
+//!
+//! ```text
+//! > stats
+//! duration_secs 12.5
+//! allocated_bytes 1048576
+//! allocated_objects 312
+//! in_use_bytes 524288
+//! samples 97
+//! > top 2
+//! 262144 128 2048 2048 2048 my_crate::cache::insert
+//! 131072 64 16 1841 65536 my_crate::parse
+//! > dump /tmp/heap.pb.gz
+//! ok /tmp/heap.pb.gz
+//! ```
+//!
+//! `stats` has the totals of the session, `top [n]` its `n` (10) stacks with the most bytes (in use with the
+//! `measure_free` feature, allocated without) with their objects, the minimum, mean and maximum sizes of their
+//! allocations (see [`AllocationSizes`](crate::collector::AllocationSizes)) and call site, and `dump <path> [format]`
+//! writes a snapshot as a report (a pprof profile by default, or one of the `[outputs]` keys of the configuration
+//! files), compressed if the path ends with `.gz` or `.zst`. Anything the process can write to, whoever can connect can
+//! too: the socket is only the owner's (mode 0600).
+
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::collector::MemProfileRecord;
+use crate::profiler::{HeapReport, Profiler, ReportFormat, HEAP_PROFILER_STATE};
+use crate::task::{self, Task};
+
+// How often the socket is checked for connections and commands.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How long a client that doesn't read its answers holds the console up.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+// Past which a line is no command.
+const MAX_LINE: usize = 4096;
+const DEFAULT_TOP: usize = 10;
+
+/// The socket file, removed when dropped.
+pub(crate) struct ConsoleSocket(PathBuf);
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+struct Client {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+// Listens on `path` until the socket is dropped and the task aborted.
+pub(crate) fn spawn(path: &Path) -> io::Result<(ConsoleSocket, Task<()>)> {
+    // left over by a process that didn't get to remove it.
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    let socket = ConsoleSocket(path.to_path_buf());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    let mut clients: Vec<Client> = vec![];
+    let task = task::every(POLL_INTERVAL, move || {
+        while let Ok((stream, _)) = listener.accept() {
+            if stream.set_nonblocking(true).is_ok()
+                && stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok()
+            {
+                clients.push(Client {
+                    stream,
+                    line: vec![],
+                });
+            }
+        }
+        clients.retain_mut(|client| client.serve().is_ok());
+    });
+    Ok((socket, task))
+}
+
+impl Client {
+    // Answers the commands received so far, an error once the client is gone.
+    fn serve(&mut self) -> io::Result<()> {
+        let mut buf = [0; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.line.extend_from_slice(&buf[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        while let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            let answer = answer(String::from_utf8_lossy(&line).trim());
+            // the answer is written whole, the client waits for it.
+            self.stream.set_nonblocking(false)?;
+            self.stream.write_all(answer.as_bytes())?;
+            self.stream.write_all(b"\n")?;
+            self.stream.set_nonblocking(true)?;
+        }
+        if self.line.len() > MAX_LINE {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(())
+    }
+}
+
+// The answer to `command`, its lines ending with newlines.
+fn answer(command: &str) -> String {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("stats"), None, _) => stats(),
+        (Some("top"), n, None) => match n.map(str::parse).unwrap_or(Ok(DEFAULT_TOP)) {
+            Ok(n) => top(n),
+            Err(_) => "error invalid count\n".to_string(),
+        },
+        (Some("dump"), Some(path), format) => {
+            let format = match format {
+                Some(name) => match crate::config::report_format(name) {
+                    Some(format) => format,
+                    None => return format!("error unknown format {}\n", name),
+                },
+                None => ReportFormat::Pprof,
+            };
+            match HeapReport::snapshot().write_to(format, Path::new(path)) {
+                Ok(()) => format!("ok {}\n", path),
+                Err(err) => format!("error {}\n", err),
+            }
+        }
+        (None, _, _) => String::new(),
+        _ => "error commands: stats, top [n], dump <path> [format]\n".to_string(),
+    }
+}
+
+fn stats() -> String {
+    let profiler = HEAP_PROFILER_STATE.read().unwrap();
+    let (totals, samples, elapsed) = (profiler.totals(), profiler.samples, profiler.elapsed());
+    std::mem::drop(profiler);
+    format!(
+        concat!(
+            "duration_secs {}\nallocated_bytes {}\nallocated_objects {}\nfreed_bytes {}\nfreed_objects {}\n",
+            "in_use_bytes {}\nsamples {}\n",
+        ),
+        elapsed.as_secs_f64(),
+        totals.allocated_bytes,
+        totals.allocated_objects,
+        totals.freed_bytes,
+        totals.freed_objects,
+        totals.in_use_bytes(),
+        samples
+    )
+}
+
+fn top(n: usize) -> String {
+    let profiler = HEAP_PROFILER_STATE.read().unwrap();
+    let top = Profiler::untracked(|| {
+        let mut stacks: Vec<_> = profiler.collector.iter().collect();
+        stacks.sort_by_key(|(_, rec)| std::cmp::Reverse(weight(rec)));
+        stacks
+            .into_iter()
+            .take(n)
+            .map(|(key, rec)| (key.clone(), weight(rec), objects(rec), rec.allocated.sizes))
+            .collect::<Vec<_>>()
+    })
+    .unwrap_or_default();
+    // symbolized without holding up the flushes.
+    std::mem::drop(profiler);
+    Profiler::untracked(|| {
+        top.into_iter()
+            .map(|(key, bytes, objects, sizes)| {
+                let frames: pprof::Frames = key.frames.into();
+                let leaf = crate::callsite::leaf(&frames).map(|symbol| symbol.name());
+                format!(
+                    "{} {} {} {:.0} {} {}\n",
+                    bytes,
+                    objects,
+                    sizes.min_bytes,
+                    sizes.mean_bytes().unwrap_or_default(),
+                    sizes.max_bytes,
+                    leaf.as_deref().unwrap_or("?")
+                )
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+// What's still in use with the `measure_free` feature, what was allocated without.
+fn weight(rec: &MemProfileRecord) -> i64 {
+    #[cfg(feature = "measure_free")]
+    return rec.in_use_bytes();
+    #[cfg(not(feature = "measure_free"))]
+    return rec.alloc_bytes;
+}
+
+fn objects(rec: &MemProfileRecord) -> i64 {
+    #[cfg(feature = "measure_free")]
+    return rec.in_use_objects();
+    #[cfg(not(feature = "measure_free"))]
+    return rec.alloc_objects;
+}
@@ -0,0 +1,209 @@
+//! Allocations per iteration of [criterion](https://docs.rs/criterion) benchmarks, measured along with their time and
+//! compared with the previous run, so that the benches catch allocation regressions too:
+//!
+//! ```ignore
+//! fn parse(c: &mut Criterion) {
+//!     heappy::dummy_force_link();
+//!     c.bench_function("parse", |b| {
+//!         b.iter_custom(|iters| heappy::criterion::iter("parse", iters, || parse(black_box(INPUT))))
+//!     });
+//! }
+//!
+//! criterion_group!(benches, parse);
+//!
+//! fn main() {
+//!     benches();
+//!     Criterion::default().configure_from_args().final_summary();
+//!     heappy::criterion::print_summary().unwrap();
+//! }
+//! ```
+//!
+//! prints, after criterion's own results:
+//!
+//! ```text
+//! parse: 1824.0 bytes in 12.00 allocations per iteration (bytes +4.21%, allocations +0.00%)
+//! ```
+//!
+//! The allocations are counted as for [`testing::assert_no_alloc`](crate::testing::assert_no_alloc), by the
+//! `enable_heap_profiler` hooks or a [`ProfilingAllocator`](crate::ProfilingAllocator), on the benchmarking thread
+//! only; no session is needed. The summary is saved to `allocations.tsv` in criterion's directory (`$CRITERION_HOME`,
+//! or `criterion` in the target directory) for the next run to be compared with.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const SUMMARY: &str = "allocations.tsv";
+
+lazy_static::lazy_static! {
+    static ref BENCHMARKS: spin::Mutex<BTreeMap<String, Benchmark>> = Default::default();
+}
+
+/// What the iterations of a benchmark allocated, over all the samples criterion took (its warm-up included).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Benchmark {
+    pub id: String,
+    pub iterations: u64,
+    /// The bytes asked for, a reallocation counting its new size.
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+impl Benchmark {
+    pub fn bytes_per_iteration(&self) -> f64 {
+        self.bytes as f64 / self.iterations.max(1) as f64
+    }
+
+    pub fn objects_per_iteration(&self) -> f64 {
+        self.objects as f64 / self.iterations.max(1) as f64
+    }
+}
+
+impl fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:.1} bytes in {:.2} allocations per iteration",
+            self.id,
+            self.bytes_per_iteration(),
+            self.objects_per_iteration()
+        )
+    }
+}
+
+/// Runs `routine` `iters` times for `Bencher::iter_custom`, returning how long that took and adding what it allocated
+/// to the benchmark `id`.
+pub fn iter<R>(id: &str, iters: u64, mut routine: impl FnMut() -> R) -> Duration {
+    // the actual time, whichever clock the sessions use.
+    let start = Instant::now();
+    let ((), bytes, objects) = crate::testing::count(|| {
+        for _ in 0..iters {
+            std::hint::black_box(routine());
+        }
+    });
+    let elapsed = start.elapsed();
+    add(id, iters, bytes, objects);
+    elapsed
+}
+
+/// Like [`iter`], with an input made by `setup` for each iteration: neither timed nor counted, nor is dropping the
+/// output.
+pub fn iter_batched<I, R>(
+    id: &str,
+    iters: u64,
+    mut setup: impl FnMut() -> I,
+    mut routine: impl FnMut(I) -> R,
+) -> Duration {
+    let inputs: Vec<I> = (0..iters).map(|_| setup()).collect();
+    let mut outputs: Vec<R> = Vec::with_capacity(inputs.len());
+    let start = Instant::now();
+    let ((), bytes, objects) = crate::testing::count(|| {
+        for input in inputs {
+            outputs.push(routine(input));
+        }
+    });
+    let elapsed = start.elapsed();
+    drop(outputs);
+    add(id, iters, bytes, objects);
+    elapsed
+}
+
+fn add(id: &str, iters: u64, bytes: u64, objects: u64) {
+    let mut benchmarks = BENCHMARKS.lock();
+    let benchmark = benchmarks
+        .entry(id.to_string())
+        .or_insert_with(|| Benchmark {
+            id: id.to_string(),
+            ..Default::default()
+        });
+    benchmark.iterations += iters;
+    benchmark.bytes += bytes;
+    benchmark.objects += objects;
+}
+
+/// The benchmarks measured so far, by id.
+pub fn summary() -> Vec<Benchmark> {
+    BENCHMARKS.lock().values().cloned().collect()
+}
+
+/// Prints the [`summary`] with the changes since the previous run, and saves it for the next one.
+pub fn print_summary() -> io::Result<()> {
+    let path = directory().join(SUMMARY);
+    let mut saved = match std::fs::read_to_string(&path) {
+        Ok(previous) => parse(&previous),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(err) => return Err(err),
+    };
+    let mut stdout = io::stdout().lock();
+    for benchmark in summary() {
+        write!(stdout, "{}", benchmark)?;
+        if let Some(previous) = saved.get(&benchmark.id) {
+            write!(
+                stdout,
+                " (bytes {}, allocations {})",
+                change(
+                    previous.bytes_per_iteration(),
+                    benchmark.bytes_per_iteration()
+                ),
+                change(
+                    previous.objects_per_iteration(),
+                    benchmark.objects_per_iteration()
+                )
+            )?;
+        }
+        writeln!(stdout)?;
+        saved.insert(benchmark.id.clone(), benchmark);
+    }
+    // the benchmarks filtered out of this run keep their previous numbers.
+    std::fs::create_dir_all(directory())?;
+    let mut file = io::BufWriter::new(std::fs::File::create(&path)?);
+    for benchmark in saved.values() {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}",
+            benchmark.id.replace(['\t', '\n'], " "),
+            benchmark.iterations,
+            benchmark.bytes,
+            benchmark.objects
+        )?;
+    }
+    file.flush()
+}
+
+// Where criterion writes its results.
+fn directory() -> PathBuf {
+    if let Some(home) = std::env::var_os("CRITERION_HOME") {
+        return home.into();
+    }
+    std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| "target".into())
+        .join("criterion")
+}
+
+fn parse(summary: &str) -> BTreeMap<String, Benchmark> {
+    summary
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let id = fields.next()?.to_string();
+            let mut number = || fields.next()?.parse().ok();
+            let benchmark = Benchmark {
+                id: id.clone(),
+                iterations: number()?,
+                bytes: number()?,
+                objects: number()?,
+            };
+            Some((id, benchmark))
+        })
+        .collect()
+}
+
+fn change(previous: f64, current: f64) -> String {
+    if previous == 0.0 {
+        return if current == 0.0 { "+0.00%" } else { "from 0" }.to_string();
+    }
+    format!("{:+.2}%", (current - previous) / previous * 100.0)
+}
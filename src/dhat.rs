@@ -0,0 +1,73 @@
+//! DHAT's `dhat-heap.json` files of the reports, see [`HeapReport::write_dhat`](crate::HeapReport::write_dhat), for
+//! Valgrind's `dh_view.html` (as written by the `dhat` crate). The samples don't record their blocks' lifetimes, so the
+//! files are without them (`"bklt": false`): the viewer shows the bytes and blocks allocated by stack, not the ones
+//! live at the peak or at the end.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::collector::MemProfileRecord;
+use crate::profiler::json_string;
+
+// Writes the `stacks` of a session that ran for `duration`.
+pub(crate) fn write<'a, W: Write>(
+    mut writer: W,
+    duration: Duration,
+    stacks: impl IntoIterator<Item = (&'a pprof::Frames, &'a MemProfileRecord)>,
+) -> std::io::Result<()> {
+    let command: Vec<_> = std::env::args().collect();
+    write!(
+        writer,
+        concat!(
+            r#"{{"dhatFileVersion":2,"mode":"rust-heap","verb":"Allocated","bklt":false,"bkacc":false,"#,
+            r#""tu":"µs","Mtu":"s","cmd":{},"pid":{},"te":{},"pps":["#,
+        ),
+        json_string(&command.join(" ")),
+        std::process::id(),
+        duration.as_micros(),
+    )?;
+    // the first frame is the root of the tree.
+    let mut frames = vec!["[root]".to_string()];
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    for (i, (stack, rec)) in stacks.into_iter().enumerate() {
+        let fs: Vec<_> = crate::callsite::past_hook(stack)
+            .into_iter()
+            .map(|symbol| {
+                let frame = frame(symbol);
+                let next = frames.len();
+                let id = *ids.entry(frame).or_insert_with_key(|frame| {
+                    frames.push(frame.clone());
+                    next
+                });
+                id.to_string()
+            })
+            .collect();
+        write!(
+            writer,
+            "{}\n{{\"tb\":{},\"tbk\":{},\"fs\":[{}]}}",
+            if i == 0 { "" } else { "," },
+            rec.alloc_bytes,
+            rec.alloc_objects,
+            fs.join(","),
+        )?;
+    }
+    let frames: Vec<_> = frames.iter().map(|frame| json_string(frame)).collect();
+    writeln!(writer, "\n],\"ftbl\":[\n{}\n]}}", frames.join(",\n"))
+}
+
+// A frame as DHAT writes them: `0x1234: function (file:line)`.
+fn frame(symbol: &pprof::Symbol) -> String {
+    let mut frame = match symbol.addr {
+        Some(addr) => format!("{:#x}: {}", addr as usize, symbol.name()),
+        None => symbol.name(),
+    };
+    if let Some(file) = &symbol.filename {
+        frame += &format!(" ({}", file.display());
+        if let Some(line) = symbol.lineno {
+            frame += &format!(":{}", line);
+        }
+        frame.push(')');
+    }
+    frame
+}
@@ -0,0 +1,338 @@
+//! Reports of the running session written to files automatically: when it ends, on a signal, periodically or at each
+//! new peak (see [`Watermarks`](crate::Watermarks)), named after a template and bounded to the newest few.
+//!
+//! ```ignore
+//! let dumps = heappy::DumpFiles::new("/var/lib/heappy")
+//!     .template("{service}-{ts}-{seq}.heap.pb.gz")
+//!     .keep(20);
+//! let _guard = heappy::HeapProfilerGuardBuilder::default()
+//!     .period(4096)
+//!     .dump_every(Duration::from_secs(600), dumps.clone())
+//!     .dump_on_signal(libc::SIGUSR2, dumps)
+//!     .build()
+//!     .await?;
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+
+use crate::profiler::{Error, HeapReport, ReportFormat};
+use crate::task::{self, Task};
+
+pub(crate) type ReportCallback = Arc<dyn Fn(HeapReport) + Send + Sync>;
+
+const VARIABLES: [&str; 7] = [
+    "service", "hostname", "pid", "ts", "seq", "trigger", "bytes",
+];
+
+/// Where the automatic dumps go: a directory and a file name template, made of text and the variables
+///
+/// - `{service}`, the name of the executable unless set with [`service`](Self::service),
+/// - `{hostname}` and `{pid}`,
+/// - `{ts}`, the UTC time of the dump, like `20240131T235959Z`,
+/// - `{seq}`, the number of the dump among the ones of these files (and of their clones), from `0001`,
+/// - `{trigger}`, what the dump is for: `drop` (the end of the session), `signal`, `periodic` or `peak`,
+/// - `{bytes}`, the bytes in use when the dump was taken.
+///
+/// The extension of the name picks the compression, see [`Compression::from_path`](crate::Compression::from_path).
+#[derive(Clone, Debug)]
+pub struct DumpFiles {
+    dir: PathBuf,
+    template: String,
+    format: ReportFormat,
+    keep: usize,
+    service: Option<String>,
+    seq: Arc<AtomicU64>,
+}
+
+impl DumpFiles {
+    /// pprof dumps in `dir`, named `{service}-{trigger}-{ts}-{seq}.pb.gz`, all kept.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            template: "{service}-{trigger}-{ts}-{seq}.pb.gz".to_string(),
+            format: ReportFormat::Pprof,
+            keep: 0,
+            service: None,
+            seq: Default::default(),
+        }
+    }
+
+    // The file at `path`, whose name can use the variables too.
+    pub(crate) fn at(path: &Path, format: ReportFormat) -> Self {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Self::new(path.parent().unwrap_or(Path::new("")))
+            .template(name)
+            .format(format)
+    }
+
+    pub fn template(mut self, template: impl Into<String>) -> Self {
+        self.template = template.into();
+        self
+    }
+
+    pub fn format(mut self, format: ReportFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Keeps only the newest `keep` files of the directory whose name fits the template, removing the older ones
+    /// (e.g. of previous runs) after each dump, or all of them with 0.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        match parts(&self.template)
+            .find(|part| matches!(part, Part::Variable(name) if !VARIABLES.contains(name)))
+        {
+            Some(Part::Variable(name)) => {
+                Err(Error::DumpTemplate(self.template.clone(), name.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the report to the next file and removes the ones beyond [`keep`](Self::keep). With the `tracing`
+    /// feature the dumps that can't be written are logged as warnings.
+    pub(crate) fn write(&self, report: &HeapReport, trigger: &str) {
+        let path = self
+            .dir
+            .join(self.name(trigger, report.totals().in_use_bytes()));
+        let result =
+            std::fs::create_dir_all(&self.dir).and_then(|()| report.write_to(self.format, &path));
+        if let Err(_err) = result {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(path = %path.display(), error = %_err, "cannot write heap dump");
+            return;
+        }
+        if self.keep > 0 {
+            self.prune();
+        }
+    }
+
+    fn name(&self, trigger: &str, bytes: i64) -> String {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        parts(&self.template)
+            .map(|part| match part {
+                Part::Text(text) => text.to_string(),
+                Part::Variable("service") => self.service.clone().unwrap_or_else(service),
+                Part::Variable("hostname") => hostname(),
+                Part::Variable("pid") => std::process::id().to_string(),
+                Part::Variable("ts") => timestamp(crate::clock::system_time()),
+                Part::Variable("seq") => format!("{:04}", seq),
+                Part::Variable("trigger") => trigger.to_string(),
+                Part::Variable("bytes") => bytes.to_string(),
+                // refused by check.
+                Part::Variable(name) => format!("{{{}}}", name),
+            })
+            .collect()
+    }
+
+    // Removes the oldest files fitting the template beyond `keep`.
+    fn prune(&self) {
+        let pattern: String = parts(&self.template)
+            .map(|part| match part {
+                Part::Text(text) => regex::escape(text),
+                Part::Variable(_) => ".+".to_string(),
+            })
+            .collect();
+        let Ok(pattern) = Regex::new(&format!("^{}$", pattern)) else {
+            return;
+        };
+        let dir = match self.dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => &self.dir,
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut dumps: Vec<_> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().into_string().ok()?;
+                let modified = entry.metadata().ok()?.modified().ok()?;
+                pattern.is_match(&name).then_some((modified, name))
+            })
+            .collect();
+        dumps.sort();
+        for (_, name) in &dumps[..dumps.len().saturating_sub(self.keep)] {
+            let _ = std::fs::remove_file(dir.join(name));
+        }
+    }
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+// The text and the `{variables}` of a template.
+fn parts(template: &str) -> impl Iterator<Item = Part<'_>> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        if let Some(variable) = rest.strip_prefix('{') {
+            if let Some(end) = variable.find('}') {
+                rest = &variable[end + 1..];
+                return Some(Part::Variable(&variable[..end]));
+            }
+        }
+        let end = rest[1..].find('{').map_or(rest.len(), |end| end + 1);
+        let (text, tail) = rest.split_at(end);
+        rest = tail;
+        Some(Part::Text(text))
+    })
+}
+
+fn service() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "heappy".to_string())
+}
+
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is as long as we say, and gethostname leaves it nul terminated if the name fits.
+    let result = unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len() - 1) };
+    let len = name.iter().position(|&b| b == 0).unwrap_or(0);
+    match result {
+        0 if len > 0 => String::from_utf8_lossy(&name[..len]).into_owned(),
+        _ => "localhost".to_string(),
+    }
+}
+
+// `time` in UTC, like `20240131T235959Z`.
+fn timestamp(time: SystemTime) -> String {
+    let [year, month, day, hours, minutes, seconds] = utc(time);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year, month, day, hours, minutes, seconds
+    )
+}
+
+// `time` in UTC as RFC 3339, like `2024-01-31T23:59:59Z`.
+#[cfg(feature = "sentry")]
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let [year, month, day, hours, minutes, seconds] = utc(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hours, minutes, seconds
+    )
+}
+
+// The year, month, day, hours, minutes and seconds of `time` in UTC.
+fn utc(time: SystemTime) -> [u64; 6] {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // the civil date of a day count, from Howard Hinnant's date algorithms.
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    [year, month, day, secs / 3600, secs / 60 % 60, secs % 60]
+}
+
+// Dumps the running session every `interval` until aborted.
+pub(crate) fn spawn_periodic(interval: Duration, files: DumpFiles) -> Task<()> {
+    let mut started = false;
+    task::every(interval, move || {
+        // the first step is right away, with nothing to dump yet.
+        if std::mem::replace(&mut started, true) {
+            files.write(&HeapReport::snapshot(), "periodic");
+        }
+    })
+}
+
+// Calls `callback` with a snapshot of the session every `interval` until aborted.
+pub(crate) fn spawn_interval(interval: Duration, callback: ReportCallback) -> Task<()> {
+    let mut started = false;
+    task::every(interval, move || {
+        // the first step is right away, with nothing to report yet.
+        if std::mem::replace(&mut started, true) {
+            callback(HeapReport::snapshot());
+        }
+    })
+}
+
+#[cfg(unix)]
+pub(crate) use self::signal::*;
+
+#[cfg(unix)]
+mod signal {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::DumpFiles;
+    use crate::profiler::HeapReport;
+    use crate::task::{self, Task};
+
+    // How often the signal is checked for: the handler can't take a report itself.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn handle(_signal: libc::c_int) {
+        SIGNALED.store(true, Ordering::SeqCst);
+    }
+
+    /// Our handler of a signal, until dropped: the previous one is restored then.
+    pub(crate) struct SignalHandler {
+        signal: libc::c_int,
+        previous: libc::sigaction,
+    }
+
+    impl Drop for SignalHandler {
+        fn drop(&mut self) {
+            // SAFETY: the previous action was filled in by sigaction.
+            unsafe { libc::sigaction(self.signal, &self.previous, std::ptr::null_mut()) };
+        }
+    }
+
+    // Dumps the running session when `signal` is received, until the handler is dropped and the task aborted.
+    pub(crate) fn spawn_on_signal(
+        signal: libc::c_int,
+        files: DumpFiles,
+    ) -> std::io::Result<(SignalHandler, Task<()>)> {
+        SIGNALED.store(false, Ordering::SeqCst);
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        let previous = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, &action, &mut previous) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            previous
+        };
+        let handler = SignalHandler { signal, previous };
+        let task = task::every(POLL_INTERVAL, move || {
+            if SIGNALED.swap(false, Ordering::SeqCst) {
+                files.write(&HeapReport::snapshot(), "signal");
+            }
+        });
+        Ok((handler, task))
+    }
+}
@@ -0,0 +1,46 @@
+//! The session of [`#[heappy::main]`](macro@crate::main) (with the `macros` feature), profiling a whole process from
+//! its very start:
+//!
+//! ```ignore
+//! #[heappy::main(report = "heap-{pid}.pb")]
+//! #[tokio::main]
+//! async fn main() {
+//!     ...
+//! }
+//! ```
+//!
+//! The session is set up from the environment (see [`HeapProfilerGuardBuilder::from_env`]), and ends when `main`
+//! returns or panics, writing its reports then. A process that `std::process::exit`s, or aborts on panics, ends
+//! without them.
+
+use crate::profiler::{HeapProfilerGuard, HeapProfilerGuardBuilder, ReportFormat};
+
+/// Starts the session of the process, of [`HeapProfilerGuardBuilder::from_env`] with `defaults` (writing the pprof
+/// report to `report`, unless the environment says where), until the returned guard is dropped. A process is better
+/// off unprofiled than not running: the errors are printed to stderr, and return `None`, as does turning the
+/// profiling off.
+pub fn profile_main(
+    defaults: HeapProfilerGuardBuilder,
+    report: Option<&str>,
+) -> Option<HeapProfilerGuard> {
+    let defaults = match report {
+        Some(path) if std::env::var_os(crate::config::REPORT_ENV).is_none() => {
+            defaults.write_report(ReportFormat::Pprof, path)
+        }
+        _ => defaults,
+    };
+    let session = HeapProfilerGuardBuilder::from_env(defaults).transpose()?;
+    // before the executor of the process and after it, the session has to run without it.
+    #[cfg(feature = "async")]
+    let session = session
+        .and_then(|builder| crate::runtime::block_on(builder.runtime(crate::Threads).build()));
+    #[cfg(not(feature = "async"))]
+    let session = session.and_then(HeapProfilerGuardBuilder::build);
+    match session {
+        Ok(guard) => Some(guard),
+        Err(err) => {
+            eprintln!("heappy: not profiling: {}", err);
+            None
+        }
+    }
+}
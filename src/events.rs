@@ -0,0 +1,339 @@
+//! Recordings of the samples of a session, as they're flushed into it (see
+//! [`HeapProfilerGuardBuilder::record_events`](crate::HeapProfilerGuardBuilder::record_events)), to replay later
+//! through the same collector and reports with [`HeapReport::replay`](crate::HeapReport::replay): changes to the
+//! exporters or the aggregation can be tried on real workloads, deterministically.
+//!
+//! The file is text, one record per line, its fields separated by tabs (tabs, newlines and backslashes escaped with
+//! backslashes):
+//!
+//! ```text
+//! heappy-events 2
+//! session <period> <start, ns since the Unix epoch> <duration, ns>
+//! stack <id> <label count> [<key> <value>]... [<frame> <name> <file> <line> <address>]...
+//! event <ns since the start> <stack id> <allocated objects> <allocated bytes> <requested bytes> <freed objects>
+//!     <freed bytes> <foreign freed objects> <foreign freed bytes> <sampled allocation's bytes>
+//! ```
+//!
+//! The symbols of a stack are innermost first; `<frame>` is the index of the frame they're inlined into, and the
+//! functions are mangled like in the binary. Recording keeps the events in memory until the session ends. Version 1
+//! recordings, without the sizes of the sampled allocations, are still replayed.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::compress::{CompressedWriter, Compression};
+use crate::labels::Labels;
+use crate::profiler::{
+    Error, HeapReport, Profiler, ProfilerBuffer, ProfilerState, StackKey, MAX_DEPTH,
+};
+
+const VERSION: &str = "2";
+// The versions replayed.
+const VERSIONS: &[&str] = &["1", VERSION];
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref RECORDER: spin::Mutex<Option<Recorder>> = Default::default();
+}
+
+struct Recorder {
+    period: usize,
+    started: Instant,
+    started_at: SystemTime,
+    stopped: Option<Instant>,
+    ids: HashMap<StackKey<MAX_DEPTH>, usize>,
+    stacks: Vec<StackKey<MAX_DEPTH>>,
+    events: Vec<(Duration, usize, [i64; 7], i64)>,
+}
+
+// Starts recording the samples of the session started at `started`.
+pub(crate) fn start(period: usize, started: Instant) {
+    let recorder = Recorder {
+        period,
+        started,
+        started_at: crate::clock::system_time(),
+        stopped: None,
+        ids: HashMap::new(),
+        stacks: vec![],
+        events: vec![],
+    };
+    let previous = Profiler::untracked(|| RECORDER.lock().replace(recorder));
+    std::mem::drop(previous);
+    RECORDING.store(true, Ordering::SeqCst);
+}
+
+// The session starts over at `started`, at the end of its warm-up.
+pub(crate) fn restart(started: Instant) {
+    if !RECORDING.load(Ordering::SeqCst) {
+        return;
+    }
+    if let Some(recorder) = RECORDER.lock().as_mut() {
+        recorder.started = started;
+        recorder.started_at = crate::clock::system_time();
+    }
+}
+
+// The session stopped: what it records from now on isn't part of it.
+pub(crate) fn stop() {
+    if !RECORDING.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(recorder) = RECORDER.lock().as_mut() {
+        recorder.stopped = Some(crate::clock::now());
+    }
+}
+
+// Called with each sample flushed into the session, from the profiler.
+pub(crate) fn record(buffer: &ProfilerBuffer, key: &StackKey<MAX_DEPTH>, at: Instant) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut recorder = RECORDER.lock();
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+    let id = match recorder.ids.get(key) {
+        Some(&id) => id,
+        None => {
+            let id = recorder.stacks.len();
+            recorder.ids.insert(key.clone(), id);
+            recorder.stacks.push(key.clone());
+            id
+        }
+    };
+    let since = at.saturating_duration_since(recorder.started);
+    recorder
+        .events
+        .push((since, id, buffer.counters(), buffer.sampled_bytes()));
+}
+
+// Stops recording and writes what was recorded to `path`, compressed after its extension.
+pub(crate) fn finish(path: &Path) -> io::Result<()> {
+    RECORDING.store(false, Ordering::SeqCst);
+    let Some(recorder) = Profiler::untracked(|| RECORDER.lock().take()).flatten() else {
+        return Ok(());
+    };
+    let duration = recorder
+        .stopped
+        .unwrap_or_else(crate::clock::now)
+        .saturating_duration_since(recorder.started);
+    let mut writer = CompressedWriter::new(
+        io::BufWriter::new(std::fs::File::create(path)?),
+        Compression::from_path(path),
+    );
+    writeln!(writer, "heappy-events\t{}", VERSION)?;
+    let start = recorder
+        .started_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    writeln!(
+        writer,
+        "session\t{}\t{}\t{}",
+        recorder.period,
+        start.as_nanos(),
+        duration.as_nanos()
+    )?;
+    for (id, key) in recorder.stacks.into_iter().enumerate() {
+        write!(writer, "stack\t{}\t{}", id, key.labels.iter().count())?;
+        for (k, v) in key.labels.iter() {
+            write!(writer, "\t{}\t{}", escape(k), escape(v))?;
+        }
+        // symbolizing takes its caches along.
+        let frames: pprof::Frames =
+            Profiler::untracked(|| key.frames.into()).unwrap_or_else(empty_frames);
+        for (i, frame) in frames.frames.iter().enumerate() {
+            for symbol in frame {
+                let file = symbol
+                    .filename
+                    .as_ref()
+                    .map(|f| f.to_string_lossy().into_owned());
+                write!(
+                    writer,
+                    "\t{}\t{}\t{}\t{}\t{}",
+                    i,
+                    escape(&String::from_utf8_lossy(symbol.raw_name())),
+                    escape(file.as_deref().unwrap_or("")),
+                    symbol.lineno.map(|l| l.to_string()).unwrap_or_default(),
+                    symbol
+                        .addr
+                        .map(|a| format!("{:x}", a as usize))
+                        .unwrap_or_default(),
+                )?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    for (since, id, counters, sampled_bytes) in recorder.events {
+        write!(writer, "event\t{}\t{}", since.as_nanos(), id)?;
+        for counter in counters {
+            write!(writer, "\t{}", counter)?;
+        }
+        writeln!(writer, "\t{}", sampled_bytes)?;
+    }
+    writer.finish()?.flush()
+}
+
+// Replays the recording at `path` into a report.
+pub(crate) fn replay(path: &Path) -> Result<HeapReport, Error> {
+    let invalid = |line: usize, message: &str| {
+        Error::InvalidEvents(path.to_path_buf(), line, message.to_string())
+    };
+    let file =
+        std::fs::File::open(path).map_err(|err| Error::EventsFile(path.to_path_buf(), err))?;
+    let reader = decompress(BufReader::new(file))
+        .map_err(|err| Error::EventsFile(path.to_path_buf(), err))?;
+    let mut session: Option<(ProfilerState<MAX_DEPTH>, SystemTime, Duration)> = None;
+    let mut stacks: HashMap<usize, (StackKey<MAX_DEPTH>, pprof::Frames)> = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| Error::EventsFile(path.to_path_buf(), err))?;
+        let n = i + 1;
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        let number = |field: usize| -> Result<i64, Error> {
+            fields
+                .get(field)
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(|| invalid(n, &format!("expected a number in field {}", field + 1)))
+        };
+        match fields[0].as_str() {
+            "heappy-events" if n == 1 => {
+                if !fields
+                    .get(1)
+                    .is_some_and(|version| VERSIONS.contains(&version.as_str()))
+                {
+                    return Err(invalid(n, "unsupported version"));
+                }
+            }
+            _ if n == 1 => return Err(invalid(n, "not a heappy event recording")),
+            "session" => {
+                let period = number(1)?.max(1) as usize;
+                let start = SystemTime::UNIX_EPOCH + Duration::from_nanos(number(2)? as u64);
+                let duration = Duration::from_nanos(number(3)? as u64);
+                session = Some((ProfilerState::new(period), start, duration));
+            }
+            "stack" => {
+                let id = number(1)? as usize;
+                let label_count = number(2)? as usize;
+                let labels_end = 3 + 2 * label_count;
+                let labels = fields
+                    .get(3..labels_end)
+                    .ok_or_else(|| invalid(n, "missing labels"))?
+                    .chunks(2)
+                    .map(|kv| (kv[0].clone(), kv[1].clone()))
+                    .collect::<Labels>();
+                let symbols = &fields[labels_end..];
+                if symbols.len() % 5 != 0 {
+                    return Err(invalid(n, "truncated symbol"));
+                }
+                let mut frames = empty_frames();
+                for symbol in symbols.chunks(5) {
+                    let frame: usize = symbol[0]
+                        .parse()
+                        .map_err(|_| invalid(n, "invalid frame index"))?;
+                    if frame >= frames.frames.len() {
+                        frames.frames.resize(frame + 1, vec![]);
+                    }
+                    frames.frames[frame].push(pprof::Symbol {
+                        name: Some(symbol[1].clone().into_bytes()),
+                        filename: (!symbol[2].is_empty()).then(|| PathBuf::from(&symbol[2])),
+                        lineno: symbol[3].parse().ok(),
+                        addr: usize::from_str_radix(&symbol[4], 16)
+                            .ok()
+                            .map(|a| a as *mut std::ffi::c_void),
+                    });
+                }
+                stacks.insert(id, (StackKey::replayed(id, labels), frames));
+            }
+            "event" => {
+                let Some((state, _, _)) = session.as_mut() else {
+                    return Err(invalid(n, "event before the session"));
+                };
+                let since = Duration::from_nanos(number(1)? as u64);
+                let (key, _) = stacks
+                    .get(&(number(2)? as usize))
+                    .ok_or_else(|| invalid(n, "unknown stack"))?;
+                let mut counters = [0; 7];
+                for (c, counter) in counters.iter_mut().enumerate() {
+                    *counter = number(3 + c)?;
+                }
+                // not in version 1.
+                let sampled_bytes = if fields.len() > 10 { number(10)? } else { 0 };
+                let at = state.started + since;
+                ProfilerBuffer::from_counters(counters, sampled_bytes).flush(
+                    state,
+                    key.clone(),
+                    at,
+                );
+            }
+            _ => return Err(invalid(n, &format!("unknown record {:?}", fields[0]))),
+        }
+    }
+    let (state, started_at, duration) = session.ok_or_else(|| invalid(1, "no session"))?;
+    let frames: HashMap<usize, pprof::Frames> = stacks
+        .into_iter()
+        .map(|(id, (_, frames))| (id, frames))
+        .collect();
+    Ok(HeapReport::replayed(state, started_at, duration, |id| {
+        frames.get(&id).cloned().unwrap_or_else(empty_frames)
+    }))
+}
+
+pub(crate) fn decompress(mut reader: BufReader<std::fs::File>) -> io::Result<Box<dyn BufRead>> {
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(
+            reader,
+        ))));
+    }
+    if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        #[cfg(feature = "pprof_io")]
+        {
+            let decoder = ruzstd::StreamingDecoder::new(reader)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            return Ok(Box::new(BufReader::new(decoder)));
+        }
+        #[cfg(not(feature = "pprof_io"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reading zstd needs the pprof_io feature",
+        ));
+    }
+    Ok(Box::new(reader))
+}
+
+pub(crate) fn empty_frames() -> pprof::Frames {
+    pprof::Frames {
+        frames: vec![],
+        thread_name: String::new(),
+        thread_id: 0,
+        sample_timestamp: SystemTime::UNIX_EPOCH,
+    }
+}
+
+pub(crate) fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+pub(crate) fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
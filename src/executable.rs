@@ -0,0 +1,95 @@
+//! The symbol table of the executable, read from its file, for the frames the regular symbolization knows nothing
+//! about: fully static binaries (e.g. musl ones in Alpine containers) have no dynamic loader to ask, and would
+//! otherwise only have `unknown` frames.
+
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+
+struct Executable {
+    // what to add to an address in the file to get the one in memory, non-zero for position independent executables.
+    bias: u64,
+    // where the code is mapped, everything if we can't tell.
+    range: (u64, u64),
+    // start address in the file, size and raw name, by address.
+    symbols: Vec<(u64, u64, String)>,
+}
+
+lazy_static::lazy_static! {
+    static ref EXECUTABLE: Option<Executable> = Executable::load();
+}
+
+/// The raw (mangled) name and the address of the function of the executable that contains `ip`.
+pub(crate) fn resolve(ip: usize) -> Option<(&'static str, usize)> {
+    let executable = EXECUTABLE.as_ref()?;
+    let (start, limit) = executable.range;
+    if (ip as u64) < start || ip as u64 >= limit {
+        return None;
+    }
+    let addr = (ip as u64).wrapping_sub(executable.bias);
+    let i = executable
+        .symbols
+        .partition_point(|(start, _, _)| *start <= addr)
+        .checked_sub(1)?;
+    let (start, size, name) = &executable.symbols[i];
+    if *size > 0 && addr >= start + size {
+        return None;
+    }
+    Some((name, start.wrapping_add(executable.bias) as usize))
+}
+
+/// Reads the symbol table now if it isn't yet, whether there's one.
+pub(crate) fn load() -> bool {
+    EXECUTABLE.is_some()
+}
+
+impl Executable {
+    fn load() -> Option<Self> {
+        let path = std::env::current_exe().ok()?;
+        let data = std::fs::read(&path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+
+        let mut symbols: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.is_definition())
+            .filter_map(|symbol| {
+                let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+                Some((symbol.address(), symbol.size(), name.to_string()))
+            })
+            .collect();
+        if symbols.is_empty() {
+            return None;
+        }
+        symbols.sort_unstable();
+        symbols.dedup_by_key(|(start, _, _)| *start);
+
+        // the executable mapping of the executable, and the segment of the file it starts.
+        let path = path.to_string_lossy();
+        let mapping = crate::mappings::current()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|mapping| mapping.path == path);
+        let range = mapping
+            .as_ref()
+            .map_or((0, u64::MAX), |mapping| (mapping.start, mapping.limit));
+        let bias = mapping
+            .and_then(|mapping| {
+                file.segments().find_map(|segment| {
+                    let (offset, _) = segment.file_range();
+                    // mappings start at a page boundary, segments don't: the page can start with the end of the
+                    // previous one.
+                    let len = mapping.limit - mapping.start;
+                    (mapping.offset <= offset && offset < mapping.offset + len).then(|| {
+                        let file_addr = segment.address().wrapping_sub(offset - mapping.offset);
+                        mapping.start.wrapping_sub(file_addr)
+                    })
+                })
+            })
+            .unwrap_or(0);
+
+        Some(Self {
+            bias,
+            range,
+            symbols,
+        })
+    }
+}
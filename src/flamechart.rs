@@ -0,0 +1,207 @@
+//! A flame chart of when each stack allocated: time on the x axis, the stacks (root at the top) on the y axis, with
+//! every step of the timeline split between the stacks that allocated during it by their share of the bytes.
+
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+
+use crate::collector::TIMELINE_RESOLUTION;
+
+const WIDTH: f64 = 1200.0;
+const ROW: f64 = 16.0;
+const MARGIN: f64 = 10.0;
+// above the chart for the title, below it for the time axis.
+const HEADER: f64 = 30.0;
+const FOOTER: f64 = 30.0;
+const CHAR_WIDTH: f64 = 7.0;
+
+// Function names from the root, and the granted bytes by timeline step.
+pub(crate) type Timeline = (Vec<String>, Vec<(u32, i64)>);
+
+// A stretch of the x axis given to one stack.
+struct Slice<'a> {
+    stack: &'a [String],
+    start: f64,
+    end: f64,
+    bytes: i64,
+}
+
+/// Renders the timelines of the stacks as an SVG.
+pub(crate) fn render<W: Write>(stacks: &[Timeline], mut w: W) -> io::Result<()> {
+    let mut steps: BTreeMap<u32, Vec<(&[String], i64)>> = BTreeMap::new();
+    for (stack, timeline) in stacks {
+        for &(step, bytes) in timeline {
+            steps.entry(step).or_default().push((stack, bytes));
+        }
+    }
+    let Some((&last, _)) = steps.iter().next_back() else {
+        writeln!(
+            w,
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">"#,
+                r#"<text x="{}" y="{}">no samples</text></svg>"#
+            ),
+            WIDTH,
+            HEADER + FOOTER,
+            MARGIN,
+            HEADER
+        )?;
+        return Ok(());
+    };
+    let step_width = (WIDTH - 2.0 * MARGIN) / (last + 1) as f64;
+
+    // each step gets the same width, whatever it allocated.
+    let mut slices = vec![];
+    for (&step, stacks) in steps.iter_mut() {
+        stacks.sort();
+        let total: i64 = stacks.iter().map(|(_, bytes)| bytes).sum();
+        let mut start = MARGIN + step as f64 * step_width;
+        for &(stack, bytes) in stacks.iter() {
+            let end = start + step_width * bytes as f64 / total as f64;
+            slices.push(Slice {
+                stack,
+                start,
+                end,
+                bytes,
+            });
+            start = end;
+        }
+    }
+
+    let depth = slices.iter().map(|s| s.stack.len()).max().unwrap_or(0);
+    let height = HEADER + depth as f64 * ROW + FOOTER;
+    writeln!(
+        w,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">"#,
+        WIDTH, height
+    )?;
+    writeln!(
+        w,
+        r#"<text x="{}" y="20" font-size="16">Allocations over time ({} ms steps)</text>"#,
+        MARGIN,
+        TIMELINE_RESOLUTION.as_millis()
+    )?;
+
+    // a frame spans the consecutive slices sharing its stack up to it, like in a flamegraph.
+    for level in 0..depth {
+        let mut i = 0;
+        while i < slices.len() {
+            let Some(name) = slices[i].stack.get(level) else {
+                i += 1;
+                continue;
+            };
+            let prefix = &slices[i].stack[..=level];
+            let mut j = i;
+            let mut bytes = 0;
+            // steps without allocations in between split it.
+            while j < slices.len()
+                && slices[j].stack.get(..=level) == Some(prefix)
+                && (j == i || (slices[j].start - slices[j - 1].end).abs() < 1e-6)
+            {
+                bytes += slices[j].bytes;
+                j += 1;
+            }
+            let (x, x_end) = (slices[i].start, slices[j - 1].end);
+            let y = HEADER + level as f64 * ROW;
+            let (from, to) = (time(x, step_width), time(x_end, step_width));
+            writeln!(
+                w,
+                concat!(
+                    r#"<g><title>{} ({} bytes, {:.1}s-{:.1}s)</title>"#,
+                    r#"<rect x="{:.2}" y="{}" width="{:.2}" height="{}" fill="{}" rx="2"/>"#
+                ),
+                escape(name),
+                bytes,
+                from,
+                to,
+                x,
+                y,
+                (x_end - x).max(0.1),
+                ROW - 1.0,
+                color(name)
+            )?;
+            let chars = ((x_end - x - 6.0) / CHAR_WIDTH).floor() as usize;
+            if chars >= 3 {
+                writeln!(
+                    w,
+                    r#"<text x="{:.2}" y="{}">{}</text>"#,
+                    x + 3.0,
+                    y + ROW - 4.0,
+                    escape(&truncate(name, chars))
+                )?;
+            }
+            writeln!(w, "</g>")?;
+            i = j;
+        }
+    }
+
+    // the time axis.
+    let axis = HEADER + depth as f64 * ROW + 15.0;
+    let duration = (last + 1) as f64 * TIMELINE_RESOLUTION.as_secs_f64();
+    for tick in 0..=10 {
+        let x = MARGIN + tick as f64 * (WIDTH - 2.0 * MARGIN) / 10.0;
+        writeln!(
+            w,
+            r#"<text x="{:.2}" y="{}" text-anchor="middle">{:.1}s</text>"#,
+            x,
+            axis,
+            duration * tick as f64 / 10.0
+        )?;
+    }
+    writeln!(w, "</svg>")
+}
+
+fn time(x: f64, step_width: f64) -> f64 {
+    (x - MARGIN) / step_width * TIMELINE_RESOLUTION.as_secs_f64()
+}
+
+fn truncate(name: &str, chars: usize) -> String {
+    if name.chars().count() <= chars {
+        return name.to_string();
+    }
+    let mut truncated: String = name.chars().take(chars.saturating_sub(2)).collect();
+    truncated.push_str("..");
+    truncated
+}
+
+// Stable per function, in the greens and blues of the memory palette.
+fn color(name: &str) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    name.hash(&mut hasher);
+    let hash = hasher.finish();
+    let hue = 120 + (hash % 90);
+    let lightness = 45 + (hash >> 8) % 20;
+    format!("hsl({},60%,{}%)", hue, lightness)
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Function names from the root, without the profiler's own frames.
+pub(crate) fn stack(frames: &pprof::Frames) -> Vec<String> {
+    let mut names: Vec<String> = frames
+        .frames
+        .iter()
+        .rev()
+        .flat_map(|frame| frame.iter().rev().map(|symbol| symbol.name()))
+        .collect();
+    // the hook itself, when track_allocated is inlined into it.
+    if let Some(hook) = names.iter().position(|name| {
+        name.ends_with("::Profiler::track_allocated")
+            || crate::callsite::HOOKS.contains(&name.as_str())
+    }) {
+        names.truncate(hook);
+    }
+    names
+}
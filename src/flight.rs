@@ -0,0 +1,133 @@
+//! The flight-recorder mode of the sessions, see
+//! [`HeapProfilerGuardBuilder::flight_recorder`](crate::HeapProfilerGuardBuilder::flight_recorder): the stacks only
+//! keep the latest samples, in a ring buffer, so that an always-on session doesn't grow with its uptime and can still
+//! be dumped on an incident.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::profiler::{Profiler, ProfilerBuffer, ProfilerState, StackKey, MAX_DEPTH};
+
+/// How many of the latest samples a flight recorder keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlightRecorder {
+    /// The ones flushed within the last `Duration`.
+    Last(Duration),
+    /// As many as fit in about this many bytes.
+    Memory(usize),
+}
+
+// What a sample takes in the ring.
+const SAMPLE_SIZE: usize = std::mem::size_of::<(Instant, StackKey<MAX_DEPTH>, ProfilerBuffer)>();
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref RING: spin::Mutex<Option<Ring>> = Default::default();
+}
+
+struct Ring {
+    limit: FlightRecorder,
+    period: usize,
+    started: Instant,
+    // older samples were discarded to stay within the memory, the window starts at the oldest one left.
+    discarded: bool,
+    samples: VecDeque<(Instant, StackKey<MAX_DEPTH>, ProfilerBuffer)>,
+}
+
+impl Ring {
+    fn discard(&mut self, now: Instant) {
+        let before = self.samples.len();
+        match self.limit {
+            FlightRecorder::Last(window) => {
+                while matches!(self.samples.front(), Some((at, _, _)) if now.saturating_duration_since(*at) > window)
+                {
+                    self.samples.pop_front();
+                }
+            }
+            FlightRecorder::Memory(bytes) => {
+                let max = (bytes / SAMPLE_SIZE).max(1);
+                while self.samples.len() > max {
+                    self.samples.pop_front();
+                }
+            }
+        }
+        self.discarded |= self.samples.len() < before;
+    }
+}
+
+// Keeps the latest samples of the session started at `started` in a ring, if `limit` is given.
+pub(crate) fn start(limit: Option<FlightRecorder>, period: usize, started: Instant) {
+    let ring = limit.map(|limit| Ring {
+        limit,
+        period,
+        started,
+        discarded: false,
+        samples: VecDeque::new(),
+    });
+    // the previous one is dropped outside of the lock, which the flushes take.
+    let previous = Profiler::untracked(|| std::mem::replace(&mut *RING.lock(), ring));
+    std::mem::drop(previous);
+    RECORDING.store(limit.is_some(), Ordering::SeqCst);
+}
+
+// The session starts over at `started`, at the end of its warm-up.
+pub(crate) fn restart(started: Instant) {
+    let warm_up = Profiler::untracked(|| {
+        let mut ring = RING.lock();
+        let ring = ring.as_mut()?;
+        ring.started = started;
+        ring.discarded = false;
+        Some(std::mem::take(&mut ring.samples))
+    });
+    std::mem::drop(warm_up);
+}
+
+// The session stopped; its window can still be reported.
+pub(crate) fn stop() {
+    RECORDING.store(false, Ordering::SeqCst);
+}
+
+// Keeps a sample flushed into the session in the ring, or gives it back if the session has none.
+pub(crate) fn record(
+    buffer: ProfilerBuffer,
+    key: StackKey<MAX_DEPTH>,
+    at: Instant,
+) -> Option<(ProfilerBuffer, StackKey<MAX_DEPTH>)> {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return Some((buffer, key));
+    }
+    let mut ring = RING.lock();
+    let Some(ring) = ring.as_mut() else {
+        return Some((buffer, key));
+    };
+    ring.samples.push_back((at, key, buffer));
+    ring.discard(at);
+    None
+}
+
+// The samples of the window replayed into a state of their own, with the window's duration; none without a flight
+// recorder.
+pub(crate) fn window() -> Option<(ProfilerState<MAX_DEPTH>, Duration)> {
+    let now = clock::now();
+    // copied out, so that the flushes don't wait for the replay.
+    let (period, start, samples) = Profiler::untracked(|| {
+        let mut ring = RING.lock();
+        let ring = ring.as_mut()?;
+        ring.discard(now);
+        let start = match (ring.limit, ring.samples.front()) {
+            (FlightRecorder::Last(window), _) => now.checked_sub(window).unwrap_or(ring.started),
+            (FlightRecorder::Memory(_), Some((at, _, _))) if ring.discarded => *at,
+            _ => ring.started,
+        };
+        Some((ring.period, start.max(ring.started), ring.samples.clone()))
+    })??;
+    let mut state = ProfilerState::new(period);
+    state.started = start;
+    for (at, key, buffer) in samples {
+        buffer.flush(&mut state, key, at);
+    }
+    Some((state, now.saturating_duration_since(start)))
+}
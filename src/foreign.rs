@@ -0,0 +1,70 @@
+//! Detection of the frees of memory allocated before the session started, which would otherwise count against what
+//! the session allocated and make its in-use bytes shrink (or go negative) for no reason of its own.
+//!
+//! Every address allocated during the session sets a bit of a fixed-size set, so a free whose bit isn't set is
+//! certainly of an older allocation. Addresses sharing a bit make some of those frees pass for the session's own, so
+//! the foreign frees are undercounted rather than overcounted.
+
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use crate::profiler::Profiler;
+
+// 2^26 bits, 8 MiB.
+const WORDS: usize = 1 << 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Allocated by the first session that asks for it and kept for the next ones, so that a hook racing with the end of
+// a session never sees it go away.
+static BITS: AtomicPtr<AtomicU64> = AtomicPtr::new(std::ptr::null_mut());
+
+// Starts telling the frees apart from now on, forgetting the addresses of the previous session.
+pub(crate) fn start() {
+    let mut bits = BITS.load(Ordering::Acquire);
+    if bits.is_null() {
+        let words: Box<[AtomicU64]> =
+            Profiler::untracked(|| (0..WORDS).map(|_| AtomicU64::new(0)).collect())
+                .unwrap_or_default();
+        if words.len() != WORDS {
+            return;
+        }
+        bits = Box::leak(words).as_mut_ptr();
+        BITS.store(bits, Ordering::Release);
+    } else {
+        for word in words(bits) {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub(crate) fn stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+fn words(bits: *mut AtomicU64) -> &'static [AtomicU64] {
+    unsafe { std::slice::from_raw_parts(bits, WORDS) }
+}
+
+// The word and the bit of an address: allocations are at least 16 bytes aligned, the rest is spread by a
+// multiplicative hash.
+fn slot(ptr: usize) -> (usize, u64) {
+    let hash = ((ptr >> 4) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - 26);
+    ((hash >> 6) as usize, 1 << (hash & 63))
+}
+
+pub(crate) fn allocated(ptr: usize) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let (word, bit) = slot(ptr);
+    words(BITS.load(Ordering::Acquire))[word].fetch_or(bit, Ordering::Relaxed);
+}
+
+// Whether `ptr` is being freed without having been allocated during the session.
+pub(crate) fn is_foreign(ptr: usize) -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return false;
+    }
+    let (word, bit) = slot(ptr);
+    words(BITS.load(Ordering::Acquire))[word].load(Ordering::Relaxed) & bit == 0
+}
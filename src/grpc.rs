@@ -0,0 +1,134 @@
+//! Labels samples with the gRPC method being served.
+//!
+//! A tonic `Interceptor` only sees the request metadata and returns before the handler runs, so it can't keep a
+//! label scope open for the duration of the call, and there's no implementation of one. [`RpcLabelsLayer`] wraps the
+//! whole call future instead, as the layer of tonic's server:
+//!
+//! ```ignore
+//! Server::builder()
+//!     .layer(heappy::grpc::RpcLabelsLayer::tonic())
+//!     .add_service(GreeterServer::new(greeter))
+//!     .serve(addr)
+//!     .await?;
+//! ```
+//!
+//! Samples can then be filtered per RPC, e.g. `pprof -tagfocus=grpc.method=SayHello`.
+
+use std::future::Future;
+use std::task::{Context, Poll};
+
+use crate::labels::{label_scope, labeled, Labeled, Labels};
+
+pub const SERVICE_LABEL: &str = "grpc.service";
+pub const METHOD_LABEL: &str = "grpc.method";
+
+/// Builds the labels for a request `path` of the form `/package.Service/Method`.
+pub fn rpc_labels(path: &str) -> Labels {
+    match path.trim_start_matches('/').split_once('/') {
+        Some((service, method)) => Labels::new()
+            .with(SERVICE_LABEL, service)
+            .with(METHOD_LABEL, method),
+        None => Labels::new().with(METHOD_LABEL, path),
+    }
+}
+
+/// Runs `future` with the service and method of `path` in scope.
+pub fn rpc_scope<F: Future>(path: &str, future: F) -> Labeled<F> {
+    labeled(rpc_labels(path), future)
+}
+
+/// Tower layer that puts the RPC's service and method in scope for the duration of each call.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RpcLabelsLayer;
+
+impl RpcLabelsLayer {
+    /// The layer for `tonic::transport::Server::layer`, labelling the calls of all the services added to the server,
+    /// or for a `tower::ServiceBuilder` over a tonic `Channel`, labelling the allocations of the client's calls.
+    pub fn tonic() -> Self {
+        Self
+    }
+}
+
+impl<S> tower::Layer<S> for RpcLabelsLayer {
+    type Service = RpcLabels<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcLabels { inner }
+    }
+}
+
+/// Service returned by [`RpcLabelsLayer`].
+#[derive(Clone, Debug)]
+pub struct RpcLabels<S> {
+    inner: S,
+}
+
+impl<S, B> tower::Service<http::Request<B>> for RpcLabels<S>
+where
+    S: tower::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Labeled<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let labels = rpc_labels(req.uri().path());
+        // the inner service may already allocate while building its future.
+        let _guard = label_scope(labels.clone());
+        labeled(labels, self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future::Future;
+    use std::pin::Pin;
+    use tower::{Layer, Service};
+
+    // records the labels in scope while building the future and while polling it.
+    struct Inner;
+
+    impl Service<http::Request<()>> for Inner {
+        type Response = (Labels, Labels);
+        type Error = ();
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, ()>>>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            let called = Labels::current();
+            Box::pin(async move { Ok((called, Labels::current())) })
+        }
+    }
+
+    #[test]
+    fn labels_calls() {
+        let mut service = RpcLabelsLayer::tonic().layer(Inner);
+        let request = http::Request::builder()
+            .uri("/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        let (called, polled) = crate::runtime::block_on(service.call(request)).unwrap();
+        for labels in [&called, &polled] {
+            assert_eq!(labels.get(SERVICE_LABEL), Some("helloworld.Greeter"));
+            assert_eq!(labels.get(METHOD_LABEL), Some("SayHello"));
+        }
+        // and out of scope once the call is done.
+        assert!(Labels::current().is_empty());
+    }
+
+    #[test]
+    fn labels_paths() {
+        let labels = rpc_labels("/Unqualified");
+        assert_eq!(labels.get(SERVICE_LABEL), None);
+        assert_eq!(labels.get(METHOD_LABEL), Some("/Unqualified"));
+    }
+}
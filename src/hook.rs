@@ -10,17 +10,46 @@ use libc::{c_int, c_void, size_t};
 // On linux we need to reference at least one symbol in a module for it to not be pruned at link time.
 pub(crate) fn dummy_force_link() {}
 
+// The hooks recording samples, by address, to tell where the profiler's frames end in a stack.
+pub(crate) fn entry_points() -> [usize; 5] {
+    [
+        malloc as usize,
+        calloc as usize,
+        realloc as usize,
+        aligned_alloc as usize,
+        free as usize,
+    ]
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    crate::testing::allocating(size);
+    #[cfg(feature = "canary")]
+    if let Some(res) = crate::canary::alloc(size, |padded| sys_malloc(padded)) {
+        Profiler::track_allocated(res, size as i64, size as i64);
+        return res;
+    }
     let res = sys_malloc(size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize);
+    Profiler::track_allocated(res, sys_malloc_usable_size(res) as i64, size as i64);
     res
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn calloc(number: size_t, size: size_t) -> *mut c_void {
+    crate::testing::allocating(number.saturating_mul(size));
+    #[cfg(feature = "canary")]
+    if let Some(total) = number.checked_mul(size) {
+        if let Some(res) = crate::canary::alloc(total, |padded| sys_calloc(1, padded)) {
+            Profiler::track_allocated(res, total as i64, total as i64);
+            return res;
+        }
+    }
     let res = sys_calloc(number, size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize);
+    Profiler::track_allocated(
+        res,
+        sys_malloc_usable_size(res) as i64,
+        number.saturating_mul(size) as i64,
+    );
     res
 }
 
@@ -28,22 +57,52 @@ pub unsafe extern "C" fn calloc(number: size_t, size: size_t) -> *mut c_void {
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
     #[cfg(feature = "measure_free")]
     {
-        let size = sys_malloc_usable_size(ptr) as isize;
-        Profiler::track_allocated(-size);
+        let size = malloc_usable_size(ptr) as i64;
+        Profiler::track_allocated(ptr, -size, -size);
     }
+    Profiler::track_freed(ptr);
+    #[cfg(feature = "canary")]
+    crate::canary::check(ptr);
     sys_free(ptr)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
-    let old_size = sys_malloc_usable_size(ptr) as isize;
+    crate::testing::allocating(size);
+    let old_size = malloc_usable_size(ptr) as i64;
+    #[cfg(feature = "canary")]
+    crate::canary::check(ptr);
+    #[cfg(feature = "measure_free")]
+    let foreign = !ptr.is_null() && crate::foreign::is_foreign(ptr as usize);
     let res = sys_realloc(ptr, size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize - old_size);
+    // failed, the old allocation is left as it was; with a size of 0, it's freed.
+    if res.is_null() && size != 0 {
+        return res;
+    }
+    // an allocation from before the session is gone, and one of the session takes its place.
+    #[cfg(feature = "measure_free")]
+    if foreign {
+        Profiler::track_allocated(ptr, -old_size, -old_size);
+        Profiler::track_allocated(res, sys_malloc_usable_size(res) as i64, size as i64);
+        return res;
+    }
+    // a sample of the old allocation is gone either way, even if it's been resized in place.
+    Profiler::track_freed(ptr);
+    // the old allocation's waste goes away with it, what's left is the new one's.
+    Profiler::track_allocated(
+        res,
+        sys_malloc_usable_size(res) as i64 - old_size,
+        size as i64 - old_size,
+    );
     res
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn malloc_usable_size(ptr: *const c_void) -> size_t {
+    #[cfg(feature = "canary")]
+    if let Some(size) = crate::canary::usable_size(ptr) {
+        return size;
+    }
     sys_malloc_usable_size(ptr)
 }
 
@@ -53,12 +112,14 @@ pub unsafe extern "C" fn posix_memalign(
     alignment: size_t,
     size: size_t,
 ) -> c_int {
+    crate::testing::allocating(size);
     sys_posix_memalign(ptr, alignment, size)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn aligned_alloc(alignment: size_t, size: size_t) -> *mut c_void {
+    crate::testing::allocating(size);
     let res = sys_aligned_alloc(alignment, size);
-    Profiler::track_allocated(sys_malloc_usable_size(res) as isize);
+    Profiler::track_allocated(res, sys_malloc_usable_size(res) as i64, size as i64);
     res
 }
@@ -0,0 +1,211 @@
+//! Profilers of their own, apart from the session of [`HeapProfilerGuard`](crate::HeapProfilerGuard): for a library
+//! embedded in a larger application to profile its own allocations, without fighting over the process's session with
+//! the application (or another library). A [`ProfilerInstance`] has its own state and records what goes through its
+//! [`InstanceAllocator`], and only that:
+//!
+//! ```ignore
+//! static PROFILER: heappy::ProfilerInstance = heappy::ProfilerInstance::new(64 * 1024);
+//!
+//! PROFILER.start();
+//! let alloc = PROFILER.allocator();
+//! let mut index = allocator_api2::vec::Vec::new_in(&alloc);
+//! ...
+//! let report = PROFILER.report();
+//! ```
+//!
+//! The allocator is a [`GlobalAlloc`], and with the `allocator_api2` feature an `allocator_api2::alloc::Allocator`
+//! too, for the collections of `allocator-api2` and `hashbrown`. The stacks are walked with the unwinder of the
+//! process's session (see [`HeapProfilerGuardBuilder::unwinder`](crate::HeapProfilerGuardBuilder::unwinder)), and
+//! carry the [labels](crate::label_scope) in scope.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::clock;
+use crate::profiler::{
+    HeapReport, Profiler, ProfilerBuffer, ProfilerState, StackKey, MAX_DEPTH, MAX_SPILLED,
+};
+
+/// A heap profiler of its own state, fed by its [`allocator`](Self::allocator).
+pub struct ProfilerInstance {
+    period: usize,
+    depth: usize,
+    running: AtomicBool,
+    recording: spin::Mutex<Option<Recording>>,
+}
+
+struct Recording {
+    state: ProfilerState<MAX_DEPTH>,
+    // what was allocated since the previous sample.
+    buffer: ProfilerBuffer,
+}
+
+impl ProfilerInstance {
+    /// A profiler taking a sample every `period` bytes allocated (or freed) through its allocator, once started.
+    pub const fn new(period: usize) -> Self {
+        Self {
+            period,
+            depth: MAX_DEPTH,
+            running: AtomicBool::new(false),
+            recording: spin::Mutex::new(None),
+        }
+    }
+
+    /// Keeps the innermost `depth` frames of each stack, like
+    /// [`HeapProfilerGuardBuilder::max_depth`](crate::HeapProfilerGuardBuilder::max_depth).
+    pub const fn max_depth(mut self, depth: usize) -> Self {
+        self.depth = match depth {
+            0 => 1,
+            depth if depth > MAX_DEPTH + MAX_SPILLED => MAX_DEPTH + MAX_SPILLED,
+            depth => depth,
+        };
+        self
+    }
+
+    /// Starts recording, over: what was recorded before is forgotten.
+    pub fn start(&self) {
+        let previous = Profiler::untracked(|| {
+            self.recording.lock().replace(Recording {
+                state: ProfilerState::new(self.period),
+                buffer: ProfilerBuffer::default(),
+            })
+        });
+        std::mem::drop(previous);
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops recording, keeping what was recorded for the reports.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// An allocator of the system's, recording into this profiler.
+    pub const fn allocator(&'static self) -> InstanceAllocator {
+        InstanceAllocator::new(self, System)
+    }
+
+    /// A report of what was recorded since the start, without stopping: empty if it never started.
+    pub fn report(&self) -> HeapReport {
+        // symbolizing may go through the allocator.
+        let report = || {
+            let recording = self.recording.lock();
+            let (collector, duration) = match &*recording {
+                Some(recording) => (recording.state.collector.clone(), recording.state.elapsed()),
+                None => Default::default(),
+            };
+            std::mem::drop(recording);
+            HeapReport::from_collector(&collector, self.period, duration, |key| {
+                ((&key.frames).into(), key.labels.clone())
+            })
+        };
+        Profiler::untracked(report).unwrap_or_else(report)
+    }
+
+    // `size` bytes were allocated (or freed, if negative). Never inlined: its frame tells where the profiler's own end
+    // in the stacks.
+    #[inline(never)]
+    fn track(&self, size: i64) {
+        if !self.running.load(Ordering::Relaxed) {
+            return;
+        }
+        // what's allocated while recording isn't recorded.
+        Profiler::untracked(|| {
+            let buffer = {
+                let mut recording = self.recording.lock();
+                let Some(recording) = recording.as_mut() else {
+                    return;
+                };
+                recording.buffer.track(size, size);
+                if !recording.buffer.should_flush(self.period as i64) {
+                    return;
+                }
+                std::mem::take(&mut recording.buffer)
+            };
+            // unwound without holding up the other threads.
+            let key = unsafe { StackKey::capture_below(Self::track as usize, self.depth, 0) };
+            let at = clock::now();
+            if let Some(recording) = self.recording.lock().as_mut() {
+                buffer.flush(&mut recording.state, key, at);
+            }
+        });
+    }
+}
+
+/// Records the allocations of the wrapped allocator into a [`ProfilerInstance`], while it runs.
+#[derive(Clone, Copy)]
+pub struct InstanceAllocator<A = System> {
+    instance: &'static ProfilerInstance,
+    inner: A,
+}
+
+impl<A> InstanceAllocator<A> {
+    pub const fn new(instance: &'static ProfilerInstance, inner: A) -> Self {
+        Self { instance, inner }
+    }
+}
+
+// Never inlined: the stacks start at the caller of the frame calling `track`, which has to be the allocator's.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for InstanceAllocator<A> {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let res = self.inner.alloc(layout);
+        if !res.is_null() {
+            self.instance.track(layout.size() as i64);
+        }
+        res
+    }
+
+    #[inline(never)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let res = self.inner.alloc_zeroed(layout);
+        if !res.is_null() {
+            self.instance.track(layout.size() as i64);
+        }
+        res
+    }
+
+    #[inline(never)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "measure_free")]
+        self.instance.track(-(layout.size() as i64));
+        self.inner.dealloc(ptr, layout)
+    }
+
+    #[inline(never)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let res = self.inner.realloc(ptr, layout, new_size);
+        if !res.is_null() {
+            self.instance.track(new_size as i64 - layout.size() as i64);
+        }
+        res
+    }
+}
+
+#[cfg(feature = "allocator_api2")]
+unsafe impl<A: GlobalAlloc> allocator_api2::alloc::Allocator for InstanceAllocator<A> {
+    fn allocate(
+        &self,
+        layout: Layout,
+    ) -> Result<std::ptr::NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        // a `GlobalAlloc` can't be asked for nothing.
+        if layout.size() == 0 {
+            let dangling = std::ptr::NonNull::new(layout.align() as *mut u8)
+                .ok_or(allocator_api2::alloc::AllocError)?;
+            return Ok(std::ptr::NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let ptr = unsafe { self.alloc(layout) };
+        std::ptr::NonNull::new(ptr)
+            .map(|ptr| std::ptr::NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(allocator_api2::alloc::AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc(ptr.as_ptr(), layout)
+        }
+    }
+}
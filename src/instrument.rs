@@ -0,0 +1,93 @@
+//! The functions of [`#[heappy::instrument]`](macro@crate::instrument) (with the `macros` feature), and the scopes
+//! in focus: a session of [`HeapProfilerGuardBuilder::only_focused`](crate::HeapProfilerGuardBuilder::only_focused)
+//! only records the allocations made in a focus, for a profile of the code under scrutiny alone:
+//!
+//! ```ignore
+//! let _focus = heappy::focus();
+//! let index = load_index(&path)?;
+//! ```
+//!
+//! A focus is per thread, and per future with [`focused`].
+
+use std::cell::Cell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::labels::{label_scope, labeled, LabelGuard, Labeled, Labels};
+
+/// The label of the instrumented function.
+pub const FUNCTION_LABEL: &str = "function";
+
+// The focuses the current thread is in.
+thread_local!(static FOCUS: Cell<usize> = Cell::new(0));
+
+/// Labels the allocations of the current thread with the function `name` until the returned guard is dropped.
+///
+/// Don't hold the guard across an `.await`, use [`in_function`] for async code instead.
+pub fn function_scope(name: &str) -> LabelGuard {
+    label_scope(Labels::new().with(FUNCTION_LABEL, name))
+}
+
+/// Wraps a future so that its allocations are labeled with the function `name` whenever it is polled.
+pub fn in_function<F: Future>(name: &str, future: F) -> Labeled<F> {
+    labeled(Labels::new().with(FUNCTION_LABEL, name), future)
+}
+
+/// RAII structure that leaves the focus when dropped. See [`focus`].
+#[must_use = "the thread is only in focus until the guard is dropped"]
+pub struct FocusGuard {
+    // the guard restores a thread local, it must be dropped on the thread that created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl FocusGuard {
+    fn enter() -> Self {
+        let _ = FOCUS.try_with(|focus| focus.set(focus.get() + 1));
+        FocusGuard {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for FocusGuard {
+    fn drop(&mut self) {
+        let _ = FOCUS.try_with(|focus| focus.set(focus.get() - 1));
+    }
+}
+
+/// Puts the current thread in focus until the returned guard is dropped.
+///
+/// Don't hold the guard across an `.await`, use [`focused`] for async code instead.
+pub fn focus() -> FocusGuard {
+    FocusGuard::enter()
+}
+
+/// Wraps a future so that it's in focus whenever it is polled.
+pub fn focused<F: Future>(future: F) -> Focused<F> {
+    Focused { future }
+}
+
+pin_project_lite::pin_project! {
+    /// Future returned by [`focused`].
+    pub struct Focused<F> {
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F: Future> Future for Focused<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _guard = FocusGuard::enter();
+        self.project().future.poll(cx)
+    }
+}
+
+// Whether the current thread is in focus. Called from the allocation hooks, so it must not panic if the thread local
+// is being torn down.
+pub(crate) fn in_focus() -> bool {
+    FOCUS.try_with(|focus| focus.get() > 0).unwrap_or(false)
+}
@@ -0,0 +1,88 @@
+//! jemalloc's text heap dumps (`heap_v2`), see
+//! [`UnsymbolizedHeapReport::write_jeprof`](crate::UnsymbolizedHeapReport::write_jeprof), for `jeprof` (and the
+//! scripts around it) to symbolize against the binary like one of jemalloc's own:
+//!
+//! ```text
+//! heap_v2/1
+//!   t*: <in-use objects>: <in-use bytes> [<allocated objects>: <allocated bytes>]
+//! @ 0x55d0c1a2b3c4 0x55d0c1a2b5d6 ...
+//!   t*: <in-use objects>: <in-use bytes> [<allocated objects>: <allocated bytes>]
+//! ...
+//!
+//! MAPPED_LIBRARIES:
+//! 55d0c1a00000-55d0c1c00000 r-xp 00000000 00:00 0 /usr/bin/service
+//! ```
+//!
+//! jeprof scales the counters of a dump up by its sampling period, which heappy's counters already are: the dumps say
+//! a period of 1, which leaves them as they are.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::collector::MemProfileRecord;
+use crate::mappings::Mapping;
+
+// Writes the `stacks`, by their addresses (the innermost first, the others pointing into the calls), and the
+// `mappings` they're in.
+pub(crate) fn write<'a, W: Write>(
+    mut writer: W,
+    stacks: impl IntoIterator<Item = (&'a [u64], &'a MemProfileRecord)>,
+    mappings: &[Mapping],
+) -> std::io::Result<()> {
+    // the stacks of different labels are the same stack to jeprof.
+    let mut counters: HashMap<&[u64], [i64; 4]> = HashMap::new();
+    for (addrs, rec) in stacks {
+        let counters = counters.entry(addrs).or_default();
+        for (counter, value) in counters.iter_mut().zip(values(rec)) {
+            *counter += value;
+        }
+    }
+    let mut stacks: Vec<_> = counters.into_iter().collect();
+    stacks.sort_by_key(|(_, counters)| std::cmp::Reverse(counters[3]));
+    let mut totals = [0; 4];
+    for (_, counters) in &stacks {
+        for (total, value) in totals.iter_mut().zip(counters) {
+            *total += value;
+        }
+    }
+    writeln!(writer, "heap_v2/1")?;
+    write_counters(&mut writer, &totals)?;
+    for (addrs, counters) in &stacks {
+        write!(writer, "@")?;
+        // not the zero ending some stacks, where the unwinder found no return address.
+        for (i, addr) in addrs.iter().enumerate().filter(|(_, addr)| **addr != 0) {
+            // jeprof wants the return addresses, which it points back into the calls itself.
+            let addr = if i == 0 { *addr } else { addr + 1 };
+            write!(writer, " {:#x}", addr)?;
+        }
+        writeln!(writer)?;
+        write_counters(&mut writer, counters)?;
+    }
+    writeln!(writer, "\nMAPPED_LIBRARIES:")?;
+    for mapping in mappings {
+        writeln!(
+            writer,
+            "{:x}-{:x} r-xp {:08x} 00:00 0 {}",
+            mapping.start, mapping.limit, mapping.offset, mapping.path
+        )?;
+    }
+    Ok(())
+}
+
+fn write_counters<W: Write>(writer: &mut W, counters: &[i64; 4]) -> std::io::Result<()> {
+    let [objects, bytes, allocated_objects, allocated_bytes] = counters;
+    writeln!(
+        writer,
+        "  t*: {}: {} [{}: {}]",
+        objects, bytes, allocated_objects, allocated_bytes
+    )
+}
+
+// What's in use (all that was allocated without the `measure_free` feature), then what was allocated.
+fn values(rec: &MemProfileRecord) -> [i64; 4] {
+    #[cfg(feature = "measure_free")]
+    let (objects, bytes) = (rec.in_use_objects(), rec.in_use_bytes());
+    #[cfg(not(feature = "measure_free"))]
+    let (objects, bytes) = (rec.alloc_objects, rec.alloc_bytes);
+    [objects, bytes, rec.alloc_objects, rec.alloc_bytes]
+}
@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+thread_local!(static CURRENT_LABELS: RefCell<Labels> = RefCell::new(Labels::new()));
+
+/// A set of key/value pairs attached to every sample taken while they are in scope.
+///
+/// Labels end up as pprof sample labels, so profiles can be filtered with e.g. `pprof -tagfocus`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Labels(Arc<Vec<(String, String)>>);
+
+impl Labels {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns a copy of these labels with `key` set to `value`, replacing any previous value for `key`.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let key = key.into();
+        let value = value.into();
+        let labels = Arc::make_mut(&mut self.0);
+        match labels.binary_search_by(|(k, _)| k.as_str().cmp(&key)) {
+            Ok(idx) => labels[idx].1 = value,
+            Err(idx) => labels.insert(idx, (key, value)),
+        }
+        self
+    }
+
+    /// Returns a copy of these labels with all of `other`'s labels set on top.
+    pub fn merge(self, other: &Labels) -> Self {
+        other.iter().fold(self, |labels, (k, v)| labels.with(k, v))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The labels currently in scope on this thread.
+    pub fn current() -> Self {
+        CURRENT_LABELS.with(|current| current.borrow().clone())
+    }
+
+    // Called from the allocation hooks, so it must not panic if the thread local is being torn down
+    // or if the allocation happened while a scope was being entered.
+    pub(crate) fn try_current() -> Self {
+        CURRENT_LABELS
+            .try_with(|current| current.try_borrow().map(|l| l.clone()).unwrap_or_default())
+            .unwrap_or_default()
+    }
+
+    fn replace_current(labels: Labels) -> Labels {
+        CURRENT_LABELS.with(|current| current.replace(labels))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Labels {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        iter.into_iter()
+            .fold(Labels::new(), |labels, (k, v)| labels.with(k, v))
+    }
+}
+
+/// RAII structure that restores the previous labels when dropped. See [`label_scope`].
+#[must_use = "the labels are only in scope until the guard is dropped"]
+pub struct LabelGuard {
+    prev: Option<Labels>,
+    // the guard restores a thread local, it must be dropped on the thread that created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for LabelGuard {
+    fn drop(&mut self) {
+        if let Some(prev) = self.prev.take() {
+            Labels::replace_current(prev);
+        }
+    }
+}
+
+/// Adds `labels` to the current thread's labels until the returned guard is dropped.
+///
+/// Don't hold the guard across an `.await`, use [`labeled`] for async code instead.
+pub fn label_scope(labels: Labels) -> LabelGuard {
+    let merged = Labels::current().merge(&labels);
+    LabelGuard {
+        prev: Some(Labels::replace_current(merged)),
+        _not_send: PhantomData,
+    }
+}
+
+/// Wraps a future so that `labels` (on top of the labels in scope right now) are in scope whenever it is polled.
+pub fn labeled<F: Future>(labels: Labels, future: F) -> Labeled<F> {
+    Labeled {
+        labels: Labels::current().merge(&labels),
+        future,
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Future returned by [`labeled`].
+    pub struct Labeled<F> {
+        labels: Labels,
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F: Future> Future for Labeled<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = LabelGuard {
+            prev: Some(Labels::replace_current(this.labels.clone())),
+            _not_send: PhantomData,
+        };
+        this.future.poll(cx)
+    }
+}
@@ -0,0 +1,165 @@
+//! A [`tracing_subscriber::Layer`] measuring what each span allocates while it's entered, see [`AllocationLayer`]:
+//!
+//! ```ignore
+//! use tracing_subscriber::prelude::*;
+//!
+//! heappy::dummy_force_link();
+//! tracing_subscriber::registry()
+//!     .with(heappy::AllocationLayer::new())
+//!     .with(tracing_subscriber::fmt::layer())
+//!     .init();
+//! ```
+//!
+//! The allocations are counted as for [`testing::assert_no_alloc`](crate::testing::assert_no_alloc), by the
+//! `enable_heap_profiler` hooks or a [`ProfilingAllocator`](crate::ProfilingAllocator); no session is needed.
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tracing::span::{Attributes, Id};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::profiler::Profiler;
+use crate::testing::untracked;
+
+// The layers installed, so that the hooks don't need to look at their thread locals otherwise.
+static LAYERS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The bytes and objects the thread allocated since a layer was installed.
+    static ALLOCATED: Cell<(u64, u64)> = Cell::new((0, 0));
+    // The spans the thread is in, the innermost last, with what it had allocated when entering them.
+    static ENTERED: RefCell<Vec<(Id, (u64, u64))>> = RefCell::new(vec![]);
+}
+
+/// What a span allocated while it was entered, including the spans entered inside it, in its extensions for the
+/// layers after the [`AllocationLayer`]: `span.extensions().get::<SpanAllocations>()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpanAllocations {
+    /// The bytes asked for, a reallocation counting its new size.
+    pub bytes: u64,
+    pub objects: u64,
+    /// How many times the span was entered, e.g. polled for an instrumented future.
+    pub entered: u64,
+}
+
+/// Counts what the spans allocate while they're entered, into their [`SpanAllocations`], and when they close records
+/// an event (target `heappy`, at the span's level, in the span) with `alloc_bytes` and `alloc_objects` fields, so that
+/// the cost of a span shows up in the existing backends. Spans that were never entered get no event.
+#[derive(Debug)]
+pub struct AllocationLayer(());
+
+impl AllocationLayer {
+    pub fn new() -> Self {
+        LAYERS.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Default for AllocationLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AllocationLayer {
+    fn drop(&mut self) {
+        LAYERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<S> Layer<S> for AllocationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            untracked(|| span.extensions_mut().insert(SpanAllocations::default()));
+        }
+    }
+
+    fn on_enter(&self, id: &Id, _: Context<'_, S>) {
+        untracked(|| {
+            let _ =
+                ENTERED.try_with(|entered| entered.borrow_mut().push((id.clone(), allocated())));
+        });
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let now = allocated();
+        untracked(|| {
+            let Ok(Some(since)) = ENTERED.try_with(|entered| {
+                let mut entered = entered.borrow_mut();
+                let i = entered.iter().rposition(|(entered, _)| entered == id)?;
+                Some(entered.remove(i).1)
+            }) else {
+                return;
+            };
+            let Some(span) = ctx.span(id) else {
+                return;
+            };
+            let mut extensions = span.extensions_mut();
+            if let Some(allocations) = extensions.get_mut::<SpanAllocations>() {
+                allocations.bytes += now.0.saturating_sub(since.0);
+                allocations.objects += now.1.saturating_sub(since.1);
+                allocations.entered += 1;
+            }
+        });
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let allocations = span.extensions().get::<SpanAllocations>().copied();
+        let Some(SpanAllocations {
+            bytes,
+            objects,
+            entered: 1..,
+        }) = allocations
+        else {
+            return;
+        };
+        let level = *span.metadata().level();
+        macro_rules! allocated {
+            ($level:expr) => {
+                tracing::event!(
+                    target: "heappy",
+                    parent: &id,
+                    $level,
+                    alloc_bytes = bytes,
+                    alloc_objects = objects,
+                    "span allocations"
+                )
+            };
+        }
+        match level {
+            Level::ERROR => allocated!(Level::ERROR),
+            Level::WARN => allocated!(Level::WARN),
+            Level::INFO => allocated!(Level::INFO),
+            Level::DEBUG => allocated!(Level::DEBUG),
+            Level::TRACE => allocated!(Level::TRACE),
+        }
+    }
+}
+
+fn allocated() -> (u64, u64) {
+    ALLOCATED.try_with(Cell::get).unwrap_or_default()
+}
+
+// Called by the hooks for each allocation (not for the frees) of `size` bytes.
+pub(crate) fn allocating(size: usize) {
+    if LAYERS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    // the profiler's own allocations don't count.
+    Profiler::untracked(|| {
+        // the thread locals are gone once the thread is exiting.
+        let _ = ALLOCATED.try_with(|allocated| {
+            let (bytes, objects) = allocated.get();
+            allocated.set((bytes + size as u64, objects + 1));
+        });
+    });
+}
@@ -1,16 +1,128 @@
 mod profiler;
 pub use profiler::*;
 
-mod collector;
+mod alerts;
+pub use alerts::*;
+mod allocator;
+pub use allocator::*;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod backpressure;
+pub use backpressure::*;
+mod budget;
+pub use budget::*;
+mod build_info;
+pub use build_info::*;
+mod callsite;
+pub mod checkpoint;
+#[cfg(unix)]
+pub mod children;
+mod clock;
+pub use clock::*;
+#[cfg(feature = "canary")]
+pub mod canary;
+pub mod collector;
+mod components;
+pub use components::*;
+mod compress;
+pub use compress::*;
+pub mod config;
+#[cfg(unix)]
+pub mod console;
+pub mod criterion;
+mod dhat;
+mod dumps;
+pub use dumps::*;
+mod entry;
+pub use entry::*;
+pub mod events;
+mod executable;
+mod flamechart;
+mod flight;
+pub use flight::*;
+mod foreign;
+mod memory;
+pub use memory::*;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod labels;
+pub use labels::*;
+mod phase;
+pub use phase::*;
+#[cfg(feature = "tracing_layer")]
+mod layer;
+#[cfg(feature = "tracing_layer")]
+pub use layer::*;
 #[cfg(feature = "enable_heap_profiler")]
 mod hook;
+mod instance;
+pub use instance::*;
+mod instrument;
+#[cfg(feature = "macros")]
+pub use heappy_macros::{instrument, main};
+pub use instrument::*;
+pub mod jeprof;
+pub mod mappings;
+mod markdown;
+mod normalize;
+mod palette;
+pub use palette::*;
+#[cfg(feature = "pprof_io")]
+pub mod pprof_io;
+pub mod protos;
+mod regression;
+pub use regression::*;
+#[cfg(feature = "async")]
+mod runtime;
+#[cfg(feature = "async")]
+pub use runtime::*;
+#[cfg(feature = "sentry")]
+pub mod sentry;
+#[cfg(feature = "sentry")]
+pub use sentry::SentryTransaction;
+#[cfg(feature = "serve")]
+pub mod serve;
+mod subscription;
+#[cfg(feature = "tracing")]
+mod summary;
+#[cfg(feature = "symbolize")]
+pub mod symbolize;
+pub use subscription::*;
+mod source;
+pub use source::*;
+mod stats;
+pub use stats::*;
+mod symbols;
+mod tags;
+pub use tags::*;
+mod task;
+pub mod testing;
+mod trace;
+pub use trace::*;
+#[cfg(feature = "tui")]
+pub mod tui;
+mod types;
+pub use types::*;
+mod unwinder;
+pub use unwinder::*;
+mod watchdog;
+pub use watchdog::*;
+mod watermark;
+pub use watermark::*;
+mod zstd;
 
-#[cfg(feature = "jemalloc_shim")]
+#[cfg(all(feature = "jemalloc_shim", not(target_os = "macos")))]
 mod jemalloc_adapter;
 
-#[cfg(feature = "jemalloc_shim")]
+#[cfg(all(feature = "jemalloc_shim", not(target_os = "macos")))]
 use jemalloc_adapter as adapter;
 
+#[cfg(all(feature = "enable_heap_profiler", target_os = "macos"))]
+mod zone_adapter;
+
+#[cfg(all(feature = "enable_heap_profiler", target_os = "macos"))]
+use zone_adapter as adapter;
+
 // On linux you need to reference at least one symbol in a module if we want it be be actually linked.
 // Otherwise the hooks like `pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void` defined in the shim
 // module won't override the respective weak symbols from libc, since they don't ever get linked in the final executable.
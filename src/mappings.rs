@@ -0,0 +1,139 @@
+//! The executable mappings of the current process, so that raw addresses can be tied back to the binary (and the
+//! exact build of it) they came from.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: u64,
+    pub limit: u64,
+    /// Offset in `path` of the first mapped byte.
+    pub offset: u64,
+    pub path: String,
+    /// Hex encoded GNU build-id of `path` (the `LC_UUID` on macOS), if it has one.
+    pub build_id: Option<String>,
+}
+
+impl Mapping {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.limit
+    }
+}
+
+/// Reads the executable file-backed mappings of the current process.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn current() -> std::io::Result<Vec<Mapping>> {
+    let maps = std::fs::read_to_string("/proc/self/maps")?;
+    let mut build_ids: std::collections::HashMap<String, Option<String>> = Default::default();
+    let mut mappings = vec![];
+    for line in maps.lines() {
+        // e.g. "55d0c0a00000-55d0c0a21000 r-xp 00002000 fd:01 1234    /usr/bin/foo"
+        let mut fields = line.splitn(6, ' ');
+        let (Some(range), Some(perms), Some(offset), Some(path)) =
+            (fields.next(), fields.next(), fields.next(), fields.nth(2))
+        else {
+            continue;
+        };
+        let path = path.trim_start();
+        if !perms.contains('x') || !path.starts_with('/') {
+            continue;
+        }
+        let Some((start, limit)) = range.split_once('-') else {
+            continue;
+        };
+        let (Ok(start), Ok(limit), Ok(offset)) = (
+            u64::from_str_radix(start, 16),
+            u64::from_str_radix(limit, 16),
+            u64::from_str_radix(offset, 16),
+        ) else {
+            continue;
+        };
+        let build_id = build_ids
+            .entry(path.to_string())
+            .or_insert_with(|| build_id(path.as_ref()))
+            .clone();
+        mappings.push(Mapping {
+            start,
+            limit,
+            offset,
+            path: path.to_string(),
+            build_id,
+        });
+    }
+    Ok(mappings)
+}
+
+/// Reads the images loaded in the current process, by the range of their `__TEXT` segment.
+#[cfg(target_os = "macos")]
+// the dyld functions are only deprecated in `libc` in favour of the `mach2` crate.
+#[allow(deprecated)]
+pub fn current() -> std::io::Result<Vec<Mapping>> {
+    use object::macho::MachHeader64;
+    use object::read::macho::{MachHeader, Segment};
+    use object::Endianness;
+
+    let mut mappings = vec![];
+    for image in 0..unsafe { libc::_dyld_image_count() } {
+        let (header, slide, name) = unsafe {
+            (
+                libc::_dyld_get_image_header(image) as *const MachHeader64<Endianness>,
+                libc::_dyld_get_image_vmaddr_slide(image),
+                libc::_dyld_get_image_name(image),
+            )
+        };
+        if header.is_null() || name.is_null() {
+            continue;
+        }
+        let header = unsafe { &*header };
+        let Ok(endian) = header.endian() else {
+            continue;
+        };
+        // the load commands are mapped right after the header.
+        let len =
+            std::mem::size_of::<MachHeader64<Endianness>>() + header.sizeofcmds(endian) as usize;
+        let data = unsafe { std::slice::from_raw_parts(header as *const _ as *const u8, len) };
+        let Ok(mut commands) = header.load_commands(endian, data, 0) else {
+            continue;
+        };
+        let (mut text, mut uuid) = (None, None);
+        while let Ok(Some(command)) = commands.next() {
+            if let Ok(Some((segment, _))) = command.segment_64() {
+                if segment.name() == b"__TEXT" {
+                    text = Some(segment);
+                }
+            } else if let Ok(Some(command)) = command.uuid() {
+                uuid = Some(command.uuid);
+            }
+        }
+        let Some(text) = text else {
+            continue;
+        };
+        let start = text.vmaddr(endian).wrapping_add(slide as u64);
+        mappings.push(Mapping {
+            start,
+            limit: start + text.vmsize(endian),
+            offset: text.fileoff(endian),
+            path: unsafe { std::ffi::CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned(),
+            build_id: uuid.map(|uuid| uuid.iter().map(|b| format!("{:02x}", b)).collect()),
+        });
+    }
+    Ok(mappings)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub fn current() -> std::io::Result<Vec<Mapping>> {
+    Ok(vec![])
+}
+
+/// Reads the hex encoded GNU build-id of an object file.
+pub fn build_id(path: &std::path::Path) -> Option<String> {
+    use object::Object;
+
+    let data = std::fs::read(path).ok()?;
+    let file = object::File::parse(&*data).ok()?;
+    let id = match file.build_id().ok()? {
+        Some(id) => id.to_vec(),
+        None => file.mach_uuid().ok()??.to_vec(),
+    };
+    Some(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
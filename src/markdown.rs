@@ -0,0 +1,119 @@
+// A summary of a report in Markdown, to paste into GitHub issues and incident docs: see HeapReport::write_markdown.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::labels::Labels;
+use crate::profiler::HeapTotals;
+
+// How many stacks a summary shows.
+const TOP: usize = 10;
+
+// Writes the totals, the `TOP` heaviest of `stacks` (function names from the root, labels, bytes and objects), each
+// with its frames folded away, and the `settings` of the session. The bytes of a `live` report are the ones in use.
+pub(crate) fn write<'a, W: Write>(
+    mut writer: W,
+    totals: HeapTotals,
+    duration: Duration,
+    live: bool,
+    stacks: impl Iterator<Item = (Vec<String>, &'a Labels, i64, i64)>,
+    settings: &[(&str, String)],
+) -> io::Result<()> {
+    writeln!(writer, "### Heap profile")?;
+    writeln!(writer)?;
+    writeln!(writer, "| | bytes | objects |")?;
+    writeln!(writer, "|---|---:|---:|")?;
+    for (name, bytes, objects) in [
+        (
+            "allocated",
+            totals.allocated_bytes,
+            totals.allocated_objects,
+        ),
+        ("freed", totals.freed_bytes, totals.freed_objects),
+        ("in use", totals.in_use_bytes(), totals.in_use_objects()),
+    ] {
+        writeln!(
+            writer,
+            "| {} | {} | {} |",
+            name,
+            format_bytes(bytes),
+            objects
+        )?;
+    }
+    writeln!(
+        writer,
+        "| requested | {} | |",
+        format_bytes(totals.requested_bytes)
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "Over {:.2}s.", duration.as_secs_f64())?;
+
+    let mut stacks: Vec<_> = stacks.filter(|(_, _, bytes, _)| *bytes > 0).collect();
+    // the heaviest first, then by stack for the same report to make the same summary.
+    stacks.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    writeln!(writer)?;
+    if live {
+        writeln!(writer, "#### Top stacks by bytes in use")?;
+    } else {
+        writeln!(writer, "#### Top stacks by bytes allocated")?;
+    }
+    for (i, (names, labels, bytes, objects)) in stacks.into_iter().take(TOP).enumerate() {
+        let leaf = names.last().map_or("?", String::as_str);
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(k, v)| format!(", <code>{}={}</code>", escape(k), escape(v)))
+            .collect();
+        writeln!(writer)?;
+        writeln!(writer, "<details>")?;
+        writeln!(
+            writer,
+            "<summary><b>{}.</b> <code>{}</code>: {} in {} object{}{}</summary>",
+            i + 1,
+            escape(leaf),
+            format_bytes(bytes),
+            objects,
+            if objects == 1 { "" } else { "s" },
+            labels.concat()
+        )?;
+        writeln!(writer)?;
+        // innermost first, like a backtrace.
+        writeln!(writer, "```text")?;
+        for name in names.iter().rev() {
+            writeln!(writer, "{}", name)?;
+        }
+        writeln!(writer, "```")?;
+        writeln!(writer)?;
+        writeln!(writer, "</details>")?;
+    }
+
+    writeln!(writer)?;
+    writeln!(writer, "#### Session")?;
+    writeln!(writer)?;
+    for (name, value) in settings {
+        writeln!(writer, "- {}: `{}`", name, value)?;
+    }
+    Ok(())
+}
+
+// `text` in HTML, where the generics of the function names would be taken for tags.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn format_bytes(value: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut scaled = value.abs() as f64;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    let sign = if value < 0 { "-" } else { "" };
+    if unit == 0 {
+        format!("{}{}{}", sign, scaled, UNITS[unit])
+    } else {
+        format!("{}{:.2}{}", sign, scaled, UNITS[unit])
+    }
+}
@@ -0,0 +1,92 @@
+//! Sampling of the process memory as the OS accounts it, to compare with the heap profile: a resident set much larger
+//! than the sampled heap points at fragmentation, allocator caches or memory the profiler doesn't see (e.g. mmap).
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::profiler::{Profiler, HEAP_PROFILER_STATE};
+use crate::task::{self, Task};
+
+/// The memory of the process at some point of the session, see
+/// [`HeapReport::memory_samples`](crate::HeapReport::memory_samples).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemorySample {
+    /// Since the start of the session.
+    pub elapsed: Duration,
+    /// The resident set size, from `/proc/self/statm` (the task info on macOS).
+    pub rss_bytes: Option<u64>,
+    /// The usage of the process' memory cgroup, if asked for (it includes e.g. the page cache).
+    pub cgroup_bytes: Option<u64>,
+    /// What the heap profiler counted as in use at the time, see [`HeapTotals::in_use_bytes`](crate::HeapTotals).
+    pub heap_bytes: i64,
+}
+
+// Samples every `interval` until aborted.
+pub(crate) fn spawn(interval: Duration, cgroup: bool) -> Task<()> {
+    let cgroup = cgroup.then(cgroup_usage_file).flatten();
+    task::every(interval, move || {
+        let (rss_bytes, cgroup_bytes) =
+            Profiler::untracked(|| (rss(), cgroup.as_ref().and_then(|path| read_number(path))))
+                .unwrap_or_default();
+
+        let mut profiler = HEAP_PROFILER_STATE.write().unwrap();
+        let sample = MemorySample {
+            elapsed: profiler.elapsed(),
+            rss_bytes,
+            cgroup_bytes,
+            heap_bytes: profiler.totals().in_use_bytes(),
+        };
+        Profiler::untracked(|| profiler.memory.push(sample));
+    })
+}
+
+#[cfg(not(target_os = "macos"))]
+fn rss() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(target_os = "macos")]
+fn rss() -> Option<u64> {
+    let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_taskinfo>() as libc::c_int;
+    let written = unsafe {
+        libc::proc_pidinfo(
+            libc::getpid(),
+            libc::PROC_PIDTASKINFO,
+            0,
+            &mut info as *mut _ as *mut libc::c_void,
+            size,
+        )
+    };
+    (written == size).then_some(info.pti_resident_size)
+}
+
+// The file with the memory usage of the cgroup of the process, v2 or v1.
+fn cgroup_usage_file() -> Option<PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    // `hierarchy-ID:controllers:path` lines, v2 is `0::/path`.
+    cgroups.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let path = path.trim_start_matches('/');
+        let file = if controllers.is_empty() {
+            PathBuf::from("/sys/fs/cgroup")
+                .join(path)
+                .join("memory.current")
+        } else if controllers.split(',').any(|c| c == "memory") {
+            PathBuf::from("/sys/fs/cgroup/memory")
+                .join(path)
+                .join("memory.usage_in_bytes")
+        } else {
+            return None;
+        };
+        file.exists().then_some(file)
+    })
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,76 @@
+// The stacks of a report without what changes from a build, a machine or a run to the next, for snapshot tests: see
+// HeapReport::write_normalized.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use regex::Regex;
+
+lazy_static::lazy_static! {
+    // the legacy mangling's, and the crate disambiguators of the v0 one (`heappy[1a2b3c4d]::...`), not slices.
+    static ref HASHES: Regex = Regex::new(r"::h[0-9a-f]{16}\b|\[[0-9a-f]{4,16}\](::)").unwrap();
+    static ref ADDRESSES: Regex = Regex::new(r"0x[0-9a-fA-F]+").unwrap();
+    static ref DIRECTORIES: Regex = Regex::new(r"(?:[A-Za-z]:)?(?:[/\\][^/\\\s:<>]+)+[/\\]").unwrap();
+    static ref LINES: Regex = Regex::new(r"(\.[A-Za-z]+):\d+(?::\d+)?").unwrap();
+}
+
+// `name` without the symbol hashes, the addresses, the directories and the line numbers.
+pub(crate) fn function(name: &str) -> String {
+    let name = HASHES.replace_all(name, "$1");
+    let name = ADDRESSES.replace_all(&name, "0x?");
+    let name = DIRECTORIES.replace_all(&name, "");
+    LINES.replace_all(&name, "$1").into_owned()
+}
+
+// Writes the total and then a `root;..;leaf bytes objects` line per stack, sorted by stack: the stacks that are the
+// same once normalized are merged, and the ones without any allocation left out.
+pub(crate) fn write<W: Write>(
+    mut writer: W,
+    stacks: impl Iterator<Item = (Vec<String>, i64, i64)>,
+) -> io::Result<()> {
+    let mut merged: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    for (functions, bytes, objects) in stacks {
+        if objects == 0 {
+            continue;
+        }
+        let stack: Vec<_> = functions.iter().map(|name| function(name)).collect();
+        let total = merged.entry(stack.join(";")).or_default();
+        total.0 = total.0.saturating_add(bytes);
+        total.1 = total.1.saturating_add(objects);
+    }
+    let (bytes, objects) = merged
+        .values()
+        .fold((0i64, 0i64), |(b, o), &(bytes, objects)| {
+            (b.saturating_add(bytes), o.saturating_add(objects))
+        });
+    writeln!(writer, "total {} {}", bytes, objects)?;
+    for (stack, (bytes, objects)) in merged {
+        writeln!(writer, "{} {} {}", stack, bytes, objects)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes() {
+        assert_eq!(
+            function("heappy::profiler::track::h0123456789abcdef"),
+            "heappy::profiler::track"
+        );
+        assert_eq!(
+            function("<alloc[5d8f9a1b2c3d4e5f]::vec::Vec<u8>>::push"),
+            "<alloc::vec::Vec<u8>>::push"
+        );
+        assert_eq!(function("<[f32]>::sort_unstable"), "<[f32]>::sort_unstable");
+        assert_eq!(function("<[abcdef]>::len"), "<[abcdef]>::len");
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(function("/home/ci/src/main.rs:12:5"), "main.rs");
+        assert_eq!(function("alloc at 0x7f00dead"), "alloc at 0x?");
+    }
+}
@@ -0,0 +1,79 @@
+// The colors of the flamegraphs, see HeapReport::flamegraph_palette.
+
+use std::hash::{Hash, Hasher};
+
+use pprof::flamegraph::color::{Color, PaletteMap};
+
+/// How the frames of a flamegraph are colored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlamegraphPalette {
+    /// The memory palette of `inferno`, a color per function in greens and blues.
+    #[default]
+    Memory,
+    /// A color per crate, from the paths of the functions (`tokio` for `tokio::runtime::park::Inner::park` and
+    /// `<tokio::sync::Mutex<T> as Drop>::drop`), the same in every report: which dependency a tower of frames is
+    /// of shows at a glance. The functions of no crate, e.g. of C, are grey.
+    ByCrate,
+}
+
+const GREY: Color = Color {
+    r: 180,
+    g: 180,
+    b: 180,
+};
+
+// The colors of the functions of `names` in `palette`, none for the ones inferno picks itself.
+pub(crate) fn palette_map<'a>(
+    palette: FlamegraphPalette,
+    names: impl Iterator<Item = &'a str>,
+) -> Option<PaletteMap> {
+    match palette {
+        FlamegraphPalette::Memory => None,
+        FlamegraphPalette::ByCrate => {
+            let mut map = PaletteMap::default();
+            for name in names {
+                if map.get(name).is_none() {
+                    map.insert(name, crate_of(name).map_or(GREY, crate_color));
+                }
+            }
+            Some(map)
+        }
+    }
+}
+
+// The crate of a function, none if its name isn't a path, e.g. in C.
+fn crate_of(name: &str) -> Option<&str> {
+    // trait impls, e.g. `<&mut tokio::io::Stdout as core::fmt::Write>::write_str`.
+    let name = name
+        .trim_start_matches(|c| c == '<' || c == '&')
+        .trim_start_matches("mut ")
+        .trim_start_matches("dyn ");
+    let (name, _) = name.split_once("::")?;
+    let path = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    path.then_some(name)
+}
+
+// Stable per crate: a hue of its own, of the same saturation and lightness as the others.
+fn crate_color(name: &str) -> Color {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    name.hash(&mut hasher);
+    let hue = (hasher.finish() % 360) as f64;
+    let (saturation, lightness) = (0.6, 0.6);
+    let chroma = (1.0 - (2.0 * lightness - 1.0_f64).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |c: f64| ((c + m) * 255.0).round() as u8;
+    Color {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
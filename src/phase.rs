@@ -0,0 +1,38 @@
+//! Named stages of a process, for batch jobs to tell which one dominates allocation:
+//!
+//! ```ignore
+//! let _phase = heappy::phase("load_index");
+//! load_index(&path)?;
+//! ```
+//!
+//! The samples taken in a phase have its name as their `phase` label, a phase within another one the names of both
+//! (`build/load_index`), so that [`HeapReport::by_phase`](crate::HeapReport::by_phase) breaks the totals down per
+//! phase and `pprof -tagfocus phase=load_index` keeps to one.
+
+use std::future::Future;
+
+use crate::labels::{label_scope, labeled, LabelGuard, Labeled, Labels};
+
+/// The label of the phase.
+pub const PHASE_LABEL: &str = "phase";
+
+// The labels of the phase `name`, within the one in scope right now if there's one.
+fn phase_labels(name: &str) -> Labels {
+    let phase = match Labels::current().get(PHASE_LABEL) {
+        Some(outer) => format!("{}/{}", outer, name),
+        None => name.to_owned(),
+    };
+    Labels::new().with(PHASE_LABEL, phase)
+}
+
+/// Enters the phase `name` on the current thread until the returned guard is dropped.
+///
+/// Don't hold the guard across an `.await`, use [`in_phase`] for async code instead.
+pub fn phase(name: &str) -> LabelGuard {
+    label_scope(phase_labels(name))
+}
+
+/// Wraps a future so that it's in the phase `name` (within the phase in scope right now) whenever it is polled.
+pub fn in_phase<F: Future>(name: &str, future: F) -> Labeled<F> {
+    labeled(phase_labels(name), future)
+}
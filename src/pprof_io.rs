@@ -0,0 +1,476 @@
+//! Reading, merging and writing pprof profiles, e.g. to aggregate the heap profiles of several hosts without
+//! shelling out to `pprof -proto`.
+//!
+//! ```ignore
+//! let profiles = paths.iter().map(heappy::pprof_io::read).collect::<Result<Vec<_>, _>>()?;
+//! heappy::pprof_io::write("merged.pb.gz", &heappy::pprof_io::merge(&profiles)?)?;
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::compress::{CompressedWriter, Compression};
+use crate::protos;
+use regex::Regex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid profile: {0}")]
+    Decode(#[from] protos::DecodeError),
+    #[error("cannot merge profiles with different sample types: {0} and {1}")]
+    IncompatibleSampleTypes(String, String),
+    #[error("no profiles to merge")]
+    Empty,
+    #[error("no sample type named {0:?}")]
+    UnknownSampleType(String),
+    #[error("profile has no sample types")]
+    NoSampleTypes,
+    #[error("invalid frame filter: {0}")]
+    FrameFilter(#[from] regex::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Decodes a profile, gzipped (like `.pb.gz` files), zstd compressed or not.
+pub fn decode(mut buf: &[u8]) -> Result<protos::Profile> {
+    let mut decoded = vec![];
+    if buf.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::MultiGzDecoder::new(buf).read_to_end(&mut decoded)?;
+    } else if buf.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        ruzstd::StreamingDecoder::new(&mut buf)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?
+            .read_to_end(&mut decoded)?;
+    } else {
+        return Ok(protos::decode(buf)?);
+    }
+    Ok(protos::decode(&decoded)?)
+}
+
+/// Reads a profile from a file, compressed or not.
+pub fn read(path: impl AsRef<Path>) -> Result<protos::Profile> {
+    decode(&std::fs::read(path)?)
+}
+
+/// Encodes a profile, gzipped if `gzip` is set.
+pub fn encode(profile: &protos::Profile, gzip: bool) -> Result<Vec<u8>> {
+    let compression = if gzip {
+        Compression::Gzip
+    } else {
+        Compression::None
+    };
+    encode_compressed(profile, compression)
+}
+
+/// Encodes a profile, compressed with `compression`.
+pub fn encode_compressed(profile: &protos::Profile, compression: Compression) -> Result<Vec<u8>> {
+    let buf = protos::encode(profile);
+    if compression == Compression::None {
+        return Ok(buf);
+    }
+    let mut writer = CompressedWriter::new(vec![], compression);
+    writer.write_all(&buf)?;
+    Ok(writer.finish()?)
+}
+
+/// Writes a profile to a file, compressed if its name ends with `.gz` or `.zst`.
+pub fn write(path: impl AsRef<Path>, profile: &protos::Profile) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(
+        path,
+        encode_compressed(profile, Compression::from_path(path))?,
+    )?;
+    Ok(())
+}
+
+/// Merges profiles with the same sample types into one, adding up the values of identical samples (same stack and
+/// labels).
+///
+/// The period, comments and frame filters are taken from the first profile; the time range covers all of them.
+pub fn merge<'a>(
+    profiles: impl IntoIterator<Item = &'a protos::Profile>,
+) -> Result<protos::Profile> {
+    let mut profiles = profiles.into_iter();
+    let first = profiles.next().ok_or(Error::Empty)?;
+
+    let mut merger = Merger::new(first);
+    merger.add(first);
+    for profile in profiles {
+        let (expected, got) = (sample_types(first), sample_types(profile));
+        if expected != got {
+            return Err(Error::IncompatibleSampleTypes(
+                expected.join(","),
+                got.join(","),
+            ));
+        }
+        merger.add(profile);
+    }
+    Ok(merger.finish())
+}
+
+/// The samples of one sample type of a profile, keyed by their stack of function names (root first).
+pub struct Stacks {
+    pub sample_type: String,
+    pub unit: String,
+    pub counts: HashMap<Vec<String>, i64>,
+}
+
+impl Stacks {
+    pub fn from_profile(profile: &protos::Profile, sample_type: Option<&str>) -> Result<Self> {
+        let string = |idx: i64| lookup(profile, idx);
+
+        let value_idx = match sample_type {
+            Some(name) => profile
+                .sample_type
+                .iter()
+                .position(|st| string(st.ty) == name)
+                .ok_or_else(|| Error::UnknownSampleType(name.to_string()))?,
+            // pprof's convention is the default_sample_type if set, the last sample type otherwise.
+            None => profile
+                .sample_type
+                .iter()
+                .position(|st| {
+                    profile.default_sample_type != 0 && st.ty == profile.default_sample_type
+                })
+                .unwrap_or_else(|| profile.sample_type.len().saturating_sub(1)),
+        };
+        let value_type = profile
+            .sample_type
+            .get(value_idx)
+            .ok_or(Error::NoSampleTypes)?;
+
+        let functions: HashMap<u64, &str> = profile
+            .function
+            .iter()
+            .map(|f| (f.id, string(f.name)))
+            .collect();
+        let locations: HashMap<u64, Vec<String>> = profile
+            .location
+            .iter()
+            .map(|loc| {
+                // the last line is the caller the preceding ones were inlined into.
+                let names = loc
+                    .line
+                    .iter()
+                    .rev()
+                    .map(|line| {
+                        functions
+                            .get(&line.function_id)
+                            .copied()
+                            .unwrap_or("unknown")
+                            .to_string()
+                    })
+                    .collect();
+                (loc.id, names)
+            })
+            .collect();
+
+        // like pprof, drop frames fully matching drop_frames along with everything they call, unless they match
+        // keep_frames.
+        let frame_filter = |idx: i64| match string(idx) {
+            "" => Ok(None),
+            re => Regex::new(&format!("^(?:{})$", re)).map(Some),
+        };
+        let drop_frames = frame_filter(profile.drop_frames)?;
+        let keep_frames = frame_filter(profile.keep_frames)?;
+        let dropped = |name: &str| {
+            drop_frames.as_ref().map_or(false, |re| re.is_match(name))
+                && !keep_frames.as_ref().map_or(false, |re| re.is_match(name))
+        };
+
+        let mut counts = HashMap::new();
+        for sample in &profile.sample {
+            let value = sample.value.get(value_idx).copied().unwrap_or_default();
+            // location_id starts at the leaf.
+            let stack: Vec<String> = sample
+                .location_id
+                .iter()
+                .rev()
+                .flat_map(|id| locations.get(id).cloned().unwrap_or_default())
+                .take_while(|name| !dropped(name))
+                .collect();
+            *counts.entry(stack).or_default() += value;
+        }
+
+        Ok(Self {
+            sample_type: string(value_type.ty).to_string(),
+            unit: string(value_type.unit).to_string(),
+            counts,
+        })
+    }
+
+    pub fn total(&self) -> i64 {
+        self.counts.values().sum()
+    }
+
+    /// Folded stack lines (`root;..;leaf value`) sorted by stack, skipping empty stacks.
+    pub fn folded(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .counts
+            .iter()
+            .filter(|(_, v)| **v != 0)
+            .map(|(stack, value)| format!("{} {}", stack.join(";"), value))
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    /// The flat (as the leaf) and cumulative value of every function.
+    pub fn flat_and_cum(&self) -> HashMap<&str, (i64, i64)> {
+        let mut funcs: HashMap<&str, (i64, i64)> = HashMap::new();
+        for (stack, value) in &self.counts {
+            if let Some(leaf) = stack.last() {
+                funcs.entry(leaf).or_default().0 += value;
+            }
+            let unique: HashSet<&str> = stack.iter().map(String::as_str).collect();
+            for name in unique {
+                funcs.entry(name).or_default().1 += value;
+            }
+        }
+        funcs
+    }
+
+    pub fn format_value(&self, value: i64) -> String {
+        if self.unit == "bytes" {
+            format_bytes(value)
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+// e.g. "alloc_space/bytes".
+fn sample_types(profile: &protos::Profile) -> Vec<String> {
+    let string = |idx: i64| lookup(profile, idx);
+    profile
+        .sample_type
+        .iter()
+        .map(|st| format!("{}/{}", string(st.ty), string(st.unit)))
+        .collect()
+}
+
+fn lookup(profile: &protos::Profile, idx: i64) -> &str {
+    profile
+        .string_table
+        .get(idx as usize)
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+// (mapping id, address, (function id, line) of each line)
+type LocationKey = (u64, u64, Vec<(u64, i64)>);
+// (location ids, sorted (key, str, num, num_unit) of each label)
+type SampleKey = (Vec<u64>, Vec<(i64, i64, i64, i64)>);
+
+// Everything is interned by value in the merged profile, ids of the inputs are remapped.
+#[derive(Default)]
+struct Merger {
+    profile: protos::Profile,
+    strings: HashMap<String, i64>,
+    mappings: HashMap<(u64, u64, u64, i64, i64), u64>,
+    functions: HashMap<(i64, i64, i64, i64), u64>,
+    locations: HashMap<LocationKey, u64>,
+    samples: HashMap<SampleKey, usize>,
+    end_nanos: i64,
+}
+
+impl Merger {
+    fn new(first: &protos::Profile) -> Self {
+        let mut merger = Self::default();
+        merger.intern("");
+        let mut intern = |idx: i64| merger.intern(lookup(first, idx));
+        let value_type =
+            |vt: &protos::ValueType, intern: &mut dyn FnMut(i64) -> i64| protos::ValueType {
+                ty: intern(vt.ty),
+                unit: intern(vt.unit),
+            };
+        let sample_type = first
+            .sample_type
+            .iter()
+            .map(|vt| value_type(vt, &mut intern))
+            .collect();
+        let period_type = first
+            .period_type
+            .as_ref()
+            .map(|vt| value_type(vt, &mut intern));
+        let default_sample_type = intern(first.default_sample_type);
+        let drop_frames = intern(first.drop_frames);
+        let keep_frames = intern(first.keep_frames);
+        let comment = first.comment.iter().map(|&c| intern(c)).collect();
+
+        merger.profile.sample_type = sample_type;
+        merger.profile.period_type = period_type;
+        merger.profile.period = first.period;
+        merger.profile.default_sample_type = default_sample_type;
+        merger.profile.drop_frames = drop_frames;
+        merger.profile.keep_frames = keep_frames;
+        merger.profile.comment = comment;
+        merger
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(&idx) = self.strings.get(s) {
+            return idx;
+        }
+        let idx = self.profile.string_table.len() as i64;
+        self.profile.string_table.push(s.to_owned());
+        self.strings.insert(s.to_owned(), idx);
+        idx
+    }
+
+    fn add(&mut self, profile: &protos::Profile) {
+        let mut mapping_ids = HashMap::new();
+        for mapping in &profile.mapping {
+            let filename = self.intern(lookup(profile, mapping.filename));
+            let build_id = self.intern(lookup(profile, mapping.build_id));
+            let key = (
+                mapping.memory_start,
+                mapping.memory_limit,
+                mapping.file_offset,
+                filename,
+                build_id,
+            );
+            let id = match self.mappings.get(&key) {
+                Some(&id) => id,
+                None => {
+                    let id = self.profile.mapping.len() as u64 + 1;
+                    self.profile.mapping.push(protos::Mapping {
+                        id,
+                        filename,
+                        build_id,
+                        ..mapping.clone()
+                    });
+                    self.mappings.insert(key, id);
+                    id
+                }
+            };
+            mapping_ids.insert(mapping.id, id);
+        }
+
+        let mut function_ids = HashMap::new();
+        for function in &profile.function {
+            let name = self.intern(lookup(profile, function.name));
+            let system_name = self.intern(lookup(profile, function.system_name));
+            let filename = self.intern(lookup(profile, function.filename));
+            let key = (name, system_name, filename, function.start_line);
+            let id = *self.functions.entry(key).or_insert_with(|| {
+                let id = self.profile.function.len() as u64 + 1;
+                self.profile.function.push(protos::Function {
+                    id,
+                    name,
+                    system_name,
+                    filename,
+                    start_line: function.start_line,
+                });
+                id
+            });
+            function_ids.insert(function.id, id);
+        }
+
+        let mut location_ids = HashMap::new();
+        for location in &profile.location {
+            let mapping_id = mapping_ids.get(&location.mapping_id).copied().unwrap_or(0);
+            let line: Vec<protos::Line> = location
+                .line
+                .iter()
+                .map(|line| protos::Line {
+                    function_id: function_ids.get(&line.function_id).copied().unwrap_or(0),
+                    line: line.line,
+                })
+                .collect();
+            let key = (
+                mapping_id,
+                location.address,
+                line.iter().map(|l| (l.function_id, l.line)).collect(),
+            );
+            let id = *self.locations.entry(key).or_insert_with(|| {
+                let id = self.profile.location.len() as u64 + 1;
+                self.profile.location.push(protos::Location {
+                    id,
+                    mapping_id,
+                    address: location.address,
+                    line,
+                    is_folded: location.is_folded,
+                });
+                id
+            });
+            location_ids.insert(location.id, id);
+        }
+
+        for sample in &profile.sample {
+            let location_id: Vec<u64> = sample
+                .location_id
+                .iter()
+                .map(|id| location_ids.get(id).copied().unwrap_or(0))
+                .collect();
+            let label: Vec<protos::Label> = sample
+                .label
+                .iter()
+                .map(|label| protos::Label {
+                    key: self.intern(lookup(profile, label.key)),
+                    str: self.intern(lookup(profile, label.str)),
+                    num: label.num,
+                    num_unit: self.intern(lookup(profile, label.num_unit)),
+                })
+                .collect();
+            let mut label_key: Vec<_> = label
+                .iter()
+                .map(|l| (l.key, l.str, l.num, l.num_unit))
+                .collect();
+            label_key.sort_unstable();
+
+            match self.samples.get(&(location_id.clone(), label_key.clone())) {
+                Some(&idx) => {
+                    let merged = &mut self.profile.sample[idx];
+                    for (total, value) in merged.value.iter_mut().zip(&sample.value) {
+                        *total += value;
+                    }
+                }
+                None => {
+                    self.samples
+                        .insert((location_id.clone(), label_key), self.profile.sample.len());
+                    self.profile.sample.push(protos::Sample {
+                        location_id,
+                        value: sample.value.clone(),
+                        label,
+                    });
+                }
+            }
+        }
+
+        if profile.time_nanos != 0 {
+            if self.profile.time_nanos == 0 || profile.time_nanos < self.profile.time_nanos {
+                self.profile.time_nanos = profile.time_nanos;
+            }
+            self.end_nanos = self
+                .end_nanos
+                .max(profile.time_nanos + profile.duration_nanos);
+        }
+    }
+
+    fn finish(mut self) -> protos::Profile {
+        if self.profile.time_nanos != 0 {
+            self.profile.duration_nanos = self.end_nanos - self.profile.time_nanos;
+        }
+        self.profile
+    }
+}
+
+fn format_bytes(value: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut scaled = value.abs() as f64;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    let sign = if value < 0 { "-" } else { "" };
+    if unit == 0 {
+        format!("{}{}{}", sign, scaled, UNITS[unit])
+    } else {
+        format!("{}{:.2}{}", sign, scaled, UNITS[unit])
+    }
+}
@@ -1,450 +1,4304 @@
-use std::cell::{Cell, RefCell};
-use std::collections::{HashMap, HashSet};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
 use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::mem::MaybeUninit;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, RwLock};
 
-use std::time::SystemTime;
-use tokio::sync::{Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use backtrace::Frame;
-use pprof::protos::Message;
+use regex::Regex;
 use thiserror::Error;
 
+use crate::alerts::{self, GrowthAlert, GrowthCallback, GrowthThresholds};
+use crate::backpressure::{self, Backpressure};
+use crate::budget::{self, OverBudget};
+use crate::build_info::BuildInfo;
+use crate::clock::{self, Clock, SystemClock};
 use crate::collector;
+use crate::components::Components;
+use crate::compress::{CompressedWriter, Compression};
+use crate::dumps::{self, DumpFiles, ReportCallback};
+use crate::flamechart;
+use crate::flight::FlightRecorder;
+use crate::labels::Labels;
+use crate::mappings::{self, Mapping};
+use crate::memory::{self, MemorySample};
+use crate::palette::FlamegraphPalette;
+#[cfg(feature = "async")]
+use crate::runtime::Runtime;
+use crate::stats;
+use crate::task::{self, Task};
+use crate::trace::{self, TraceContext};
+use crate::unwinder::{self, Backtrace, StackFrame, Unwinder};
+use crate::watchdog::{self, CollectorIncident, Heartbeat, IncidentKind};
+use crate::watermark::{self, PeakSink, Watermarks};
 
-const MAX_DEPTH: usize = 32;
+pub(crate) const MAX_DEPTH: usize = 32;
+// the frames of a stack past the first MAX_DEPTH, see `HeapProfilerGuardBuilder::max_depth`.
+pub(crate) const MAX_SPILLED: usize = 96;
+// the spill buffers the pool is kept topped up to.
+const SPILL_POOL: usize = 128;
+// how far into a stack the profiler's own frames are looked for.
+pub(crate) const MAX_OWN_FRAMES: usize = 16;
 
 static HEAP_PROFILER_ENABLED: AtomicBool = AtomicBool::new(false);
+static HEAP_PROFILER_PERIOD: AtomicUsize = AtomicUsize::new(1);
+static HEAP_PROFILER_TRACK_LIVE: AtomicBool = AtomicBool::new(false);
+static HEAP_PROFILER_CALL_SITES: AtomicBool = AtomicBool::new(false);
+// whether the stacks are told apart by their return addresses, see HeapProfilerGuardBuilder::key_by_ip.
+static HEAP_PROFILER_KEY_BY_IP: AtomicBool = AtomicBool::new(false);
+static HEAP_PROFILER_DEPTH: AtomicUsize = AtomicUsize::new(MAX_DEPTH);
+// the innermost frames past the profiler's own that aren't recorded.
+static HEAP_PROFILER_SKIP_FRAMES: AtomicUsize = AtomicUsize::new(0);
+// whether the profiler's own frames are told apart by the executable's symbols, for the unwinders that may not tell
+// where the functions start.
+static HEAP_PROFILER_RESOLVE_FRAMES: AtomicBool = AtomicBool::new(false);
+// nothing is recorded while warming up.
+static HEAP_PROFILER_WARMING: AtomicBool = AtomicBool::new(false);
+// in nanoseconds, 0 when not detecting churn.
+static HEAP_PROFILER_CHURN_WINDOW: AtomicU64 = AtomicU64::new(0);
+// in bytes, 0 when not tracing large allocations.
+static HEAP_PROFILER_LARGE_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+// the stacks dropped by the eviction policy since the start of the session.
+static HEAP_PROFILER_EVICTED: AtomicU64 = AtomicU64::new(0);
+// the sessions started so far, for the threads to tell whether what they found out is of the running one.
+static HEAP_PROFILER_SESSION: AtomicU64 = AtomicU64::new(0);
+// whether some threads are left out, see HeapProfilerGuardBuilder::include_threads.
+static HEAP_PROFILER_FILTER_THREADS: AtomicBool = AtomicBool::new(false);
+// whether only the allocations in focus are recorded, see HeapProfilerGuardBuilder::only_focused.
+static HEAP_PROFILER_ONLY_FOCUSED: AtomicBool = AtomicBool::new(false);
+// the stacks the pprof reports of the session keep, 0 for all of them, see HeapProfilerGuardBuilder::pprof_top.
+static HEAP_PROFILER_PPROF_TOP: AtomicUsize = AtomicUsize::new(0);
+// so that something allocating huge buffers in a loop doesn't make the profiler itself grow without bounds.
+const MAX_LARGE_ALLOCATIONS: usize = 1024;
+// the flushes waiting for the collector thread, past which the backpressure policy applies.
+const MAX_QUEUED_FLUSHES: usize = 256;
+// how many times `Backpressure::Block` looks for room before dropping the samples, a few microseconds.
+const MAX_BLOCKED_SPINS: usize = 256;
 
 lazy_static::lazy_static! {
-    static ref HEAP_PROFILER_STATE: RwLock<ProfilerState<MAX_DEPTH>> = RwLock::new(Default::default());
-    static ref HEAP_PROFILER_ENTER: Mutex<()> = Mutex::new(());
+    pub(crate) static ref HEAP_PROFILER_STATE: RwLock<ProfilerState<MAX_DEPTH>> = RwLock::new(Default::default());
+    static ref HEAP_PROFILER_ENTER: task::Exclusive = Default::default();
+    // The thread the hooks hand their samples over to, while a session is running and if it could be started.
+    static ref HEAP_PROFILER_COLLECTOR: spin::RwLock<Option<Collector>> = Default::default();
+    static ref HEAP_PROFILER_FLUSH_STRATEGY: spin::RwLock<FlushStrategy> = spin::RwLock::new(FlushStrategy::Threshold);
+    static ref HEAP_PROFILER_BACKPRESSURE: spin::RwLock<Backpressure> = Default::default();
+    static ref HEAP_PROFILER_MAX_STACKS: spin::RwLock<Option<(usize, collector::Eviction)>> = Default::default();
+    static ref HEAP_PROFILER_FRAME_FILTERS: spin::RwLock<FrameFilters> = Default::default();
+    static ref HEAP_PROFILER_THREAD_FILTERS: spin::RwLock<ThreadFilters> = Default::default();
+    static ref HEAP_PROFILER_BUILD: spin::RwLock<BuildInfo> = Default::default();
+    static ref HEAP_PROFILER_SAMPLE_TYPES: spin::RwLock<PprofSampleTypes> = Default::default();
+    static ref HEAP_PROFILER_PALETTE: spin::RwLock<FlamegraphPalette> = Default::default();
+    // The buffers of the threads that have allocated, whose samples are flushed before reporting. The threads that
+    // are gone are only referenced from here.
+    static ref HEAP_PROFILER_THREADS: spin::Mutex<Vec<Arc<std::sync::Mutex<ThreadBuffer>>>> = Default::default();
+    // Sampled allocations that haven't been freed yet, by address. It's touched from the hooks, including frees of
+    // memory allocated before the profiler started, so it can't be behind an async lock.
+    static ref HEAP_PROFILER_LIVE: spin::Mutex<LiveHeap> = Default::default();
+    static ref HEAP_PROFILER_LARGE: spin::Mutex<Vec<(StackKey<MAX_DEPTH>, usize, SystemTime)>> = Default::default();
+    // The buffers the hooks spill the frames of deeper stacks into, allocated outside of them.
+    static ref HEAP_PROFILER_SPILLS: spin::Mutex<Vec<Vec<StackFrame>>> = Default::default();
 }
 
+thread_local!(static ENTERED: Cell<bool> = Cell::new(false));
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("attempting to run a heap profiler while the another heap profiler is being run")]
     ConcurrentHeapProfiler,
+    #[error("profile has no sample type named {0:?}")]
+    UnknownSampleType(String),
+    #[error("line {0}: expected `<symbol prefix> = <component>`, got {1:?}")]
+    InvalidComponentRule(usize, String),
+    #[error("no async runtime to run the session on, see HeapProfilerGuardBuilder::runtime")]
+    NoRuntime,
+    #[error("cannot read {}: {1}", .0.display())]
+    ConfigFile(PathBuf, std::io::Error),
+    #[error("{}: {1}", .0.display())]
+    InvalidConfig(PathBuf, String),
+    #[error("invalid {0}: {1}")]
+    InvalidEnv(&'static str, String),
+    #[error("invalid frame regex: {0}")]
+    FrameRegex(#[from] regex::Error),
+    #[error("invalid thread regex: {0}")]
+    ThreadRegex(regex::Error),
+    #[error("cannot start the CPU profiler: {0}")]
+    CpuProfiler(#[from] pprof::Error),
+    #[error("unknown variable {{{1}}} in the dump file template {0:?}")]
+    DumpTemplate(String, String),
+    #[error("cannot handle signal {0}: {1}")]
+    Signal(i32, std::io::Error),
+    #[error("cannot listen on {}: {1}", .0.display())]
+    Console(PathBuf, std::io::Error),
+    #[error("cannot listen for child processes on {}: {1}", .0.display())]
+    Children(PathBuf, std::io::Error),
+    #[error("cannot resume from {}: {1}", .0.display())]
+    Checkpoint(PathBuf, std::io::Error),
+    #[error("cannot read {}: {1}", .0.display())]
+    EventsFile(PathBuf, std::io::Error),
+    #[error("{}:{1}: {2}", .0.display())]
+    InvalidEvents(PathBuf, usize, String),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// RAII structure used to stop profiling when dropped. It is the only interface to access the heap profiler.
+///
+/// Its methods are async with the `async` feature (see [`Runtime`]), and block the calling thread without it.
 pub struct HeapProfilerGuard {
-    _guard: MutexGuard<'static, ()>,
+    _guard: task::ExclusiveGuard,
+    // background tasks of the session, stopped with it.
+    watchers: Vec<Task<()>>,
+    cpu: Option<pprof::ProfilerGuard<'static>>,
+    baseline: Option<Task<HeapReport>>,
+    addresses: bool,
+    // written with the report, see HeapProfilerGuardBuilder::dump_on_drop.
+    outputs: Vec<DumpFiles>,
+    // see HeapProfilerGuardBuilder::record_events.
+    events: Option<PathBuf>,
+    #[cfg(unix)]
+    _signal: Option<dumps::SignalHandler>,
+    #[cfg(unix)]
+    _console: Option<crate::console::ConsoleSocket>,
+    #[cfg(unix)]
+    _children: Option<crate::children::ChildrenSocket>,
+    // sent the report, see HeapProfilerGuardBuilder::from_parent.
+    #[cfg(unix)]
+    parent: Option<PathBuf>,
+    _symbolizer: Option<crate::symbols::Symbolizer>,
 }
 
+#[cfg(feature = "async")]
 impl HeapProfilerGuard {
     pub async fn new(period: usize) -> Result<Self> {
-        let guard = HEAP_PROFILER_ENTER.lock().await;
-        Profiler::start(period).await;
-        Ok(Self { _guard: guard })
+        HeapProfilerGuardBuilder::default()
+            .period(period)
+            .build()
+            .await
+    }
+
+    pub async fn report(mut self) -> HeapReport {
+        // symbolizing is slow.
+        task::unblock(move || self.finish()).await
     }
 
-    pub async fn report(self) -> HeapReport {
+    /// Like [`report`](Self::report) but keeps raw addresses along with the build-ids of the mapped binaries, so the
+    /// profile can be symbolized later (e.g. with `heappy symbolize`) and production binaries can stay stripped.
+    pub async fn report_unsymbolized(mut self) -> UnsymbolizedHeapReport {
+        self.outputs.clear();
         Profiler::stop();
-        HeapReport::new().await
+        let report = UnsymbolizedHeapReport::new();
+        self.write_events();
+        report
+    }
+
+    /// Ends the [warm-up](HeapProfilerGuardBuilder::warm_up) of the session if it isn't over yet: what's allocated
+    /// from now on is recorded.
+    pub async fn mark_warm(&self) {
+        Profiler::end_warm_up();
+    }
+
+    /// A report of the session so far, without stopping the profiler, e.g. to [subtract](HeapReport::subtract_baseline)
+    /// from the final one.
+    pub async fn snapshot(&self) -> HeapReport {
+        // symbolizing is slow.
+        task::unblock(HeapReport::snapshot).await
+    }
+
+    /// The report of the [flight recorder](HeapProfilerGuardBuilder::flight_recorder)'s window, without stopping the
+    /// profiler; like [`snapshot`](Self::snapshot), which it is without a flight recorder.
+    pub async fn dump_last(&self) -> HeapReport {
+        task::unblock(HeapReport::snapshot).await
+    }
+
+    /// The sampled allocations that are still allocated right now, without stopping the profiler.
+    ///
+    /// Only available with [`HeapProfilerGuardBuilder::track_live`], otherwise the report is empty.
+    pub async fn live_report(&self) -> HeapReport {
+        self.live()
     }
 }
 
-impl Drop for HeapProfilerGuard {
-    fn drop(&mut self) {
+#[cfg(not(feature = "async"))]
+impl HeapProfilerGuard {
+    pub fn new(period: usize) -> Result<Self> {
+        HeapProfilerGuardBuilder::default().period(period).build()
+    }
+
+    pub fn report(mut self) -> HeapReport {
+        self.finish()
+    }
+
+    /// Like [`report`](Self::report) but keeps raw addresses along with the build-ids of the mapped binaries, so the
+    /// profile can be symbolized later (e.g. with `heappy symbolize`) and production binaries can stay stripped.
+    pub fn report_unsymbolized(mut self) -> UnsymbolizedHeapReport {
+        self.outputs.clear();
+        Profiler::stop();
+        let report = UnsymbolizedHeapReport::new();
+        self.write_events();
+        report
+    }
+
+    /// Ends the [warm-up](HeapProfilerGuardBuilder::warm_up) of the session if it isn't over yet: what's allocated
+    /// from now on is recorded.
+    pub fn mark_warm(&self) {
+        Profiler::end_warm_up();
+    }
+
+    /// A report of the session so far, without stopping the profiler, e.g. to [subtract](HeapReport::subtract_baseline)
+    /// from the final one.
+    pub fn snapshot(&self) -> HeapReport {
+        HeapReport::snapshot()
+    }
+
+    /// The report of the [flight recorder](HeapProfilerGuardBuilder::flight_recorder)'s window, without stopping the
+    /// profiler; like [`snapshot`](Self::snapshot), which it is without a flight recorder.
+    pub fn dump_last(&self) -> HeapReport {
+        HeapReport::snapshot()
+    }
+
+    /// The sampled allocations that are still allocated right now, without stopping the profiler.
+    ///
+    /// Only available with [`HeapProfilerGuardBuilder::track_live`], otherwise the report is empty.
+    pub fn live_report(&self) -> HeapReport {
+        self.live()
+    }
+}
+
+impl HeapProfilerGuard {
+    // Stops the session, reports it and writes the outputs.
+    fn finish(&mut self) -> HeapReport {
+        // stopping forgets the live allocations.
+        let addresses = self.addresses.then(Profiler::live_addresses);
         Profiler::stop();
+        // stopped right after the heap profiler, so that both cover the same window.
+        let cpu = self.cpu.take().and_then(|cpu| cpu.report().build().ok());
+        let mut report = HeapReport::new();
+        #[cfg(feature = "prost_codec")]
+        let cpu = cpu.and_then(|cpu| cpu.pprof().ok());
+        #[cfg(not(feature = "prost_codec"))]
+        let cpu = cpu.as_ref().map(cpu_pprof);
+        report.cpu = cpu;
+        report.addresses = addresses.map(symbolize_addresses).unwrap_or_default();
+        if let Some(baseline) = self.finished_baseline() {
+            report.since(&baseline);
+        }
+        for files in std::mem::take(&mut self.outputs) {
+            files.write(&report, "drop");
+        }
+        self.write_events();
+        #[cfg(unix)]
+        self.send_to_parent(&report);
+        report
+    }
+
+    #[cfg(unix)]
+    fn send_to_parent(&mut self, report: &HeapReport) {
+        let Some(path) = self.parent.take() else {
+            return;
+        };
+        if let Err(_err) = crate::children::send(&path, report.totals, &report.data) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(path = %path.display(), error = %_err, "cannot send the heap report to the parent process");
+        }
+    }
+
+    fn write_events(&mut self) {
+        let Some(path) = self.events.take() else {
+            return;
+        };
+        if let Err(_err) = crate::events::finish(&path) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(path = %path.display(), error = %_err, "cannot write the event recording");
+        }
+    }
+
+    // The baseline to take out of the report, if it was taken.
+    fn finished_baseline(&mut self) -> Option<HeapReport> {
+        let baseline = self.baseline.take()?;
+        // reported before the end of the startup: there's nothing to take out yet.
+        if !baseline.is_finished() {
+            baseline.abort();
+            return None;
+        }
+        baseline.join()
+    }
+
+    fn live(&self) -> HeapReport {
+        let mut report = HeapReport::live();
+        if self.addresses {
+            report.addresses =
+                Profiler::untracked(|| symbolize_addresses(Profiler::live_addresses()))
+                    .unwrap_or_default();
+        }
+        report
     }
 }
 
+/// Configures and starts a [`HeapProfilerGuard`].
 #[derive(Clone)]
-struct ProfilerBuffer {
-    allocated_objects: isize,
-    allocated_bytes: isize,
-    freed_objects: isize,
-    freed_bytes: isize,
-    next_sample: isize,
+pub struct HeapProfilerGuardBuilder {
     period: usize,
+    track_live: bool,
+    call_sites_only: bool,
+    max_depth: usize,
+    skip_frames: usize,
+    unwinder: Option<Arc<dyn Unwinder>>,
+    key_by_ip: bool,
+    trace_context: Option<Arc<dyn TraceContext>>,
+    flush_strategy: FlushStrategy,
+    backpressure: Backpressure,
+    max_stacks: Option<(usize, collector::Eviction)>,
+    memory_budget: Option<(usize, OverBudget)>,
+    clock: Option<Arc<dyn Clock>>,
+    deterministic: bool,
+    #[cfg(feature = "async")]
+    runtime: Option<Arc<dyn Runtime>>,
+    foreign_frees: bool,
+    baseline_after: Option<Duration>,
+    warm_up: Option<Duration>,
+    addresses: bool,
+    churn_window: Option<Duration>,
+    large_threshold: Option<usize>,
+    #[cfg(feature = "canary")]
+    canary_every: usize,
+    memory_sampling: Option<(Duration, bool)>,
+    cpu_frequency: Option<i32>,
+    on_growth: Option<(GrowthThresholds, GrowthCallback)>,
+    on_peak: Option<(Watermarks, PeakSink)>,
+    frame_filters: FrameFilters,
+    thread_filters: ThreadFilters,
+    only_focused: bool,
+    pprof_top: usize,
+    palette: FlamegraphPalette,
+    build: BuildInfo,
+    sample_types: PprofSampleTypes,
+    outputs: Vec<DumpFiles>,
+    periodic_dumps: Option<(Duration, DumpFiles)>,
+    on_interval: Option<(Duration, ReportCallback)>,
+    #[cfg(feature = "tracing")]
+    summary: Option<Duration>,
+    #[cfg(unix)]
+    signal_dumps: Option<(i32, DumpFiles)>,
+    #[cfg(unix)]
+    console: Option<PathBuf>,
+    #[cfg(unix)]
+    children: Option<PathBuf>,
+    #[cfg(unix)]
+    parent: Option<PathBuf>,
+    events: Option<PathBuf>,
+    checkpoints: Option<(Duration, PathBuf)>,
+    resume: Option<PathBuf>,
+    flight_recorder: Option<FlightRecorder>,
+    background_symbols: Option<Duration>,
 }
 
-impl ProfilerBuffer {
-    fn new() -> Self {
+impl Default for HeapProfilerGuardBuilder {
+    fn default() -> Self {
         Self {
-            allocated_objects: 0,
-            allocated_bytes: 0,
-            freed_objects: 0,
-            freed_bytes: 0,
-            next_sample: 1024 * 1024,
-            period: 1024 * 1024,
+            period: 1,
+            track_live: false,
+            call_sites_only: false,
+            max_depth: MAX_DEPTH,
+            skip_frames: 0,
+            unwinder: None,
+            key_by_ip: false,
+            trace_context: None,
+            flush_strategy: FlushStrategy::Threshold,
+            backpressure: Backpressure::CoalesceInPlace,
+            max_stacks: None,
+            memory_budget: None,
+            clock: None,
+            deterministic: false,
+            #[cfg(feature = "async")]
+            runtime: None,
+            foreign_frees: false,
+            baseline_after: None,
+            warm_up: None,
+            addresses: false,
+            churn_window: None,
+            large_threshold: None,
+            #[cfg(feature = "canary")]
+            canary_every: 0,
+            memory_sampling: None,
+            cpu_frequency: None,
+            on_growth: None,
+            on_peak: None,
+            frame_filters: Default::default(),
+            thread_filters: Default::default(),
+            only_focused: false,
+            pprof_top: 0,
+            palette: Default::default(),
+            build: Default::default(),
+            sample_types: Default::default(),
+            outputs: vec![],
+            periodic_dumps: None,
+            on_interval: None,
+            #[cfg(feature = "tracing")]
+            summary: None,
+            #[cfg(unix)]
+            signal_dumps: None,
+            #[cfg(unix)]
+            console: None,
+            #[cfg(unix)]
+            children: None,
+            #[cfg(unix)]
+            parent: None,
+            events: None,
+            checkpoints: None,
+            resume: None,
+            flight_recorder: None,
+            background_symbols: None,
         }
     }
+}
 
-    fn track(&mut self, size: isize) {
-        if size > 0 {
-            self.allocated_objects += 1;
-            self.allocated_bytes += size;
-        } else if size < 0 {
-            self.freed_objects += 1;
-            self.freed_bytes += -size;
-        }
+impl HeapProfilerGuardBuilder {
+    /// Takes a sample every `period` bytes allocated (or freed).
+    pub fn period(mut self, period: usize) -> Self {
+        self.period = period;
+        self
+    }
 
-        // Check if either allocated bytes or freed bytes cross the threshold
-        if self.allocated_bytes >= self.next_sample || self.freed_bytes >= self.next_sample {
-            self.next_sample += self.period as isize;
-        }
+    /// Remembers the address of every sampled allocation until it's freed, so that
+    /// [`HeapProfilerGuard::live_report`] can tell what's still allocated at any moment.
+    ///
+    /// It's heavier: every free looks up the address, and every live sample keeps its stack around.
+    pub fn track_live(mut self, track_live: bool) -> Self {
+        self.track_live = track_live;
+        self
+    }
+
+    /// Records only the function that asked for the memory instead of the whole stack, along with the functions
+    /// inlined into it. The unwinding stops there, which makes each sample much cheaper: cheap enough to leave a
+    /// session running in production as a baseline, at the cost of the context of the allocations.
+    pub fn call_sites_only(mut self, call_sites_only: bool) -> Self {
+        self.call_sites_only = call_sites_only;
+        self
     }
 
-    fn should_flush(&self) -> bool {
-        self.allocated_bytes >= self.next_sample || self.freed_bytes >= self.next_sample
+    /// Records at most the `depth` innermost frames of each stack (past the profiler's own), 32 by default and
+    /// 128 at most: shallower stacks are cheaper to walk and to keep, at the cost of their roots. The frames past the
+    /// 32nd go into buffers of a pool, which the collector thread keeps topped up: a stack sampled while it's empty
+    /// (e.g. in a burst of deep samples) is cut at 32 frames.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth.clamp(1, MAX_DEPTH + MAX_SPILLED);
+        self
     }
 
-    unsafe fn flush(&mut self, profiler: &mut ProfilerState<MAX_DEPTH>) {
-        let current_net_change = self.allocated_bytes - self.freed_bytes;
+    /// Leaves out the `n` innermost frames of each stack, none by default, e.g. the allocation functions of the
+    /// standard library or a crate's own allocation wrappers, so that the stacks start at the code asking for memory.
+    ///
+    /// The profiler's own frames (its hooks, or what calls into it like the
+    /// [`ProfilingAllocator`](crate::ProfilingAllocator), and everything they call) are always left out, and the `n`
+    /// are past them: with an [unwinder](Self::unwinder) that doesn't tell where the functions start, by the symbols of
+    /// the executable, which has to have them. Where they can't be told apart, the `n` are the innermost of all.
+    pub fn skip_frames(mut self, n: usize) -> Self {
+        self.skip_frames = n;
+        self
+    }
 
-        // Update profiler state
-        profiler.allocated_objects += self.allocated_objects;
-        profiler.allocated_bytes += self.allocated_bytes;
-        profiler.freed_objects += self.freed_objects;
-        profiler.freed_bytes += self.freed_bytes;
+    /// When the samples of a thread are flushed into the session, [`FlushStrategy::Threshold`] by default.
+    pub fn flush_strategy(mut self, strategy: FlushStrategy) -> Self {
+        self.flush_strategy = strategy;
+        self
+    }
 
-        // Check if the net change since the last sample is significant
-        if current_net_change.abs() >= self.next_sample {
-            // Adjust the next_sample based on current activity
-            self.next_sample = current_net_change.abs() + self.period as isize;
-        }
+    /// What the threads do with their samples when the collector thread can't keep up with them,
+    /// [`Backpressure::CoalesceInPlace`] by default. [`Profiler::backpressure`] tells how often it happened.
+    pub fn backpressure(mut self, policy: Backpressure) -> Self {
+        self.backpressure = policy;
+        self
+    }
+
+    /// Keeps at most `max` stacks, dropping some by `eviction` (see [`collector::Eviction`]) when there are more, so
+    /// that a long session over code with countless stacks (heavily generic, deeply async) doesn't grow the profiler's
+    /// memory without bound. The reports miss what the dropped stacks counted, but not the totals;
+    /// [`Profiler::evicted_stacks`] tells how many there were. Unbounded by default.
+    pub fn max_stacks(mut self, max: usize, eviction: collector::Eviction) -> Self {
+        self.max_stacks = Some((max.max(1), eviction));
+        self
+    }
+
+    /// Keeps the profiler's own memory (its stacks, the live allocations it tracks, the samples waiting for the
+    /// collector thread and the buffers of the threads) within about `bytes`, by `policy` (see [`OverBudget`]) when it
+    /// outgrows them: a failsafe for the sessions left running in production. The collector thread estimates it at
+    /// most every 100ms, [`Profiler::footprint`] tells the last estimate; deterministic sessions, which have no
+    /// collector thread, aren't bounded. Unbounded by default.
+    pub fn memory_budget(mut self, bytes: usize, policy: OverBudget) -> Self {
+        self.memory_budget = Some((bytes.max(1), policy));
+        self
+    }
+
+    /// Where the session gets the time from, the OS by default: a [`ManualClock`](crate::ManualClock) makes the flush
+    /// intervals, the timelines and the durations of the reports independent of how long the test takes. The
+    /// watchers (alerts, peaks, memory samples, periodic dumps) and the warm-up still run on the OS' time.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Makes the samples reproducible, for tests: each thread takes its first sample `period` bytes into the session
+    /// (rather than carrying over what it allocated before), and flushes its samples itself instead of handing them to
+    /// the collector thread, so that a report sees all the samples of the thread taking it whatever the scheduling.
+    /// Allocations of other threads racing with a report can still make it or not.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// How the stacks are walked, [`Backtrace`] by default, see [`Unwinder`]. Sessions recording only the call sites
+    /// always use `backtrace-rs`.
+    pub fn unwinder(mut self, unwinder: impl Unwinder + 'static) -> Self {
+        self.unwinder = Some(Arc::new(unwinder));
+        self
+    }
+
+    /// Tells the stacks apart by the return addresses of their frames rather than by their functions, if `key_by_ip`
+    /// is true, like the unwinders that don't know where the functions start: the allocations of a function calling
+    /// from different lines, or through different inlined functions, count apart in the session instead of all under
+    /// the first stack sampled. The reports still merge the stacks of the same functions, their symbols telling the
+    /// inlined ones apart, and what keeps the addresses (the live allocations, the recordings) tells the lines apart
+    /// too. The session keeps more stacks.
+    pub fn key_by_ip(mut self, key_by_ip: bool) -> Self {
+        self.key_by_ip = key_by_ip;
+        self
+    }
+
+    /// Labels the samples with the distributed trace they were taken in (see [`TRACE_ID_LABEL`](crate::TRACE_ID_LABEL)
+    /// and [`SPAN_ID_LABEL`](crate::SPAN_ID_LABEL)), as told by `context`, e.g. from `tracing-opentelemetry`, see
+    /// [`TraceContext`].
+    pub fn trace_context(mut self, context: impl TraceContext + 'static) -> Self {
+        self.trace_context = Some(Arc::new(context));
+        self
+    }
+
+    /// Counts the frees of memory allocated before the session apart (see [`HeapTotals::foreign_freed_bytes`]), so that
+    /// they don't make the in-use bytes of the session look smaller than they are, nor get attributed to the stacks it
+    /// samples. Only with the `measure_free` feature; it takes 8 MiB to remember which addresses the session allocated.
+    pub fn separate_foreign_frees(mut self, separate: bool) -> Self {
+        self.foreign_frees = separate;
+        self
+    }
+
+    /// Records nothing until `max` has passed or [`HeapProfilerGuard::mark_warm`] is called, whichever comes first, so
+    /// that warm-up phases (filling caches, connection pools, JIT-like lazy work) don't pollute a profile of the steady
+    /// state. The session, its duration and timelines included, starts when the warm-up ends. `Duration::MAX` waits
+    /// for `mark_warm`.
+    pub fn warm_up(mut self, max: Duration) -> Self {
+        self.warm_up = Some(max);
+        self
+    }
+
+    /// Takes a [snapshot](HeapProfilerGuard::snapshot) `startup` after the start of the session and subtracts it from
+    /// the [report](HeapProfilerGuard::report), so that one-time initialization (lazy statics, caches, the first
+    /// `println!`) doesn't dominate a profile of the steady state. A session reported before the end of `startup` is
+    /// reported whole.
+    pub fn baseline_after(mut self, startup: Duration) -> Self {
+        self.baseline_after = Some(startup);
+        self
+    }
+
+    /// Keeps the address and size of the sampled allocations that are still allocated in the
+    /// [report](HeapProfilerGuard::report) and the [live reports](HeapProfilerGuard::live_report), so that tooling
+    /// can tie the pages of a core dump (or of `/proc/<pid>/mem`) back to the stacks that allocated them, see
+    /// [`HeapReport::addresses`]. Implies [`track_live`](Self::track_live).
+    pub fn record_addresses(mut self, record: bool) -> Self {
+        self.addresses = record;
+        self.track_live |= record;
+        self
+    }
+
+    /// Counts the sampled allocations freed within `window` of being allocated, per stack, to point at temporary
+    /// allocations worth hoisting or pooling (see [`HeapReport::churn`]). Implies [`track_live`](Self::track_live).
+    pub fn churn_window(mut self, window: Duration) -> Self {
+        self.track_live = true;
+        self.churn_window = Some(window);
+        self
+    }
+
+    /// Records every allocation of at least `threshold` bytes with its stack, sampled or not (see
+    /// [`HeapReport::large_allocations`]). With the `tracing` feature each one is also logged as a warning right
+    /// away, from the allocating thread.
+    pub fn trace_large(mut self, threshold: usize) -> Self {
+        self.large_threshold = Some(threshold.max(1));
+        self
+    }
+
+    /// Pads one allocation out of `every` of each thread with canary bytes, checked when it's freed, see
+    /// [`canary`](crate::canary). Meant for debugging: it costs memory, and a lock on every free while there are padded
+    /// allocations around.
+    #[cfg(feature = "canary")]
+    pub fn canaries(mut self, every: usize) -> Self {
+        self.canary_every = every;
+        self
+    }
+
+    /// Records the resident set size of the process every `interval`, along with the usage of its memory cgroup if
+    /// `cgroup` is set, see [`HeapReport::memory_samples`].
+    pub fn sample_memory(mut self, interval: Duration, cgroup: bool) -> Self {
+        self.memory_sampling = Some((interval, cgroup));
+        self
+    }
+
+    /// Also runs pprof's CPU profiler at `frequency` Hz over the same window, see [`HeapReport::cpu_pprof`]. Only
+    /// [`HeapProfilerGuard::report`] has the CPU profile.
+    pub fn cpu_profile(mut self, frequency: i32) -> Self {
+        self.cpu_frequency = Some(frequency);
+        self
+    }
+
+    /// Calls `callback` from a blocking thread when the sampled in-use bytes grow past `thresholds`, see
+    /// [`GrowthThresholds`].
+    pub fn on_growth(
+        mut self,
+        thresholds: GrowthThresholds,
+        callback: impl Fn(GrowthAlert) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_growth = Some((thresholds, Arc::new(callback)));
+        self
+    }
+
+    /// Calls `callback` from a blocking thread with a report of the session so far whenever its in-use bytes reach a
+    /// new peak, see [`Watermarks`].
+    pub fn on_peak(
+        mut self,
+        watermarks: Watermarks,
+        callback: impl Fn(HeapReport) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_peak = Some((watermarks, PeakSink::Callback(Arc::new(callback))));
+        self
+    }
+
+    /// Like [`on_peak`](Self::on_peak), but writes the reports as pprof files to `dir`, named
+    /// `heap-peak-{seq}-{bytes}.pb`, keeping only the last `keep` of them (or all with 0).
+    pub fn write_peaks(self, watermarks: Watermarks, dir: impl Into<PathBuf>, keep: usize) -> Self {
+        let files = DumpFiles::new(dir)
+            .template("heap-peak-{seq}-{bytes}.pb")
+            .keep(keep);
+        self.dump_peaks(watermarks, files)
+    }
+
+    /// Like [`write_peaks`](Self::write_peaks), to any [`DumpFiles`].
+    pub fn dump_peaks(mut self, watermarks: Watermarks, files: DumpFiles) -> Self {
+        self.on_peak = Some((watermarks, PeakSink::Files(files)));
+        self
+    }
+
+    /// Leaves the frames whose function name fully matches the regex `regex` out of the reports, along with everything
+    /// they call, like the profiler's own frames: e.g. `tokio::runtime::.*` to start the stacks at the tasks. The pprof
+    /// reports get it as their `drop_frames`, for their viewers to apply, and the flamegraphs and flame charts are
+    /// rendered without the frames. A regex that doesn't compile fails [`build`](Self::build).
+    pub fn drop_frames(mut self, regex: impl Into<String>) -> Self {
+        self.frame_filters.drop.push(regex.into());
+        self
+    }
+
+    /// Keeps the frames whose function name fully matches the regex `regex` even if they match
+    /// [`drop_frames`](Self::drop_frames), like pprof's `keep_frames`.
+    pub fn keep_frames(mut self, regex: impl Into<String>) -> Self {
+        self.frame_filters.keep.push(regex.into());
+        self
+    }
+
+    /// Only records the allocations of the threads whose name fully matches the regex `regex` (any of them, if called
+    /// more than once), e.g. `query-.*` for the workers of a pool, leaving the others out of the session entirely.
+    /// The names are the ones the OS knows the threads by: on linux the first 15 bytes of the names Rust gives them. A
+    /// regex that doesn't compile fails [`build`](Self::build).
+    pub fn include_threads(mut self, regex: impl Into<String>) -> Self {
+        self.thread_filters.include.push(regex.into());
+        self
+    }
+
+    /// Leaves the allocations of the threads whose name fully matches the regex `regex` out of the session, e.g. the
+    /// threads of the I/O driver or of the logger, even if they match [`include_threads`](Self::include_threads).
+    pub fn exclude_threads(mut self, regex: impl Into<String>) -> Self {
+        self.thread_filters.exclude.push(regex.into());
+        self
+    }
+
+    /// Only records the allocations made in a [`focus`](crate::focus), such as the functions of
+    /// `#[heappy::instrument(focus)]` (with the `macros` feature), if `only_focused` is true.
+    pub fn only_focused(mut self, only_focused: bool) -> Self {
+        self.only_focused = only_focused;
+        self
+    }
+
+    /// Makes the pprof reports of the session keep only the `n` stacks that allocated the most, see
+    /// [`HeapReport::pprof_top`].
+    pub fn pprof_top(mut self, n: usize) -> Self {
+        self.pprof_top = n.max(1);
+        self
+    }
+
+    /// Makes the pprof reports of the session have only the `types` of samples, see
+    /// [`HeapReport::pprof_sample_types`].
+    pub fn pprof_sample_types(mut self, types: impl IntoIterator<Item = SampleType>) -> Self {
+        self.sample_types.emitted = Some(types.into_iter().collect());
+        self
+    }
+
+    /// Makes `ty` the sample type the viewers show first in the pprof reports of the session, see
+    /// [`HeapReport::default_sample_type`].
+    pub fn default_sample_type(mut self, ty: SampleType) -> Self {
+        self.sample_types.default = Some(ty);
+        self
+    }
+
+    /// How the flamegraphs of the session are colored, see [`HeapReport::flamegraph_palette`].
+    pub fn flamegraph_palette(mut self, palette: FlamegraphPalette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Writes the report to `path` in `format` when the session is [reported](HeapProfilerGuard::report), or
+    /// dropped without being reported (the report is then taken in `drop`). The file name can use the variables of
+    /// [`DumpFiles`], e.g. `heap-{pid}.pb`. With the `tracing` feature the files that can't be written are logged
+    /// as warnings.
+    pub fn write_report(self, format: ReportFormat, path: impl Into<PathBuf>) -> Self {
+        self.dump_on_drop(DumpFiles::at(&path.into(), format))
+    }
 
-        // Record backtrace and other data if there was a significant net change
-        if current_net_change != 0 {
-            let mut bt = Frames::new();
-            backtrace::trace_unsynchronized(|frame| bt.push(frame));
-            profiler.collector.record(bt, current_net_change);
+    /// Like [`write_report`](Self::write_report), to any [`DumpFiles`].
+    pub fn dump_on_drop(mut self, files: DumpFiles) -> Self {
+        self.outputs.push(files);
+        self
+    }
+
+    /// Writes a snapshot of the session every `interval`.
+    pub fn dump_every(mut self, interval: Duration, files: DumpFiles) -> Self {
+        self.periodic_dumps = Some((interval, files));
+        self
+    }
+
+    /// Calls `callback` from a blocking thread with a report of the session so far every `interval`, e.g. to ship
+    /// the profiles somewhere; [`dump_every`](Self::dump_every) writes them to files.
+    pub fn on_interval(
+        mut self,
+        interval: Duration,
+        callback: impl Fn(HeapReport) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_interval = Some((interval, Arc::new(callback)));
+        self
+    }
+
+    /// Logs a one-line summary of the session every `interval`, at the info level with the `heappy` target: the
+    /// bytes allocated per second since the previous one, the bytes in use and the 3 stacks with the most bytes (in
+    /// use with the `measure_free` feature, allocated without), by their call site.
+    #[cfg(feature = "tracing")]
+    pub fn log_summary(mut self, interval: Duration) -> Self {
+        self.summary = Some(interval);
+        self
+    }
+
+    /// Writes a snapshot of the session each time the process receives `signal` (e.g. `libc::SIGUSR2`), whose
+    /// previous handler is restored when the session ends. The snapshot is taken within 100ms of the signal.
+    #[cfg(unix)]
+    pub fn dump_on_signal(mut self, signal: i32, files: DumpFiles) -> Self {
+        self.signal_dumps = Some((signal, files));
+        self
+    }
+
+    /// Listens on the unix socket `path` for the commands of `heappy console` (the totals and top stacks of the
+    /// session, snapshots written on demand) while the session runs, see [`crate::console`]. The socket is removed
+    /// when the session ends.
+    #[cfg(unix)]
+    pub fn console(mut self, path: impl Into<PathBuf>) -> Self {
+        self.console = Some(path.into());
+        self
+    }
+
+    /// Listens on the unix socket `path` for the reports of the subprocesses started while the session runs, which
+    /// their reports include (their stacks labeled with their `pid`), see [`crate::children`]. The path and the
+    /// sampling settings are set in the environment of the process for the children to inherit, and start their own
+    /// sessions from with [`from_parent`](Self::from_parent). The socket and the variables are removed when the
+    /// session ends.
+    #[cfg(unix)]
+    pub fn profile_children(mut self, path: impl Into<PathBuf>) -> Self {
+        self.children = Some(path.into());
+        self
+    }
+
+    /// A builder of the sampling period and depth of the parent process, if it's profiling its children (see
+    /// [`profile_children`](Self::profile_children)), whose session sends its report to the parent when it ends:
+    /// `None` in a process started otherwise.
+    #[cfg(unix)]
+    pub fn from_parent() -> Option<Self> {
+        let path = std::env::var_os(crate::children::SOCKET_ENV).filter(|path| !path.is_empty())?;
+        let number = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        };
+        let mut builder = Self::default();
+        if let Some(period) = number(crate::children::PERIOD_ENV) {
+            builder = builder.period(period);
+        }
+        if let Some(depth) = number(crate::children::DEPTH_ENV) {
+            builder = builder.max_depth(depth);
         }
+        builder.parent = Some(path.into());
+        Some(builder)
     }
 
-    fn reset_buffer(&mut self) {
-        // Reset local buffer
-        self.allocated_objects = 0;
-        self.allocated_bytes = 0;
-        self.freed_objects = 0;
-        self.freed_bytes = 0;
+    /// Records the samples as they're flushed into the session and writes them to `path` when the session ends
+    /// (compressed after its extension, see [`Compression::from_path`]), for [`HeapReport::replay`] to replay them
+    /// later, see [`events`](crate::events). The recording is kept in memory until then. With the `tracing` feature a
+    /// file that can't be written is logged as a warning.
+    pub fn record_events(mut self, path: impl Into<PathBuf>) -> Self {
+        self.events = Some(path.into());
+        self
     }
-}
 
-// Called by malloc hooks to record a memory allocation event.
-pub struct Profiler;
+    /// Writes the totals and the raw records of the stacks of the session to `path` (compressed after its extension)
+    /// every `interval`, replacing the previous checkpoint, so that a process restarted with
+    /// [`resume_from`](Self::resume_from) the same path loses at most an interval of a long session, see
+    /// [`checkpoint`](crate::checkpoint). With the `tracing` feature a checkpoint that can't be written is logged as a
+    /// warning.
+    pub fn checkpoint(mut self, interval: Duration, path: impl Into<PathBuf>) -> Self {
+        self.checkpoints = Some((interval, path.into()));
+        self
+    }
 
-impl Profiler {
-    fn enabled() -> bool {
-        HEAP_PROFILER_ENABLED.load(Ordering::SeqCst)
+    /// Starts the session from the checkpoint at `path`, if there's one: its totals, stacks and duration are added
+    /// to the session's, the stacks of code no longer loaded (or rebuilt) left out. A checkpoint that can't be read
+    /// fails the build. The warm-up, if any, discards it along with what it ran over.
+    pub fn resume_from(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume = Some(path.into());
+        self
     }
 
-    fn set_enabled(value: bool) {
-        HEAP_PROFILER_ENABLED.store(value, Ordering::SeqCst)
+    /// Keeps the stacks of only the latest samples, in a ring buffer, rather than of the whole session: the reports
+    /// (see [`HeapProfilerGuard::dump_last`]) cover the samples of the window, with its totals, timelines and duration,
+    /// but not the churn, the large allocations and the memory samples of the session. For always-on sessions dumped
+    /// on an incident, e.g. with [`dump_on_signal`](Self::dump_on_signal).
+    pub fn flight_recorder(mut self, limit: FlightRecorder) -> Self {
+        self.flight_recorder = Some(limit);
+        self
     }
 
-    async fn start(period: usize) {
-        let mut profiler = HEAP_PROFILER_STATE.write().await;
-        *profiler = ProfilerState::new(period);
-        std::mem::drop(profiler);
+    /// Stamps the reports with the name of the service, see [`BuildInfo`](crate::BuildInfo).
+    pub fn service_name(mut self, name: impl Into<String>) -> Self {
+        self.build.service = Some(name.into());
+        self
+    }
 
-        Self::set_enabled(true);
+    /// Stamps the reports with the version of the build, e.g. `env!("CARGO_PKG_VERSION")`.
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.build.version = Some(version.into());
+        self
     }
 
-    fn stop() {
-        Self::set_enabled(false);
+    /// Stamps the reports with the git commit of the build, e.g. `env!("GIT_SHA")` set by a build script or CI.
+    pub fn git_sha(mut self, sha: impl Into<String>) -> Self {
+        self.build.git_sha = Some(sha.into());
+        self
     }
 
-    pub(crate) unsafe fn track_allocated(size: isize) {
-        thread_local!(static ENTERED: Cell<bool> = Cell::new(false));
-        thread_local!(static BUFFER: std::sync::Mutex<ProfilerBuffer> = std::sync::Mutex::new(ProfilerBuffer::new()));
+    /// Resolves the symbols of the session's stacks on a background thread of low priority, the new ones every
+    /// `interval`, so that the reports only resolve the frames that are new since instead of all of them: for
+    /// sessions of tens of thousands of stacks, whose reports would spend seconds symbolizing. The symbols are kept
+    /// until the next session starts.
+    pub fn symbolize_in_background(mut self, interval: Duration) -> Self {
+        self.background_symbols = Some(interval);
+        self
+    }
 
-        struct ResetOnDrop;
+    /// The runtime the session runs on, [`Tokio`](crate::Tokio) by default with the `tokio` feature.
+    #[cfg(feature = "async")]
+    pub fn runtime(mut self, runtime: impl Runtime + 'static) -> Self {
+        self.runtime = Some(Arc::new(runtime));
+        self
+    }
 
-        impl Drop for ResetOnDrop {
-            fn drop(&mut self) {
-                ENTERED.with(|b| b.set(false));
+    #[cfg(feature = "async")]
+    pub async fn build(self) -> Result<HeapProfilerGuard> {
+        #[cfg(feature = "tokio")]
+        let default: Option<Arc<dyn Runtime>> = Some(Arc::new(crate::Tokio));
+        #[cfg(not(feature = "tokio"))]
+        let default = None;
+        let runtime = self.runtime.clone().or(default).ok_or(Error::NoRuntime)?;
+        // waiting for the previous session blocks.
+        let entered = task::unblock_on(&*runtime, || HEAP_PROFILER_ENTER.lock()).await;
+        task::set_runtime(runtime);
+        self.start(entered)
+    }
+
+    #[cfg(not(feature = "async"))]
+    pub fn build(self) -> Result<HeapProfilerGuard> {
+        let entered = HEAP_PROFILER_ENTER.lock();
+        self.start(entered)
+    }
+
+    fn start(mut self, entered: task::ExclusiveGuard) -> Result<HeapProfilerGuard> {
+        self.frame_filters.compile()?;
+        self.thread_filters.compile().map_err(Error::ThreadRegex)?;
+        let peaks = match &self.on_peak {
+            Some((_, PeakSink::Files(files))) => Some(files),
+            _ => None,
+        };
+        let periodic = self.periodic_dumps.as_ref().map(|(_, files)| files);
+        #[cfg(unix)]
+        let signal = self.signal_dumps.as_ref().map(|(_, files)| files);
+        #[cfg(not(unix))]
+        let signal = None;
+        for files in self
+            .outputs
+            .iter()
+            .chain(peaks)
+            .chain(periodic)
+            .chain(signal)
+        {
+            files.check()?;
+        }
+        // before starting the heap profiler: nothing would stop it if this failed.
+        let cpu = match self.cpu_frequency {
+            Some(frequency) => {
+                let builder = pprof::ProfilerGuardBuilder::default().frequency(frequency);
+                // unwinding through these from a signal handler can deadlock, pprof-rs only knows how to skip them
+                // on 64-bit targets.
+                #[cfg(any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ))]
+                let builder = builder.blocklist(&["libc", "libgcc", "pthread", "vdso"]);
+                Some(builder.build()?)
+            }
+            None => None,
+        };
+        // before the session, which would count the thread's allocations.
+        crate::symbols::clear();
+        #[cfg(unix)]
+        crate::children::clear();
+        let symbolizer = self.background_symbols.and_then(crate::symbols::spawn);
+        Profiler::start(&self);
+        if let Some(path) = self.resume {
+            if let Err(err) = crate::checkpoint::resume(&path) {
+                Profiler::stop();
+                return Err(Error::Checkpoint(path, err));
             }
         }
-
-        ENTERED.with(|entered| {
-            let mut entered_guard = entered.get();
-            if !entered_guard {
-                entered_guard = true;
-                let _reset_on_drop = ResetOnDrop;
-                if Self::enabled() {
-                    BUFFER.with(|buffer| {
-                        let mut buffer = buffer.lock().unwrap();
-                        buffer.track(size);
-
-                        if buffer.should_flush() {
-                            // Flush asynchronously only when needed
-                            let mut passed_buffer: ProfilerBuffer = buffer.clone();
-                            buffer.reset_buffer();
-                            tokio::spawn(async move {
-                                let mut profiler = HEAP_PROFILER_STATE.write().await;
-                                passed_buffer.flush(&mut profiler);
-                            });
-                        }
-                    });
+        let mut watchers = vec![];
+        if let Some((thresholds, callback)) = self.on_growth {
+            watchers.push(alerts::spawn(thresholds, callback));
+        }
+        if let Some((interval, cgroup)) = self.memory_sampling {
+            watchers.push(memory::spawn(interval, cgroup));
+        }
+        if let Some((watermarks, sink)) = self.on_peak {
+            watchers.push(watermark::spawn(watermarks, sink));
+        }
+        if let Some((interval, files)) = self.periodic_dumps {
+            watchers.push(dumps::spawn_periodic(interval, files));
+        }
+        if let Some((interval, callback)) = self.on_interval {
+            watchers.push(dumps::spawn_interval(interval, callback));
+        }
+        if let Some((interval, path)) = self.checkpoints {
+            watchers.push(crate::checkpoint::spawn(interval, path));
+        }
+        #[cfg(feature = "tracing")]
+        if let Some(interval) = self.summary {
+            watchers.push(crate::summary::spawn(interval));
+        }
+        #[cfg(unix)]
+        let signal = match self.signal_dumps {
+            Some((signal, files)) => match dumps::spawn_on_signal(signal, files) {
+                Ok((handler, task)) => {
+                    watchers.push(task);
+                    Some(handler)
                 }
-            }
-        });
+                Err(err) => {
+                    Profiler::stop();
+                    for watcher in &watchers {
+                        watcher.abort();
+                    }
+                    return Err(Error::Signal(signal, err));
+                }
+            },
+            None => None,
+        };
+        #[cfg(unix)]
+        let console = match self.console {
+            Some(path) => match crate::console::spawn(&path) {
+                Ok((socket, task)) => {
+                    watchers.push(task);
+                    Some(socket)
+                }
+                Err(err) => {
+                    Profiler::stop();
+                    for watcher in &watchers {
+                        watcher.abort();
+                    }
+                    return Err(Error::Console(path, err));
+                }
+            },
+            None => None,
+        };
+        #[cfg(unix)]
+        let children = match self.children {
+            Some(path) => match crate::children::spawn(&path, self.period, self.max_depth) {
+                Ok((socket, task)) => {
+                    watchers.push(task);
+                    Some(socket)
+                }
+                Err(err) => {
+                    Profiler::stop();
+                    for watcher in &watchers {
+                        watcher.abort();
+                    }
+                    return Err(Error::Children(path, err));
+                }
+            },
+            None => None,
+        };
+        if let Some(max) = self.warm_up {
+            watchers.push(task::after(max, Profiler::end_warm_up));
+        }
+        let baseline = self
+            .baseline_after
+            .map(|startup| task::after(startup, HeapReport::snapshot));
+        Ok(HeapProfilerGuard {
+            _guard: entered,
+            watchers,
+            cpu,
+            baseline,
+            addresses: self.addresses,
+            outputs: self.outputs,
+            events: self.events,
+            #[cfg(unix)]
+            _signal: signal,
+            #[cfg(unix)]
+            _console: console,
+            #[cfg(unix)]
+            _children: children,
+            #[cfg(unix)]
+            parent: self.parent,
+            _symbolizer: symbolizer,
+        })
     }
+}
 
-    // Called by malloc hooks to record a memory allocation event.
+impl Drop for HeapProfilerGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        let parent = self.parent.is_some();
+        #[cfg(not(unix))]
+        let parent = false;
+        if !self.outputs.is_empty() || self.events.is_some() || parent {
+            self.finish();
+        }
+        Profiler::stop();
+        for watcher in &self.watchers {
+            watcher.abort();
+        }
+        if let Some(baseline) = &self.baseline {
+            baseline.abort();
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct HeapReport {
-    data: HashMap<pprof::Frames, collector::MemProfileRecord>,
-    period: usize,
+#[derive(Clone, Default)]
+pub(crate) struct ProfilerBuffer {
+    allocated_objects: i64,
+    allocated_bytes: i64,
+    requested_bytes: i64,
+    freed_objects: i64,
+    freed_bytes: i64,
+    foreign_freed_objects: i64,
+    foreign_freed_bytes: i64,
+    // the size of the latest allocation, the one sampled, 0 if a free came after it.
+    sampled_bytes: i64,
 }
 
-impl HeapReport {
-    async fn new() -> Self {
-        let mut profiler = HEAP_PROFILER_STATE.write().await;
-        let collector = std::mem::take(&mut profiler.collector);
+impl ProfilerBuffer {
+    pub(crate) fn track(&mut self, size: i64, requested: i64) {
+        self.sampled_bytes = size.max(0);
+        match size.cmp(&0) {
+            std::cmp::Ordering::Greater => {
+                self.allocated_objects = self.allocated_objects.saturating_add(1);
+                self.allocated_bytes = self.allocated_bytes.saturating_add(size);
+                self.requested_bytes = self.requested_bytes.saturating_add(requested);
+            }
+            std::cmp::Ordering::Less => {
+                self.freed_objects = self.freed_objects.saturating_add(1);
+                self.freed_bytes = self.freed_bytes.saturating_add(size.saturating_neg());
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+    }
 
-        let data = collector
-            .into_iter()
-            .map(|(frames, rec)| (frames.into(), rec))
-            .collect();
+    fn track_foreign(&mut self, size: i64) {
+        self.sampled_bytes = 0;
+        self.foreign_freed_objects = self.foreign_freed_objects.saturating_add(1);
+        self.foreign_freed_bytes = self.foreign_freed_bytes.saturating_add(size);
+    }
+
+    // A thread takes a sample every `period` bytes allocated (or freed).
+    pub(crate) fn should_flush(&self, period: i64) -> bool {
+        self.allocated_bytes >= period
+            || self.freed_bytes >= period
+            || self.foreign_freed_bytes >= period
+    }
+
+    pub(crate) fn sampled_bytes(&self) -> i64 {
+        self.sampled_bytes
+    }
+
+    // The counters in the order of the event recordings.
+    pub(crate) fn counters(&self) -> [i64; 7] {
+        [
+            self.allocated_objects,
+            self.allocated_bytes,
+            self.requested_bytes,
+            self.freed_objects,
+            self.freed_bytes,
+            self.foreign_freed_objects,
+            self.foreign_freed_bytes,
+        ]
+    }
+
+    pub(crate) fn from_counters(counters: [i64; 7], sampled_bytes: i64) -> Self {
+        let [objects, bytes, requested, freed_objects, freed_bytes, foreign_objects, foreign_bytes] =
+            counters;
         Self {
-            data,
-            period: profiler.period,
+            allocated_objects: objects,
+            allocated_bytes: bytes,
+            requested_bytes: requested,
+            freed_objects,
+            freed_bytes,
+            foreign_freed_objects: foreign_objects,
+            foreign_freed_bytes: foreign_bytes,
+            sampled_bytes,
         }
     }
 
-    /// flamegraph will write an svg flamegraph into writer.
-    pub fn flamegraph<W>(&self, writer: W)
-    where
-        W: Write,
-    {
-        // the pprof crate already has all the necessary plumbing for the embedded flamegraph library, let's just render
-        // the alloc_bytes stat with it.
-        let data = self
-            .data
-            .iter()
-            .map(|(frames, rec)| (frames.clone(), rec.alloc_bytes))
-            .collect();
+    // The sampled allocation of `other` (the later sample) stands for both.
+    fn merge(&mut self, other: &Self) {
+        let mut counters = self.counters();
+        for (counter, other) in counters.iter_mut().zip(other.counters()) {
+            *counter = counter.saturating_add(other);
+        }
+        let sampled_bytes = match other.sampled_bytes {
+            0 => self.sampled_bytes,
+            sampled_bytes => sampled_bytes,
+        };
+        *self = Self::from_counters(counters, sampled_bytes);
+    }
 
-        let timing = Default::default();
+    pub(crate) fn flush(
+        self,
+        profiler: &mut ProfilerState<MAX_DEPTH>,
+        key: StackKey<MAX_DEPTH>,
+        at: Instant,
+    ) {
+        self.add_totals(profiler);
+        self.flush_stack(profiler, key, at);
+    }
 
-        let report = pprof::Report { data, timing };
+    pub(crate) fn add_totals(&self, profiler: &mut ProfilerState<MAX_DEPTH>) {
+        profiler.allocated_objects = profiler
+            .allocated_objects
+            .saturating_add(self.allocated_objects);
+        profiler.allocated_bytes = profiler
+            .allocated_bytes
+            .saturating_add(self.allocated_bytes);
+        profiler.requested_bytes = profiler
+            .requested_bytes
+            .saturating_add(self.requested_bytes);
+        #[cfg(feature = "measure_free")]
+        {
+            profiler.freed_objects = profiler.freed_objects.saturating_add(self.freed_objects);
+            profiler.freed_bytes = profiler.freed_bytes.saturating_add(self.freed_bytes);
+            profiler.foreign_freed_objects = profiler
+                .foreign_freed_objects
+                .saturating_add(self.foreign_freed_objects);
+            profiler.foreign_freed_bytes = profiler
+                .foreign_freed_bytes
+                .saturating_add(self.foreign_freed_bytes);
+        }
+    }
 
-        let mut options: pprof::flamegraph::Options = Default::default();
+    fn flush_stack(
+        self,
+        profiler: &mut ProfilerState<MAX_DEPTH>,
+        key: StackKey<MAX_DEPTH>,
+        at: Instant,
+    ) {
+        // The whole net change since the previous sample is attributed to the sampled stack.
+        let net_change = self.allocated_bytes - self.freed_bytes;
+        // without measure_free only shrinking reallocs can make it negative, and those aren't recorded.
+        #[cfg(not(feature = "measure_free"))]
+        let net_change = net_change.max(0);
+        // churn (allocated and freed since the previous sample) is still worth recording.
+        if net_change != 0 || self.allocated_objects > 0 {
+            let allocated = collector::Allocations {
+                objects: self.allocated_objects,
+                requested_bytes: self.requested_bytes,
+                granted_bytes: self.allocated_bytes,
+                sizes: collector::AllocationSizes::sampled(self.sampled_bytes, profiler.period),
+            };
+            let step = at.saturating_duration_since(profiler.started).as_nanos()
+                / collector::TIMELINE_RESOLUTION.as_nanos();
+            profiler
+                .collector
+                .record(key, net_change, allocated, step as u32);
+            // an eighth more at a time, rather than one per new stack.
+            if let Some((max, eviction)) = *HEAP_PROFILER_MAX_STACKS.read() {
+                if profiler.collector.len() > max {
+                    let evicted = profiler.collector.evict(max - max / 8, eviction);
+                    HEAP_PROFILER_EVICTED.fetch_add(evicted as u64, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
 
-        options.count_name = "bytes".to_string();
+// The batches the hooks hand over to the collector thread.
+#[derive(Default)]
+struct FlushQueue {
+    batches: std::sync::Mutex<Queued>,
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct Queued {
+    batches: VecDeque<Vec<Sample>>,
+    // the session stopped, the collector only flushes what's left.
+    closed: bool,
+}
+
+impl FlushQueue {
+    // Queues the samples, applying `policy` if the queue is full; gives them back if it couldn't.
+    fn push(&self, samples: Vec<Sample>, policy: Backpressure) -> Result<(), Vec<Sample>> {
+        let mut queued = self.batches.lock().unwrap();
+        let mut dropped = vec![];
+        if queued.closed {
+            return Err(samples);
+        }
+        if queued.batches.len() >= MAX_QUEUED_FLUSHES {
+            backpressure::full();
+            match policy {
+                // it's the hook waiting, which mustn't sleep nor hold the queue the collector pops from.
+                Backpressure::Block => {
+                    std::mem::drop(queued);
+                    let start = Instant::now();
+                    let room = self.spin_for_room();
+                    backpressure::blocked(start.elapsed());
+                    match room {
+                        Some(room) if room.closed => return Err(samples),
+                        Some(room) => queued = room,
+                        None => {
+                            Self::drop_samples(samples);
+                            return Ok(());
+                        }
+                    }
+                }
+                Backpressure::DropNewest => {
+                    std::mem::drop(queued);
+                    Self::drop_samples(samples);
+                    return Ok(());
+                }
+                Backpressure::DropOldest => {
+                    dropped = queued.batches.pop_front().unwrap_or_default()
+                }
+                Backpressure::CoalesceInPlace => return Err(samples),
+            }
+        }
+        queued.batches.push_back(samples);
+        std::mem::drop(queued);
+        self.queued.notify_one();
+        Self::drop_samples(dropped);
+        Ok(())
+    }
+
+    // The queue once it has room or is closed, none if it's still full after a short spin.
+    fn spin_for_room(&self) -> Option<std::sync::MutexGuard<'_, Queued>> {
+        for _ in 0..MAX_BLOCKED_SPINS {
+            std::hint::spin_loop();
+            if let Ok(queued) = self.batches.try_lock() {
+                if queued.closed || queued.batches.len() < MAX_QUEUED_FLUSHES {
+                    return Some(queued);
+                }
+            }
+        }
+        None
+    }
+
+    fn drop_samples(samples: Vec<Sample>) {
+        if !samples.is_empty() {
+            let bytes = samples.iter().map(|s| s.buffer.allocated_bytes).sum();
+            backpressure::dropped(samples.len(), bytes);
+        }
+    }
+
+    // The oldest batch, none once the queue is closed and empty.
+    fn pop(&self) -> Option<Vec<Sample>> {
+        let mut queued = self.batches.lock().unwrap();
+        loop {
+            if let Some(samples) = queued.batches.pop_front() {
+                return Some(samples);
+            }
+            if queued.closed {
+                return None;
+            }
+            queued = self.queued.wait(queued).unwrap();
+        }
+    }
+
+    // The samples queued, in all the batches.
+    fn samples(&self) -> usize {
+        let queued = self.batches.lock().unwrap();
+        queued.batches.iter().map(Vec::len).sum()
+    }
+
+    fn close(&self) {
+        self.batches.lock().unwrap().closed = true;
+        self.queued.notify_all();
+    }
+}
+
+// Flushes the batches of the hooks into the session, in the order they were queued, so that the allocating threads
+// don't wait for the lock. It runs from the start of the session to its stop.
+//
+// There's one, not a shard per CPU: a shard count to tune would come with sharding it.
+//
+// A watchdog thread restarts it when it's gone, and tells when it's stuck, see `watchdog`.
+struct Collector {
+    queue: Arc<FlushQueue>,
+    stop: mpsc::SyncSender<()>,
+    watchdog: std::thread::JoinHandle<()>,
+}
+
+impl Collector {
+    fn spawn() -> Option<Self> {
+        let queue = Arc::new(FlushQueue::default());
+        let heartbeat = Arc::new(Heartbeat::new());
+        let thread = Self::spawn_thread(&queue, &heartbeat)?;
+        let (stop, stopped) = mpsc::sync_channel(1);
+        let watchdog = std::thread::Builder::new()
+            .name("heappy-watchdog".to_string())
+            .spawn({
+                let queue = Arc::clone(&queue);
+                move || {
+                    Profiler::untracked(|| {
+                        watchdog::watch(&heartbeat, thread, stopped, || {
+                            Self::spawn_thread(&queue, &heartbeat)
+                        })
+                    });
+                }
+            });
+        match watchdog {
+            Ok(watchdog) => Some(Self {
+                queue,
+                stop,
+                watchdog,
+            }),
+            Err(_) => {
+                queue.close();
+                None
+            }
+        }
+    }
+
+    // The thread flushing the batches of `queue` until it's closed.
+    fn spawn_thread(
+        queue: &Arc<FlushQueue>,
+        heartbeat: &Arc<Heartbeat>,
+    ) -> Option<std::thread::JoinHandle<()>> {
+        let (queue, heartbeat) = (Arc::clone(queue), Arc::clone(heartbeat));
+        std::thread::Builder::new()
+            .name("heappy-collector".to_string())
+            .spawn(move || {
+                // growing the state would be attributed to whatever sample the thread is flushing.
+                Profiler::untracked(|| {
+                    while let Some(samples) = queue.pop() {
+                        let mut profiler = HEAP_PROFILER_STATE.write().unwrap();
+                        // busy from when it holds the lock: waiting for a report isn't being stuck.
+                        heartbeat.busy();
+                        // unwinding with the lock held would poison it for the reports.
+                        let flushed = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            for sample in samples {
+                                sample.flush(&mut profiler);
+                            }
+                        }));
+                        std::mem::drop(profiler);
+                        if let Err(panic) = flushed {
+                            heartbeat
+                                .record(IncidentKind::Panicked(watchdog::panic_message(&*panic)));
+                        }
+                        heartbeat.idle();
+                        refill_spills();
+                        Profiler::check_budget(|| queue.samples());
+                    }
+                });
+            })
+            .ok()
+    }
+
+    // Flushes what's still queued into the session, and ends the threads.
+    fn stop(self) {
+        self.queue.close();
+        let _ = self.stop.send(());
+        let _ = self.watchdog.join();
+    }
+}
+
+/// When the samples a thread takes are flushed into the session, where the reports, the watchers (alerts, peaks,
+/// memory samples) and the viewer see them. Flushing less often costs the allocating threads less, in exchange for
+/// a staler view of the running session; the reports always flush everything first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushStrategy {
+    /// Each sample as soon as it's taken, i.e. every `period` bytes of the thread.
+    Threshold,
+    /// Batched, once `interval` has passed since the previous flush of the thread: suits steady workloads.
+    Interval(Duration),
+    /// Batched, once the thread holds `samples` samples or `interval` has passed, whichever comes first: bounds
+    /// both the staleness and the batches of bursty workloads.
+    Hybrid { samples: usize, interval: Duration },
+    /// Only when a report or a snapshot is taken, the cheapest: the watchers and the viewer see nothing meanwhile.
+    OnReport,
+}
+
+impl FlushStrategy {
+    fn due(&self, batched: usize, since: Duration) -> bool {
+        match *self {
+            Self::Threshold => true,
+            Self::Interval(interval) => since >= interval,
+            Self::Hybrid { samples, interval } => batched >= samples || since >= interval,
+            Self::OnReport => false,
+        }
+    }
+}
+
+// What a thread has allocated since its previous sample, attributed to the stack of the sample.
+struct Sample {
+    buffer: ProfilerBuffer,
+    key: StackKey<MAX_DEPTH>,
+    at: Instant,
+}
+
+impl Sample {
+    fn flush(self, profiler: &mut ProfilerState<MAX_DEPTH>) {
+        crate::events::record(&self.buffer, &self.key, self.at);
+        crate::subscription::record(&self.buffer, &self.key, self.at, profiler.started);
+        profiler.samples += 1;
+        self.buffer.add_totals(profiler);
+        if let Some((buffer, key)) = crate::flight::record(self.buffer, self.key, self.at) {
+            buffer.flush_stack(profiler, key, self.at);
+        }
+    }
+}
+
+// The allocations of a thread since its previous sample, and the samples it hasn't flushed yet.
+struct ThreadBuffer {
+    buffer: ProfilerBuffer,
+    batch: Vec<Sample>,
+    flushed_at: Instant,
+}
+
+impl ThreadBuffer {
+    fn register() -> Arc<std::sync::Mutex<Self>> {
+        let buffer = Arc::new(std::sync::Mutex::new(Self {
+            buffer: Default::default(),
+            batch: vec![],
+            flushed_at: clock::now(),
+        }));
+        HEAP_PROFILER_THREADS.lock().push(Arc::clone(&buffer));
+        buffer
+    }
+}
+
+// Called by malloc hooks to record a memory allocation event.
+pub struct Profiler;
+
+impl Profiler {
+    /// The stacks the running (or last) session dropped to stay within its
+    /// [`max_stacks`](HeapProfilerGuardBuilder::max_stacks).
+    pub fn evicted_stacks() -> u64 {
+        HEAP_PROFILER_EVICTED.load(Ordering::Relaxed)
+    }
+
+    /// The profiler's own memory by the last estimate of the running (or last) session, in bytes, 0 without a
+    /// [`memory_budget`](HeapProfilerGuardBuilder::memory_budget).
+    pub fn footprint() -> usize {
+        budget::footprint()
+    }
+
+    // Keeps the profiler's memory within the budget of the session, if it's due for an estimate, `queued` telling the
+    // samples queued for the collector thread. Called from the collector thread and from the threads that just
+    // sampled (which covers the sessions without a collector, or flushing on report), with none of the profiler's
+    // locks held. The state's can still be the thread's own, e.g. allocating for a report, so it's only tried, and so
+    // are the buffers of the threads, which can be waiting for the collector thread.
+    fn check_budget(queued: impl FnOnce() -> usize) {
+        if !budget::due() {
+            return;
+        }
+        let Ok(profiler) = HEAP_PROFILER_STATE.try_read() else {
+            return;
+        };
+        let stacks = profiler.footprint();
+        std::mem::drop(profiler);
+        let live = {
+            let live = HEAP_PROFILER_LIVE.lock();
+            live.allocations.capacity() * std::mem::size_of::<(usize, LiveAllocation)>()
+                + live.churn.capacity() * std::mem::size_of::<(StackKey<MAX_DEPTH>, Churn)>()
+                + live.cross_thread.capacity()
+                    * std::mem::size_of::<(StackKey<MAX_DEPTH>, CrossThreadFrees)>()
+        };
+        let queued = queued() * std::mem::size_of::<Sample>();
+        let spills =
+            HEAP_PROFILER_SPILLS.lock().len() * MAX_SPILLED * std::mem::size_of::<StackFrame>();
+        // the batch of a thread busy sampling is left out.
+        let threads: usize = HEAP_PROFILER_THREADS
+            .lock()
+            .iter()
+            .map(|buffer| {
+                let batch = buffer
+                    .try_lock()
+                    .map_or(0, |thread| thread.batch.capacity());
+                std::mem::size_of::<ThreadBuffer>() + batch * std::mem::size_of::<Sample>()
+            })
+            .sum();
+        let footprint = stacks + live + queued + spills + threads;
+        match budget::step(footprint) {
+            Some(budget::Step::Coarsen) => {
+                // done on the next estimate.
+                let Ok(mut profiler) = HEAP_PROFILER_STATE.try_write() else {
+                    return;
+                };
+                budget::coarsened(footprint);
+                profiler.period = profiler.period.saturating_mul(2);
+                HEAP_PROFILER_PERIOD.store(profiler.period, Ordering::Relaxed);
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    footprint,
+                    period = profiler.period,
+                    "the heap profiler is over its memory budget, sampling less often"
+                );
+            }
+            Some(budget::Step::Stop) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    footprint,
+                    "the heap profiler is over its memory budget, not recording anymore"
+                );
+            }
+            None => {}
+        }
+    }
+
+    pub(crate) fn enabled() -> bool {
+        HEAP_PROFILER_ENABLED.load(Ordering::SeqCst)
+    }
+
+    fn set_enabled(value: bool) {
+        HEAP_PROFILER_ENABLED.store(value, Ordering::SeqCst)
+    }
+
+    fn period() -> i64 {
+        HEAP_PROFILER_PERIOD.load(Ordering::Relaxed) as i64
+    }
+
+    fn tracking_live() -> bool {
+        HEAP_PROFILER_TRACK_LIVE.load(Ordering::Relaxed)
+    }
+
+    fn start(config: &HeapProfilerGuardBuilder) {
+        // before the state, which takes its start from it; the previous one is dropped outside of the lock.
+        let selected = config
+            .clock
+            .clone()
+            .unwrap_or_else(|| Arc::new(SystemClock));
+        std::mem::drop(clock::set(selected));
+        let mut profiler = HEAP_PROFILER_STATE.write().unwrap();
+        *profiler = ProfilerState::new(config.period);
+        if config.events.is_some() {
+            crate::events::start(config.period, profiler.started);
+        }
+        crate::flight::start(config.flight_recorder, config.period, profiler.started);
+        std::mem::drop(profiler);
+        watchdog::start();
+        // a session that wasn't stopped (its guard leaked) leaves its collector behind.
+        let collector = (!config.deterministic).then(Collector::spawn).flatten();
+        if let Some(previous) = std::mem::replace(&mut *HEAP_PROFILER_COLLECTOR.write(), collector)
+        {
+            previous.stop();
+        }
+
+        HEAP_PROFILER_PERIOD.store(config.period, Ordering::Relaxed);
+        HEAP_PROFILER_TRACK_LIVE.store(config.track_live, Ordering::Relaxed);
+        HEAP_PROFILER_CALL_SITES.store(config.call_sites_only, Ordering::Relaxed);
+        HEAP_PROFILER_KEY_BY_IP.store(config.key_by_ip, Ordering::Relaxed);
+        HEAP_PROFILER_DEPTH.store(config.max_depth, Ordering::Relaxed);
+        HEAP_PROFILER_SKIP_FRAMES.store(config.skip_frames, Ordering::Relaxed);
+        // loaded here, the hooks can't: they're only needed when the unwinder isn't backtrace-rs.
+        let resolve = config.unwinder.is_some() && crate::executable::load();
+        HEAP_PROFILER_RESOLVE_FRAMES.store(resolve, Ordering::Relaxed);
+        refill_spills();
+        *HEAP_PROFILER_FLUSH_STRATEGY.write() = config.flush_strategy;
+        *HEAP_PROFILER_BACKPRESSURE.write() = config.backpressure;
+        backpressure::reset();
+        stats::reset();
+        *HEAP_PROFILER_MAX_STACKS.write() = config.max_stacks;
+        HEAP_PROFILER_EVICTED.store(0, Ordering::Relaxed);
+        budget::start(config.memory_budget);
+        *HEAP_PROFILER_FRAME_FILTERS.write() = config.frame_filters.clone();
+        HEAP_PROFILER_FILTER_THREADS
+            .store(config.thread_filters.compiled.is_some(), Ordering::Relaxed);
+        *HEAP_PROFILER_THREAD_FILTERS.write() = config.thread_filters.clone();
+        HEAP_PROFILER_ONLY_FOCUSED.store(config.only_focused, Ordering::Relaxed);
+        HEAP_PROFILER_PPROF_TOP.store(config.pprof_top, Ordering::Relaxed);
+        *HEAP_PROFILER_SAMPLE_TYPES.write() = config.sample_types.clone();
+        *HEAP_PROFILER_PALETTE.write() = config.palette;
+        HEAP_PROFILER_SESSION.fetch_add(1, Ordering::Relaxed);
+        *HEAP_PROFILER_BUILD.write() = config.build.clone();
+        // left over from the previous session.
+        let batches = Self::take_batches();
+        std::mem::drop(batches);
+        if config.deterministic {
+            Self::reset_buffers();
+        }
+        let selected = config
+            .unwinder
+            .clone()
+            .unwrap_or_else(|| Arc::new(Backtrace));
+        // the previous one is dropped outside of the lock, which the hooks take.
+        let previous = std::mem::replace(&mut *unwinder::UNWINDER.write(), selected);
+        std::mem::drop(previous);
+        let previous = std::mem::replace(
+            &mut *trace::TRACE_CONTEXT.write(),
+            config.trace_context.clone(),
+        );
+        std::mem::drop(previous);
+        HEAP_PROFILER_WARMING.store(config.warm_up.is_some(), Ordering::SeqCst);
+        let window = config
+            .churn_window
+            .map_or(0, |w| w.as_nanos().max(1) as u64);
+        HEAP_PROFILER_CHURN_WINDOW.store(window, Ordering::Relaxed);
+        let previous = Self::untracked(|| {
+            (
+                std::mem::take(&mut *HEAP_PROFILER_LIVE.lock()),
+                std::mem::take(&mut *HEAP_PROFILER_LARGE.lock()),
+            )
+        });
+        std::mem::drop(previous);
+        HEAP_PROFILER_LARGE_THRESHOLD.store(config.large_threshold.unwrap_or(0), Ordering::Relaxed);
+        #[cfg(feature = "canary")]
+        crate::canary::configure(config.canary_every);
+        if config.foreign_frees && cfg!(feature = "measure_free") {
+            crate::foreign::start();
+        }
+        Self::set_enabled(true);
+    }
+
+    fn stop() {
+        let running = HEAP_PROFILER_ENABLED.swap(false, Ordering::SeqCst);
+        HEAP_PROFILER_WARMING.store(false, Ordering::SeqCst);
+        let collector = HEAP_PROFILER_COLLECTOR.write().take();
+        if let Some(collector) = collector {
+            collector.stop();
+        }
+        if running {
+            // what the threads batched is part of the session, for its recording and subscribers too.
+            Self::flush_batches();
+            crate::events::stop();
+            crate::subscription::stop();
+            crate::flight::stop();
+        }
+        crate::foreign::stop();
+        HEAP_PROFILER_TRACK_LIVE.store(false, Ordering::Relaxed);
+        HEAP_PROFILER_CALL_SITES.store(false, Ordering::Relaxed);
+        HEAP_PROFILER_CHURN_WINDOW.store(0, Ordering::Relaxed);
+        HEAP_PROFILER_LARGE_THRESHOLD.store(0, Ordering::Relaxed);
+        #[cfg(feature = "canary")]
+        crate::canary::configure(0);
+        Self::clear_live();
+    }
+
+    // Starts the session over, if it's still warming up.
+    fn end_warm_up() {
+        let mut profiler = HEAP_PROFILER_STATE.write().unwrap();
+        // checked under the lock: a session stopped meanwhile must keep its state for the report.
+        if !HEAP_PROFILER_WARMING.load(Ordering::SeqCst) {
+            return;
+        }
+        let period = profiler.period;
+        let warm_up = std::mem::replace(&mut *profiler, ProfilerState::new(period));
+        HEAP_PROFILER_WARMING.store(false, Ordering::SeqCst);
+        crate::events::restart(profiler.started);
+        crate::flight::restart(profiler.started);
+        std::mem::drop(profiler);
+        std::mem::drop(warm_up);
+    }
+
+    // The address and size of the sampled allocations not freed yet, by stack.
+    fn live_addresses() -> HashMap<StackKey<MAX_DEPTH>, Vec<(usize, usize)>> {
+        Self::untracked(|| {
+            let mut by_stack: HashMap<_, Vec<_>> = HashMap::new();
+            for (&address, allocation) in &HEAP_PROFILER_LIVE.lock().allocations {
+                by_stack
+                    .entry(allocation.key.clone())
+                    .or_default()
+                    .push((address, allocation.size));
+            }
+            by_stack
+        })
+        .unwrap_or_default()
+    }
+
+    // Forgets the tracked allocations, but keeps what was counted of their frees for the report.
+    fn clear_live() {
+        // dropping the allocations frees memory, which must not happen while holding the lock.
+        let live = Self::untracked(|| std::mem::take(&mut HEAP_PROFILER_LIVE.lock().allocations));
+        std::mem::drop(live);
+    }
+
+    fn churn_window() -> Option<Duration> {
+        match HEAP_PROFILER_CHURN_WINDOW.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    // Runs `f` with the hooks of the current thread ignoring its allocations, or not at all if the thread is already
+    // inside the profiler (i.e. `f` would be tracking the profiler's own allocations).
+    pub(crate) fn untracked<R>(f: impl FnOnce() -> R) -> Option<R> {
+        struct ResetOnDrop;
+
+        impl Drop for ResetOnDrop {
+            fn drop(&mut self) {
+                ENTERED.with(|b| b.set(false));
+            }
+        }
+
+        ENTERED.with(|entered| {
+            if entered.get() {
+                return None;
+            }
+            entered.set(true);
+            let _reset_on_drop = ResetOnDrop;
+            Some(f())
+        })
+    }
+
+    // Called by the free hooks, so the live allocations don't outlive the memory.
+    pub(crate) fn track_freed(ptr: *mut libc::c_void) {
+        if Self::tracking_live() {
+            Self::untracked(|| {
+                let mut live = HEAP_PROFILER_LIVE.lock();
+                let Some(allocation) = live.allocations.remove(&(ptr as usize)) else {
+                    return;
+                };
+                let freed_by = thread_id();
+                let frees = live.cross_thread.entry(allocation.key.clone()).or_default();
+                frees.freed += 1;
+                if freed_by != allocation.thread {
+                    frees.samples += 1;
+                    frees.bytes += allocation.bytes;
+                    *frees
+                        .threads
+                        .entry((allocation.thread, freed_by))
+                        .or_default() += 1;
+                }
+                if Self::churn_window().map_or(false, |window| {
+                    clock::now().saturating_duration_since(allocation.at) <= window
+                }) {
+                    let churn = live.churn.entry(allocation.key).or_default();
+                    churn.samples += 1;
+                    churn.bytes += allocation.bytes;
+                }
+            });
+        }
+    }
+
+    // `ptr` is the allocated (or freed, for negative sizes) memory, `size` what the allocator granted and `requested`
+    // what was asked for. Never inlined: its frame tells where the profiler's own end in the stacks.
+    #[inline(never)]
+    pub(crate) unsafe fn track_allocated(ptr: *mut libc::c_void, size: i64, requested: i64) {
+        Self::untracked(|| {
+            // the tag of the next allocation is this one's, sampled or not.
+            let next_tag = (size > 0).then(crate::tags::take_next).flatten();
+            if Self::recording() {
+                Self::trace_large(size);
+                Self::sample(Some(ptr), size, requested, || {
+                    let mut key = StackKey::capture();
+                    if let Some(tag) = next_tag {
+                        key.labels = key.labels.with(crate::tags::TAG_LABEL, tag);
+                    }
+                    key
+                });
+            }
+        });
+    }
+
+    // `size` bytes acquired (or released, if negative) by the heap source `name`, see HeapSource. Never inlined, like
+    // `track_allocated`.
+    #[inline(never)]
+    pub(crate) fn track_source(name: &str, size: i64) {
+        Self::untracked(|| {
+            if Self::recording() {
+                Self::sample(None, size, size, || {
+                    let mut key = unsafe {
+                        StackKey::capture_below(
+                            Self::track_source as usize,
+                            HEAP_PROFILER_DEPTH.load(Ordering::Relaxed),
+                            HEAP_PROFILER_SKIP_FRAMES.load(Ordering::Relaxed),
+                        )
+                    };
+                    key.labels = key.labels.with(crate::source::SOURCE_LABEL, name);
+                    key
+                });
+            }
+        });
+    }
+
+    // Whether the session records what the current thread allocates right now.
+    fn recording() -> bool {
+        Self::enabled()
+            && !HEAP_PROFILER_WARMING.load(Ordering::Relaxed)
+            && !budget::stopped()
+            && Self::thread_profiled()
+            && (!HEAP_PROFILER_ONLY_FOCUSED.load(Ordering::Relaxed)
+                || crate::instrument::in_focus())
+    }
+
+    // Adds `size` bytes to the buffer of the current thread, and takes a sample of the stack `capture` walks if one is
+    // due. `ptr` is the memory of the hooks', none for the heap sources.
+    fn sample(
+        ptr: Option<*mut libc::c_void>,
+        size: i64,
+        requested: i64,
+        capture: impl FnOnce() -> StackKey<MAX_DEPTH>,
+    ) {
+        thread_local!(static BUFFER: Arc<std::sync::Mutex<ThreadBuffer>> = ThreadBuffer::register());
+
+        // the buffer is gone once the thread is exiting.
+        let sampled = BUFFER.try_with(|buffer| {
+            let mut thread = buffer.lock().unwrap();
+            let buffer = &mut thread.buffer;
+            match ptr {
+                Some(ptr) if size < 0 && crate::foreign::is_foreign(ptr as usize) => {
+                    buffer.track_foreign(-size)
+                }
+                _ => {
+                    if let Some(ptr) = ptr.filter(|_| size > 0) {
+                        crate::foreign::allocated(ptr as usize);
+                    }
+                    buffer.track(size, requested);
+                }
+            }
+
+            if buffer.should_flush(Self::period()) {
+                // the OS' time: a manual clock doesn't tell what sampling costs.
+                let start = Instant::now();
+                let key = capture();
+                let buffer = std::mem::take(buffer);
+                if let Some(ptr) = ptr {
+                    Self::track_live(ptr, size, &buffer, &key);
+                }
+                let now = clock::now();
+                thread.batch.push(Sample {
+                    buffer,
+                    key,
+                    at: now,
+                });
+                let since = now.saturating_duration_since(thread.flushed_at);
+                if HEAP_PROFILER_FLUSH_STRATEGY
+                    .read()
+                    .due(thread.batch.len(), since)
+                {
+                    match Self::flush(std::mem::take(&mut thread.batch)) {
+                        Ok(()) => {
+                            thread.flushed_at = now;
+                            stats::flushed();
+                        }
+                        // try again on the next sample.
+                        Err(batch) => thread.batch = Self::coalesce(batch),
+                    }
+                }
+                stats::sampled(start.elapsed());
+                return true;
+            }
+            false
+        });
+        if sampled == Ok(true) {
+            Self::check_budget(|| {
+                HEAP_PROFILER_COLLECTOR
+                    .read()
+                    .as_ref()
+                    .map_or(0, |collector| collector.queue.samples())
+            });
+        }
+    }
+
+    // Whether the session records the allocations of the current thread, see HeapProfilerGuardBuilder::include_threads.
+    // Found out once per thread and session.
+    fn thread_profiled() -> bool {
+        thread_local!(static PROFILED: Cell<(u64, bool)> = Cell::new((0, true)));
+
+        if !HEAP_PROFILER_FILTER_THREADS.load(Ordering::Relaxed) {
+            return true;
+        }
+        let session = HEAP_PROFILER_SESSION.load(Ordering::Relaxed);
+        let profiled = || HEAP_PROFILER_THREAD_FILTERS.read().profiles(&thread_name());
+        PROFILED
+            .try_with(|cached| match cached.get() {
+                (of, profiled) if of == session => profiled,
+                _ => {
+                    let profiled = profiled();
+                    cached.set((session, profiled));
+                    profiled
+                }
+            })
+            .unwrap_or_else(|_| profiled())
+    }
+
+    // Hands the samples over to the collector thread, or flushes them in place if the collector is behind and the
+    // backpressure policy keeps them. Blocking on the lock could deadlock if this thread is the one holding it, so if
+    // it's busy they're given back.
+    fn flush(samples: Vec<Sample>) -> Result<(), Vec<Sample>> {
+        let collector = HEAP_PROFILER_COLLECTOR
+            .read()
+            .as_ref()
+            .map(|collector| Arc::clone(&collector.queue));
+        let samples = match &collector {
+            Some(collector) => match collector.push(samples, *HEAP_PROFILER_BACKPRESSURE.read()) {
+                Ok(()) => return Ok(()),
+                Err(samples) => samples,
+            },
+            None => samples,
+        };
+        match HEAP_PROFILER_STATE.try_write() {
+            Ok(mut profiler) => {
+                for sample in samples {
+                    sample.flush(&mut profiler);
+                }
+                if collector.is_some() {
+                    backpressure::flushed_in_place();
+                }
+                Ok(())
+            }
+            Err(_) => Err(samples),
+        }
+    }
+
+    // Merges the samples given back to a thread by stack, the latest one standing for the others, so that the batch
+    // doesn't grow with every sample while the session is busy.
+    fn coalesce(batch: Vec<Sample>) -> Vec<Sample> {
+        if *HEAP_PROFILER_BACKPRESSURE.read() != Backpressure::CoalesceInPlace || batch.len() < 2 {
+            return batch;
+        }
+        let before = batch.len();
+        let mut coalesced: Vec<Sample> = Vec::with_capacity(before);
+        let mut stacks: HashMap<StackKey<MAX_DEPTH>, usize> = HashMap::new();
+        for sample in batch {
+            match stacks.get(&sample.key) {
+                Some(&i) => {
+                    coalesced[i].buffer.merge(&sample.buffer);
+                    coalesced[i].at = sample.at;
+                }
+                None => {
+                    stacks.insert(sample.key.clone(), coalesced.len());
+                    coalesced.push(sample);
+                }
+            }
+        }
+        backpressure::coalesced(before - coalesced.len());
+        coalesced
+    }
+
+    // The samples the threads haven't flushed yet, forgetting the threads that are gone.
+    fn take_batches() -> Vec<Sample> {
+        Self::untracked(|| {
+            let mut batches = vec![];
+            HEAP_PROFILER_THREADS.lock().retain(|buffer| {
+                batches.append(&mut buffer.lock().unwrap().batch);
+                Arc::strong_count(buffer) > 1
+            });
+            batches
+        })
+        .unwrap_or_default()
+    }
+
+    // Forgets what the threads allocated since their previous sample, so that they start over.
+    fn reset_buffers() {
+        Self::untracked(|| {
+            for buffer in HEAP_PROFILER_THREADS.lock().iter() {
+                let mut thread = buffer.lock().unwrap();
+                thread.buffer = Default::default();
+                thread.flushed_at = clock::now();
+            }
+        });
+    }
+
+    // Flushes what the threads have batched, before reporting.
+    pub(crate) fn flush_batches() {
+        refill_spills();
+        let batches = Self::take_batches();
+        if batches.is_empty() {
+            return;
+        }
+        Self::untracked(|| {
+            let mut profiler = HEAP_PROFILER_STATE.write().unwrap();
+            for sample in batches {
+                sample.flush(&mut profiler);
+            }
+        });
+    }
+
+    fn trace_large(size: i64) {
+        let threshold = HEAP_PROFILER_LARGE_THRESHOLD.load(Ordering::Relaxed);
+        if threshold == 0 || size < threshold as i64 {
+            return;
+        }
+        let key = unsafe { StackKey::capture() };
+        #[cfg(feature = "tracing")]
+        {
+            let frames: pprof::Frames = key.frames.clone().into();
+            tracing::warn!(
+                size,
+                labels = ?key.labels,
+                stack = %stack_names(&frames),
+                "large allocation"
+            );
+        }
+        let mut large = HEAP_PROFILER_LARGE.lock();
+        if large.len() < MAX_LARGE_ALLOCATIONS {
+            large.push((key, size as usize, clock::system_time()));
+        }
+    }
+
+    // The sampled allocation stands for all the bytes allocated since the previous sample.
+    fn track_live(
+        ptr: *mut libc::c_void,
+        size: i64,
+        buffer: &ProfilerBuffer,
+        key: &StackKey<MAX_DEPTH>,
+    ) {
+        if size > 0 && Self::tracking_live() {
+            let allocation = LiveAllocation {
+                key: key.clone(),
+                bytes: buffer.allocated_bytes,
+                size: size as usize,
+                at: clock::now(),
+                thread: thread_id(),
+            };
+            HEAP_PROFILER_LIVE
+                .lock()
+                .allocations
+                .insert(ptr as usize, allocation);
+        }
+    }
+}
+
+/// Running totals of everything tracked by the current (or last) session, not just the sampled stacks.
+///
+/// Frees are only tracked with the `measure_free` feature, without it the freed counters stay at zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapTotals {
+    pub allocated_objects: i64,
+    pub allocated_bytes: i64,
+    /// What the allocations asked for; `allocated_bytes` is what the allocator granted them.
+    pub requested_bytes: i64,
+    pub freed_objects: i64,
+    pub freed_bytes: i64,
+    /// Frees of memory allocated before the session, not part of `freed_*`, see
+    /// [`HeapProfilerGuardBuilder::separate_foreign_frees`].
+    pub foreign_freed_objects: i64,
+    pub foreign_freed_bytes: i64,
+}
+
+impl HeapTotals {
+    pub(crate) fn add(&mut self, other: &HeapTotals) {
+        let add = |value: &mut i64, other: i64| *value = value.saturating_add(other);
+        add(&mut self.allocated_objects, other.allocated_objects);
+        add(&mut self.allocated_bytes, other.allocated_bytes);
+        add(&mut self.requested_bytes, other.requested_bytes);
+        add(&mut self.freed_objects, other.freed_objects);
+        add(&mut self.freed_bytes, other.freed_bytes);
+        add(&mut self.foreign_freed_objects, other.foreign_freed_objects);
+        add(&mut self.foreign_freed_bytes, other.foreign_freed_bytes);
+    }
+
+    fn subtract(&mut self, baseline: &HeapTotals) {
+        let sub = |value: &mut i64, baseline: i64| *value = value.saturating_sub(baseline).max(0);
+        sub(&mut self.allocated_objects, baseline.allocated_objects);
+        sub(&mut self.allocated_bytes, baseline.allocated_bytes);
+        sub(&mut self.requested_bytes, baseline.requested_bytes);
+        sub(&mut self.freed_objects, baseline.freed_objects);
+        sub(&mut self.freed_bytes, baseline.freed_bytes);
+        sub(
+            &mut self.foreign_freed_objects,
+            baseline.foreign_freed_objects,
+        );
+        sub(&mut self.foreign_freed_bytes, baseline.foreign_freed_bytes);
+    }
+
+    pub fn in_use_bytes(&self) -> i64 {
+        self.allocated_bytes.saturating_sub(self.freed_bytes)
+    }
+
+    pub fn in_use_objects(&self) -> i64 {
+        self.allocated_objects.saturating_sub(self.freed_objects)
+    }
+
+    pub fn fragmentation(&self) -> Fragmentation {
+        Fragmentation {
+            requested_bytes: self.requested_bytes,
+            granted_bytes: self.allocated_bytes,
+        }
+    }
+}
+
+/// How fast a stack allocates, see [`HeapReport::hot_paths`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AllocationRate {
+    pub bytes_per_sec: f64,
+    pub objects_per_sec: f64,
+}
+
+/// Internal fragmentation: the bytes lost to rounding allocations up to the allocator's size classes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    pub requested_bytes: i64,
+    pub granted_bytes: i64,
+}
+
+impl Fragmentation {
+    pub fn wasted_bytes(&self) -> i64 {
+        self.granted_bytes.saturating_sub(self.requested_bytes)
+    }
+
+    /// The share of the granted bytes that wasn't asked for.
+    pub fn ratio(&self) -> f64 {
+        if self.granted_bytes == 0 {
+            return 0.0;
+        }
+        self.wasted_bytes() as f64 / self.granted_bytes as f64
+    }
+}
+
+const TYPE_LABEL: &str = "allocated_type";
+
+/// What the sampled stacks of a group add up to, see [`HeapReport::by_type`], [`HeapReport::by_component`] and
+/// [`HeapReport::by_phase`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GroupTotals {
+    pub bytes: i64,
+    pub objects: i64,
+}
+
+impl GroupTotals {
+    fn add(&mut self, rec: &collector::MemProfileRecord) {
+        self.bytes = self.bytes.saturating_add(rec.alloc_bytes);
+        self.objects = self.objects.saturating_add(rec.alloc_objects);
+    }
+}
+
+/// What a stack allocated in two phases of the same run, see [`HeapReport::compare_phases`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhaseDelta {
+    pub before: GroupTotals,
+    pub after: GroupTotals,
+}
+
+impl PhaseDelta {
+    /// How many more bytes the stack allocated in the later phase, negative if fewer.
+    pub fn bytes(&self) -> i64 {
+        self.after.bytes.saturating_sub(self.before.bytes)
+    }
+
+    /// How many more objects the stack allocated in the later phase, negative if fewer.
+    pub fn objects(&self) -> i64 {
+        self.after.objects.saturating_sub(self.before.objects)
+    }
+}
+
+// The records of the `collector` by their symbolized stacks, leaving the collector as it is.
+fn symbolize_collector(
+    collector: &collector::Collector<StackKey<MAX_DEPTH>>,
+) -> HashMap<(pprof::Frames, Labels), collector::MemProfileRecord> {
+    collector
+        .iter()
+        .map(|(key, rec)| (((&key.frames).into(), key.labels.clone()), rec.clone()))
+        .collect()
+}
+
+// Tops the pool of spill buffers up, when the stacks are deeper than what they keep inline.
+fn refill_spills() {
+    if HEAP_PROFILER_DEPTH.load(Ordering::Relaxed) <= MAX_DEPTH {
+        return;
+    }
+    let missing = SPILL_POOL.saturating_sub(HEAP_PROFILER_SPILLS.lock().len());
+    if missing == 0 {
+        return;
+    }
+    // with room for the buffers the stacks give back, which the hooks can drop.
+    let allocate = || {
+        let spills: Vec<_> = (0..missing)
+            .map(|_| Vec::with_capacity(MAX_SPILLED))
+            .collect();
+        let mut pool = HEAP_PROFILER_SPILLS.lock();
+        let len = pool.len();
+        pool.reserve(SPILL_POOL.saturating_sub(len));
+        pool.extend(spills);
+    };
+    // the pool isn't the session's.
+    Profiler::untracked(allocate).unwrap_or_else(allocate);
+}
+
+fn symbolize_by_stack<T>(
+    by_stack: HashMap<StackKey<MAX_DEPTH>, T>,
+) -> HashMap<(pprof::Frames, Labels), T> {
+    by_stack
+        .into_iter()
+        .map(|(key, value)| ((key.frames.into(), key.labels), value))
+        .collect()
+}
+
+// The kernel id of the current thread on linux and android (as `top -H` shows it) and macOS, cached per thread.
+fn thread_id() -> u64 {
+    thread_local!(static THREAD_ID: std::cell::Cell<u64> = std::cell::Cell::new(0));
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let current = || unsafe { libc::syscall(libc::SYS_gettid) as u64 };
+    #[cfg(target_os = "macos")]
+    let current = || unsafe {
+        let mut id = 0;
+        libc::pthread_threadid_np(0, &mut id);
+        id
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    let current = || unsafe { libc::pthread_self() as u64 };
+    THREAD_ID
+        .try_with(|id| {
+            if id.get() == 0 {
+                id.set(current());
+            }
+            id.get()
+        })
+        .unwrap_or_else(|_| current())
+}
+
+// The name of the current thread as the OS knows it, empty where it can't be told.
+fn thread_name() -> String {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    unsafe {
+        let mut name = [0 as libc::c_char; 64];
+        if libc::pthread_getname_np(libc::pthread_self(), name.as_mut_ptr(), name.len()) == 0 {
+            return std::ffi::CStr::from_ptr(name.as_ptr())
+                .to_string_lossy()
+                .into_owned();
+        }
+    }
+    String::new()
+}
+
+fn symbolize_large(large: Vec<(StackKey<MAX_DEPTH>, usize, SystemTime)>) -> Vec<LargeAllocation> {
+    large
+        .into_iter()
+        .map(|(key, size, at)| LargeAllocation {
+            frames: key.frames.into(),
+            labels: key.labels,
+            size,
+            at,
+        })
+        .collect()
+}
+
+fn symbolize_addresses(
+    by_stack: HashMap<StackKey<MAX_DEPTH>, Vec<(usize, usize)>>,
+) -> Vec<StackAddresses> {
+    let mut stacks: Vec<_> = by_stack
+        .into_iter()
+        .map(|(key, mut allocations)| {
+            allocations.sort_unstable();
+            StackAddresses {
+                frames: key.frames.into(),
+                labels: key.labels,
+                allocations,
+            }
+        })
+        .collect();
+    stacks.sort_by_key(|stack| stack.allocations.first().copied());
+    stacks
+}
+
+// Innermost first, starting at the hook.
+pub(crate) fn stack_names(frames: &pprof::Frames) -> String {
+    let names: Vec<_> = frames
+        .frames
+        .iter()
+        .flatten()
+        .map(|symbol| symbol.name())
+        .collect();
+    // past the hook, or else the innermost heappy frames: the caller may be one too, e.g. testing::assert_no_alloc.
+    let hook = names
+        .iter()
+        .position(|name| crate::callsite::HOOKS.contains(&name.as_str()))
+        .map(|i| i + 1)
+        .unwrap_or_else(|| {
+            let first = names
+                .iter()
+                .position(|name| name.starts_with("heappy::"))
+                .unwrap_or(0);
+            names[first..]
+                .iter()
+                .position(|name| !name.starts_with("heappy::"))
+                .map_or(names.len(), |i| first + i)
+        });
+    names[hook..].join(" <- ")
+}
+
+/// An allocation above the threshold of [`HeapProfilerGuardBuilder::trace_large`].
+#[derive(Clone, Debug)]
+pub struct LargeAllocation {
+    pub frames: pprof::Frames,
+    pub labels: Labels,
+    pub size: usize,
+    pub at: SystemTime,
+}
+
+/// The sampled allocations of a stack that were still allocated when the report was taken, see
+/// [`HeapProfilerGuardBuilder::record_addresses`].
+#[derive(Clone, Debug)]
+pub struct StackAddresses {
+    pub frames: pprof::Frames,
+    pub labels: Labels,
+    /// The address and size of each allocation, by address.
+    pub allocations: Vec<(usize, usize)>,
+}
+
+// A view of the running session, taken without disturbing it.
+#[cfg(feature = "tui")]
+pub(crate) struct LiveSnapshot {
+    pub(crate) enabled: bool,
+    pub(crate) totals: HeapTotals,
+    pub(crate) stacks: usize,
+    pub(crate) top: Vec<(pprof::Frames, Labels, collector::MemProfileRecord)>,
+}
+
+#[cfg(feature = "tui")]
+impl LiveSnapshot {
+    // Symbolizes only the `n` heaviest stacks according to `weight`.
+    pub(crate) fn take(n: usize, weight: fn(&collector::MemProfileRecord) -> i64) -> Self {
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let mut entries: Vec<_> = profiler.collector.iter().collect();
+        entries.sort_by_key(|(_, rec)| std::cmp::Reverse(weight(rec)));
+        let top: Vec<_> = entries
+            .into_iter()
+            .take(n)
+            .map(|(key, rec)| (key.clone(), rec.clone()))
+            .collect();
+        let totals = profiler.totals();
+        let stacks = profiler.collector.len();
+        // symbolization is slow, don't hold up the flushes while doing it.
+        std::mem::drop(profiler);
+
+        Self {
+            enabled: Profiler::enabled(),
+            totals,
+            stacks,
+            top: top
+                .into_iter()
+                .map(|(key, rec)| (key.frames.into(), key.labels, rec))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HeapReport {
+    data: HashMap<(pprof::Frames, Labels), collector::MemProfileRecord>,
+    period: usize,
+    totals: HeapTotals,
+    duration: Duration,
+    started_at: SystemTime,
+    churn: HashMap<(pprof::Frames, Labels), Churn>,
+    cross_thread: HashMap<(pprof::Frames, Labels), CrossThreadFrees>,
+    large: Vec<LargeAllocation>,
+    addresses: Vec<StackAddresses>,
+    memory: Vec<MemorySample>,
+    cpu: Option<crate::protos::Profile>,
+    frame_filters: FrameFilters,
+    build: BuildInfo,
+    sample_types: PprofSampleTypes,
+    // the stacks the pprof profile keeps, all of them if None.
+    pprof_top: Option<usize>,
+    palette: FlamegraphPalette,
+    // the session stopped recording over its memory budget.
+    over_budget: bool,
+    incidents: Vec<CollectorIncident>,
+    // the data is what's still allocated (in the alloc_* fields) rather than the cumulative counters.
+    live: bool,
+}
+
+impl HeapReport {
+    fn new() -> Self {
+        Profiler::flush_batches();
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let (period, totals, duration) = (profiler.period, profiler.totals(), profiler.elapsed());
+        // copied rather than taken, like the stacks, for the reports after this one.
+        let memory = Profiler::untracked(|| profiler.memory.clone()).unwrap_or_default();
+        // a flight recorder only has the stacks of its window. The session's stopped: its stacks are read in place,
+        // and stay for the reports after this one.
+        let (totals, data, duration) = match crate::flight::window() {
+            Some((window, duration)) => (
+                window.totals(),
+                symbolize_collector(&window.collector),
+                duration,
+            ),
+            None => (totals, symbolize_collector(&profiler.collector), duration),
+        };
+        std::mem::drop(profiler);
+        #[cfg(unix)]
+        let (data, totals) = {
+            let (mut data, mut totals) = (data, totals);
+            Profiler::untracked(|| crate::children::add(&mut data, &mut totals));
+            (data, totals)
+        };
+        let (churn, cross_thread, large) = Profiler::untracked(|| {
+            let live = HEAP_PROFILER_LIVE.lock();
+            let (churn, cross_thread) = (live.churn.clone(), live.cross_thread.clone());
+            std::mem::drop(live);
+            (churn, cross_thread, HEAP_PROFILER_LARGE.lock().clone())
+        })
+        .unwrap_or_default();
+
+        Self {
+            data,
+            period,
+            totals,
+            duration,
+            started_at: clock::system_time() - duration,
+            churn: symbolize_by_stack(churn),
+            cross_thread: symbolize_by_stack(cross_thread),
+            large: symbolize_large(large),
+            addresses: vec![],
+            memory,
+            cpu: None,
+            frame_filters: HEAP_PROFILER_FRAME_FILTERS.read().clone(),
+            build: HEAP_PROFILER_BUILD.read().clone(),
+            sample_types: HEAP_PROFILER_SAMPLE_TYPES.read().clone(),
+            pprof_top: pprof_top(),
+            palette: *HEAP_PROFILER_PALETTE.read(),
+            over_budget: budget::stopped(),
+            incidents: watchdog::incidents(),
+            live: false,
+        }
+    }
+
+    // The report of a replayed event recording, whose stacks are told apart by their ids.
+    pub(crate) fn replayed(
+        state: ProfilerState<MAX_DEPTH>,
+        started_at: SystemTime,
+        duration: Duration,
+        frames: impl Fn(usize) -> pprof::Frames,
+    ) -> Self {
+        let totals = state.totals();
+        let data = state
+            .collector
+            .into_iter()
+            .map(|(key, rec)| ((frames(key.replayed_id()), key.labels), rec))
+            .collect();
+        Self {
+            data,
+            period: state.period,
+            totals,
+            duration,
+            started_at,
+            churn: HashMap::new(),
+            cross_thread: HashMap::new(),
+            large: vec![],
+            addresses: vec![],
+            memory: state.memory,
+            cpu: None,
+            frame_filters: Default::default(),
+            build: Default::default(),
+            sample_types: Default::default(),
+            pprof_top: None,
+            palette: Default::default(),
+            over_budget: false,
+            incidents: vec![],
+            live: false,
+        }
+    }
+
+    /// Replays an event recording (see [`HeapProfilerGuardBuilder::record_events`]) through the collector into a
+    /// report, like the one of the recorded session: the same samples, totals, timelines and duration. What isn't
+    /// recorded is left out (the live allocations, churn, large allocations, memory samples and CPU profile), and so
+    /// are the frame filters.
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self> {
+        crate::events::replay(path.as_ref())
+    }
+
+    /// The report of a collector of one's own keys, left as it is to keep recording (and be reported on again), which
+    /// `stack` gives the frames and labels of (keys with the same ones are added up), as if its records were the
+    /// samples of a session sampling every `period` bytes that ran for `duration` until now. The totals are those of
+    /// the records; there's nothing but the stacks.
+    pub fn from_collector<K: Hash + Eq + 'static>(
+        collector: &collector::Collector<K>,
+        period: usize,
+        duration: Duration,
+        stack: impl Fn(&K) -> (pprof::Frames, Labels),
+    ) -> Self {
+        let mut data: HashMap<_, collector::MemProfileRecord> = HashMap::new();
+        let mut totals = HeapTotals::default();
+        for (key, rec) in collector {
+            totals.add(&HeapTotals {
+                allocated_objects: rec.allocated.objects,
+                allocated_bytes: rec.allocated.granted_bytes,
+                requested_bytes: rec.allocated.requested_bytes,
+                #[cfg(feature = "measure_free")]
+                freed_objects: rec.free_objects,
+                #[cfg(feature = "measure_free")]
+                freed_bytes: rec.free_bytes,
+                ..HeapTotals::default()
+            });
+            data.entry(stack(key)).or_default().add(rec);
+        }
+        Self {
+            data,
+            period,
+            totals,
+            duration,
+            started_at: clock::system_time() - duration,
+            churn: HashMap::new(),
+            cross_thread: HashMap::new(),
+            large: vec![],
+            addresses: vec![],
+            memory: vec![],
+            cpu: None,
+            frame_filters: Default::default(),
+            build: Default::default(),
+            sample_types: Default::default(),
+            pprof_top: None,
+            palette: Default::default(),
+            over_budget: false,
+            incidents: vec![],
+            live: false,
+        }
+    }
+
+    fn live() -> Self {
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let (period, totals, duration) = (profiler.period, profiler.totals(), profiler.elapsed());
+        let memory = Profiler::untracked(|| profiler.memory.clone()).unwrap_or_default();
+        std::mem::drop(profiler);
+
+        // the profiler keeps running: building the map under the lock would deadlock this thread's hooks, and the
+        // symbolization caches would show up as live allocations.
+        let (data, churn, cross_thread, large) = Profiler::untracked(|| {
+            let mut live: HashMap<StackKey<MAX_DEPTH>, collector::MemProfileRecord> =
+                HashMap::new();
+            let heap = HEAP_PROFILER_LIVE.lock();
+            for allocation in heap.allocations.values() {
+                let rec = live.entry(allocation.key.clone()).or_default();
+                rec.alloc_bytes = rec.alloc_bytes.saturating_add(allocation.bytes);
+                rec.alloc_objects = rec.alloc_objects.saturating_add(1);
+                rec.allocated
+                    .sizes
+                    .add(&collector::AllocationSizes::sampled(
+                        allocation.size as i64,
+                        period,
+                    ));
+            }
+            let (churn, cross_thread) = (heap.churn.clone(), heap.cross_thread.clone());
+            std::mem::drop(heap);
+            let data = live
+                .into_iter()
+                .map(|(key, rec)| ((key.frames.into(), key.labels), rec))
+                .collect();
+            let large = HEAP_PROFILER_LARGE.lock().clone();
+            (
+                data,
+                symbolize_by_stack(churn),
+                symbolize_by_stack(cross_thread),
+                symbolize_large(large),
+            )
+        })
+        .unwrap_or_default();
+        Self {
+            data,
+            period,
+            totals,
+            duration,
+            started_at: clock::system_time() - duration,
+            churn,
+            cross_thread,
+            large,
+            addresses: vec![],
+            memory,
+            cpu: None,
+            frame_filters: HEAP_PROFILER_FRAME_FILTERS.read().clone(),
+            build: HEAP_PROFILER_BUILD.read().clone(),
+            sample_types: HEAP_PROFILER_SAMPLE_TYPES.read().clone(),
+            pprof_top: pprof_top(),
+            palette: *HEAP_PROFILER_PALETTE.read(),
+            over_budget: budget::stopped(),
+            incidents: watchdog::incidents(),
+            live: true,
+        }
+    }
+
+    // A copy of the running session so far.
+    pub(crate) fn snapshot() -> Self {
+        Profiler::flush_batches();
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let (period, totals, duration) = (profiler.period, profiler.totals(), profiler.elapsed());
+        let (collector, memory) =
+            Profiler::untracked(|| (profiler.collector.clone(), profiler.memory.clone()))
+                .unwrap_or_default();
+        std::mem::drop(profiler);
+        let (totals, collector, duration) = match crate::flight::window() {
+            Some((window, duration)) => (window.totals(), window.collector, duration),
+            None => (totals, collector, duration),
+        };
+
+        // the symbolization caches aren't part of the session.
+        let (data, churn, cross_thread, large) = Profiler::untracked(|| {
+            let data = symbolize_collector(&collector);
+            let live = HEAP_PROFILER_LIVE.lock();
+            let (churn, cross_thread) = (live.churn.clone(), live.cross_thread.clone());
+            std::mem::drop(live);
+            let large = HEAP_PROFILER_LARGE.lock().clone();
+            (
+                data,
+                symbolize_by_stack(churn),
+                symbolize_by_stack(cross_thread),
+                symbolize_large(large),
+            )
+        })
+        .unwrap_or_default();
+        #[cfg(unix)]
+        let (data, totals) = {
+            let (mut data, mut totals) = (data, totals);
+            Profiler::untracked(|| crate::children::add(&mut data, &mut totals));
+            (data, totals)
+        };
+        Self {
+            data,
+            period,
+            totals,
+            duration,
+            started_at: clock::system_time() - duration,
+            churn,
+            cross_thread,
+            large,
+            addresses: vec![],
+            memory,
+            cpu: None,
+            frame_filters: HEAP_PROFILER_FRAME_FILTERS.read().clone(),
+            build: HEAP_PROFILER_BUILD.read().clone(),
+            sample_types: HEAP_PROFILER_SAMPLE_TYPES.read().clone(),
+            pprof_top: pprof_top(),
+            palette: *HEAP_PROFILER_PALETTE.read(),
+            over_budget: budget::stopped(),
+            incidents: watchdog::incidents(),
+            live: false,
+        }
+    }
+
+    /// Totals of everything the session tracked, sampled or not.
+    pub fn totals(&self) -> HeapTotals {
+        self.totals
+    }
+
+    /// What built the profiled process, as the session was told, see [`BuildInfo`](crate::BuildInfo).
+    pub fn build_info(&self) -> &BuildInfo {
+        &self.build
+    }
+
+    /// The report stamped with `build` instead, e.g. the one of a [collector](Self::from_collector) or of a
+    /// [replay](Self::replay).
+    pub fn with_build_info(mut self, build: BuildInfo) -> Self {
+        self.build = build;
+        self
+    }
+
+    /// The report with a pprof profile of only the `n` stacks that allocated the most bytes (the ones in use, for a
+    /// [live report](HeapProfilerGuard::live_report)), the others added up into a sample of its own, at a
+    /// `[other stacks]` frame and with their number as its `other_stacks` label: the profiles of a big service can
+    /// have more stacks than some viewers load. The other formats keep all of them.
+    pub fn pprof_top(mut self, n: usize) -> Self {
+        self.pprof_top = Some(n.max(1));
+        self
+    }
+
+    /// The report with a pprof profile of only the `types` of samples (the ones the report has: a
+    /// [live report](HeapProfilerGuard::live_report) only has `inuse_objects` and `inuse_space`, the others only have
+    /// `free_*` and `inuse_*` with the `measure_free` feature), in their usual order, or all of them if it has none of
+    /// them. The other formats keep all of them.
+    pub fn pprof_sample_types(mut self, types: impl IntoIterator<Item = SampleType>) -> Self {
+        self.sample_types.emitted = Some(types.into_iter().collect());
+        self
+    }
+
+    /// The report with `ty` as the `default_sample_type` of its pprof profile, the one the viewers show first, e.g.
+    /// `inuse_space` for the leaks rather than `alloc_space`, if the profile has it. By default, it's `alloc_space`,
+    /// `inuse_space` for a live report, or the last one the profile has.
+    pub fn default_sample_type(mut self, ty: SampleType) -> Self {
+        self.sample_types.default = Some(ty);
+        self
+    }
+
+    /// The report with its flamegraph colored by `palette`, [`FlamegraphPalette::Memory`] by default:
+    /// [`FlamegraphPalette::ByCrate`] colors each crate's frames alike.
+    pub fn flamegraph_palette(mut self, palette: FlamegraphPalette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Whether the session had stopped recording by the time of the report, over its
+    /// [`memory_budget`](HeapProfilerGuardBuilder::memory_budget): the report misses what was allocated afterwards.
+    /// The pprof profile says so in a comment.
+    pub fn over_budget(&self) -> bool {
+        self.over_budget
+    }
+
+    /// What went wrong with the collector thread of the session by the time of the report (see [`CollectorIncident`]),
+    /// for which the report may miss samples. The pprof profile has them as comments.
+    pub fn collector_incidents(&self) -> &[CollectorIncident] {
+        &self.incidents
+    }
+
+    /// Internal fragmentation of everything the session allocated, see [`HeapTotals::fragmentation`].
+    pub fn fragmentation(&self) -> Fragmentation {
+        self.totals.fragmentation()
+    }
+
+    /// The sizes of the sampled allocations per stack, the largest first: few large allocations and many small ones
+    /// take different fixes.
+    pub fn allocation_sizes(&self) -> Vec<(&pprof::Frames, &Labels, collector::AllocationSizes)> {
+        let mut stacks: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(_, rec)| !rec.allocated.sizes.is_empty())
+            .map(|((frames, labels), rec)| (frames, labels, rec.allocated.sizes))
+            .collect();
+        stacks.sort_by_key(|(_, _, sizes)| std::cmp::Reverse(sizes.max_bytes));
+        stacks
+    }
+
+    /// Internal fragmentation per sampled stack, most wasted bytes first.
+    pub fn fragmentation_by_stack(&self) -> Vec<(&pprof::Frames, &Labels, Fragmentation)> {
+        let mut stacks: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(_, rec)| rec.allocated.granted_bytes > 0)
+            .map(|((frames, labels), rec)| {
+                let fragmentation = Fragmentation {
+                    requested_bytes: rec.allocated.requested_bytes,
+                    granted_bytes: rec.allocated.granted_bytes,
+                };
+                (frames, labels, fragmentation)
+            })
+            .collect();
+        stacks.sort_by_key(|(_, _, f)| std::cmp::Reverse(f.wasted_bytes()));
+        stacks
+    }
+
+    // The records of the stacks.
+    pub(crate) fn records(
+        &self,
+    ) -> impl Iterator<Item = (&pprof::Frames, &Labels, &collector::MemProfileRecord)> {
+        self.data
+            .iter()
+            .map(|((frames, labels), rec)| (frames, labels, rec))
+    }
+
+    /// How long the session ran (or has been running, for a live report).
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// The sampled stacks by allocation rate over the session, highest first.
+    ///
+    /// Unlike the profile, which attributes the net change between samples, this counts everything allocated: code
+    /// paths that allocate and free a lot stand out even if their memory never grows.
+    pub fn hot_paths(&self) -> Vec<(&pprof::Frames, &Labels, AllocationRate)> {
+        let secs = self.duration.as_secs_f64();
+        let mut stacks: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(_, rec)| rec.allocated.objects > 0)
+            .map(|((frames, labels), rec)| {
+                let rate = if secs > 0.0 {
+                    AllocationRate {
+                        bytes_per_sec: rec.allocated.granted_bytes as f64 / secs,
+                        objects_per_sec: rec.allocated.objects as f64 / secs,
+                    }
+                } else {
+                    AllocationRate::default()
+                };
+                (frames, labels, rate)
+            })
+            .collect();
+        stacks.sort_by(|a, b| b.2.bytes_per_sec.total_cmp(&a.2.bytes_per_sec));
+        stacks
+    }
+
+    /// The stacks whose sampled allocations were freed within the
+    /// [churn window](HeapProfilerGuardBuilder::churn_window), most bytes first. Empty without one.
+    pub fn churn(&self) -> Vec<(&pprof::Frames, &Labels, Churn)> {
+        let mut stacks: Vec<_> = self
+            .churn
+            .iter()
+            .map(|((frames, labels), churn)| (frames, labels, *churn))
+            .collect();
+        stacks.sort_by_key(|(_, _, churn)| std::cmp::Reverse(churn.bytes));
+        stacks
+    }
+
+    /// The stacks whose sampled allocations were freed on another thread than the one that allocated them, most bytes
+    /// first: handing memory over between threads contends on the allocator and misses the caches, which matters for
+    /// the stacks where it's routine rather than occasional (compare [`samples`](CrossThreadFrees::samples) with
+    /// [`freed`](CrossThreadFrees::freed)). Empty without [`HeapProfilerGuardBuilder::track_live`].
+    pub fn cross_thread_frees(&self) -> Vec<(&pprof::Frames, &Labels, &CrossThreadFrees)> {
+        let mut stacks: Vec<_> = self
+            .cross_thread
+            .iter()
+            .filter(|(_, frees)| frees.samples > 0)
+            .map(|((frames, labels), frees)| (frames, labels, frees))
+            .collect();
+        stacks.sort_by_key(|(_, _, frees)| std::cmp::Reverse(frees.bytes));
+        stacks
+    }
+
+    /// The allocations above the [large allocation threshold](HeapProfilerGuardBuilder::trace_large), in the order
+    /// they happened. Only the first 1024 are kept.
+    pub fn large_allocations(&self) -> &[LargeAllocation] {
+        &self.large
+    }
+
+    /// Takes out of this report what `baseline` counted, stack by stack and in the totals, e.g. a
+    /// [snapshot](HeapProfilerGuard::snapshot) taken once the program was done initializing, or the report of a run of
+    /// the startup alone. Stacks left with nothing are dropped.
+    pub fn subtract_baseline(&mut self, baseline: &HeapReport) {
+        for (key, rec) in &baseline.data {
+            if let Some(current) = self.data.get_mut(key) {
+                current.subtract(rec);
+                if current.is_empty() {
+                    self.data.remove(key);
+                }
+            }
+        }
+        self.totals.subtract(&baseline.totals);
+    }
+
+    /// The sampled allocations still allocated when the report was taken, by stack, if they were asked for with
+    /// [`HeapProfilerGuardBuilder::record_addresses`].
+    pub fn addresses(&self) -> &[StackAddresses] {
+        &self.addresses
+    }
+
+    /// Writes the [`addresses`](Self::addresses) as text for tooling, one allocation per line: the hex address, the
+    /// size and the stack from the root, `;` separated as in folded stacks.
+    pub fn write_addresses<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let mut allocations: Vec<_> = self
+            .addresses
+            .iter()
+            .flat_map(|stack| {
+                let folded = crate::flamechart::stack(&stack.frames).join(";");
+                stack
+                    .allocations
+                    .iter()
+                    .map(move |&(address, size)| (address, size, folded.clone()))
+            })
+            .collect();
+        allocations.sort_unstable();
+        for (address, size, stack) in allocations {
+            writeln!(writer, "{:#x} {} {}", address, size, stack)?;
+        }
+        Ok(())
+    }
+
+    /// What happened between `earlier` and this report, two [snapshots](HeapProfilerGuard::snapshot) of the same
+    /// session (e.g. "what allocated during the last five minutes"), without stopping it. The counters of a stack
+    /// don't go below zero, so a diff of [live reports](HeapProfilerGuard::live_report) only shows what grew.
+    pub fn diff(&self, earlier: &HeapReport) -> HeapReport {
+        let mut diff = self.clone();
+        diff.since(earlier);
+        diff
+    }
+
+    /// What the running session allocated and freed over the next `duration`: the [`diff`](Self::diff) of a snapshot
+    /// taken now and one taken once `duration` has passed, like the `?seconds=` heap profiles of Go services.
+    #[cfg(feature = "async")]
+    pub async fn delta(duration: Duration) -> HeapReport {
+        let earlier = task::unblock(HeapReport::snapshot).await;
+        task::delay(duration).await;
+        let later = task::unblock(HeapReport::snapshot).await;
+        later.diff(&earlier)
+    }
+
+    /// What the running session allocated and freed over the next `duration`: the [`diff`](Self::diff) of a snapshot
+    /// taken now and one taken once `duration` has passed, like the `?seconds=` heap profiles of Go services.
+    #[cfg(not(feature = "async"))]
+    pub fn delta(duration: Duration) -> HeapReport {
+        let earlier = HeapReport::snapshot();
+        std::thread::sleep(duration);
+        HeapReport::snapshot().diff(&earlier)
+    }
+
+    // Keeps what happened after `earlier`, an earlier report of the same session.
+    fn since(&mut self, earlier: &HeapReport) {
+        self.subtract_baseline(earlier);
+        let sub = |value: &mut i64, earlier: i64| *value = value.saturating_sub(earlier).max(0);
+        for (key, churn) in &earlier.churn {
+            if let Some(current) = self.churn.get_mut(key) {
+                sub(&mut current.samples, churn.samples);
+                sub(&mut current.bytes, churn.bytes);
+            }
+        }
+        self.churn.retain(|_, churn| churn.samples > 0);
+        for (key, frees) in &earlier.cross_thread {
+            if let Some(current) = self.cross_thread.get_mut(key) {
+                sub(&mut current.freed, frees.freed);
+                sub(&mut current.samples, frees.samples);
+                sub(&mut current.bytes, frees.bytes);
+                for (threads, samples) in &frees.threads {
+                    if let Some(current) = current.threads.get_mut(threads) {
+                        sub(current, *samples);
+                    }
+                }
+                current.threads.retain(|_, samples| *samples > 0);
+            }
+        }
+        self.cross_thread.retain(|_, frees| frees.freed > 0);
+
+        let end = earlier.started_at + earlier.duration;
+        self.large.retain(|large| large.at >= end);
+        self.memory
+            .retain(|sample| sample.elapsed >= earlier.duration);
+        for sample in &mut self.memory {
+            sample.elapsed -= earlier.duration;
+        }
+        // the timelines too start with the report, the growth of the step it starts in counting at its start.
+        let offset =
+            (earlier.duration.as_nanos() / collector::TIMELINE_RESOLUTION.as_nanos()) as u32;
+        for rec in self.data.values_mut() {
+            let mut timeline: Vec<(u32, i64)> = Vec::with_capacity(rec.timeline.len());
+            for &(step, bytes) in &rec.timeline {
+                let step = step.saturating_sub(offset);
+                match timeline.last_mut() {
+                    Some((last, total)) if *last == step => *total += bytes,
+                    _ => timeline.push((step, bytes)),
+                }
+            }
+            rec.timeline = timeline;
+        }
+        self.duration = self.duration.saturating_sub(earlier.duration);
+        self.started_at = end;
+    }
+
+    /// The sampled bytes and objects by [allocated type](crate::allocated_type), most bytes first. `None` stands for
+    /// the stacks the type couldn't be told of.
+    pub fn by_type(&self) -> Vec<(Option<String>, GroupTotals)> {
+        let mut types: HashMap<Option<String>, GroupTotals> = HashMap::new();
+        for ((frames, _), rec) in &self.data {
+            let totals = types.entry(crate::allocated_type(frames)).or_default();
+            totals.add(rec);
+        }
+        let mut types: Vec<_> = types.into_iter().collect();
+        types.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        types
+    }
+
+    /// The sampled bytes and objects by [component](crate::Components), most bytes first. `None` stands for the stacks
+    /// without any function attributed to a component.
+    pub fn by_component(&self, components: &Components) -> Vec<(Option<String>, GroupTotals)> {
+        let mut groups: HashMap<Option<String>, GroupTotals> = HashMap::new();
+        for ((frames, _), rec) in &self.data {
+            let names: Vec<_> = frames.frames.iter().flatten().map(|s| s.name()).collect();
+            let component = components.attribute(names.iter().map(String::as_str));
+            let totals = groups.entry(component.map(str::to_owned)).or_default();
+            totals.add(rec);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        groups
+    }
+
+    /// The sampled bytes and objects by [phase](crate::phase()), most bytes first. `None` stands for the stacks sampled
+    /// outside of any phase.
+    pub fn by_phase(&self) -> Vec<(Option<String>, GroupTotals)> {
+        let mut groups: HashMap<Option<String>, GroupTotals> = HashMap::new();
+        for ((_, labels), rec) in &self.data {
+            let phase = labels.get(crate::PHASE_LABEL).map(str::to_owned);
+            let totals = groups.entry(phase).or_default();
+            totals.add(rec);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        groups
+    }
+
+    /// What each stack allocated in the [phase](crate::phase()) `after` compared to the phase `before` of the same run,
+    /// e.g. the second pass of a job over the first, or the requests after a cache warmed up over the ones before, the
+    /// largest changes first. The phases are their full names (`build/load_index` for a nested one), the stacks are
+    /// told apart by their frames alone, adding up their other labels, and the ones of neither phase are left out.
+    pub fn compare_phases(&self, before: &str, after: &str) -> Vec<(&pprof::Frames, PhaseDelta)> {
+        // by the names of the symbols: the frames of the two phases were sampled at different times.
+        let mut stacks: HashMap<Vec<Vec<&[u8]>>, (&pprof::Frames, PhaseDelta)> = HashMap::new();
+        for ((frames, labels), rec) in &self.data {
+            let phase = labels.get(crate::PHASE_LABEL);
+            if phase != Some(before) && phase != Some(after) {
+                continue;
+            }
+            let names = frames
+                .frames
+                .iter()
+                .map(|frame| frame.iter().map(pprof::Symbol::raw_name).collect())
+                .collect();
+            let (_, delta) = stacks
+                .entry(names)
+                .or_insert_with(|| (frames, PhaseDelta::default()));
+            let totals = if phase == Some(before) {
+                &mut delta.before
+            } else {
+                &mut delta.after
+            };
+            totals.add(rec);
+        }
+        let mut stacks: Vec<_> = stacks.into_values().collect();
+        stacks.sort_by_key(|(_, delta)| std::cmp::Reverse(delta.bytes().unsigned_abs()));
+        stacks
+    }
+
+    /// The sampled bytes and objects by [tag](crate::tag_scope), most bytes first. `None` stands for the stacks sampled
+    /// untagged.
+    pub fn by_tag(&self) -> Vec<(Option<String>, GroupTotals)> {
+        let mut groups: HashMap<Option<String>, GroupTotals> = HashMap::new();
+        for ((_, labels), rec) in &self.data {
+            let tag = labels.get(crate::TAG_LABEL).map(str::to_owned);
+            let totals = groups.entry(tag).or_default();
+            totals.add(rec);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        groups
+    }
+
+    /// The sampled bytes and objects by [heap source](crate::HeapSource), most bytes first. `None` stands for the
+    /// stacks of the hooks' allocations.
+    pub fn by_source(&self) -> Vec<(Option<String>, GroupTotals)> {
+        let mut groups: HashMap<Option<String>, GroupTotals> = HashMap::new();
+        for ((_, labels), rec) in &self.data {
+            let source = labels.get(crate::SOURCE_LABEL).map(str::to_owned);
+            let totals = groups.entry(source).or_default();
+            totals.add(rec);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        groups
+    }
+
+    /// The sampled bytes and objects by the executable or shared library the innermost function past the allocator is
+    /// in, most bytes first, to tell which library is allocating in a process mixing Rust with C or C++. `None` stands
+    /// for the stacks that function couldn't be located for.
+    pub fn by_dso(&self) -> Vec<(Option<String>, GroupTotals)> {
+        let mappings = crate::mappings::current().unwrap_or_default();
+        let mut groups: HashMap<Option<String>, GroupTotals> = HashMap::new();
+        for ((frames, _), rec) in &self.data {
+            let dso = crate::callsite::leaf(frames)
+                .and_then(|symbol| symbol.addr)
+                .and_then(|addr| mappings.iter().find(|m| m.contains(addr as u64)))
+                .map(|m| m.path.clone());
+            let totals = groups.entry(dso).or_default();
+            totals.add(rec);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        groups
+    }
+
+    /// The CPU profile of the session, if it was asked for with [`HeapProfilerGuardBuilder::cpu_profile`]. Its time
+    /// and duration are the ones of the session, like for [`pprof`](Self::pprof), so the two line up side by side.
+    pub fn cpu_pprof(&self) -> Option<&crate::protos::Profile> {
+        self.cpu.as_ref()
+    }
+
+    /// The process memory recorded with [`HeapProfilerGuardBuilder::sample_memory`], oldest first.
+    pub fn memory_samples(&self) -> &[MemorySample] {
+        &self.memory
+    }
+
+    /// flamegraph will write an svg flamegraph into writer.
+    pub fn flamegraph<W>(&self, writer: W)
+    where
+        W: Write,
+    {
+        // the pprof crate already has all the necessary plumbing for the embedded flamegraph library, let's just render
+        // the alloc_bytes stat with it.
+        let data = self
+            .data
+            .iter()
+            .fold(HashMap::new(), |mut data, ((frames, _), rec)| {
+                let bytes: &mut i64 = data.entry(self.frame_filters.apply(frames)).or_default();
+                *bytes = bytes.saturating_add(rec.alloc_bytes);
+                data
+            })
+            .into_iter()
+            // the flamegraph library counts in isize, which is 32 bits wide on 32-bit targets.
+            .map(|(frames, bytes)| (frames, isize::try_from(bytes).unwrap_or(isize::MAX)))
+            .collect();
+
+        let timing = Default::default();
+
+        let report = pprof::Report { data, timing };
+
+        let names: Vec<String> = report
+            .data
+            .keys()
+            .flat_map(|frames| frames.frames.iter().flatten())
+            .map(|symbol| symbol.to_string())
+            .collect();
+        let mut palette_map =
+            crate::palette::palette_map(self.palette, names.iter().map(String::as_str));
+
+        let mut options: pprof::flamegraph::Options = Default::default();
+
+        options.count_name = "bytes".to_string();
         options.colors =
             pprof::flamegraph::color::Palette::Basic(pprof::flamegraph::color::BasicPalette::Mem);
+        options.palette_map = palette_map.as_mut();
+
+        report
+            .flamegraph_with_options(writer, &mut options)
+            .unwrap();
+    }
+
+    /// Writes an SVG flame chart of when each stack allocated over the session, with time on the x axis (unlike the
+    /// flamegraph, which merges the whole session). The times are only known to 100ms, and live reports have none.
+    pub fn flame_chart<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let stacks: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(_, rec)| !rec.timeline.is_empty())
+            .map(|((frames, _), rec)| {
+                let stack = flamechart::stack(frames)
+                    .into_iter()
+                    .take_while(|name| !self.frame_filters.drops(name))
+                    .collect();
+                (stack, rec.timeline.clone())
+            })
+            .collect();
+        flamechart::render(&stacks, writer)
+    }
+
+    /// Writes the allocated bytes by stack as folded stacks (`root;..;leaf bytes` lines, sorted by stack), e.g. for
+    /// `inferno` or `flamegraph.pl`, without the dropped frames.
+    pub fn write_folded<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let mut stacks: HashMap<String, i64> = HashMap::new();
+        for ((frames, _), rec) in &self.data {
+            let stack: Vec<_> = flamechart::stack(frames)
+                .into_iter()
+                .take_while(|name| !self.frame_filters.drops(name))
+                .collect();
+            if stack.is_empty() {
+                continue;
+            }
+            let bytes = stacks.entry(stack.join(";")).or_default();
+            *bytes = bytes.saturating_add(rec.alloc_bytes);
+        }
+        let mut stacks: Vec<_> = stacks.into_iter().collect();
+        stacks.sort_unstable();
+        for (stack, bytes) in stacks {
+            writeln!(writer, "{} {}", stack, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the allocations by stack for snapshot tests (e.g. with `insta`), stable across builds, machines and runs:
+    /// a `total bytes objects` line, then `root;..;leaf bytes objects` lines sorted by stack, with the requested bytes
+    /// (which don't depend on the allocator) and the function names without symbol hashes, addresses, directories and
+    /// line numbers. Counts that vary from a run to the next still do: a period of 1 and a
+    /// [deterministic](HeapProfilerGuardBuilder::deterministic) session keep them to what the code allocates.
+    pub fn write_normalized<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let stacks = self.data.iter().map(|((frames, _), rec)| {
+            let stack: Vec<_> = flamechart::stack(frames)
+                .into_iter()
+                .take_while(|name| !self.frame_filters.drops(name))
+                .collect();
+            (stack, rec.allocated.requested_bytes, rec.allocated.objects)
+        });
+        crate::normalize::write(writer, stacks)
+    }
+
+    /// Writes a summary of the report in Markdown, to paste into GitHub issues and incident docs: the totals, the 10
+    /// stacks that allocated the most bytes (the ones in use, for a [live report](HeapProfilerGuard::live_report)),
+    /// each with its frames folded under its leaf function, and what the session was set up with.
+    pub fn write_markdown<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let stacks = self.data.iter().map(|((frames, labels), rec)| {
+            let stack: Vec<_> = flamechart::stack(frames)
+                .into_iter()
+                .take_while(|name| !self.frame_filters.drops(name))
+                .collect();
+            (stack, labels, rec.alloc_bytes, rec.alloc_objects)
+        });
+        let mut settings = vec![
+            ("period (bytes)", self.period.to_string()),
+            (
+                "report",
+                (if self.live { "live" } else { "cumulative" }).to_string(),
+            ),
+        ];
+        if !self.frame_filters.drop.is_empty() {
+            settings.push(("drop_frames", self.frame_filters.drop.join(" ")));
+        }
+        if !self.frame_filters.keep.is_empty() {
+            settings.push(("keep_frames", self.frame_filters.keep.join(" ")));
+        }
+        if let Some(n) = self.pprof_top {
+            settings.push(("pprof_top", n.to_string()));
+        }
+        if self.over_budget {
+            settings.push(("over_budget", "stopped recording".to_string()));
+        }
+        for incident in &self.incidents {
+            settings.push(("collector_incident", incident.to_string()));
+        }
+        settings.extend(
+            self.build
+                .labels()
+                .map(|(key, value)| (key, value.to_string())),
+        );
+        crate::markdown::write(
+            writer,
+            self.totals,
+            self.duration,
+            self.live,
+            stacks,
+            &settings,
+        )
+    }
+
+    /// Writes the report as JSON, for tools without a pprof decoder (e.g. in JavaScript): the `period`,
+    /// `duration_secs`, the `totals` and the `stacks` by allocated bytes, each with its function names from the root,
+    /// its labels, its counters and the sizes of its allocations (`min_size_bytes`, `max_size_bytes` and
+    /// `mean_size_bytes`, see [`collector::AllocationSizes`], `null` without any).
+    pub fn write_json<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let totals = self.totals();
+        write!(
+            writer,
+            concat!(
+                r#"{{"period":{},"duration_secs":{},"totals":{{"allocated_objects":{},"allocated_bytes":{},"#,
+                r#""requested_bytes":{},"freed_objects":{},"freed_bytes":{}}},"stacks":["#
+            ),
+            self.period,
+            self.duration.as_secs_f64(),
+            totals.allocated_objects,
+            totals.allocated_bytes,
+            totals.requested_bytes,
+            totals.freed_objects,
+            totals.freed_bytes,
+        )?;
+        let mut stacks: Vec<_> = self.data.iter().collect();
+        stacks.sort_by_key(|(_, rec)| std::cmp::Reverse(rec.alloc_bytes));
+        for (i, ((frames, labels), rec)) in stacks.into_iter().enumerate() {
+            #[cfg(feature = "measure_free")]
+            let (freed_objects, freed_bytes) = (rec.free_objects, rec.free_bytes);
+            #[cfg(not(feature = "measure_free"))]
+            let (freed_objects, freed_bytes) = (0, 0);
+            let stack: Vec<_> = flamechart::stack(frames)
+                .iter()
+                .map(|name| json_string(name))
+                .collect();
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+                .collect();
+            let sizes = &rec.allocated.sizes;
+            let (min, max, mean) = match sizes.mean_bytes() {
+                Some(mean) => (
+                    sizes.min_bytes.to_string(),
+                    sizes.max_bytes.to_string(),
+                    format!("{:.1}", mean),
+                ),
+                None => ("null".to_string(), "null".to_string(), "null".to_string()),
+            };
+            write!(
+                writer,
+                concat!(
+                    r#"{}{{"stack":[{}],"labels":{{{}}},"allocated_objects":{},"allocated_bytes":{},"#,
+                    r#""freed_objects":{},"freed_bytes":{},"#,
+                    r#""min_size_bytes":{},"max_size_bytes":{},"mean_size_bytes":{}}}"#,
+                ),
+                if i == 0 { "" } else { "," },
+                stack.join(","),
+                labels.join(","),
+                rec.alloc_objects,
+                rec.alloc_bytes,
+                freed_objects,
+                freed_bytes,
+                min,
+                max,
+                mean,
+            )?;
+        }
+        writeln!(writer, "]}}")
+    }
+
+    /// Writes the report as DHAT's `dhat-heap.json`, for Valgrind's `dh_view.html`: the bytes and blocks allocated by
+    /// each stack (what's still allocated, for a [live report](HeapProfilerGuard::live_report)), without the lifetimes
+    /// DHAT measures.
+    pub fn write_dhat<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let stacks: Vec<_> = self
+            .data
+            .iter()
+            .map(|((frames, _), rec)| (self.frame_filters.apply(frames), rec))
+            .collect();
+        crate::dhat::write(
+            writer,
+            self.duration,
+            stacks.iter().map(|(frames, rec)| (frames, *rec)),
+        )
+    }
+
+    /// The report as an Arrow IPC file (Feather v2), one row per stack and its labels, for loading it into a dataframe
+    /// (`polars.read_ipc`, `pandas.read_feather`, DuckDB's `read_arrow`...): the `stack` (function names from the root,
+    /// separated by `;`, without the dropped frames), its `leaf` function, its `labels` (`key=value` pairs separated by
+    /// `,`), the `allocated_bytes`, `allocated_objects`, `requested_bytes`, `freed_bytes` and `freed_objects`, and the
+    /// `first_allocated` and `last_allocated` timestamps, to the 100ms of the timelines.
+    #[cfg(feature = "arrow")]
+    pub fn to_arrow(&self) -> Vec<u8> {
+        use crate::arrow::Column;
+
+        let mut stacks: Vec<_> = self.data.iter().collect();
+        stacks.sort_by_key(|(_, rec)| std::cmp::Reverse(rec.alloc_bytes));
+        let at = |step: u32| {
+            let at = self.started_at + collector::TIMELINE_RESOLUTION * step;
+            let since = at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            i64::try_from(since.as_nanos()).unwrap_or(i64::MAX)
+        };
+        let (mut stack, mut leaf, mut labels) = (vec![], vec![], vec![]);
+        let mut counters: [Vec<i64>; 5] = Default::default();
+        let (mut first, mut last) = (vec![], vec![]);
+        for ((frames, stack_labels), rec) in stacks {
+            let names: Vec<_> = flamechart::stack(frames)
+                .into_iter()
+                .take_while(|name| !self.frame_filters.drops(name))
+                .collect();
+            leaf.push(names.last().cloned().unwrap_or_default());
+            stack.push(names.join(";"));
+            let pairs: Vec<_> = stack_labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            labels.push(pairs.join(","));
+            #[cfg(feature = "measure_free")]
+            let (freed_bytes, freed_objects) = (rec.free_bytes, rec.free_objects);
+            #[cfg(not(feature = "measure_free"))]
+            let (freed_bytes, freed_objects) = (0, 0);
+            let values = [
+                rec.alloc_bytes,
+                rec.alloc_objects,
+                rec.allocated.requested_bytes,
+                freed_bytes,
+                freed_objects,
+            ];
+            for (column, value) in counters.iter_mut().zip(values) {
+                column.push(value);
+            }
+            first.push(at(rec.timeline.first().map_or(0, |(step, _)| *step)));
+            last.push(at(rec.timeline.last().map_or(0, |(step, _)| *step)));
+        }
+        let [allocated_bytes, allocated_objects, requested_bytes, freed_bytes, freed_objects] =
+            counters;
+        crate::arrow::file(&[
+            ("stack", Column::Utf8(stack)),
+            ("leaf", Column::Utf8(leaf)),
+            ("labels", Column::Utf8(labels)),
+            ("allocated_bytes", Column::Int64(allocated_bytes)),
+            ("allocated_objects", Column::Int64(allocated_objects)),
+            ("requested_bytes", Column::Int64(requested_bytes)),
+            ("freed_bytes", Column::Int64(freed_bytes)),
+            ("freed_objects", Column::Int64(freed_objects)),
+            ("first_allocated", Column::Timestamp(first)),
+            ("last_allocated", Column::Timestamp(last)),
+        ])
+    }
+
+    /// Writes [`to_arrow`](Self::to_arrow).
+    #[cfg(feature = "arrow")]
+    pub fn write_arrow<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writer.write_all(&self.to_arrow())
+    }
+
+    /// The report as a Sentry profile of `transaction`, the JSON payload of a `profile` envelope item, for the
+    /// profiling views of Sentry to show the allocations of the transaction next to its spans and errors: it goes in
+    /// the envelope of the transaction (e.g. from a `sentry::Transport` wrapping the SDK's), see
+    /// [`write_sentry_item`](Self::write_sentry_item). The report should cover the transaction, e.g. a
+    /// [`diff`](Self::diff) of the snapshots at its start and end. See [`crate::sentry`] for how the samples are
+    /// laid out.
+    #[cfg(feature = "sentry")]
+    pub fn to_sentry_profile(&self, transaction: &crate::SentryTransaction) -> String {
+        let stacks: Vec<_> = self
+            .data
+            .iter()
+            .map(|((frames, _), rec)| (self.frame_filters.apply(frames), rec))
+            .collect();
+        crate::sentry::profile(
+            transaction,
+            self.started_at,
+            self.period,
+            stacks.iter().map(|(frames, rec)| (frames, *rec)),
+            &self.memory,
+        )
+    }
+
+    /// Writes [`to_sentry_profile`](Self::to_sentry_profile) as an envelope item, its header line then the payload, to
+    /// append to the serialized envelope of the transaction.
+    #[cfg(feature = "sentry")]
+    pub fn write_sentry_item<W: Write>(
+        &self,
+        mut writer: W,
+        transaction: &crate::SentryTransaction,
+    ) -> std::io::Result<()> {
+        writer.write_all(crate::sentry::item(&self.to_sentry_profile(transaction)).as_bytes())
+    }
+
+    fn inner_pprof(&self) -> crate::protos::Profile {
+        use crate::protos;
+
+        // also a label, so that pprof can group by it (e.g. `-tagroot allocated_type`).
+        let types: HashMap<&pprof::Frames, String> = self
+            .data
+            .keys()
+            .filter_map(|(frames, _)| Some((frames, crate::allocated_type(frames)?)))
+            .collect();
+
+        // interned as they come, in the order of the stacks' symbols and labels: the same report makes the same
+        // profile, and every string that ends up in it has an index. The first one must be the empty string.
+        let mut string_table = vec!["".to_owned()];
+        let mut strings = HashMap::new();
+        let mut intern = |s: &str| -> i64 {
+            *strings.entry(s.to_owned()).or_insert_with(|| {
+                string_table.push(s.to_owned());
+                string_table.len() as i64 - 1
+            })
+        };
+        let mut data: Vec<_> = self.data.iter().collect();
+        data.sort_by_cached_key(|((frames, labels), _)| stack_order(frames, labels));
+
+        let mut samples: Vec<protos::Sample> = vec![];
+        // the stacks that symbolize to the same locations and labels (e.g. frames of different addresses resolving
+        // to the same lines, or to nothing) are one sample, which pprof would add up anyway.
+        let mut merged: HashMap<SampleKey, usize> = HashMap::new();
+        let mut locations = Locations::default();
+        for ((key, labels), rec) in data {
+            let locs = locations.stack(key, &mut intern);
+            let label: Vec<protos::Label> = labels
+                .iter()
+                .chain(types.get(key).map(|ty| (TYPE_LABEL, ty.as_str())))
+                .map(|(k, v)| protos::Label {
+                    key: intern(k),
+                    str: intern(v),
+                    ..protos::Label::default()
+                })
+                .collect();
+            let value = if self.live {
+                vec![rec.alloc_objects, rec.alloc_bytes]
+            } else {
+                sample_value(rec)
+            };
+            let label_key = label.iter().map(|l| (l.key, l.str)).collect();
+            match merged.entry((locs.clone(), label_key)) {
+                std::collections::hash_map::Entry::Occupied(idx) => {
+                    for (total, value) in samples[*idx.get()].value.iter_mut().zip(&value) {
+                        *total += value;
+                    }
+                }
+                std::collections::hash_map::Entry::Vacant(idx) => {
+                    idx.insert(samples.len());
+                    samples.push(protos::Sample {
+                        location_id: locs,
+                        label,
+                        value,
+                    });
+                }
+            }
+        }
+        if let Some(n) = self.pprof_top.filter(|n| samples.len() > *n) {
+            // by the bytes, the default sample type whether or not the report's live. The sort is stable: the same
+            // report keeps the same stacks.
+            samples.sort_by_key(|sample| std::cmp::Reverse(sample.value[1]));
+            let others = samples.split_off(n);
+            let mut value = vec![0; others[0].value.len()];
+            for sample in &others {
+                for (total, value) in value.iter_mut().zip(&sample.value) {
+                    *total += value;
+                }
+            }
+            samples.push(protos::Sample {
+                location_id: vec![locations.other(&mut intern)],
+                label: vec![protos::Label {
+                    key: intern(OTHER_STACKS_LABEL),
+                    num: others.len() as i64,
+                    ..protos::Label::default()
+                }],
+                value,
+            });
+        }
+
+        let mut profile = protos::Profile {
+            sample: samples,
+            string_table,
+            period: self.period as i64,
+            function: locations.function,
+            location: locations.location,
+            ..protos::Profile::default()
+        };
+        if self.live {
+            set_live_sample_types(&mut profile);
+        } else {
+            set_sample_types(&mut profile);
+        }
+        self.sample_types.apply(&mut profile);
+        profile
+    }
+
+    /// produce a pprof proto (for use with go tool pprof and compatible visualizers)
+    pub fn pprof(&self) -> crate::protos::Profile {
+        let mut proto = self.inner_pprof();
+
+        let (drop_frames, keep_frames) = self.frame_filters.pprof();
+        proto.drop_frames = proto.string_table.len() as i64;
+        proto.string_table.push(drop_frames);
+        if !keep_frames.is_empty() {
+            proto.keep_frames = proto.string_table.len() as i64;
+            proto.string_table.push(keep_frames);
+        }
+        proto.time_nanos = self
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i64);
+        proto.duration_nanos = self.duration.as_nanos() as i64;
+
+        for incident in &self.incidents {
+            proto.comment.push(proto.string_table.len() as i64);
+            proto.string_table.push(incident.to_string());
+        }
+        if self.over_budget {
+            proto.comment.push(proto.string_table.len() as i64);
+            proto.string_table.push(
+                "over_budget: the session stopped recording over its memory budget".to_string(),
+            );
+        }
+        // there's nowhere else to put a time series, `pprof -comments` shows them.
+        for sample in &self.memory {
+            let optional = |bytes: Option<u64>| bytes.map_or("-".to_string(), |b| b.to_string());
+            proto.comment.push(proto.string_table.len() as i64);
+            proto.string_table.push(format!(
+                "memory elapsed_ms={} rss_bytes={} cgroup_bytes={} heap_bytes={}",
+                sample.elapsed.as_millis(),
+                optional(sample.rss_bytes),
+                optional(sample.cgroup_bytes),
+                sample.heap_bytes
+            ));
+        }
+        self.build.stamp(&mut proto);
+
+        proto
+    }
+
+    pub fn write_pprof<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let buf = crate::protos::encode(&self.pprof());
+        writer.write_all(&buf)
+    }
+
+    /// Writes the report to the file at `path`, replacing it, compressed if its name ends with `.gz` or `.zst` (see
+    /// [`Compression::from_path`]).
+    pub fn write_to(&self, format: ReportFormat, path: &Path) -> std::io::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_compressed(format, Compression::from_path(path), file)?
+            .flush()
+    }
+
+    /// Writes the report in `format` to `writer`, compressed with `compression`, and returns the writer.
+    pub fn write_compressed<W: Write>(
+        &self,
+        format: ReportFormat,
+        compression: Compression,
+        writer: W,
+    ) -> std::io::Result<W> {
+        let mut writer = CompressedWriter::new(writer, compression);
+        match format {
+            ReportFormat::Pprof => self.write_pprof(&mut writer)?,
+            ReportFormat::Flamegraph => self.flamegraph(&mut writer),
+            ReportFormat::FlameChart => self.flame_chart(&mut writer)?,
+            ReportFormat::Json => self.write_json(&mut writer)?,
+            ReportFormat::Folded => self.write_folded(&mut writer)?,
+            ReportFormat::Normalized => self.write_normalized(&mut writer)?,
+            ReportFormat::Dhat => self.write_dhat(&mut writer)?,
+            ReportFormat::Markdown => self.write_markdown(&mut writer)?,
+        }
+        writer.finish()
+    }
+}
+
+/// The formats [`HeapReport::write_to`] and [`HeapReport::write_compressed`] write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// [`HeapReport::write_pprof`]
+    Pprof,
+    /// [`HeapReport::flamegraph`]
+    Flamegraph,
+    /// [`HeapReport::flame_chart`]
+    FlameChart,
+    /// [`HeapReport::write_json`]
+    Json,
+    /// [`HeapReport::write_folded`]
+    Folded,
+    /// [`HeapReport::write_normalized`]
+    Normalized,
+    /// [`HeapReport::write_dhat`]
+    Dhat,
+    /// [`HeapReport::write_markdown`]
+    Markdown,
+}
+
+/// A heap report with raw instruction addresses instead of symbols, see [`HeapProfilerGuard::report_unsymbolized`].
+#[derive(Debug)]
+pub struct UnsymbolizedHeapReport {
+    data: HashMap<(Vec<u64>, Labels), collector::MemProfileRecord>,
+    mappings: Vec<Mapping>,
+    period: usize,
+    totals: HeapTotals,
+    frame_filters: FrameFilters,
+    build: BuildInfo,
+    sample_types: PprofSampleTypes,
+}
+
+impl UnsymbolizedHeapReport {
+    fn new() -> Self {
+        Profiler::flush_batches();
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let period = profiler.period;
+        let totals = profiler.totals();
+        let data = profiler
+            .collector
+            .iter()
+            .map(|(key, rec)| {
+                let addrs = key
+                    .frames
+                    .iter()
+                    .enumerate()
+                    .map(|(i, frame)| {
+                        // all but the innermost frame are return addresses, point back into the call instead.
+                        let ip = frame.ip as u64;
+                        if i == 0 {
+                            ip
+                        } else {
+                            ip.saturating_sub(1)
+                        }
+                    })
+                    .collect();
+                ((addrs, key.labels.clone()), rec.clone())
+            })
+            .collect();
+        std::mem::drop(profiler);
+        Self {
+            data,
+            mappings: mappings::current().unwrap_or_default(),
+            period,
+            totals,
+            frame_filters: HEAP_PROFILER_FRAME_FILTERS.read().clone(),
+            build: HEAP_PROFILER_BUILD.read().clone(),
+            sample_types: HEAP_PROFILER_SAMPLE_TYPES.read().clone(),
+        }
+    }
+
+    pub fn totals(&self) -> HeapTotals {
+        self.totals
+    }
+
+    /// What built the profiled process, as the session was told.
+    pub fn build_info(&self) -> &BuildInfo {
+        &self.build
+    }
+
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// produce a pprof proto with mappings and addresses but no functions.
+    pub fn pprof(&self) -> crate::protos::Profile {
+        use crate::protos;
+
+        let mut string_table = vec!["".to_owned()];
+        let mut strings = HashMap::new();
+        let mut intern = |s: &str| -> i64 {
+            *strings.entry(s.to_owned()).or_insert_with(|| {
+                string_table.push(s.to_owned());
+                string_table.len() as i64 - 1
+            })
+        };
+
+        let mapping: Vec<_> = self
+            .mappings
+            .iter()
+            .enumerate()
+            .map(|(i, m)| protos::Mapping {
+                id: i as u64 + 1,
+                memory_start: m.start,
+                memory_limit: m.limit,
+                file_offset: m.offset,
+                filename: intern(&m.path),
+                build_id: intern(m.build_id.as_deref().unwrap_or_default()),
+                ..protos::Mapping::default()
+            })
+            .collect();
+
+        let mut locations = HashMap::new();
+        let mut location = vec![];
+        let mut samples = vec![];
+        // in the order of the addresses, for the same report to make the same profile.
+        let mut data: Vec<_> = self.data.iter().collect();
+        data.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        for ((addrs, labels), rec) in data {
+            let location_id = addrs
+                .iter()
+                .map(|&address| {
+                    *locations.entry(address).or_insert_with(|| {
+                        let id = location.len() as u64 + 1;
+                        let mapping_id = self
+                            .mappings
+                            .iter()
+                            .position(|m| m.contains(address))
+                            .map_or(0, |i| i as u64 + 1);
+                        location.push(protos::Location {
+                            id,
+                            mapping_id,
+                            address,
+                            ..protos::Location::default()
+                        });
+                        id
+                    })
+                })
+                .collect();
+            let label = labels
+                .iter()
+                .map(|(k, v)| protos::Label {
+                    key: intern(k),
+                    str: intern(v),
+                    ..protos::Label::default()
+                })
+                .collect();
+            samples.push(protos::Sample {
+                location_id,
+                label,
+                value: sample_value(rec),
+            });
+        }
+        let (drop_frames, keep_frames) = self.frame_filters.pprof();
+        let drop_frames = intern(&drop_frames);
+        let keep_frames = intern(&keep_frames);
+
+        let mut profile = protos::Profile {
+            sample: samples,
+            mapping,
+            location,
+            string_table,
+            drop_frames,
+            keep_frames,
+            period: self.period as i64,
+            ..protos::Profile::default()
+        };
+        set_sample_types(&mut profile);
+        self.sample_types.apply(&mut profile);
+        self.build.stamp(&mut profile);
+        profile
+    }
+
+    pub fn write_pprof<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let buf = crate::protos::encode(&self.pprof());
+        writer.write_all(&buf)
+    }
+
+    /// Writes the report as a jemalloc text heap dump (`heap_v2`, see [`jeprof`](crate::jeprof)), for jeprof-based
+    /// tools: `jeprof --text ./service heap.heap`, with its `--inuse_space` (with the `measure_free` feature) and
+    /// `--alloc_space` views. The labels are left out.
+    pub fn write_jeprof<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let stacks = self
+            .data
+            .iter()
+            .map(|((addrs, _), rec)| (addrs.as_slice(), rec));
+        crate::jeprof::write(writer, stacks, &self.mappings)
+    }
+}
+
+// the number of stacks added up into the sample of the others, see HeapReport::pprof_top.
+const OTHER_STACKS_LABEL: &str = "other_stacks";
+
+// The stacks the pprof reports of the session keep.
+fn pprof_top() -> Option<usize> {
+    match HEAP_PROFILER_PPROF_TOP.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+// (location ids, (key, str) of each label)
+type SampleKey = (Vec<u64>, Vec<(i64, i64)>);
+
+// (name, file and line of each symbol of each frame, labels)
+type StackOrder = (Vec<Vec<(Vec<u8>, String, u32)>>, Labels);
+
+// The order of the samples of a stack in the profiles: by the names, files and lines of the symbols of its frames,
+// the innermost first, then by its labels.
+fn stack_order(frames: &pprof::Frames, labels: &Labels) -> StackOrder {
+    let frames = frames
+        .frames
+        .iter()
+        .map(|frame| {
+            frame
+                .iter()
+                .map(|symbol| {
+                    (
+                        symbol.raw_name().to_vec(),
+                        symbol.filename().into_owned(),
+                        symbol.lineno(),
+                    )
+                })
+                .collect()
+        })
+        .collect();
+    (frames, labels.clone())
+}
+
+// The values of a pprof sample, matching the sample types set by `set_sample_types`.
+#[cfg(feature = "measure_free")]
+fn sample_value(rec: &collector::MemProfileRecord) -> Vec<i64> {
+    vec![
+        rec.alloc_objects,
+        rec.alloc_bytes,
+        rec.free_objects,
+        rec.free_bytes,
+        rec.in_use_objects(),
+        rec.in_use_bytes(),
+    ]
+}
+
+#[cfg(not(feature = "measure_free"))]
+fn sample_value(rec: &collector::MemProfileRecord) -> Vec<i64> {
+    vec![rec.alloc_objects, rec.alloc_bytes]
+}
+
+// The functions and locations of symbolized stacks, each once: a location per frame, with a line per function
+// inlined into it, the innermost first, so that pprof tells the inlined functions and their lines apart.
+#[derive(Default)]
+struct Locations {
+    functions: HashMap<(String, String), u64>,
+    locations: HashMap<Vec<(u64, i64)>, u64>,
+    function: Vec<crate::protos::Function>,
+    location: Vec<crate::protos::Location>,
+}
+
+impl Locations {
+    // The ids of the locations of the frames of `stack`, the innermost first, with the ids `intern` gives the strings.
+    fn stack(&mut self, stack: &pprof::Frames, intern: &mut dyn FnMut(&str) -> i64) -> Vec<u64> {
+        use crate::protos;
+
+        let mut ids = vec![];
+        // the frames that didn't resolve to anything have nothing to show.
+        for frame in stack.frames.iter().filter(|frame| !frame.is_empty()) {
+            let line: Vec<_> = frame
+                .iter()
+                .map(|symbol| {
+                    let (name, filename) = (symbol.name(), symbol.filename());
+                    let next = self.function.len() as u64 + 1;
+                    let function_id = *self
+                        .functions
+                        .entry((name, filename.to_string()))
+                        .or_insert_with_key(|(name, filename)| {
+                            self.function.push(protos::Function {
+                                id: next,
+                                name: intern(name),
+                                system_name: intern(&symbol.sys_name()),
+                                filename: intern(filename),
+                                ..protos::Function::default()
+                            });
+                            next
+                        });
+                    protos::Line {
+                        function_id,
+                        line: symbol.lineno() as i64,
+                    }
+                })
+                .collect();
+            let key = line.iter().map(|l| (l.function_id, l.line)).collect();
+            let next = self.location.len() as u64 + 1;
+            ids.push(*self.locations.entry(key).or_insert_with(|| {
+                self.location.push(protos::Location {
+                    id: next,
+                    line,
+                    ..protos::Location::default()
+                });
+                next
+            }));
+        }
+        ids
+    }
+
+    // The id of the location of the stacks left out of a profile, see HeapReport::pprof_top.
+    fn other(&mut self, intern: &mut dyn FnMut(&str) -> i64) -> u64 {
+        use crate::protos;
 
-        report
-            .flamegraph_with_options(writer, &mut options)
-            .unwrap();
+        let next = self.function.len() as u64 + 1;
+        let function_id = *self
+            .functions
+            .entry(("[other stacks]".to_string(), String::new()))
+            .or_insert_with_key(|(name, _)| {
+                self.function.push(protos::Function {
+                    id: next,
+                    name: intern(name),
+                    system_name: intern(name),
+                    ..protos::Function::default()
+                });
+                next
+            });
+        let id = self.location.len() as u64 + 1;
+        self.location.push(protos::Location {
+            id,
+            line: vec![protos::Line {
+                function_id,
+                line: 0,
+            }],
+            ..protos::Location::default()
+        });
+        id
     }
+}
 
-    fn inner_pprof(&self) -> pprof::protos::Profile {
-        use pprof::protos;
-        let data = self.data.clone();
+// What pprof-rs makes of a CPU profile, which it only does with prost.
+#[cfg(not(feature = "prost_codec"))]
+fn cpu_pprof(report: &pprof::Report) -> crate::protos::Profile {
+    use crate::protos;
 
-        let mut dudup_str = HashSet::new();
-        for key in data.iter().map(|(key, _)| key) {
-            for frame in key.frames.iter() {
-                for symbol in frame {
-                    dudup_str.insert(symbol.name());
-                    dudup_str.insert(symbol.sys_name().into_owned());
-                    dudup_str.insert(symbol.filename().into_owned());
-                }
-            }
-        }
-        // string table's first element must be an empty string
-        let mut string_table = vec!["".to_owned()];
-        string_table.extend(dudup_str.into_iter());
+    let mut string_table = vec!["".to_owned()];
+    let mut strings = HashMap::new();
+    let mut intern = |s: &str| -> i64 {
+        *strings.entry(s.to_owned()).or_insert_with(|| {
+            string_table.push(s.to_owned());
+            string_table.len() as i64 - 1
+        })
+    };
+    let mut locations = Locations::default();
+    let mut sample = vec![];
+    let frequency = report.timing.frequency.max(1) as i64;
+    for (frames, &count) in &report.data {
+        let location_id = locations.stack(frames, &mut intern);
+        let thread = protos::Label {
+            key: intern("thread"),
+            str: intern(&frames.thread_name_or_id()),
+            ..protos::Label::default()
+        };
+        sample.push(protos::Sample {
+            location_id,
+            value: vec![count as i64, count as i64 * 1_000_000_000 / frequency],
+            label: vec![thread],
+        });
+    }
+    let samples = protos::ValueType {
+        ty: intern("samples"),
+        unit: intern("count"),
+    };
+    let cpu = protos::ValueType {
+        ty: intern("cpu"),
+        unit: intern("nanoseconds"),
+    };
+    protos::Profile {
+        sample_type: vec![samples, cpu.clone()],
+        sample,
+        location: locations.location,
+        function: locations.function,
+        string_table,
+        time_nanos: report
+            .timing
+            .start_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i64),
+        duration_nanos: report.timing.duration.as_nanos() as i64,
+        period_type: Some(cpu),
+        period: 1_000_000_000 / frequency,
+        ..protos::Profile::default()
+    }
+}
 
-        let mut strings = HashMap::new();
-        for (index, name) in string_table.iter().enumerate() {
-            strings.insert(name.as_str(), index);
-        }
+fn set_sample_types(profile: &mut crate::protos::Profile) {
+    use crate::protos;
 
-        let mut samples = vec![];
-        let mut loc_tbl = vec![];
-        let mut fn_tbl = vec![];
-        let mut functions = HashMap::new();
-        for (key, rec) in data.iter() {
-            let mut locs = vec![];
-            for frame in key.frames.iter() {
-                for symbol in frame {
-                    let name = symbol.name();
-                    if let Some(loc_idx) = functions.get(&name) {
-                        locs.push(*loc_idx);
-                        continue;
-                    }
-                    let sys_name = symbol.sys_name();
-                    let filename = symbol.filename();
-                    let lineno = symbol.lineno();
-                    let function_id = fn_tbl.len() as u64 + 1;
-                    let function = protos::Function {
-                        id: function_id,
-                        name: *strings.get(name.as_str()).unwrap() as i64,
-                        system_name: *strings.get(sys_name.as_ref()).unwrap() as i64,
-                        filename: *strings.get(filename.as_ref()).unwrap() as i64,
-                        ..protos::Function::default()
-                    };
-                    functions.insert(name, function_id);
-                    let line = protos::Line {
-                        function_id,
-                        line: lineno as i64,
-                    };
-                    let loc = protos::Location {
-                        id: function_id,
-                        line: vec![line],
-                        ..protos::Location::default()
-                    };
-                    // the fn_tbl has the same length with loc_tbl
-                    fn_tbl.push(function);
-                    loc_tbl.push(loc);
-                    // current frame locations
-                    locs.push(function_id);
-                }
-            }
-            let sample = protos::Sample {
-                location_id: locs,
-                #[cfg(feature = "measure_free")]
-                value: vec![
-                    rec.alloc_objects as i64,
-                    rec.alloc_bytes as i64,
-                    rec.free_objects as i64,
-                    rec.free_bytes as i64,
-                    rec.in_use_objects() as i64,
-                    rec.in_use_bytes() as i64,
-                ],
-                #[cfg(not(feature = "measure_free"))]
-                value: vec![rec.alloc_objects as i64, rec.alloc_bytes as i64],
-                ..protos::Sample::default()
-            };
-            samples.push(sample);
-        }
+    let string_table = &mut profile.string_table;
+    let mut push_string = |s: &str| {
+        let idx = string_table.len();
+        string_table.push(s.to_string());
+        idx as i64
+    };
 
-        let mut push_string = |s: &str| {
-            let idx = string_table.len();
-            string_table.push(s.to_string());
-            idx as i64
-        };
+    let alloc_objects_idx = push_string("alloc_objects");
+    let count_idx = push_string("count");
+    let alloc_space_idx = push_string("alloc_space");
+    let bytes_idx = push_string("bytes");
+    #[cfg(feature = "measure_free")]
+    let free_objects_idx = push_string("free_objects");
+    #[cfg(feature = "measure_free")]
+    let free_space_idx = push_string("free_space");
+    #[cfg(feature = "measure_free")]
+    let inuse_objects_idx = push_string("inuse_objects");
+    #[cfg(feature = "measure_free")]
+    let inuse_space_idx = push_string("inuse_space");
+    let space_idx = push_string("space");
 
-        let alloc_objects_idx = push_string("alloc_objects");
-        let count_idx = push_string("count");
-        let alloc_space_idx = push_string("alloc_space");
-        let bytes_idx = push_string("bytes");
+    profile.sample_type = vec![
+        protos::ValueType {
+            ty: alloc_objects_idx,
+            unit: count_idx,
+        },
+        protos::ValueType {
+            ty: alloc_space_idx,
+            unit: bytes_idx,
+        },
         #[cfg(feature = "measure_free")]
-        let free_objects_idx = push_string("free_objects");
+        protos::ValueType {
+            ty: free_objects_idx,
+            unit: count_idx,
+        },
         #[cfg(feature = "measure_free")]
-        let free_space_idx = push_string("free_space");
+        protos::ValueType {
+            ty: free_space_idx,
+            unit: bytes_idx,
+        },
         #[cfg(feature = "measure_free")]
-        let inuse_objects_idx = push_string("inuse_objects");
+        protos::ValueType {
+            ty: inuse_objects_idx,
+            unit: count_idx,
+        },
         #[cfg(feature = "measure_free")]
-        let inuse_space_idx = push_string("inuse_space");
-        let space_idx = push_string("space");
+        protos::ValueType {
+            ty: inuse_space_idx,
+            unit: bytes_idx,
+        },
+    ];
+    profile.default_sample_type = alloc_space_idx;
+    profile.period_type = Some(protos::ValueType {
+        ty: space_idx,
+        unit: bytes_idx,
+    });
+}
 
-        let sample_type = vec![
-            protos::ValueType {
-                ty: alloc_objects_idx,
-                unit: count_idx,
-            },
-            protos::ValueType {
-                ty: alloc_space_idx,
-                unit: bytes_idx,
-            },
-            #[cfg(feature = "measure_free")]
-            protos::ValueType {
-                ty: free_objects_idx,
-                unit: count_idx,
-            },
-            #[cfg(feature = "measure_free")]
-            protos::ValueType {
-                ty: free_space_idx,
-                unit: count_idx,
-            },
-            #[cfg(feature = "measure_free")]
-            protos::ValueType {
-                ty: inuse_objects_idx,
-                unit: count_idx,
-            },
-            #[cfg(feature = "measure_free")]
-            protos::ValueType {
-                ty: inuse_space_idx,
-                unit: bytes_idx,
-            },
-        ];
+/// A sample type of the pprof profiles, see [`HeapReport::pprof_sample_types`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SampleType {
+    AllocObjects,
+    AllocSpace,
+    FreeObjects,
+    FreeSpace,
+    InuseObjects,
+    InuseSpace,
+}
 
-        let period_type = Some(pprof::protos::ValueType {
-            ty: space_idx,
-            unit: bytes_idx,
-        });
+impl SampleType {
+    const ALL: [SampleType; 6] = [
+        SampleType::AllocObjects,
+        SampleType::AllocSpace,
+        SampleType::FreeObjects,
+        SampleType::FreeSpace,
+        SampleType::InuseObjects,
+        SampleType::InuseSpace,
+    ];
 
-        protos::Profile {
-            sample_type,
-            default_sample_type: alloc_space_idx,
-            sample: samples,
-            string_table,
-            period_type,
-            period: self.period as i64,
-            function: fn_tbl,
-            location: loc_tbl,
-            ..protos::Profile::default()
+    /// Its name in the profiles, e.g. `inuse_space`.
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleType::AllocObjects => "alloc_objects",
+            SampleType::AllocSpace => "alloc_space",
+            SampleType::FreeObjects => "free_objects",
+            SampleType::FreeSpace => "free_space",
+            SampleType::InuseObjects => "inuse_objects",
+            SampleType::InuseSpace => "inuse_space",
         }
     }
 
-    /// produce a pprof proto (for use with go tool pprof and compatible visualizers)
-    pub fn pprof(&self) -> pprof::protos::Profile {
-        let mut proto = self.inner_pprof();
+    /// The sample type of the `name`, none if there's no such sample type.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|ty| ty.name() == name)
+    }
+}
 
-        let drop_frames_idx = proto.string_table.len();
-        proto
-            .string_table
-            .push(".*::Profiler::track_allocated".to_string());
-        proto.drop_frames = drop_frames_idx as i64;
+// The sample types a pprof profile keeps, all of them if None, and the one it shows first.
+#[derive(Clone, Debug, Default)]
+struct PprofSampleTypes {
+    emitted: Option<Vec<SampleType>>,
+    default: Option<SampleType>,
+}
 
-        proto
+impl PprofSampleTypes {
+    // Drops the values of the sample types `profile` doesn't keep, and sets its default one.
+    fn apply(&self, profile: &mut crate::protos::Profile) {
+        let name = |ty: i64| profile.string_table[ty as usize].as_str();
+        let kept: Vec<bool> = profile
+            .sample_type
+            .iter()
+            .map(|st| match &self.emitted {
+                Some(emitted) => {
+                    SampleType::from_name(name(st.ty)).map_or(false, |ty| emitted.contains(&ty))
+                }
+                None => true,
+            })
+            .collect();
+        let default = self
+            .default
+            .and_then(|ty| {
+                profile
+                    .sample_type
+                    .iter()
+                    .zip(&kept)
+                    .find(|(st, kept)| **kept && name(st.ty) == ty.name())
+            })
+            .map(|(st, _)| st.ty);
+        if kept.contains(&true) && kept.contains(&false) {
+            fn keep<T>(values: &mut Vec<T>, kept: &[bool]) {
+                let mut kept = kept.iter();
+                values.retain(|_| kept.next().map_or(true, |kept| *kept));
+            }
+            keep(&mut profile.sample_type, &kept);
+            for sample in &mut profile.sample {
+                keep(&mut sample.value, &kept);
+            }
+        }
+        let shown = profile
+            .sample_type
+            .iter()
+            .any(|st| st.ty == profile.default_sample_type);
+        match default {
+            Some(ty) => profile.default_sample_type = ty,
+            // pprof's convention when there's no default_sample_type, e.g. inuse_space.
+            None if !shown => {
+                profile.default_sample_type = profile.sample_type.last().map_or(0, |st| st.ty)
+            }
+            None => {}
+        }
     }
+}
 
-    pub fn write_pprof<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        let mut buf = vec![];
-        self.pprof().encode(&mut buf)?;
-        writer.write_all(&buf)
-    }
+// The sample types of a live report, see `HeapProfilerGuard::live_report`.
+fn set_live_sample_types(profile: &mut crate::protos::Profile) {
+    use crate::protos;
+
+    let string_table = &mut profile.string_table;
+    let mut push_string = |s: &str| {
+        let idx = string_table.len();
+        string_table.push(s.to_string());
+        idx as i64
+    };
+
+    let inuse_objects_idx = push_string("inuse_objects");
+    let count_idx = push_string("count");
+    let inuse_space_idx = push_string("inuse_space");
+    let bytes_idx = push_string("bytes");
+    let space_idx = push_string("space");
+
+    profile.sample_type = vec![
+        protos::ValueType {
+            ty: inuse_objects_idx,
+            unit: count_idx,
+        },
+        protos::ValueType {
+            ty: inuse_space_idx,
+            unit: bytes_idx,
+        },
+    ];
+    profile.default_sample_type = inuse_space_idx;
+    profile.period_type = Some(protos::ValueType {
+        ty: space_idx,
+        unit: bytes_idx,
+    });
 }
 
 // Current profiler state, collection of sampled frames.
-struct ProfilerState<const N: usize> {
-    collector: collector::Collector<Frames<N>>,
-    allocated_objects: isize,
-    allocated_bytes: isize,
+pub(crate) struct ProfilerState<const N: usize> {
+    pub(crate) collector: collector::Collector<StackKey<N>>,
+    allocated_objects: i64,
+    allocated_bytes: i64,
+    requested_bytes: i64,
     #[cfg(feature = "measure_free")]
-    freed_objects: isize,
+    freed_objects: i64,
     #[cfg(feature = "measure_free")]
-    freed_bytes: isize,
-    // take a sample when allocated crosses this threshold
-    next_sample: isize,
-    // take a sample when free crosses this threshold
+    freed_bytes: i64,
     #[cfg(feature = "measure_free")]
-    next_free_sample: isize,
+    foreign_freed_objects: i64,
+    #[cfg(feature = "measure_free")]
+    foreign_freed_bytes: i64,
     // take a sample every period bytes.
     period: usize,
+    // the samples flushed into the session.
+    pub(crate) samples: u64,
+    pub(crate) started: Instant,
+    pub(crate) memory: Vec<MemorySample>,
 }
 
 impl<const N: usize> ProfilerState<N> {
-    fn new(period: usize) -> Self {
+    pub(crate) fn new(period: usize) -> Self {
         Self {
             collector: collector::Collector::new(),
             period,
+            samples: 0,
+            started: clock::now(),
+            memory: vec![],
             allocated_objects: 0,
             allocated_bytes: 0,
+            requested_bytes: 0,
             #[cfg(feature = "measure_free")]
             freed_objects: 0,
             #[cfg(feature = "measure_free")]
             freed_bytes: 0,
-            next_sample: period as isize,
             #[cfg(feature = "measure_free")]
-            next_free_sample: period as isize,
+            foreign_freed_objects: 0,
+            #[cfg(feature = "measure_free")]
+            foreign_freed_bytes: 0,
+        }
+    }
+
+    pub(crate) fn totals(&self) -> HeapTotals {
+        #[allow(unused_mut)]
+        let mut totals = HeapTotals {
+            allocated_objects: self.allocated_objects,
+            allocated_bytes: self.allocated_bytes,
+            requested_bytes: self.requested_bytes,
+            ..Default::default()
+        };
+        #[cfg(feature = "measure_free")]
+        {
+            totals.freed_objects = self.freed_objects;
+            totals.freed_bytes = self.freed_bytes;
+            totals.foreign_freed_objects = self.foreign_freed_objects;
+            totals.foreign_freed_bytes = self.foreign_freed_bytes;
         }
+        totals
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        clock::now().saturating_duration_since(self.started)
+    }
+
+    // The memory the records of the stacks take, roughly.
+    pub(crate) fn footprint(&self) -> usize {
+        self.collector
+            .footprint(|key| key.frames.spilled.capacity() * std::mem::size_of::<StackFrame>())
+    }
+
+    pub(crate) fn period(&self) -> usize {
+        self.period
     }
 }
 
@@ -454,107 +4308,389 @@ impl<const N: usize> Default for ProfilerState<N> {
     }
 }
 
-struct Frames<const N: usize> {
-    frames: [MaybeUninit<Frame>; N],
+#[derive(Default)]
+struct LiveHeap {
+    // sampled allocations that haven't been freed yet, by address.
+    allocations: HashMap<usize, LiveAllocation>,
+    // sampled allocations freed within the churn window.
+    churn: HashMap<StackKey<MAX_DEPTH>, Churn>,
+    cross_thread: HashMap<StackKey<MAX_DEPTH>, CrossThreadFrees>,
+}
+
+struct LiveAllocation {
+    key: StackKey<MAX_DEPTH>,
+    // the bytes the sample stands for, `size` is the one of the allocation itself.
+    bytes: i64,
     size: usize,
-    ts: SystemTime,
+    at: Instant,
+    thread: u64,
+}
+
+/// Sampled allocations freed shortly after being allocated, see [`HeapProfilerGuardBuilder::churn_window`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Churn {
+    pub samples: i64,
+    /// The bytes the samples stand for.
+    pub bytes: i64,
+}
+
+/// Sampled allocations freed on another thread than the one that allocated them, see
+/// [`HeapReport::cross_thread_frees`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CrossThreadFrees {
+    /// All the sampled allocations of the stack that were freed, on any thread.
+    pub freed: i64,
+    pub samples: i64,
+    /// The bytes the samples stand for.
+    pub bytes: i64,
+    /// The samples by allocating and freeing thread, as kernel thread ids on linux.
+    pub threads: HashMap<(u64, u64), i64>,
+}
+
+// A sampled stack along with the labels that were in scope when it was captured.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct StackKey<const N: usize> {
+    pub(crate) frames: Frames<N>,
+    pub(crate) labels: Labels,
 }
 
-impl<const N: usize> Clone for Frames<N> {
-    fn clone(&self) -> Self {
-        let mut n = Self::new();
-        for i in 0..self.size {
-            n.frames[i].write(unsafe { self.frames[i].assume_init_ref().clone() });
+impl<const N: usize> StackKey<N> {
+    // A stack of a recording being replayed, told apart by its `id` alone.
+    pub(crate) fn replayed(id: usize, labels: Labels) -> Self {
+        let mut frames = Frames::new();
+        frames.push(&StackFrame {
+            ip: id,
+            function: id,
+        });
+        Self { frames, labels }
+    }
+
+    // The id of a replayed stack.
+    pub(crate) fn replayed_id(&self) -> usize {
+        self.frames.frames[0].ip
+    }
+
+    // A stack of `frames`, the innermost first, as many as fit.
+    pub(crate) fn from_frames(frames: &[StackFrame], labels: Labels) -> Self {
+        let mut stack = Frames::new();
+        if frames.len() > N {
+            stack.spilled = Vec::with_capacity(MAX_SPILLED);
+        }
+        for frame in frames.iter().take(N + MAX_SPILLED) {
+            stack.push(frame);
+        }
+        Self {
+            frames: stack,
+            labels,
+        }
+    }
+
+    pub(crate) fn frames(&self) -> impl Iterator<Item = &StackFrame> {
+        self.frames.iter()
+    }
+
+    // The stack and the labels only make sense on the allocating thread, so this must be called from the hook.
+    pub(crate) unsafe fn capture() -> Self {
+        #[cfg(feature = "enable_heap_profiler")]
+        if HEAP_PROFILER_CALL_SITES.load(Ordering::Relaxed) {
+            let mut frames = Frames::new();
+            // only `backtrace-rs` tells where the functions start, to find the hooks.
+            let mut walk = crate::callsite::Walk::Profiler;
+            let by_ip = HEAP_PROFILER_KEY_BY_IP.load(Ordering::Relaxed);
+            backtrace::trace_unsynchronized(|frame| {
+                if !walk.call_site(frame) {
+                    return true;
+                }
+                frames.push(&StackFrame::from(frame).keyed(by_ip));
+                false
+            });
+            return Self {
+                frames,
+                labels: crate::tags::labels(trace::labels(Labels::try_current())),
+            };
+        }
+        Self::capture_below(
+            Profiler::track_allocated as usize,
+            HEAP_PROFILER_DEPTH.load(Ordering::Relaxed),
+            HEAP_PROFILER_SKIP_FRAMES.load(Ordering::Relaxed),
+        )
+    }
+
+    // The stack of what called into the profiler, `depth` frames past the first `skip`: the frames up to the caller
+    // of the never inlined function `track` (or up to a hook) are the profiler's own.
+    pub(crate) unsafe fn capture_below(track: usize, depth: usize, skip: usize) -> Self {
+        let mut frames = Frames::new();
+        let resolve = HEAP_PROFILER_RESOLVE_FRAMES.load(Ordering::Relaxed);
+        let by_ip = HEAP_PROFILER_KEY_BY_IP.load(Ordering::Relaxed);
+        #[cfg(feature = "enable_heap_profiler")]
+        let hooks = crate::hook::entry_points();
+        #[cfg(not(feature = "enable_heap_profiler"))]
+        let hooks: [usize; 0] = [];
+        let (mut looked, mut caller, mut skipped) = (0, false, 0);
+        // the profiler's own frames and the skipped ones come on top of the depth.
+        let max = depth.saturating_add(MAX_OWN_FRAMES).saturating_add(skip);
+        unwinder::UNWINDER.read().trace(max, &mut |frame| {
+            if looked < MAX_OWN_FRAMES {
+                looked += 1;
+                let function = crate::callsite::function(frame, resolve);
+                if caller || (function != 0 && hooks.contains(&function)) {
+                    // everything so far is the profiler's: up to the hook, or what called `track_allocated`.
+                    frames.clear();
+                    (looked, skipped) = (MAX_OWN_FRAMES, 0);
+                    return true;
+                }
+                caller = function != 0 && function == track;
+            }
+            if skipped < skip {
+                skipped += 1;
+                return true;
+            }
+            // while looking for the profiler's frames, the unwinding goes on past the depth.
+            let room = frames.size < depth && frames.push(&frame.keyed(by_ip));
+            (room && frames.size < depth) || looked < MAX_OWN_FRAMES
+        });
+        Self {
+            frames,
+            labels: crate::tags::labels(trace::labels(Labels::try_current())),
         }
-        n.size = self.size;
-        n.ts = self.ts;
-        n
     }
 }
 
+#[derive(Clone)]
+pub(crate) struct Frames<const N: usize> {
+    frames: [StackFrame; N],
+    // the frames past the N-th, in a buffer of the pool: pushing within its capacity doesn't allocate.
+    spilled: Vec<StackFrame>,
+    size: usize,
+    ts: SystemTime,
+}
+
 impl<const N: usize> Frames<N> {
     fn new() -> Self {
         Self {
-            frames: std::array::from_fn(|_| MaybeUninit::uninit()),
+            frames: [StackFrame::default(); N],
+            spilled: Vec::new(),
             size: 0,
-            ts: SystemTime::now(),
+            ts: clock::system_time(),
         }
     }
 
-    /// Push will push up to N frames in the frames array.
-    fn push(&mut self, frame: &Frame) -> bool {
-        assert!(self.size < N);
-        self.frames[self.size].write(frame.clone());
+    /// Push will push up to N frames in the frames array, and the ones past them in a spill buffer as long as the
+    /// pool has one; whether there's room for more.
+    fn push(&mut self, frame: &StackFrame) -> bool {
+        if self.size < N {
+            self.frames[self.size] = *frame;
+        } else {
+            if self.spilled.capacity() == 0 {
+                self.spilled = HEAP_PROFILER_SPILLS.lock().pop().unwrap_or_default();
+            }
+            if self.spilled.len() == self.spilled.capacity() {
+                return false;
+            }
+            self.spilled.push(*frame);
+        }
         self.size += 1;
-        self.size < N
+        self.size < N + MAX_SPILLED
+    }
+
+    // Forgets the frames, keeping the spill buffer.
+    fn clear(&mut self) {
+        self.spilled.clear();
+        self.size = 0;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &StackFrame> {
+        self.frames[..self.size.min(N)]
+            .iter()
+            .chain(self.spilled.iter())
     }
+}
 
-    fn iter(&self) -> FramesIterator<N> {
-        FramesIterator(self, 0)
+impl<const N: usize> Drop for Frames<N> {
+    fn drop(&mut self) {
+        // back to the pool if it has room for it, the hooks drop stacks too; not the copies, which only have room for
+        // their own frames.
+        if self.spilled.capacity() < MAX_SPILLED {
+            return;
+        }
+        let mut buffer = std::mem::take(&mut self.spilled);
+        buffer.clear();
+        let mut pool = HEAP_PROFILER_SPILLS.lock();
+        if pool.len() < pool.capacity() {
+            pool.push(buffer);
+        } else {
+            std::mem::drop(pool);
+            std::mem::drop(buffer);
+        }
     }
 }
 
 impl<const N: usize> Hash for Frames<N> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.iter()
-            .for_each(|frame| frame.symbol_address().hash(state));
+        self.iter().for_each(|frame| frame.key().hash(state));
     }
 }
 
 impl<const N: usize> PartialEq for Frames<N> {
     fn eq(&self, other: &Self) -> bool {
         Iterator::zip(self.iter(), other.iter())
-            .map(|(s1, s2)| s1.symbol_address() == s2.symbol_address())
+            .map(|(s1, s2)| s1.key() == s2.key())
             .all(|equal| equal)
     }
 }
 
 impl<const N: usize> Eq for Frames<N> {}
 
-struct FramesIterator<'a, const N: usize>(&'a Frames<N>, usize);
+impl<const N: usize> From<Frames<N>> for pprof::Frames {
+    fn from(bt: Frames<N>) -> Self {
+        (&bt).into()
+    }
+}
 
-impl<'a, const N: usize> Iterator for FramesIterator<'a, N> {
-    type Item = &'a Frame;
+impl<const N: usize> From<&Frames<N>> for pprof::Frames {
+    fn from(bt: &Frames<N>) -> Self {
+        let frames = bt.iter().map(crate::symbols::symbols).collect();
+        Self {
+            frames,
+            thread_name: "".to_string(),
+            thread_id: 0,
+            sample_timestamp: bt.ts,
+        }
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.1 < self.0.size {
-            let res = Some(unsafe { self.0.frames[self.1].assume_init_ref() });
-            self.1 += 1;
-            res
-        } else {
-            None
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
     }
+    out.push('"');
+    out
 }
 
-impl<const N: usize> From<Frames<N>> for pprof::Frames {
-    fn from(bt: Frames<N>) -> Self {
-        let frames = bt
+// The frames to leave out of the reports besides the profiler's own, see HeapProfilerGuardBuilder::drop_frames.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FrameFilters {
+    drop: Vec<String>,
+    keep: Vec<String>,
+    // the compiled drop and keep regexes, none without drop regexes.
+    compiled: Option<(Regex, Option<Regex>)>,
+}
+
+impl FrameFilters {
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        if self.drop.is_empty() {
+            return Ok(());
+        }
+        let drop = Regex::new(&format!("^(?:{})$", Self::any(&self.drop)))?;
+        let keep = (!self.keep.is_empty())
+            .then(|| Regex::new(&format!("^(?:{})$", Self::any(&self.keep))))
+            .transpose()?;
+        self.compiled = Some((drop, keep));
+        Ok(())
+    }
+
+    fn any(regexes: &[String]) -> String {
+        regexes
             .iter()
-            .map(|frame| {
-                let mut symbols = Vec::new();
-                backtrace::resolve_frame(frame, |symbol| {
-                    if let Some(name) = symbol.name() {
-                        let name = format!("{:#}", name);
-                        if !name.starts_with("alloc::alloc::")
-                            && name != "<alloc::alloc::Global as core::alloc::Allocator>::allocate"
-                        {
-                            symbols.push(symbol.into());
-                        }
+            .map(|re| format!("(?:{})", re))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    fn drops(&self, name: &str) -> bool {
+        self.compiled.as_ref().map_or(false, |(drop, keep)| {
+            drop.is_match(name) && !keep.as_ref().map_or(false, |keep| keep.is_match(name))
+        })
+    }
+
+    // The stack without the dropped frames and what they call, like pprof shows it.
+    fn apply(&self, frames: &pprof::Frames) -> pprof::Frames {
+        let mut frames = frames.clone();
+        if self.compiled.is_none() {
+            return frames;
+        }
+        // from the root: the frames are innermost first, and so are the functions inlined into each one.
+        let mut kept = vec![];
+        'frames: for frame in frames.frames.iter().rev() {
+            let mut symbols = vec![];
+            for symbol in frame.iter().rev() {
+                if self.drops(&symbol.name()) {
+                    if !symbols.is_empty() {
+                        kept.push(symbols);
                     }
-                });
+                    break 'frames;
+                }
+                symbols.push(symbol.clone());
+            }
+            kept.push(symbols);
+        }
+        frames.frames = kept
+            .into_iter()
+            .rev()
+            .map(|mut symbols| {
+                symbols.reverse();
                 symbols
             })
             .collect();
-        Self {
-            frames,
-            thread_name: "".to_string(),
-            thread_id: 0,
-            sample_timestamp: bt.ts,
+        frames
+    }
+
+    // pprof's drop_frames and keep_frames: each one a regex that has to match the whole name.
+    fn pprof(&self) -> (String, String) {
+        let mut drop = ".*::Profiler::track_allocated".to_string();
+        if !self.drop.is_empty() {
+            drop = format!("(?:{})|{}", drop, Self::any(&self.drop));
         }
+        (drop, Self::any(&self.keep))
+    }
+}
+
+// The threads whose allocations are recorded, see HeapProfilerGuardBuilder::include_threads.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ThreadFilters {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    // the compiled include and exclude regexes, none without either.
+    compiled: Option<(Option<Regex>, Option<Regex>)>,
+}
+
+impl ThreadFilters {
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        let compile = |regexes: &[String]| {
+            (!regexes.is_empty())
+                .then(|| Regex::new(&format!("^(?:{})$", FrameFilters::any(regexes))))
+                .transpose()
+        };
+        let (include, exclude) = (compile(&self.include)?, compile(&self.exclude)?);
+        self.compiled = (include.is_some() || exclude.is_some()).then_some((include, exclude));
+        Ok(())
+    }
+
+    fn profiles(&self, name: &str) -> bool {
+        self.compiled.as_ref().map_or(true, |(include, exclude)| {
+            include
+                .as_ref()
+                .map_or(true, |include| include.is_match(name))
+                && !exclude
+                    .as_ref()
+                    .map_or(false, |exclude| exclude.is_match(name))
+        })
     }
 }
 
+// The allocation functions of the standard library, above the frame of the hook.
+pub(crate) fn is_hidden(name: &str) -> bool {
+    name.starts_with("alloc::alloc::")
+        || name == "<alloc::alloc::Global as core::alloc::Allocator>::allocate"
+}
+
 // #[cfg(test)]
 // mod test {
 //     use super::*;
@@ -569,3 +4705,361 @@ impl<const N: usize> From<Frames<N>> for pprof::Frames {
 //         ));
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos;
+    #[cfg(feature = "prost_codec")]
+    use prost::Message;
+
+    // A frame of one symbol.
+    fn frame(name: &str, file: &str, line: u32) -> Vec<pprof::Symbol> {
+        vec![pprof::Symbol {
+            name: Some(name.as_bytes().to_vec()),
+            addr: None,
+            filename: Some(PathBuf::from(file)),
+            lineno: Some(line),
+        }]
+    }
+
+    // An allocation of `bytes` by `key`.
+    fn record<K: Hash + Eq>(collector: &mut collector::Collector<K>, key: K, bytes: i64) {
+        let allocated = collector::Allocations {
+            objects: 1,
+            requested_bytes: bytes,
+            granted_bytes: bytes,
+            sizes: Default::default(),
+        };
+        collector.record(key, bytes, allocated, 0);
+    }
+
+    // (name, file and line of each frame, the innermost first, labels)
+    type Stack = (
+        &'static [(&'static str, &'static str, u32)],
+        &'static [(&'static str, &'static str)],
+    );
+
+    // The stacks share functions and files, and some labels.
+    fn report() -> HeapReport {
+        let stacks: &[Stack] = &[
+            (
+                &[("alloc_a", "src/a.rs", 3), ("main", "src/main.rs", 10)],
+                &[],
+            ),
+            (
+                &[("alloc_b", "src/b.rs", 7), ("main", "src/main.rs", 11)],
+                &[("task", "worker")],
+            ),
+            (
+                &[
+                    (
+                        "<u8 as alloc::vec::spec_from_elem::SpecFromElem>::from_elem",
+                        "vec.rs",
+                        1,
+                    ),
+                    ("alloc_a", "src/a.rs", 4),
+                    ("worker", "src/main.rs", 20),
+                ],
+                &[("task", "worker"), ("tenant", "a")],
+            ),
+        ];
+        let mut collector = collector::Collector::new();
+        for i in 0..stacks.len() {
+            record(&mut collector, i, 1024 * (i as i64 + 1));
+        }
+        HeapReport::from_collector(&collector, 1, Duration::from_secs(1), |&i| {
+            let (frames, labels) = stacks[i];
+            let mut stack = crate::events::empty_frames();
+            stack.frames = frames
+                .iter()
+                .map(|&(name, file, line)| frame(name, file, line))
+                .collect();
+            let labels = labels
+                .iter()
+                .fold(Labels::new(), |labels, &(k, v)| labels.with(k, v));
+            (stack, labels)
+        })
+    }
+
+    #[test]
+    fn pprof_is_deterministic() {
+        let report = report();
+        assert_eq!(
+            protos::encode(&report.pprof()),
+            protos::encode(&report.pprof())
+        );
+        // nor does it depend on the order of the maps of the report.
+        let (mut a, mut b) = (report.pprof(), self::report().pprof());
+        (a.time_nanos, b.time_nanos) = (0, 0);
+        assert_eq!(protos::encode(&a), protos::encode(&b));
+    }
+
+    #[test]
+    fn pprof_string_table() {
+        let profile = report().pprof();
+        let strings = &profile.string_table;
+        assert_eq!(strings[0], "");
+        let unique: std::collections::HashSet<_> = strings.iter().collect();
+        assert_eq!(unique.len(), strings.len(), "duplicates in {:?}", strings);
+    }
+
+    #[test]
+    fn pprof_strings_decode() {
+        let profile = report().pprof();
+        let decoded = protos::Profile::decode(protos::encode(&profile).as_slice()).unwrap();
+        let string = |index: i64| decoded.string_table[index as usize].as_str();
+        let mut names: Vec<_> = decoded
+            .function
+            .iter()
+            .map(|function| (string(function.name), string(function.filename)))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(
+            names,
+            [
+                (
+                    "<u8 as alloc::vec::spec_from_elem::SpecFromElem>::from_elem",
+                    "vec.rs"
+                ),
+                ("alloc_a", "src/a.rs"),
+                ("alloc_b", "src/b.rs"),
+                ("main", "src/main.rs"),
+                ("worker", "src/main.rs"),
+            ]
+        );
+        let mut labels: Vec<_> = decoded
+            .sample
+            .iter()
+            .flat_map(|sample| &sample.label)
+            .map(|label| (string(label.key), string(label.str)))
+            .collect();
+        labels.sort_unstable();
+        labels.dedup();
+        assert_eq!(
+            labels,
+            [(TYPE_LABEL, "Vec<u8>"), ("task", "worker"), ("tenant", "a")]
+        );
+        let types: Vec<_> = decoded
+            .sample_type
+            .iter()
+            .map(|ty| (string(ty.ty), string(ty.unit)))
+            .collect();
+        assert_eq!(types[0], ("alloc_objects", "count"));
+        assert_eq!(
+            string(decoded.drop_frames),
+            profile.string_table[profile.drop_frames as usize]
+        );
+    }
+
+    #[test]
+    fn totals_saturate() {
+        let mut totals = HeapTotals {
+            allocated_objects: i64::MAX - 1,
+            allocated_bytes: i64::MAX,
+            requested_bytes: i64::MAX / 2 + 1,
+            freed_bytes: i64::MAX,
+            ..HeapTotals::default()
+        };
+        totals.add(&totals.clone());
+        assert_eq!(
+            totals,
+            HeapTotals {
+                allocated_objects: i64::MAX,
+                allocated_bytes: i64::MAX,
+                requested_bytes: i64::MAX,
+                freed_bytes: i64::MAX,
+                ..HeapTotals::default()
+            }
+        );
+        assert_eq!(totals.in_use_bytes(), 0);
+        let fragmentation = Fragmentation {
+            requested_bytes: i64::MIN,
+            granted_bytes: i64::MAX,
+        };
+        assert_eq!(fragmentation.wasted_bytes(), i64::MAX);
+    }
+
+    // Captures the stack `frames` calls below this one, with `unwinder`.
+    #[inline(never)]
+    fn deep(frames: usize, unwinder: Arc<dyn Unwinder>, depth: usize) -> StackKey<MAX_DEPTH> {
+        if frames == 0 {
+            *unwinder::UNWINDER.write() = unwinder;
+            let key = unsafe { StackKey::capture_below(0, depth, 3) };
+            *unwinder::UNWINDER.write() = Arc::new(unwinder::Backtrace);
+            return key;
+        }
+        std::hint::black_box(deep(std::hint::black_box(frames - 1), unwinder, depth))
+    }
+
+    #[test]
+    fn deep_stacks_reach_depth() {
+        // the others are of some platforms only.
+        #[allow(unused_mut)]
+        let mut unwinders: Vec<(&str, Arc<dyn Unwinder>)> =
+            vec![("backtrace", Arc::new(unwinder::Backtrace))];
+        #[cfg(all(
+            any(target_arch = "x86_64", target_arch = "aarch64"),
+            any(target_os = "linux", target_os = "android", target_os = "macos")
+        ))]
+        unwinders.push(("frame pointers", Arc::new(unwinder::FramePointers)));
+        #[cfg(all(feature = "libunwind", target_os = "linux"))]
+        unwinders.push(("libunwind", Arc::new(unwinder::LibUnwind)));
+        for (name, unwinder) in unwinders {
+            for depth in [MAX_DEPTH, MAX_DEPTH + MAX_SPILLED] {
+                // the spill buffer a session would have pooled.
+                HEAP_PROFILER_SPILLS
+                    .lock()
+                    .push(Vec::with_capacity(MAX_SPILLED));
+                let key = deep(depth + MAX_OWN_FRAMES, unwinder.clone(), depth);
+                // the walk stops right away without `-C force-frame-pointers=yes`, see CI.
+                if name == "frame pointers" && key.frames.size == 0 {
+                    continue;
+                }
+                assert_eq!(key.frames.size, depth, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_keep_live_data() {
+        let key = || StackKey::from_frames(&[], Labels::new());
+        HEAP_PROFILER_STATE
+            .write()
+            .unwrap()
+            .memory
+            .push(MemorySample {
+                elapsed: Duration::from_secs(1),
+                rss_bytes: Some(4096),
+                cgroup_bytes: None,
+                heap_bytes: 1024,
+            });
+        HEAP_PROFILER_LIVE
+            .lock()
+            .churn
+            .insert(key(), Churn::default());
+        HEAP_PROFILER_LIVE
+            .lock()
+            .cross_thread
+            .insert(key(), Default::default());
+        HEAP_PROFILER_LARGE
+            .lock()
+            .push((key(), 1 << 20, clock::system_time()));
+        // the second report still has what the first one had.
+        for _ in 0..2 {
+            let report = HeapReport::new();
+            assert_eq!(report.memory_samples().len(), 1);
+            assert_eq!(report.memory_samples()[0].heap_bytes, 1024);
+            assert_eq!(report.churn.len(), 1);
+            assert_eq!(report.cross_thread.len(), 1);
+            assert_eq!(report.large_allocations().len(), 1);
+            assert_eq!(report.large_allocations()[0].size, 1 << 20);
+        }
+        HEAP_PROFILER_STATE.write().unwrap().memory.clear();
+        std::mem::take(&mut *HEAP_PROFILER_LIVE.lock());
+        HEAP_PROFILER_LARGE.lock().clear();
+    }
+
+    #[test]
+    fn block_doesnt_wait() {
+        let queue = FlushQueue::default();
+        for _ in 0..MAX_QUEUED_FLUSHES {
+            assert!(queue.push(vec![], Backpressure::Block).is_ok());
+        }
+        // nothing pops from it: the batch is dropped rather than waited for.
+        let start = Instant::now();
+        assert!(queue.push(vec![], Backpressure::Block).is_ok());
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(
+            queue.batches.lock().unwrap().batches.len(),
+            MAX_QUEUED_FLUSHES
+        );
+        queue.close();
+        assert!(queue.push(vec![], Backpressure::Block).is_err());
+    }
+
+    #[test]
+    fn buffer_saturates() {
+        let mut buffer = ProfilerBuffer::default();
+        for _ in 0..2 {
+            buffer.track(i64::MAX, i64::MAX);
+            buffer.track(i64::MIN, 0);
+            buffer.track_foreign(i64::MAX);
+        }
+        assert_eq!(
+            (
+                buffer.allocated_bytes,
+                buffer.requested_bytes,
+                buffer.allocated_objects
+            ),
+            (i64::MAX, i64::MAX, 2)
+        );
+        assert_eq!((buffer.freed_bytes, buffer.freed_objects), (i64::MAX, 2));
+        assert_eq!(buffer.foreign_freed_bytes, i64::MAX);
+    }
+
+    #[test]
+    fn report_totals_saturate() {
+        let mut collector = collector::Collector::new();
+        for key in 0..3 {
+            record(&mut collector, key, i64::MAX - 10);
+        }
+        // all of them one stack, whose record saturates too.
+        let report = HeapReport::from_collector(&collector, 1, Duration::from_secs(1), |_| {
+            (crate::events::empty_frames(), Labels::new())
+        });
+        assert_eq!(report.totals().allocated_bytes, i64::MAX);
+        assert_eq!(report.totals().requested_bytes, i64::MAX);
+        assert_eq!(report.totals().allocated_objects, 3);
+        let rec = report.data.values().next().unwrap();
+        assert_eq!((rec.alloc_bytes, rec.alloc_objects), (i64::MAX, 3));
+    }
+
+    #[test]
+    fn phase_deltas_saturate() {
+        let mut collector = collector::Collector::new();
+        for key in 0..3 {
+            record(&mut collector, key, i64::MAX);
+        }
+        // the first two in the phase before, of stacks `a` and `b`, the third in the one after, of stack `a`.
+        let stack = |&key: &i32, name| {
+            let mut frames = crate::events::empty_frames();
+            frames.frames = vec![frame(name, "src/a.rs", 1)];
+            let phase = if key < 2 { "before" } else { "after" };
+            (frames, Labels::new().with(crate::PHASE_LABEL, phase))
+        };
+        let report = HeapReport::from_collector(&collector, 1, Duration::from_secs(1), |key| {
+            stack(key, if *key == 1 { "b" } else { "a" })
+        });
+        let deltas = report.compare_phases("before", "after");
+        let delta = |name: &str| {
+            deltas
+                .iter()
+                .find(|(frames, _)| frames.frames[0][0].name() == name)
+                .map(|(_, delta)| *delta)
+                .unwrap()
+        };
+        assert_eq!(delta("a").bytes(), 0);
+        assert_eq!(delta("b").bytes(), -i64::MAX);
+        // all of them the same stack: the phase before adds up to more than the counters hold.
+        let report = HeapReport::from_collector(&collector, 1, Duration::from_secs(1), |key| {
+            stack(key, "a")
+        });
+        let deltas = report.compare_phases("before", "after");
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].1.before.bytes, i64::MAX);
+        assert_eq!(deltas[0].1.bytes(), 0);
+        let delta = PhaseDelta {
+            before: GroupTotals {
+                bytes: i64::MIN,
+                objects: 0,
+            },
+            after: GroupTotals {
+                bytes: i64::MAX,
+                objects: i64::MIN,
+            },
+        };
+        assert_eq!((delta.bytes(), delta.objects()), (i64::MAX, i64::MIN));
+    }
+}
@@ -0,0 +1,639 @@
+//! The pprof protobuf messages of the reports: pprof-rs's, encoded with prost, with the `prost_codec` feature (the
+//! default), and the same messages encoded by heappy itself without it, for a leaner dependency tree and faster
+//! builds (no prost, nor its derive macros and build-time code generation).
+
+#[cfg(feature = "prost_codec")]
+pub use pprof::protos::*;
+
+#[cfg(not(feature = "prost_codec"))]
+pub use self::lean::*;
+
+/// The encoded `profile`.
+pub(crate) fn encode(profile: &Profile) -> Vec<u8> {
+    #[cfg(feature = "prost_codec")]
+    return profile.encode_to_vec();
+    #[cfg(not(feature = "prost_codec"))]
+    return lean::encode(profile);
+}
+
+#[cfg(feature = "prost_codec")]
+pub type DecodeError = prost::DecodeError;
+
+/// Decodes an (un-gzipped) profile.
+#[cfg(feature = "pprof_io")]
+pub(crate) fn decode(buf: &[u8]) -> Result<Profile, DecodeError> {
+    Profile::decode(buf)
+}
+
+// also built for the tests comparing it with prost.
+#[cfg(any(not(feature = "prost_codec"), test))]
+#[cfg_attr(feature = "prost_codec", allow(dead_code))]
+mod lean {
+    //! Only what pprof's profile.proto uses: varints, length-delimited messages, strings and packed repeated
+    //! integers. Like prost, zero fields aren't written; unknown fields are skipped when decoding.
+
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    #[error("failed to decode Protobuf message: {0}")]
+    pub struct DecodeError(&'static str);
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Profile {
+        pub sample_type: Vec<ValueType>,
+        pub sample: Vec<Sample>,
+        pub mapping: Vec<Mapping>,
+        pub location: Vec<Location>,
+        pub function: Vec<Function>,
+        pub string_table: Vec<String>,
+        pub drop_frames: i64,
+        pub keep_frames: i64,
+        pub time_nanos: i64,
+        pub duration_nanos: i64,
+        pub period_type: Option<ValueType>,
+        pub period: i64,
+        pub comment: Vec<i64>,
+        pub default_sample_type: i64,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct ValueType {
+        pub ty: i64,
+        pub unit: i64,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Sample {
+        pub location_id: Vec<u64>,
+        pub value: Vec<i64>,
+        pub label: Vec<Label>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Label {
+        pub key: i64,
+        pub str: i64,
+        pub num: i64,
+        pub num_unit: i64,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Mapping {
+        pub id: u64,
+        pub memory_start: u64,
+        pub memory_limit: u64,
+        pub file_offset: u64,
+        pub filename: i64,
+        pub build_id: i64,
+        pub has_functions: bool,
+        pub has_filenames: bool,
+        pub has_line_numbers: bool,
+        pub has_inline_frames: bool,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Location {
+        pub id: u64,
+        pub mapping_id: u64,
+        pub address: u64,
+        pub line: Vec<Line>,
+        pub is_folded: bool,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Line {
+        pub function_id: u64,
+        pub line: i64,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Function {
+        pub id: u64,
+        pub name: i64,
+        pub system_name: i64,
+        pub filename: i64,
+        pub start_line: i64,
+    }
+
+    pub(crate) fn encode(profile: &Profile) -> Vec<u8> {
+        let mut buf = vec![];
+        profile.encode(&mut Writer(&mut buf));
+        buf
+    }
+
+    // Like prost's, for code built both ways.
+    impl Profile {
+        pub fn encode_to_vec(&self) -> Vec<u8> {
+            encode(self)
+        }
+
+        pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+            decode_message(buf)
+        }
+    }
+
+    const VARINT: u8 = 0;
+    const FIXED64: u8 = 1;
+    const LEN: u8 = 2;
+    const FIXED32: u8 = 5;
+
+    struct Writer<'a>(&'a mut Vec<u8>);
+
+    impl Writer<'_> {
+        fn varint(&mut self, mut v: u64) {
+            while v >= 0x80 {
+                self.0.push(v as u8 | 0x80);
+                v >>= 7;
+            }
+            self.0.push(v as u8);
+        }
+
+        fn key(&mut self, field: u32, wire: u8) {
+            self.varint(u64::from(field) << 3 | u64::from(wire));
+        }
+
+        fn uint(&mut self, field: u32, v: u64) {
+            if v != 0 {
+                self.key(field, VARINT);
+                self.varint(v);
+            }
+        }
+
+        fn int(&mut self, field: u32, v: i64) {
+            self.uint(field, v as u64);
+        }
+
+        fn bool(&mut self, field: u32, v: bool) {
+            self.uint(field, v.into());
+        }
+
+        fn bytes(&mut self, field: u32, bytes: &[u8]) {
+            self.key(field, LEN);
+            self.varint(bytes.len() as u64);
+            self.0.extend_from_slice(bytes);
+        }
+
+        fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+            let mut packed = vec![];
+            let mut writer = Writer(&mut packed);
+            for v in values {
+                writer.varint(v);
+            }
+            if !packed.is_empty() {
+                self.bytes(field, &packed);
+            }
+        }
+
+        fn message(&mut self, field: u32, message: &impl Message) {
+            let mut inner = vec![];
+            message.encode(&mut Writer(&mut inner));
+            self.bytes(field, &inner);
+        }
+
+        fn messages<'m, M: Message + 'm>(
+            &mut self,
+            field: u32,
+            messages: impl IntoIterator<Item = &'m M>,
+        ) {
+            for message in messages {
+                self.message(field, message);
+            }
+        }
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn varint(&mut self) -> Result<u64, DecodeError> {
+            let mut v = 0;
+            for shift in (0..64).step_by(7) {
+                let (&byte, rest) = self
+                    .0
+                    .split_first()
+                    .ok_or(DecodeError("truncated varint"))?;
+                self.0 = rest;
+                v |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    return Ok(v);
+                }
+            }
+            Err(DecodeError("invalid varint"))
+        }
+
+        fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+            if len > self.0.len() {
+                return Err(DecodeError("buffer underflow"));
+            }
+            let (taken, rest) = self.0.split_at(len);
+            self.0 = rest;
+            Ok(taken)
+        }
+
+        fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+            let len = self.varint()?;
+            self.take(usize::try_from(len).map_err(|_| DecodeError("buffer underflow"))?)
+        }
+
+        fn skip(&mut self, wire: u8) -> Result<(), DecodeError> {
+            match wire {
+                VARINT => self.varint().map(drop),
+                FIXED64 => self.take(8).map(drop),
+                LEN => self.bytes().map(drop),
+                FIXED32 => self.take(4).map(drop),
+                _ => Err(DecodeError("invalid wire type")),
+            }
+        }
+    }
+
+    // The value of a field, checked against its wire type.
+    struct Field<'r, 'a> {
+        wire: u8,
+        reader: &'r mut Reader<'a>,
+    }
+
+    impl<'a> Field<'_, 'a> {
+        fn uint(self) -> Result<u64, DecodeError> {
+            match self.wire {
+                VARINT => self.reader.varint(),
+                _ => Err(DecodeError("invalid wire type")),
+            }
+        }
+
+        fn int(self) -> Result<i64, DecodeError> {
+            self.uint().map(|v| v as i64)
+        }
+
+        fn bool(self) -> Result<bool, DecodeError> {
+            self.uint().map(|v| v != 0)
+        }
+
+        fn bytes(self) -> Result<&'a [u8], DecodeError> {
+            match self.wire {
+                LEN => self.reader.bytes(),
+                _ => Err(DecodeError("invalid wire type")),
+            }
+        }
+
+        fn string(self) -> Result<String, DecodeError> {
+            String::from_utf8(self.bytes()?.to_vec())
+                .map_err(|_| DecodeError("invalid string value: data is not UTF-8 encoded"))
+        }
+
+        fn message<M: Message>(self) -> Result<M, DecodeError> {
+            decode_message(self.bytes()?)
+        }
+
+        // An element of a repeated integer field, or all of them when they're packed.
+        fn repeated(self, mut push: impl FnMut(u64)) -> Result<(), DecodeError> {
+            if self.wire != LEN {
+                push(self.uint()?);
+                return Ok(());
+            }
+            let mut packed = Reader(self.reader.bytes()?);
+            while !packed.0.is_empty() {
+                push(packed.varint()?);
+            }
+            Ok(())
+        }
+
+        fn skip(self) -> Result<(), DecodeError> {
+            self.reader.skip(self.wire)
+        }
+    }
+
+    trait Message: Default {
+        fn encode(&self, w: &mut Writer);
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError>;
+    }
+
+    fn decode_message<M: Message>(buf: &[u8]) -> Result<M, DecodeError> {
+        let mut reader = Reader(buf);
+        let mut message = M::default();
+        while !reader.0.is_empty() {
+            let key = reader.varint()?;
+            let field = u32::try_from(key >> 3).map_err(|_| DecodeError("invalid key"))?;
+            let wire = (key & 7) as u8;
+            message.merge(
+                field,
+                Field {
+                    wire,
+                    reader: &mut reader,
+                },
+            )?;
+        }
+        Ok(message)
+    }
+
+    impl Message for Profile {
+        fn encode(&self, w: &mut Writer) {
+            w.messages(1, &self.sample_type);
+            w.messages(2, &self.sample);
+            w.messages(3, &self.mapping);
+            w.messages(4, &self.location);
+            w.messages(5, &self.function);
+            for s in &self.string_table {
+                w.bytes(6, s.as_bytes());
+            }
+            w.int(7, self.drop_frames);
+            w.int(8, self.keep_frames);
+            w.int(9, self.time_nanos);
+            w.int(10, self.duration_nanos);
+            if let Some(period_type) = &self.period_type {
+                w.message(11, period_type);
+            }
+            w.int(12, self.period);
+            w.packed(13, self.comment.iter().map(|&v| v as u64));
+            w.int(14, self.default_sample_type);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.sample_type.push(value.message()?),
+                2 => self.sample.push(value.message()?),
+                3 => self.mapping.push(value.message()?),
+                4 => self.location.push(value.message()?),
+                5 => self.function.push(value.message()?),
+                6 => self.string_table.push(value.string()?),
+                7 => self.drop_frames = value.int()?,
+                8 => self.keep_frames = value.int()?,
+                9 => self.time_nanos = value.int()?,
+                10 => self.duration_nanos = value.int()?,
+                11 => self.period_type = Some(value.message()?),
+                12 => self.period = value.int()?,
+                13 => value.repeated(|v| self.comment.push(v as i64))?,
+                14 => self.default_sample_type = value.int()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for ValueType {
+        fn encode(&self, w: &mut Writer) {
+            w.int(1, self.ty);
+            w.int(2, self.unit);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.ty = value.int()?,
+                2 => self.unit = value.int()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for Sample {
+        fn encode(&self, w: &mut Writer) {
+            w.packed(1, self.location_id.iter().copied());
+            w.packed(2, self.value.iter().map(|&v| v as u64));
+            w.messages(3, &self.label);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => value.repeated(|v| self.location_id.push(v))?,
+                2 => value.repeated(|v| self.value.push(v as i64))?,
+                3 => self.label.push(value.message()?),
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for Label {
+        fn encode(&self, w: &mut Writer) {
+            w.int(1, self.key);
+            w.int(2, self.str);
+            w.int(3, self.num);
+            w.int(4, self.num_unit);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.key = value.int()?,
+                2 => self.str = value.int()?,
+                3 => self.num = value.int()?,
+                4 => self.num_unit = value.int()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for Mapping {
+        fn encode(&self, w: &mut Writer) {
+            w.uint(1, self.id);
+            w.uint(2, self.memory_start);
+            w.uint(3, self.memory_limit);
+            w.uint(4, self.file_offset);
+            w.int(5, self.filename);
+            w.int(6, self.build_id);
+            w.bool(7, self.has_functions);
+            w.bool(8, self.has_filenames);
+            w.bool(9, self.has_line_numbers);
+            w.bool(10, self.has_inline_frames);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.id = value.uint()?,
+                2 => self.memory_start = value.uint()?,
+                3 => self.memory_limit = value.uint()?,
+                4 => self.file_offset = value.uint()?,
+                5 => self.filename = value.int()?,
+                6 => self.build_id = value.int()?,
+                7 => self.has_functions = value.bool()?,
+                8 => self.has_filenames = value.bool()?,
+                9 => self.has_line_numbers = value.bool()?,
+                10 => self.has_inline_frames = value.bool()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for Location {
+        fn encode(&self, w: &mut Writer) {
+            w.uint(1, self.id);
+            w.uint(2, self.mapping_id);
+            w.uint(3, self.address);
+            w.messages(4, &self.line);
+            w.bool(5, self.is_folded);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.id = value.uint()?,
+                2 => self.mapping_id = value.uint()?,
+                3 => self.address = value.uint()?,
+                4 => self.line.push(value.message()?),
+                5 => self.is_folded = value.bool()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for Line {
+        fn encode(&self, w: &mut Writer) {
+            w.uint(1, self.function_id);
+            w.int(2, self.line);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.function_id = value.uint()?,
+                2 => self.line = value.int()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+
+    impl Message for Function {
+        fn encode(&self, w: &mut Writer) {
+            w.uint(1, self.id);
+            w.int(2, self.name);
+            w.int(3, self.system_name);
+            w.int(4, self.filename);
+            w.int(5, self.start_line);
+        }
+
+        fn merge(&mut self, field: u32, value: Field) -> Result<(), DecodeError> {
+            match field {
+                1 => self.id = value.uint()?,
+                2 => self.name = value.int()?,
+                3 => self.system_name = value.int()?,
+                4 => self.filename = value.int()?,
+                5 => self.start_line = value.int()?,
+                _ => value.skip()?,
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "prost_codec"))]
+mod tests {
+    use super::lean;
+    use prost::Message;
+
+    // A profile with every field set, negative numbers, empty strings and repeated fields among them.
+    fn profile() -> super::Profile {
+        use super::*;
+        let strings = [
+            "",
+            "alloc_space",
+            "bytes",
+            "main",
+            "src/main.rs",
+            "",
+            "tenant",
+            "a",
+            "é",
+        ];
+        Profile {
+            sample_type: vec![ValueType { ty: 1, unit: 2 }, ValueType { ty: 0, unit: 0 }],
+            sample: vec![
+                Sample {
+                    location_id: vec![1, 2, u64::MAX],
+                    value: vec![4096, -1, i64::MIN, 0],
+                    label: vec![
+                        Label {
+                            key: 6,
+                            str: 7,
+                            num: 0,
+                            num_unit: 0,
+                        },
+                        Label {
+                            key: 6,
+                            str: 0,
+                            num: -42,
+                            num_unit: 2,
+                        },
+                    ],
+                },
+                Sample::default(),
+            ],
+            mapping: vec![Mapping {
+                id: 1,
+                memory_start: 0x5555_0000,
+                memory_limit: 0x5556_0000,
+                file_offset: 0x1000,
+                filename: 4,
+                build_id: 5,
+                has_functions: true,
+                has_filenames: false,
+                has_line_numbers: true,
+                has_inline_frames: true,
+            }],
+            location: vec![
+                Location {
+                    id: 1,
+                    mapping_id: 1,
+                    address: 0x5555_1234,
+                    line: vec![
+                        Line {
+                            function_id: 1,
+                            line: 10,
+                        },
+                        Line {
+                            function_id: 2,
+                            line: -1,
+                        },
+                    ],
+                    is_folded: true,
+                },
+                Location {
+                    id: 2,
+                    ..Location::default()
+                },
+            ],
+            function: vec![Function {
+                id: 1,
+                name: 3,
+                system_name: 3,
+                filename: 4,
+                start_line: 7,
+            }],
+            string_table: strings.iter().map(|s| s.to_string()).collect(),
+            drop_frames: 3,
+            keep_frames: 0,
+            time_nanos: 1_700_000_000_000_000_000,
+            duration_nanos: -5,
+            period_type: Some(ValueType { ty: 1, unit: 2 }),
+            period: 524288,
+            comment: vec![8, 0, 3],
+            default_sample_type: 1,
+        }
+    }
+
+    #[test]
+    fn lean_encodes_like_prost() {
+        for profile in [profile(), super::Profile::default()] {
+            let encoded = profile.encode_to_vec();
+            let decoded = lean::Profile::decode(&encoded).unwrap();
+            assert_eq!(lean::encode(&decoded), encoded);
+            assert_eq!(
+                super::Profile::decode(lean::encode(&decoded).as_slice()).unwrap(),
+                profile
+            );
+        }
+    }
+
+    #[test]
+    fn lean_decodes_unpacked() {
+        // prost reads repeated integers unpacked too, and so must the lean decoder.
+        let sample = [0x08, 0x01, 0x08, 0x02, 0x10, 0x07];
+        let mut encoded = vec![0x12, sample.len() as u8];
+        encoded.extend_from_slice(&sample);
+        let profile = super::Profile::decode(encoded.as_slice()).unwrap();
+        let decoded = lean::Profile::decode(&encoded).unwrap();
+        assert_eq!(decoded.sample[0].location_id, profile.sample[0].location_id);
+        assert_eq!(decoded.sample[0].value, [7]);
+        assert_eq!(lean::encode(&decoded), profile.encode_to_vec());
+    }
+}
@@ -0,0 +1,610 @@
+//! Comparing a heap profile against a baseline, e.g. to fail CI when a change makes a workload allocate more, and
+//! pointing at the functions that grew: [`annotations`] are the lines for CI to annotate, one JSON object per line
+//! with [`write_annotations`] (wrapped here):
+//!
+//! ```text
+//! {"function":"my_crate::cache::insert","file":"src/cache.rs","line":42,"baseline":1048576,"current":3145728,
+//!  "delta":2097152,"delta_percent":200.0,"unit":"bytes"}
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::protos;
+
+use crate::profiler::{json_string, Error, HeapReport, Result};
+
+/// How much a profile may grow over its baseline; every threshold that is set must hold.
+#[derive(Clone, Debug, Default)]
+pub struct Thresholds {
+    /// Sample type to compare, e.g. `inuse_space`. Defaults to the baseline's default sample type.
+    pub sample_type: Option<String>,
+    /// Maximum growth of the total, relative to the baseline (`0.05` for 5%).
+    pub max_growth: Option<f64>,
+    /// Maximum growth of the total, in the unit of the sample type.
+    pub max_growth_abs: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RegressionResult {
+    pub sample_type: String,
+    pub unit: String,
+    pub baseline: i64,
+    pub current: i64,
+    /// Whether the growth exceeds any of the thresholds.
+    pub regressed: bool,
+}
+
+impl RegressionResult {
+    pub fn growth(&self) -> i64 {
+        self.current.saturating_sub(self.baseline)
+    }
+
+    /// The growth relative to the baseline, infinite if the baseline is empty.
+    pub fn growth_ratio(&self) -> f64 {
+        if self.baseline == 0 {
+            if self.current == 0 {
+                return 0.0;
+            }
+            return f64::INFINITY * self.current.signum() as f64;
+        }
+        self.growth() as f64 / self.baseline.abs() as f64
+    }
+}
+
+impl fmt::Display for RegressionResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} {} ({:+} {}, {:+.2}%)",
+            self.sample_type,
+            self.baseline,
+            self.current,
+            self.unit,
+            self.growth(),
+            self.unit,
+            self.growth_ratio() * 100.0
+        )
+    }
+}
+
+/// A function that allocates more than in the baseline, for CI to annotate its code with, see [`annotations`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Annotation {
+    pub function: String,
+    /// The file of the function, empty if the profile doesn't tell.
+    pub file: String,
+    /// The line of the function that allocates the most in the current profile, 0 if the profile doesn't tell.
+    pub line: i64,
+    pub baseline: i64,
+    pub current: i64,
+    pub unit: String,
+}
+
+impl Annotation {
+    pub fn growth(&self) -> i64 {
+        self.current.saturating_sub(self.baseline)
+    }
+
+    /// The growth relative to the baseline, infinite if the function didn't allocate in the baseline.
+    pub fn growth_ratio(&self) -> f64 {
+        if self.baseline == 0 {
+            return f64::INFINITY * self.growth().signum() as f64;
+        }
+        self.growth() as f64 / self.baseline.abs() as f64
+    }
+}
+
+impl HeapReport {
+    /// Compares this report with a baseline profile, e.g. one written by [`HeapReport::write_pprof`] on the main
+    /// branch.
+    pub fn check_regression(
+        &self,
+        baseline: &protos::Profile,
+        thresholds: &Thresholds,
+    ) -> Result<RegressionResult> {
+        check_regression(baseline, &self.pprof(), thresholds)
+    }
+
+    /// The functions of this report that grew over a baseline profile, see [`annotations`].
+    pub fn regression_annotations(
+        &self,
+        baseline: &protos::Profile,
+        thresholds: &Thresholds,
+    ) -> Result<Vec<Annotation>> {
+        annotations(baseline, &self.pprof(), thresholds)
+    }
+}
+
+/// Compares two profiles, see [`HeapReport::check_regression`].
+pub fn check_regression(
+    baseline: &protos::Profile,
+    current: &protos::Profile,
+    thresholds: &Thresholds,
+) -> Result<RegressionResult> {
+    let sample_type = match &thresholds.sample_type {
+        Some(name) => name.clone(),
+        None => default_sample_type(baseline).to_string(),
+    };
+    let (baseline_total, unit) = total(baseline, &sample_type)?;
+    let (current_total, _) = total(current, &sample_type)?;
+
+    let mut result = RegressionResult {
+        sample_type,
+        unit: unit.to_string(),
+        baseline: baseline_total,
+        current: current_total,
+        regressed: false,
+    };
+    result.regressed = thresholds
+        .max_growth
+        .map_or(false, |max| result.growth_ratio() > max)
+        || thresholds
+            .max_growth_abs
+            .map_or(false, |max| result.growth() > max);
+    Ok(result)
+}
+
+/// The functions that grew by more than `thresholds` (each function against its own baseline, like the total of
+/// [`check_regression`]), or at all without thresholds, the ones that grew the most first. A function counts what it
+/// allocated itself, as the innermost function of the samples that isn't the standard library's (the code that can
+/// be annotated, calling into `Vec` or `Box`), and is told apart by its name and file, not by its lines, which the
+/// change being checked is likely to move.
+pub fn annotations(
+    baseline: &protos::Profile,
+    current: &protos::Profile,
+    thresholds: &Thresholds,
+) -> Result<Vec<Annotation>> {
+    let sample_type = match &thresholds.sample_type {
+        Some(name) => name.clone(),
+        None => default_sample_type(baseline).to_string(),
+    };
+    let (_, unit) = total(baseline, &sample_type)?;
+    let before = by_function(baseline, &sample_type)?;
+    let after = by_function(current, &sample_type)?;
+
+    let mut annotations: Vec<_> = after
+        .into_iter()
+        .map(|(key, (current, lines))| {
+            let baseline = before.get(&key).map_or(0, |(value, _)| *value);
+            // the line allocating the most, the first one of those.
+            let line = lines
+                .into_iter()
+                .max_by_key(|&(line, value)| (value, std::cmp::Reverse(line)))
+                .map_or(0, |(line, _)| line);
+            Annotation {
+                function: key.0.to_string(),
+                file: key.1.to_string(),
+                line,
+                baseline,
+                current,
+                unit: unit.to_string(),
+            }
+        })
+        .filter(|annotation| {
+            let growth = annotation.growth();
+            let exceeds = thresholds
+                .max_growth
+                .map_or(false, |max| annotation.growth_ratio() > max)
+                || thresholds.max_growth_abs.map_or(false, |max| growth > max);
+            growth > 0
+                && (exceeds
+                    || thresholds.max_growth.is_none() && thresholds.max_growth_abs.is_none())
+        })
+        .collect();
+    annotations.sort_by(|a, b| {
+        (b.growth(), &a.function, &a.file).cmp(&(a.growth(), &b.function, &b.file))
+    });
+    Ok(annotations)
+}
+
+/// Writes `annotations` as JSON lines, e.g. for a CI step to turn into annotations of the code: the `function`, its
+/// `file` and `line`, the `baseline` and `current` values with their `delta` (and `delta_percent`, `null` if the
+/// function didn't allocate in the baseline) and the `unit`.
+pub fn write_annotations<W: Write>(mut writer: W, annotations: &[Annotation]) -> io::Result<()> {
+    for annotation in annotations {
+        let ratio = annotation.growth_ratio();
+        let percent = if ratio.is_finite() {
+            format!("{:.1}", ratio * 100.0)
+        } else {
+            "null".to_string()
+        };
+        writeln!(
+            writer,
+            concat!(
+                r#"{{"function":{},"file":{},"line":{},"baseline":{},"current":{},"delta":{},"#,
+                r#""delta_percent":{},"unit":{}}}"#,
+            ),
+            json_string(&annotation.function),
+            json_string(&annotation.file),
+            annotation.line,
+            annotation.baseline,
+            annotation.current,
+            annotation.growth(),
+            percent,
+            json_string(&annotation.unit),
+        )?;
+    }
+    Ok(())
+}
+
+// The functions of the standard library and of the allocator, by their names.
+const STD_FUNCTIONS: &[&str] = &[
+    "std::", "core::", "alloc::", "<std::", "<core::", "<alloc::", "__rust_", "__rdl_", "__rg_",
+];
+
+// The values of a sample type by the innermost function of the samples outside the standard library (its name and
+// file), or the innermost of all, with the values of each of its lines.
+type ByFunction<'a> = HashMap<(&'a str, &'a str), (i64, HashMap<i64, i64>)>;
+
+fn by_function<'a>(profile: &'a protos::Profile, sample_type: &str) -> Result<ByFunction<'a>> {
+    let idx = profile
+        .sample_type
+        .iter()
+        .position(|st| string(profile, st.ty) == sample_type)
+        .ok_or_else(|| Error::UnknownSampleType(sample_type.to_string()))?;
+    let functions: HashMap<u64, &protos::Function> =
+        profile.function.iter().map(|f| (f.id, f)).collect();
+    let locations: HashMap<u64, &protos::Location> =
+        profile.location.iter().map(|l| (l.id, l)).collect();
+    let mut by_function = ByFunction::new();
+    for sample in &profile.sample {
+        let Some(&value) = sample.value.get(idx) else {
+            continue;
+        };
+        // the locations and their lines are innermost first.
+        let lines: Vec<_> = sample
+            .location_id
+            .iter()
+            .filter_map(|id| locations.get(id))
+            .flat_map(|location| &location.line)
+            .filter_map(|line| {
+                let function = functions.get(&line.function_id)?;
+                let key = (
+                    string(profile, function.name),
+                    string(profile, function.filename),
+                );
+                Some((key, line.line))
+            })
+            .collect();
+        let is_std = |&&((name, file), _): &&((&str, &str), i64)| {
+            file.starts_with("/rustc/")
+                || STD_FUNCTIONS.iter().any(|prefix| name.starts_with(prefix))
+        };
+        let leaf = lines.iter().find(|line| !is_std(line)).or(lines.first());
+        let Some(&(key, line)) = leaf else {
+            continue;
+        };
+        let (total, by_line) = by_function.entry(key).or_default();
+        // the values are the profile's, which adds them up into what an i64 holds.
+        *total = total.saturating_add(value);
+        let by_line = by_line.entry(line).or_default();
+        *by_line = by_line.saturating_add(value);
+    }
+    Ok(by_function)
+}
+
+fn string(profile: &protos::Profile, idx: i64) -> &str {
+    profile
+        .string_table
+        .get(idx as usize)
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+// pprof's convention is the default_sample_type if set, the last sample type otherwise.
+fn default_sample_type(profile: &protos::Profile) -> &str {
+    profile
+        .sample_type
+        .iter()
+        .find(|st| profile.default_sample_type != 0 && st.ty == profile.default_sample_type)
+        .or(profile.sample_type.last())
+        .map_or("", |st| string(profile, st.ty))
+}
+
+// The sum of the values of a sample type, and its unit.
+fn total<'a>(profile: &'a protos::Profile, sample_type: &str) -> Result<(i64, &'a str)> {
+    let idx = profile
+        .sample_type
+        .iter()
+        .position(|st| string(profile, st.ty) == sample_type)
+        .ok_or_else(|| Error::UnknownSampleType(sample_type.to_string()))?;
+    let total = profile
+        .sample
+        .iter()
+        .filter_map(|sample| sample.value.get(idx))
+        .fold(0i64, |total, &value| total.saturating_add(value));
+    Ok((total, string(profile, profile.sample_type[idx].unit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Stack<'a> = &'a [(&'a str, &'a str, i64)];
+
+    // A profile of `alloc_space` and `inuse_space` (the default) in bytes, the samples given by their stacks, innermost
+    // frame first, each frame a function, its file and a line.
+    fn profile(samples: &[(Stack, i64)]) -> protos::Profile {
+        let mut profile = protos::Profile::default();
+        let mut string = |s: &str| match profile.string_table.iter().position(|t| t == s) {
+            Some(idx) => idx as i64,
+            None => {
+                profile.string_table.push(s.to_string());
+                profile.string_table.len() as i64 - 1
+            }
+        };
+        string("");
+        let bytes = string("bytes");
+        let sample_type = [string("alloc_space"), string("inuse_space")];
+        let mut functions = vec![];
+        let mut locations = vec![];
+        let mut pprof_samples = vec![];
+        for &(frames, value) in samples {
+            let mut location_id = vec![];
+            for &(name, file, line) in frames {
+                let (name, filename) = (string(name), string(file));
+                let id = functions.len() as u64 + 1;
+                functions.push(protos::Function {
+                    id,
+                    name,
+                    filename,
+                    ..Default::default()
+                });
+                locations.push(protos::Location {
+                    id,
+                    line: vec![protos::Line {
+                        function_id: id,
+                        line,
+                    }],
+                    ..Default::default()
+                });
+                location_id.push(id);
+            }
+            pprof_samples.push(protos::Sample {
+                location_id,
+                value: vec![value, value],
+                ..Default::default()
+            });
+        }
+        profile.sample_type = sample_type
+            .iter()
+            .map(|&ty| protos::ValueType { ty, unit: bytes })
+            .collect();
+        profile.default_sample_type = sample_type[1];
+        profile.function = functions;
+        profile.location = locations;
+        profile.sample = pprof_samples;
+        profile
+    }
+
+    fn totalling(value: i64) -> protos::Profile {
+        profile(&[(&[("app::run", "src/main.rs", 1)], value)])
+    }
+
+    #[test]
+    fn within_threshold() {
+        let thresholds = Thresholds {
+            max_growth: Some(0.05),
+            ..Default::default()
+        };
+        let result = check_regression(&totalling(1000), &totalling(1050), &thresholds).unwrap();
+        assert_eq!(result.sample_type, "inuse_space");
+        assert_eq!(result.unit, "bytes");
+        assert_eq!(
+            (result.baseline, result.current, result.growth()),
+            (1000, 1050, 50)
+        );
+        assert!(!result.regressed);
+        // shrinking always passes.
+        assert!(
+            !check_regression(&totalling(1000), &totalling(10), &thresholds)
+                .unwrap()
+                .regressed
+        );
+    }
+
+    #[test]
+    fn over_threshold() {
+        let thresholds = Thresholds {
+            max_growth: Some(0.05),
+            ..Default::default()
+        };
+        let result = check_regression(&totalling(1000), &totalling(1051), &thresholds).unwrap();
+        assert!(result.regressed);
+        assert!((result.growth_ratio() - 0.051).abs() < 1e-9);
+    }
+
+    #[test]
+    fn max_growth_abs() {
+        let thresholds = Thresholds {
+            max_growth_abs: Some(100),
+            ..Default::default()
+        };
+        let check = |current| {
+            check_regression(&totalling(1000), &totalling(current), &thresholds)
+                .unwrap()
+                .regressed
+        };
+        assert!(!check(1100));
+        assert!(check(1101));
+        // both set, either one fails the check.
+        let thresholds = Thresholds {
+            max_growth: Some(1.0),
+            max_growth_abs: Some(100),
+            ..Default::default()
+        };
+        assert!(
+            check_regression(&totalling(1000), &totalling(1200), &thresholds)
+                .unwrap()
+                .regressed
+        );
+    }
+
+    #[test]
+    fn empty_baseline() {
+        let thresholds = Thresholds {
+            max_growth: Some(10.0),
+            ..Default::default()
+        };
+        let result = check_regression(&profile(&[]), &totalling(1), &thresholds).unwrap();
+        assert_eq!(result.growth_ratio(), f64::INFINITY);
+        assert!(result.regressed);
+        let result = check_regression(&profile(&[]), &profile(&[]), &thresholds).unwrap();
+        assert_eq!(result.growth_ratio(), 0.0);
+        assert!(!result.regressed);
+    }
+
+    #[test]
+    fn unknown_sample_type() {
+        let thresholds = Thresholds {
+            sample_type: Some("cpu".to_string()),
+            ..Default::default()
+        };
+        let result = check_regression(&totalling(1), &totalling(1), &thresholds);
+        assert!(matches!(result, Err(Error::UnknownSampleType(name)) if name == "cpu"));
+        let result = annotations(&totalling(1), &totalling(1), &thresholds);
+        assert!(matches!(result, Err(Error::UnknownSampleType(name)) if name == "cpu"));
+    }
+
+    #[test]
+    fn totals_saturate() {
+        let baseline = profile(&[(&[("a", "a.rs", 1)], i64::MIN), (&[("b", "b.rs", 1)], -1)]);
+        let current = profile(&[(&[("a", "a.rs", 1)], i64::MAX), (&[("a", "a.rs", 2)], 1)]);
+        let result = check_regression(&baseline, &current, &Thresholds::default()).unwrap();
+        assert_eq!((result.baseline, result.current), (i64::MIN, i64::MAX));
+        assert_eq!(result.growth(), i64::MAX);
+        let annotations = annotations(&baseline, &current, &Thresholds::default()).unwrap();
+        assert_eq!(
+            (annotations[0].current, annotations[0].growth()),
+            (i64::MAX, i64::MAX)
+        );
+    }
+
+    #[test]
+    fn annotations_skip_std() {
+        let push: Stack = &[
+            (
+                "alloc::raw_vec::finish_grow",
+                "/rustc/abc/library/alloc/src/raw_vec.rs",
+                1,
+            ),
+            (
+                "<alloc::vec::Vec<T> as core::clone::Clone>::clone",
+                "src/vec.rs",
+                2,
+            ),
+            ("app::cache::insert", "src/cache.rs", 42),
+            ("app::main", "src/main.rs", 7),
+        ];
+        let found =
+            annotations(&profile(&[]), &profile(&[(push, 10)]), &Default::default()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            (
+                found[0].function.as_str(),
+                found[0].file.as_str(),
+                found[0].line
+            ),
+            ("app::cache::insert", "src/cache.rs", 42)
+        );
+        // all of it the standard library's, the innermost frame.
+        let std: Stack = &[
+            ("std::alloc::alloc", "src/alloc.rs", 3),
+            ("std::rt::lang_start", "src/rt.rs", 4),
+        ];
+        let found =
+            annotations(&profile(&[]), &profile(&[(std, 10)]), &Default::default()).unwrap();
+        assert_eq!(found[0].function, "std::alloc::alloc");
+    }
+
+    #[test]
+    fn annotations_line() {
+        let current = profile(&[
+            (&[("app::a", "src/a.rs", 10)], 5),
+            (&[("app::a", "src/a.rs", 20)], 7),
+            (&[("app::a", "src/a.rs", 30)], 7),
+            (&[("app::a", "src/a.rs", 10)], 1),
+        ]);
+        let found = annotations(&totalling(1), &current, &Default::default()).unwrap();
+        // the function of its lines, the first of the ones allocating most.
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].line, found[0].current), (20, 20));
+    }
+
+    #[test]
+    fn annotations_order() {
+        let baseline = profile(&[
+            (&[("app::a", "src/a.rs", 1)], 100),
+            (&[("app::b", "src/b.rs", 1)], 100),
+        ]);
+        let current = profile(&[
+            (&[("app::a", "src/a.rs", 1)], 150),
+            (&[("app::b", "src/b.rs", 1)], 300),
+            (&[("app::c", "src/c.rs", 1)], 50),
+            (&[("app::d", "src/d.rs", 1)], 50),
+            (&[("app::e", "src/e.rs", 1)], 0),
+        ]);
+        let names = |thresholds: &Thresholds| -> Vec<_> {
+            annotations(&baseline, &current, thresholds)
+                .unwrap()
+                .into_iter()
+                .map(|annotation| annotation.function)
+                .collect()
+        };
+        // the ones that grew, most first, then by name.
+        assert_eq!(
+            names(&Thresholds::default()),
+            ["app::b", "app::a", "app::c", "app::d"]
+        );
+        let thresholds = Thresholds {
+            max_growth: Some(0.6),
+            ..Default::default()
+        };
+        assert_eq!(names(&thresholds), ["app::b", "app::c", "app::d"]);
+        let thresholds = Thresholds {
+            max_growth_abs: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(names(&thresholds), ["app::b"]);
+    }
+
+    #[test]
+    fn annotations_json() {
+        let annotation = |function: &str, file: &str, baseline| Annotation {
+            function: function.to_string(),
+            file: file.to_string(),
+            line: 3,
+            baseline,
+            current: 300,
+            unit: "bytes".to_string(),
+        };
+        let mut out = vec![];
+        write_annotations(
+            &mut out,
+            &[
+                annotation("app::a", "src/a.rs", 200),
+                annotation(r#"<app::Q<"x">>::f"#, r"C:\src\q.rs", 0),
+            ],
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            concat!(
+                r#"{"function":"app::a","file":"src/a.rs","line":3,"baseline":200,"current":300,"delta":100,"#,
+                r#""delta_percent":50.0,"unit":"bytes"}"#,
+            )
+        );
+        assert_eq!(
+            lines[1],
+            concat!(
+                r#"{"function":"<app::Q<\"x\">>::f","file":"C:\\src\\q.rs","line":3,"baseline":0,"current":300,"#,
+                r#""delta":300,"delta_percent":null,"unit":"bytes"}"#,
+            )
+        );
+        assert_eq!(lines.len(), 2);
+    }
+}
@@ -0,0 +1,158 @@
+//! The async runtime a session runs its background work on (the watchers, the warm-up and baseline timers,
+//! symbolizing the snapshots), with the `async` feature. [`Tokio`] comes with the `tokio` feature and is the default;
+//! other executors plug in through [`HeapProfilerGuardBuilder::runtime`](crate::HeapProfilerGuardBuilder::runtime),
+//! and [`Threads`] runs the sessions without one.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawns the work of a session. Nothing is waited for while holding the profiler's locks, so an executor that
+/// blocks in one of these calls only delays the session. E.g. for smol and async-std:
+///
+/// ```ignore
+/// use std::time::Duration;
+///
+/// struct Smol;
+///
+/// impl heappy::Runtime for Smol {
+///     fn spawn(&self, future: heappy::BoxFuture) {
+///         smol::spawn(future).detach();
+///     }
+///
+///     fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+///         smol::spawn(smol::unblock(f)).detach();
+///     }
+///
+///     fn sleep(&self, duration: Duration) -> heappy::BoxFuture {
+///         Box::pin(async move {
+///             smol::Timer::after(duration).await;
+///         })
+///     }
+/// }
+///
+/// struct AsyncStd;
+///
+/// impl heappy::Runtime for AsyncStd {
+///     fn spawn(&self, future: heappy::BoxFuture) {
+///         async_std::task::spawn(future);
+///     }
+///
+///     fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+///         async_std::task::spawn_blocking(f);
+///     }
+///
+///     fn sleep(&self, duration: Duration) -> heappy::BoxFuture {
+///         Box::pin(async_std::task::sleep(duration))
+///     }
+/// }
+/// ```
+pub trait Runtime: Send + Sync {
+    /// Runs `future` in the background, to completion.
+    fn spawn(&self, future: BoxFuture);
+
+    /// Runs the blocking `f` where it doesn't hold up the executor, e.g. on a thread pool for blocking work.
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>);
+
+    /// A future that completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture;
+}
+
+/// Runs the sessions on the tokio runtime they're started from.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Runtime for Tokio {
+    fn spawn(&self, future: BoxFuture) {
+        tokio::spawn(future);
+    }
+
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(f);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Runs the sessions on threads of their own, a thread per task: for the sessions that outlive the executor of the
+/// process, like the one of [`#[heappy::main]`](macro@crate::main) (with the `macros` feature), which starts before
+/// it and ends after it's shut down. Its sleeps only complete in its own tasks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Threads;
+
+thread_local! {
+    // Whether the thread runs a task of `Threads`, and when the earliest of the sleeps it's waiting on is over.
+    static IN_TASK: Cell<bool> = Cell::new(false);
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+impl Runtime for Threads {
+    fn spawn(&self, future: BoxFuture) {
+        let _ = thread::Builder::new()
+            .name("heappy-task".to_string())
+            .spawn(move || {
+                IN_TASK.with(|in_task| in_task.set(true));
+                block_on(future)
+            });
+    }
+
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+        // a task's thread is its own, nothing else waits on it.
+        if IN_TASK.with(Cell::get) {
+            return f();
+        }
+        let _ = thread::Builder::new()
+            .name("heappy-blocking".to_string())
+            .spawn(f);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        let deadline = Instant::now() + duration;
+        Box::pin(std::future::poll_fn(move |_| {
+            if Instant::now() >= deadline {
+                return Poll::Ready(());
+            }
+            DEADLINE.with(|next| {
+                next.set(Some(next.get().map_or(deadline, |next| next.min(deadline))))
+            });
+            Poll::Pending
+        }))
+    }
+}
+
+// Runs `future` to completion on the current thread, parking it in between: until woken, or until the earliest of the
+// sleeps of `Threads` it's waiting on is over.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = std::pin::pin!(future);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        match DEADLINE.with(Cell::take) {
+            Some(deadline) => {
+                thread::park_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => thread::park(),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Sentry profiles of the reports, see [`HeapReport::to_sentry_profile`](crate::HeapReport::to_sentry_profile): the
+//! sample format (version 1) of Sentry's profiling, whose samples are points in time on a thread, with the heap samples
+//! as the samples of a `heap` thread. Each stands for the sampling period of bytes (1KiB at least) allocated by its
+//! stack within a step of the timelines, spread over the step, so that the flame graphs of Sentry weigh the stacks by
+//! the bytes they allocated and its timeline shows when.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::collector::{MemProfileRecord, TIMELINE_RESOLUTION};
+use crate::memory::MemorySample;
+use crate::profiler::json_string;
+
+/// The Sentry transaction a profile is attached to, from the tracing integration of the Sentry SDK (its `event_id`
+/// and the `trace_id` of its context).
+#[derive(Clone, Debug, Default)]
+pub struct SentryTransaction {
+    /// The event id of the transaction, 32 hex digits.
+    pub id: String,
+    /// 32 hex digits.
+    pub trace_id: String,
+    pub name: String,
+    pub release: Option<String>,
+    pub environment: Option<String>,
+}
+
+// The bytes a sample stands for at least, so that exact sessions (a period of 1) don't get a sample per byte.
+const MIN_SAMPLE_BYTES: usize = 1024;
+
+// The id of the thread the samples are on.
+const THREAD: &str = "0";
+
+// The payload of a `profile` envelope item.
+pub(crate) fn profile<'a>(
+    transaction: &SentryTransaction,
+    started_at: SystemTime,
+    period: usize,
+    stacks: impl IntoIterator<Item = (&'a pprof::Frames, &'a MemProfileRecord)>,
+    memory: &[MemorySample],
+) -> String {
+    let unit = period.max(MIN_SAMPLE_BYTES) as i64;
+    let mut frames = Frames::default();
+    let mut stack_ids: HashMap<Vec<usize>, usize> = HashMap::new();
+    let mut samples = vec![];
+    for (stack, rec) in stacks {
+        let stack = frames.intern(stack);
+        let next = stack_ids.len();
+        let stack_id = *stack_ids.entry(stack).or_insert(next);
+        for &(step, bytes) in &rec.timeline {
+            let count = (bytes + unit - 1) / unit;
+            let at = TIMELINE_RESOLUTION * step;
+            for i in 0..count {
+                let within = TIMELINE_RESOLUTION.as_nanos() as i64 * i / count;
+                samples.push((at + Duration::from_nanos(within as u64), stack_id));
+            }
+        }
+    }
+    samples.sort();
+
+    let mut stacks: Vec<_> = stack_ids.into_iter().collect();
+    stacks.sort_by_key(|(_, id)| *id);
+    let stacks: Vec<_> = stacks
+        .into_iter()
+        .map(|(frames, _)| {
+            let frames: Vec<_> = frames.iter().map(|frame| frame.to_string()).collect();
+            format!("[{}]", frames.join(","))
+        })
+        .collect();
+    let samples: Vec<_> = samples
+        .into_iter()
+        .map(|(elapsed, stack_id)| {
+            format!(
+                r#"{{"stack_id":{},"thread_id":"{}","elapsed_since_start_ns":"{}"}}"#,
+                stack_id,
+                THREAD,
+                elapsed.as_nanos()
+            )
+        })
+        .collect();
+    let mut out = format!(
+        r#"{{"event_id":"{}","version":"1","platform":"rust","timestamp":"{}","#,
+        event_id(),
+        crate::dumps::rfc3339(started_at)
+    );
+    for (key, value) in [
+        ("release", &transaction.release),
+        ("environment", &transaction.environment),
+    ] {
+        if let Some(value) = value {
+            out += &format!("{}:{},", json_string(key), json_string(value));
+        }
+    }
+    out += &format!(
+        concat!(
+            r#""os":{{"name":{}}},"device":{{"architecture":{}}},"#,
+            r#""transaction":{{"id":{},"trace_id":{},"name":{},"active_thread_id":"{}"}},"#,
+        ),
+        json_string(std::env::consts::OS),
+        json_string(std::env::consts::ARCH),
+        json_string(&transaction.id),
+        json_string(&transaction.trace_id),
+        json_string(&transaction.name),
+        THREAD,
+    );
+    // the resident set size, if the session sampled it.
+    let footprint: Vec<_> = memory
+        .iter()
+        .filter_map(|sample| {
+            let rss = sample.rss_bytes?;
+            Some(format!(
+                r#"{{"elapsed_since_start_ns":"{}","value":{}}}"#,
+                sample.elapsed.as_nanos(),
+                rss
+            ))
+        })
+        .collect();
+    if !footprint.is_empty() {
+        out += &format!(
+            r#""measurements":{{"memory_footprint":{{"unit":"byte","values":[{}]}}}},"#,
+            footprint.join(",")
+        );
+    }
+    out += &format!(
+        r#""profile":{{"samples":[{}],"stacks":[{}],"frames":[{}],"thread_metadata":{{"{}":{{"name":"heap"}}}}}}}}"#,
+        samples.join(","),
+        stacks.join(","),
+        frames.json.join(","),
+        THREAD,
+    );
+    out
+}
+
+// The envelope item of a `profile`, its header line and the payload.
+pub(crate) fn item(profile: &str) -> String {
+    format!(
+        "{{\"type\":\"profile\",\"length\":{}}}\n{}\n",
+        profile.len(),
+        profile
+    )
+}
+
+// The frames of the stacks, each once.
+#[derive(Default)]
+struct Frames {
+    ids: HashMap<(String, Option<String>, Option<u32>), usize>,
+    json: Vec<String>,
+}
+
+impl Frames {
+    // The ids of the frames of `stack`, the innermost first, without the profiler's own.
+    fn intern(&mut self, stack: &pprof::Frames) -> Vec<usize> {
+        crate::callsite::past_hook(stack)
+            .into_iter()
+            .map(|symbol| {
+                let key = (
+                    symbol.name(),
+                    symbol
+                        .filename
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                    symbol.lineno,
+                );
+                let next = self.ids.len();
+                *self
+                    .ids
+                    .entry(key)
+                    .or_insert_with_key(|(name, file, line)| {
+                        let mut frame = format!(
+                            r#"{{"function":{},"in_app":{}"#,
+                            json_string(name),
+                            in_app(name)
+                        );
+                        if let Some(file) = file {
+                            frame += &format!(r#","filename":{}"#, json_string(file));
+                        }
+                        if let Some(line) = line {
+                            frame += &format!(r#","lineno":{}"#, line);
+                        }
+                        frame.push('}');
+                        self.json.push(frame);
+                        next
+                    })
+            })
+            .collect()
+    }
+}
+
+// Whether Sentry should count the function as the application's, for its grouping: not the standard library's.
+fn in_app(name: &str) -> bool {
+    let name = name.trim_start_matches('<');
+    !["std::", "core::", "alloc::", "__rust_"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+// A random event id, 32 hex digits.
+fn event_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    // the keys of each RandomState differ.
+    let random = || {
+        let mut hasher = RandomState::new().build_hasher();
+        let since = crate::clock::system_time().duration_since(SystemTime::UNIX_EPOCH);
+        hasher.write_u128(since.unwrap_or_default().as_nanos());
+        hasher.finish()
+    };
+    format!("{:016x}{:016x}", random(), random())
+}
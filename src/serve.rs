@@ -0,0 +1,606 @@
+//! A tiny web viewer for a profile: an interactive flamegraph, a top table and a per-line source view, to explore
+//! profiles without exporting them to other tools.
+//!
+//! ```ignore
+//! let report = guard.report().await;
+//! report.serve("127.0.0.1:6060").await?;
+//! ```
+//!
+//! `/weblist?fn=<regex>` shows the source of the top functions (the ones matching the regex) on one page, like
+//! `pprof -weblist`, and [`html`] (or [`HeapReport::write_html`]) writes a standalone page of the flamegraph, the
+//! top table and the source of the top functions, to attach to a bug report or archive with CI artifacts.
+//!
+//! It's meant for local use: there's no TLS, no authentication and the source view reads files from disk.
+//!
+//! [`serve_session`] serves the running session instead, like Go's `net/http/pprof`:
+//!
+//! ```text
+//! go tool pprof http://127.0.0.1:6060/debug/pprof/heap?seconds=30
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::protos;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::compress::Compression;
+use crate::flamechart::escape;
+use crate::pprof_io::Stacks;
+use crate::profiler::{HeapReport, Profiler, ReportFormat};
+
+const TOP_FUNCTIONS: usize = 100;
+// functions shown with their source in the weblist and the standalone pages.
+const TOP_SOURCES: usize = 10;
+const MAX_REQUEST: usize = 16 * 1024;
+// lines shown around the sampled lines of a function in the source view.
+const SOURCE_CONTEXT: i64 = 5;
+
+impl HeapReport {
+    /// Serves this report on `addr` until the future is dropped, see [`serve`].
+    pub async fn serve(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        serve(self.pprof(), addr).await
+    }
+
+    /// Writes this report as a standalone HTML page, see [`html`].
+    pub fn write_html<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        let html =
+            html(&self.pprof(), None).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        writer.write_all(html.as_bytes())
+    }
+}
+
+/// Serves `profile` on `addr` until the future is dropped.
+pub async fn serve(profile: protos::Profile, addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let profile = Arc::new(profile);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let profile = Arc::clone(&profile);
+        tokio::spawn(async move {
+            // a client hanging up is none of our business.
+            let _ = handle(stream, &profile).await;
+        });
+    }
+}
+
+/// Serves the running session on `addr` until the future is dropped: `GET /debug/pprof/heap` is a gzipped pprof
+/// profile of the session so far, and `/debug/pprof/heap?seconds=N` the [delta](HeapReport::delta) of the next `N`
+/// seconds.
+pub async fn serve_session(addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let _ = handle_session(stream).await;
+        });
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn html(body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: message.as_bytes().to_vec(),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, profile: &protos::Profile) -> io::Result<()> {
+    let Some(target) = read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let response = match target {
+        Some(target) => {
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            route(profile, path, &parse_query(query))
+        }
+        None => Response::error("405 Method Not Allowed", "only GET is supported"),
+    };
+    respond(stream, response).await
+}
+
+async fn handle_session(mut stream: TcpStream) -> io::Result<()> {
+    let Some(target) = read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let response = match target {
+        Some(target) => {
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            match path {
+                "/debug/pprof/heap" => heap(&parse_query(query)).await,
+                _ => Response::error("404 Not Found", "not found"),
+            }
+        }
+        None => Response::error("405 Method Not Allowed", "only GET is supported"),
+    };
+    respond(stream, response).await
+}
+
+async fn heap(query: &HashMap<String, String>) -> Response {
+    let seconds = match query.get("seconds").map(|s| s.parse::<u64>()) {
+        None => 0,
+        Some(Ok(seconds)) => seconds,
+        Some(Err(_)) => return Response::error("400 Bad Request", "invalid seconds"),
+    };
+    if !Profiler::enabled() {
+        return Response::error(
+            "503 Service Unavailable",
+            "no heap profiling session is running",
+        );
+    }
+    let report = match seconds {
+        0 => crate::task::unblock(HeapReport::snapshot).await,
+        seconds => HeapReport::delta(Duration::from_secs(seconds)).await,
+    };
+    // symbolizing is slow.
+    let profile = crate::task::unblock(move || {
+        report.write_compressed(ReportFormat::Pprof, Compression::Gzip, vec![])
+    })
+    .await;
+    match profile {
+        Ok(body) => Response {
+            status: "200 OK",
+            content_type: "application/octet-stream",
+            body,
+        },
+        Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+    }
+}
+
+// The target of a GET request, none for other methods; none at all if the client hung up.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Option<String>>> {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    Ok(Some(match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => Some(target.to_string()),
+        _ => None,
+    }))
+}
+
+async fn respond(mut stream: TcpStream, response: Response) -> io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+fn route(profile: &protos::Profile, path: &str, query: &HashMap<String, String>) -> Response {
+    let sample_type = query.get("type").map(String::as_str);
+    let stacks = match Stacks::from_profile(profile, sample_type) {
+        Ok(stacks) => stacks,
+        Err(e) => return Response::error("404 Not Found", &e.to_string()),
+    };
+    match path {
+        "/" => Response::html(index(
+            profile,
+            &stacks,
+            query.get("sort").map(String::as_str),
+        )),
+        "/flamegraph.svg" => match flamegraph(&stacks) {
+            Ok(svg) => Response {
+                status: "200 OK",
+                content_type: "image/svg+xml",
+                body: svg,
+            },
+            Err(e) => Response::error("500 Internal Server Error", &e),
+        },
+        "/source" => match query.get("fn") {
+            Some(function) => Response::html(source(profile, &stacks, function)),
+            None => Response::error("400 Bad Request", "missing fn"),
+        },
+        "/weblist" => match weblist(profile, &stacks, query.get("fn").map(String::as_str)) {
+            Ok(html) => Response::html(html),
+            Err(e) => Response::error("400 Bad Request", &e),
+        },
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
+
+fn index(profile: &protos::Profile, stacks: &Stacks, sort: Option<&str>) -> String {
+    let selected = encode(&stacks.sample_type);
+    let mut html = page_header("heappy");
+
+    html.push_str("<p>sample type:");
+    for st in &profile.sample_type {
+        let name = lookup(profile, st.ty);
+        let _ = if name == stacks.sample_type {
+            write!(html, " <b>{}</b>", escape(name))
+        } else {
+            write!(
+                html,
+                " <a href=\"/?type={}\">{}</a>",
+                encode(name),
+                escape(name)
+            )
+        };
+    }
+    let _ = write!(
+        html,
+        " | <a href=\"/weblist?type={t}\">source of the top functions</a></p>\n\
+         <object type=\"image/svg+xml\" data=\"/flamegraph.svg?type={t}\" width=\"100%\"></object>\n",
+        t = selected
+    );
+
+    let funcs = top(stacks, sort == Some("cum"));
+    let header = format!(
+        "<th><a href=\"/?type={t}\">flat</a></th><th>flat%</th><th><a href=\"/?type={t}&sort=cum\">cum</a></th>\
+         <th>cum%</th><th>function</th>",
+        t = selected
+    );
+    write_top(&mut html, stacks, &funcs, &header, |name| {
+        Some(format!("/source?type={}&fn={}", selected, encode(name)))
+    });
+    html.push_str("</body></html>\n");
+    html
+}
+
+// The functions by flat value (or by cum), the largest first.
+fn top(stacks: &Stacks, by_cum: bool) -> Vec<(&str, (i64, i64))> {
+    let mut funcs: Vec<_> = stacks.flat_and_cum().into_iter().collect();
+    funcs.sort_by(|a, b| {
+        let key = |(_, (flat, cum)): &(&str, (i64, i64))| {
+            if by_cum {
+                (*cum, *flat)
+            } else {
+                (*flat, *cum)
+            }
+        };
+        key(b).cmp(&key(a)).then(a.0.cmp(b.0))
+    });
+    funcs
+}
+
+// The table of the `TOP_FUNCTIONS` first `funcs`, under `header`, with the pages `link` has for them.
+fn write_top(
+    html: &mut String,
+    stacks: &Stacks,
+    funcs: &[(&str, (i64, i64))],
+    header: &str,
+    link: impl Fn(&str) -> Option<String>,
+) {
+    let total = stacks.total();
+    let percent = |v: i64| {
+        if total == 0 {
+            0.0
+        } else {
+            v as f64 * 100.0 / total as f64
+        }
+    };
+    let _ = write!(
+        html,
+        "<h2>top functions, total {}</h2>\n<table>\n<tr>{}</tr>\n",
+        stacks.format_value(total),
+        header
+    );
+    for &(name, (flat, cum)) in funcs.iter().take(TOP_FUNCTIONS) {
+        let function = match link(name) {
+            Some(href) => format!("<a href=\"{}\">{}</a>", href, escape(name)),
+            None => escape(name),
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{:.2}%</td><td>{}</td><td>{:.2}%</td><td>{}</td></tr>",
+            stacks.format_value(flat),
+            percent(flat),
+            stacks.format_value(cum),
+            percent(cum),
+            function
+        );
+    }
+    html.push_str("</table>\n");
+}
+
+fn flamegraph(stacks: &Stacks) -> Result<Vec<u8>, String> {
+    // the flamegraph can't draw negative widths.
+    let lines: Vec<String> = stacks
+        .counts
+        .iter()
+        .filter(|(_, v)| **v > 0)
+        .map(|(stack, value)| format!("{} {}", stack.join(";"), value))
+        .collect();
+    let mut options = pprof::flamegraph::Options::default();
+    options.title = stacks.sample_type.clone();
+    options.count_name = stacks.unit.clone();
+    options.colors =
+        pprof::flamegraph::color::Palette::Basic(pprof::flamegraph::color::BasicPalette::Mem);
+    let mut svg = vec![];
+    pprof::flamegraph::from_lines(&mut options, lines.iter().map(String::as_str), &mut svg)
+        .map_err(|e| e.to_string())?;
+    Ok(svg)
+}
+
+fn source(profile: &protos::Profile, stacks: &Stacks, function: &str) -> String {
+    let mut html = page_header(function);
+    let _ = writeln!(
+        html,
+        "<p><a href=\"/?type={}\">back</a></p>",
+        encode(&stacks.sample_type)
+    );
+    write_source(&mut html, profile, stacks, function, "");
+    html.push_str("</body></html>\n");
+    html
+}
+
+// The source of the `TOP_SOURCES` top functions matching `pattern` (all of them without one) on one page, like
+// `pprof -weblist`.
+fn weblist(
+    profile: &protos::Profile,
+    stacks: &Stacks,
+    pattern: Option<&str>,
+) -> Result<String, String> {
+    let pattern = pattern
+        .filter(|pattern| !pattern.is_empty())
+        .map(regex::Regex::new)
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    let mut html = page_header("heappy source");
+    let _ = writeln!(
+        html,
+        "<p><a href=\"/?type={}\">back</a></p>",
+        encode(&stacks.sample_type)
+    );
+    let funcs = top(stacks, false);
+    let matching = funcs
+        .iter()
+        .filter(|(name, _)| pattern.as_ref().map_or(true, |re| re.is_match(name)))
+        .take(TOP_SOURCES);
+    for (i, (name, _)) in matching.enumerate() {
+        write_source(&mut html, profile, stacks, name, &format!("source-{}", i));
+    }
+    html.push_str("</body></html>\n");
+    Ok(html)
+}
+
+/// A standalone HTML page of `profile`, without a server to browse it: the flamegraph, the top functions and the
+/// source of the top ones, annotated with their values by line (see [`HeapReport::write_html`]). The values are the
+/// ones of `sample_type`, the profile's default without it.
+pub fn html(
+    profile: &protos::Profile,
+    sample_type: Option<&str>,
+) -> Result<String, crate::pprof_io::Error> {
+    let stacks = Stacks::from_profile(profile, sample_type)?;
+    let mut html = page_header("heappy");
+    // the flamegraph is left out if it can't be drawn, the rest of the page is still worth it.
+    if let Ok(svg) = flamegraph(&stacks) {
+        let svg = String::from_utf8_lossy(&svg);
+        // inline, without its XML declaration and doctype.
+        html.push_str(&svg[svg.find("<svg").unwrap_or_default()..]);
+        html.push('\n');
+    }
+    let funcs = top(&stacks, false);
+    let sources: HashMap<&str, usize> = funcs
+        .iter()
+        .take(TOP_SOURCES)
+        .enumerate()
+        .map(|(i, (name, _))| (*name, i))
+        .collect();
+    write_top(
+        &mut html,
+        &stacks,
+        &funcs,
+        "<th>flat</th><th>flat%</th><th>cum</th><th>cum%</th><th>function</th>",
+        |name| sources.get(name).map(|i| format!("#source-{}", i)),
+    );
+    for (i, (name, _)) in funcs.iter().take(TOP_SOURCES).enumerate() {
+        write_source(&mut html, profile, &stacks, name, &format!("source-{}", i));
+    }
+    html.push_str("</body></html>\n");
+    Ok(html)
+}
+
+// The flat and cum values of the lines of `function`, by file, annotated on the source if we can read it, under a
+// heading with the `id` (if any) to link to.
+fn write_source(
+    html: &mut String,
+    profile: &protos::Profile,
+    stacks: &Stacks,
+    function: &str,
+    id: &str,
+) {
+    let idx = profile
+        .sample_type
+        .iter()
+        .position(|st| lookup(profile, st.ty) == stacks.sample_type)
+        .unwrap_or_default();
+    let ids: HashSet<u64> = profile
+        .function
+        .iter()
+        .filter(|f| lookup(profile, f.name) == function)
+        .map(|f| f.id)
+        .collect();
+    let filenames: HashMap<u64, &str> = profile
+        .function
+        .iter()
+        .map(|f| (f.id, lookup(profile, f.filename)))
+        .collect();
+    let locations: HashMap<u64, &protos::Location> =
+        profile.location.iter().map(|l| (l.id, l)).collect();
+
+    let mut files: HashMap<&str, HashMap<i64, (i64, i64)>> = HashMap::new();
+    for sample in &profile.sample {
+        let value = sample.value.get(idx).copied().unwrap_or_default();
+        let mut seen = HashSet::new();
+        let lines = sample
+            .location_id
+            .iter()
+            .filter_map(|id| locations.get(id))
+            .flat_map(|loc| loc.line.iter());
+        // the first line of the first location is where the allocation happened.
+        for (i, line) in lines.enumerate() {
+            if !ids.contains(&line.function_id) {
+                continue;
+            }
+            let file = filenames
+                .get(&line.function_id)
+                .copied()
+                .unwrap_or_default();
+            let entry = files.entry(file).or_default().entry(line.line).or_default();
+            if i == 0 {
+                entry.0 += value;
+            }
+            if seen.insert((file, line.line)) {
+                entry.1 += value;
+            }
+        }
+    }
+
+    let _ = if id.is_empty() {
+        writeln!(html, "<h2>{}</h2>", escape(function))
+    } else {
+        writeln!(html, "<h2 id=\"{}\">{}</h2>", id, escape(function))
+    };
+    if files.is_empty() {
+        html.push_str("<p>no line information for this function</p>\n");
+    }
+    let mut files: Vec<_> = files.into_iter().collect();
+    files.sort_by_key(|(file, _)| *file);
+    for (file, lines) in files {
+        let _ = writeln!(
+            html,
+            "<h3>{}</h3>\n<table>\n<tr><th>flat</th><th>cum</th><th>line</th><th></th></tr>",
+            escape(file)
+        );
+        let source = std::fs::read_to_string(file).ok();
+        let numbers: Vec<i64> = match &source {
+            Some(source) => {
+                let first = lines.keys().min().copied().unwrap_or(1);
+                let last = lines.keys().max().copied().unwrap_or(1);
+                let count = source.lines().count() as i64;
+                ((first - SOURCE_CONTEXT).max(1)..=(last + SOURCE_CONTEXT).min(count)).collect()
+            }
+            None => {
+                let mut numbers: Vec<i64> = lines.keys().copied().collect();
+                numbers.sort_unstable();
+                numbers
+            }
+        };
+        let text: Vec<&str> = source
+            .as_deref()
+            .map(|s| s.lines().collect())
+            .unwrap_or_default();
+        for number in numbers {
+            let (flat, cum) = lines.get(&number).copied().unwrap_or_default();
+            let value = |v: i64| {
+                if v == 0 {
+                    String::new()
+                } else {
+                    stacks.format_value(v)
+                }
+            };
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>",
+                value(flat),
+                value(cum),
+                number,
+                // a line 0 is one the debug info doesn't know.
+                escape(
+                    (number as usize)
+                        .checked_sub(1)
+                        .and_then(|i| text.get(i))
+                        .copied()
+                        .unwrap_or_default()
+                )
+            );
+        }
+        html.push_str("</table>\n");
+    }
+}
+
+fn page_header(title: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>\
+         body {{ font-family: sans-serif; }} td, th {{ padding: 0 .5em; text-align: right; }} \
+         td:last-child {{ text-align: left; }} pre {{ margin: 0; }}</style></head><body>\n",
+        escape(title)
+    )
+}
+
+fn lookup(profile: &protos::Profile, idx: i64) -> &str {
+    profile
+        .string_table
+        .get(idx as usize)
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+// Percent-encodes everything but unreserved characters.
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+fn decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    decoded.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
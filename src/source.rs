@@ -0,0 +1,77 @@
+//! Heap sources, the arena, bump and pool allocators whose memory the hooks only see as the chunks they acquire, if
+//! at all (the ones of `mmap`, or of a pool set aside at startup, they don't): a [`HeapSource`] reports its chunks
+//! itself, sampled into the session like the allocations of the hooks, on the stack of the code making the arena
+//! grow and labeled with the arena's owner.
+//!
+//! ```ignore
+//! impl heappy::HeapSource for QueryArena {
+//!     fn name(&self) -> &str {
+//!         "query_arena"
+//!     }
+//! }
+//!
+//! // a chunk of the global allocator: acquired with the hooks ignoring it.
+//! let chunk = arena.acquire(CHUNK_SIZE, || allocate_chunk(CHUNK_SIZE));
+//! ...
+//! arena.release(CHUNK_SIZE, || free_chunk(chunk));
+//!
+//! // a chunk of mmap, which the hooks don't see.
+//! arena.acquired(mapped.len());
+//! ...
+//! arena.released(mapped.len());
+//! ```
+
+use crate::profiler::Profiler;
+
+/// The label of the owner of a heap source.
+pub const SOURCE_LABEL: &str = "heap_source";
+
+/// An allocator keeping memory of its own, reporting the chunks it acquires and releases to the session.
+///
+/// The methods are never inlined, so that the stacks start at their callers: the code of the arena.
+pub trait HeapSource {
+    /// The owner of the memory, the [`SOURCE_LABEL`] of its samples, e.g. `query_arena`.
+    fn name(&self) -> &str;
+
+    /// Reports that the source acquired a chunk of `size` bytes, from wherever the hooks don't see.
+    #[inline(never)]
+    fn acquired(&self, size: usize) {
+        Profiler::track_source(self.name(), size as i64);
+    }
+
+    /// Reports that the source released `size` bytes, e.g. the chunks an arena frees (or recycles) when it's reset.
+    #[inline(never)]
+    fn released(&self, size: usize) {
+        Profiler::track_source(self.name(), -(size as i64));
+    }
+
+    /// Runs `acquire`, which acquires a chunk of `size` bytes from the global allocator, with the hooks ignoring it,
+    /// and reports it instead: so that the chunk isn't counted twice, nor attributed to the arena's internals.
+    #[inline(never)]
+    fn acquire<R>(&self, size: usize, acquire: impl FnOnce() -> R) -> R
+    where
+        Self: Sized,
+    {
+        let chunk = hidden(acquire);
+        Profiler::track_source(self.name(), size as i64);
+        chunk
+    }
+
+    /// Runs `release`, which gives a chunk of `size` bytes of [`acquire`](Self::acquire) back to the global
+    /// allocator, with the hooks ignoring it, and reports it instead.
+    #[inline(never)]
+    fn release<R>(&self, size: usize, release: impl FnOnce() -> R) -> R
+    where
+        Self: Sized,
+    {
+        let released = hidden(release);
+        Profiler::track_source(self.name(), -(size as i64));
+        released
+    }
+}
+
+// Runs `f` with the hooks ignoring it, or as is if they're ignoring the thread already.
+fn hidden<R>(f: impl FnOnce() -> R) -> R {
+    let mut f = Some(f);
+    Profiler::untracked(|| (f.take().unwrap())()).unwrap_or_else(|| (f.take().unwrap())())
+}
@@ -0,0 +1,62 @@
+//! What the session did so far and what it cost, see [`Profiler::stats`](crate::Profiler::stats).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::profiler::{Profiler, HEAP_PROFILER_STATE};
+
+/// The counters of the running (or last) session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProfilerStats {
+    /// The samples flushed into the session, and the ones the backpressure policy dropped (see
+    /// [`Profiler::backpressure`]).
+    pub samples: u64,
+    pub dropped_samples: u64,
+    /// The batches of samples the threads flushed, to the collector thread or in place.
+    pub flushes: u64,
+    /// The stacks the session has records of, and the memory the records take, roughly.
+    pub stacks: usize,
+    pub collector_bytes: usize,
+    /// What each allocation costs the allocating threads, by estimate: the time they spent sampling (walking the
+    /// stacks and flushing the samples) spread over the allocations of the session.
+    pub overhead_per_allocation: Duration,
+}
+
+static FLUSHES: AtomicU64 = AtomicU64::new(0);
+static SAMPLING_NS: AtomicU64 = AtomicU64::new(0);
+
+impl Profiler {
+    /// The counters of the running (or last) session so far.
+    pub fn stats() -> ProfilerStats {
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let (samples, stacks, collector_bytes) = (
+            profiler.samples,
+            profiler.collector.len(),
+            profiler.footprint(),
+        );
+        let allocations = profiler.totals().allocated_objects.max(1) as u64;
+        std::mem::drop(profiler);
+        let sampling = SAMPLING_NS.load(Ordering::Relaxed);
+        ProfilerStats {
+            samples,
+            dropped_samples: Profiler::backpressure().dropped_samples,
+            flushes: FLUSHES.load(Ordering::Relaxed),
+            stacks,
+            collector_bytes,
+            overhead_per_allocation: Duration::from_nanos(sampling / allocations),
+        }
+    }
+}
+
+pub(crate) fn flushed() {
+    FLUSHES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn sampled(took: Duration) {
+    SAMPLING_NS.fetch_add(took.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn reset() {
+    FLUSHES.store(0, Ordering::Relaxed);
+    SAMPLING_NS.store(0, Ordering::Relaxed);
+}
@@ -0,0 +1,306 @@
+//! The samples of the sessions as they're flushed, for applications streaming them into their own pipelines, see
+//! [`Profiler::subscribe`](crate::Profiler::subscribe):
+//!
+//! ```ignore
+//! let mut samples = heappy::Profiler::subscribe();
+//! tokio::spawn(async move {
+//!     while let Some(sample) = samples.recv().await {
+//!         producer.send(&sample.functions().join(";"), sample.totals.allocated_bytes).await?;
+//!     }
+//! });
+//! ```
+//!
+//! The hooks only queue the samples: a `heappy-subscriptions` thread symbolizes them (each stack once) and wakes the
+//! subscribers, so that no executor is woken from inside an allocation.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::labels::Labels;
+use crate::profiler::{HeapTotals, Profiler, ProfilerBuffer, StackKey, MAX_DEPTH};
+
+// How many samples a subscriber that doesn't keep up is behind at most, the newer ones are dropped.
+const CAPACITY: usize = 65536;
+
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+// How many sessions have stopped: the subscriptions end with the session they were made in (or the next one).
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    static ref PENDING: spin::Mutex<Vec<Pending>> = Default::default();
+    static ref SUBSCRIBERS: spin::Mutex<Vec<Arc<Channel>>> = Default::default();
+    static ref DISPATCHER: Option<Thread> = spawn_dispatcher();
+}
+
+// A sample for the subscribers of `session`, or none once it stopped.
+struct Pending {
+    session: u64,
+    sample: Option<PendingSample>,
+}
+
+struct PendingSample {
+    key: StackKey<MAX_DEPTH>,
+    counters: [i64; 7],
+    elapsed: Duration,
+    at: Instant,
+}
+
+/// What a thread allocated (and freed) since its previous sample, attributed to the stack of the sample.
+#[derive(Clone, Debug)]
+pub struct SampleEvent {
+    /// Since the start of the session.
+    pub elapsed: Duration,
+    pub timestamp: SystemTime,
+    pub frames: pprof::Frames,
+    pub labels: Labels,
+    /// Frees are only tracked with the `measure_free` feature.
+    pub totals: HeapTotals,
+}
+
+impl SampleEvent {
+    /// The names of the functions of the stack, from the root.
+    pub fn functions(&self) -> Vec<String> {
+        crate::flamechart::stack(&self.frames)
+    }
+}
+
+struct Channel {
+    session: u64,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    samples: VecDeque<SampleEvent>,
+    closed: bool,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+impl Channel {
+    fn send(&self, sample: SampleEvent) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.samples.len() >= CAPACITY {
+            queue.dropped += 1;
+            return;
+        }
+        queue.samples.push_back(sample);
+        self.wake(queue);
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        self.wake(queue);
+    }
+
+    fn wake(&self, mut queue: std::sync::MutexGuard<'_, Queue>) {
+        let waker = queue.waker.take();
+        std::mem::drop(queue);
+        self.ready.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The samples flushed into the running session (or the next one, if none is running) until it stops.
+pub struct Subscription(Arc<Channel>);
+
+impl Subscription {
+    /// The next sample, none once the session has stopped and all its samples have been received.
+    pub async fn recv(&mut self) -> Option<SampleEvent> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Like [`recv`](Self::recv), for a `Stream` implementation: e.g.
+    /// `futures::stream::poll_fn(move |cx| subscription.poll_recv(cx))`.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<SampleEvent>> {
+        let mut queue = self.0.queue.lock().unwrap();
+        if let Some(sample) = queue.samples.pop_front() {
+            return Poll::Ready(Some(sample));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Like [`recv`](Self::recv), blocking the thread.
+    pub fn blocking_recv(&mut self) -> Option<SampleEvent> {
+        let mut queue = self.0.queue.lock().unwrap();
+        loop {
+            if let Some(sample) = queue.samples.pop_front() {
+                return Some(sample);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.0.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// How many samples were dropped because the subscriber was too far behind.
+    pub fn dropped(&self) -> u64 {
+        self.0.queue.lock().unwrap().dropped
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut subscribers = SUBSCRIBERS.lock();
+        subscribers.retain(|channel| !Arc::ptr_eq(channel, &self.0));
+        SUBSCRIBED.store(!subscribers.is_empty(), Ordering::SeqCst);
+    }
+}
+
+impl Profiler {
+    /// Subscribes to the samples of the running session (or of the next one) as they're flushed, see
+    /// [`FlushStrategy`](crate::FlushStrategy): what each thread allocated between two samples, by stack.
+    pub fn subscribe() -> Subscription {
+        lazy_static::initialize(&DISPATCHER);
+        let channel = Arc::new(Channel {
+            session: SESSIONS.load(Ordering::SeqCst),
+            queue: Default::default(),
+            ready: Condvar::new(),
+        });
+        if DISPATCHER.is_none() {
+            channel.close();
+        }
+        SUBSCRIBERS.lock().push(Arc::clone(&channel));
+        SUBSCRIBED.store(true, Ordering::SeqCst);
+        Subscription(channel)
+    }
+}
+
+// Called with each sample flushed into the session started at `started`, possibly from the hooks.
+pub(crate) fn record(
+    buffer: &ProfilerBuffer,
+    key: &StackKey<MAX_DEPTH>,
+    at: Instant,
+    started: Instant,
+) {
+    if !SUBSCRIBED.load(Ordering::Relaxed) {
+        return;
+    }
+    PENDING.lock().push(Pending {
+        session: SESSIONS.load(Ordering::SeqCst),
+        sample: Some(PendingSample {
+            key: key.clone(),
+            counters: buffer.counters(),
+            elapsed: at.saturating_duration_since(started),
+            at,
+        }),
+    });
+    notify();
+}
+
+// The session stopped: the subscriptions end once its samples are delivered.
+pub(crate) fn stop() {
+    let session = SESSIONS.fetch_add(1, Ordering::SeqCst);
+    if !SUBSCRIBED.load(Ordering::SeqCst) {
+        return;
+    }
+    let push = || {
+        PENDING.lock().push(Pending {
+            session,
+            sample: None,
+        })
+    };
+    // already untracked when stopped from inside the profiler.
+    Profiler::untracked(push).unwrap_or_else(push);
+    notify();
+}
+
+fn notify() {
+    if let Some(dispatcher) = DISPATCHER.as_ref() {
+        dispatcher.unpark();
+    }
+}
+
+fn spawn_dispatcher() -> Option<Thread> {
+    let thread = std::thread::Builder::new()
+        .name("heappy-subscriptions".to_string())
+        .spawn(|| {
+            // the stacks aren't symbolized again, nor are the allocations of the thread sampled.
+            Profiler::untracked(|| {
+                let mut symbolized: HashMap<StackKey<MAX_DEPTH>, pprof::Frames> = HashMap::new();
+                loop {
+                    std::thread::park();
+                    // nothing allocates under the lock, which the hooks take.
+                    let pending = std::mem::take(&mut *PENDING.lock());
+                    for pending in pending {
+                        dispatch(pending, &mut symbolized);
+                    }
+                    if !SUBSCRIBED.load(Ordering::SeqCst) {
+                        symbolized.clear();
+                    }
+                }
+            })
+        })
+        .ok()?;
+    Some(thread.thread().clone())
+}
+
+fn dispatch(pending: Pending, symbolized: &mut HashMap<StackKey<MAX_DEPTH>, pprof::Frames>) {
+    let Some(PendingSample {
+        key,
+        counters,
+        elapsed,
+        at,
+    }) = pending.sample
+    else {
+        let ended: Vec<_> = {
+            let mut subscribers = SUBSCRIBERS.lock();
+            let (ended, running) = std::mem::take(&mut *subscribers)
+                .into_iter()
+                .partition(|channel| channel.session <= pending.session);
+            *subscribers = running;
+            SUBSCRIBED.store(!subscribers.is_empty(), Ordering::SeqCst);
+            ended
+        };
+        for channel in ended {
+            channel.close();
+        }
+        return;
+    };
+    let subscribers: Vec<_> = SUBSCRIBERS
+        .lock()
+        .iter()
+        .filter(|channel| channel.session == pending.session)
+        .cloned()
+        .collect();
+    if subscribers.is_empty() {
+        return;
+    }
+    let frames = symbolized
+        .entry(key.clone())
+        .or_insert_with(|| key.frames.clone().into())
+        .clone();
+    let [objects, bytes, requested, freed_objects, freed_bytes, foreign_objects, foreign_bytes] =
+        counters;
+    let sample = SampleEvent {
+        elapsed,
+        timestamp: crate::clock::system_time() - crate::clock::now().saturating_duration_since(at),
+        frames,
+        labels: key.labels,
+        totals: HeapTotals {
+            allocated_objects: objects,
+            allocated_bytes: bytes,
+            requested_bytes: requested,
+            freed_objects,
+            freed_bytes,
+            foreign_freed_objects: foreign_objects,
+            foreign_freed_bytes: foreign_bytes,
+        },
+    };
+    for channel in subscribers {
+        channel.send(sample.clone());
+    }
+}
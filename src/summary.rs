@@ -0,0 +1,92 @@
+//! One-line summaries of the running session logged periodically with the `tracing` feature, see
+//! [`HeapProfilerGuardBuilder::log_summary`](crate::HeapProfilerGuardBuilder::log_summary), for some visibility
+//! without a profiling backend:
+//!
+//! ```text
+//! INFO heappy: heap: 12.40MiB/s allocated, 310.25MiB in use, top: app::cache::insert 180.00MiB, app::parse 64.12MiB
+//! ```
+
+use std::time::Duration;
+
+use crate::collector::MemProfileRecord;
+use crate::profiler::{Profiler, HEAP_PROFILER_STATE};
+use crate::task::{self, Task};
+
+// How many stacks a summary names.
+const TOP: usize = 3;
+
+// Logs a summary every `interval` until aborted.
+pub(crate) fn spawn(interval: Duration) -> Task<()> {
+    // the allocated bytes at the previous summary, and when it was.
+    let mut previous = None;
+    task::every(interval, move || {
+        let profiler = HEAP_PROFILER_STATE.read().unwrap();
+        let (totals, elapsed) = (profiler.totals(), profiler.elapsed());
+        // copied out, the flushes shouldn't wait for the symbolization.
+        let top = Profiler::untracked(|| {
+            let mut stacks: Vec<_> = profiler.collector.iter().collect();
+            stacks.sort_by_key(|(_, rec)| std::cmp::Reverse(weight(rec)));
+            stacks
+                .into_iter()
+                .take(TOP)
+                .map(|(key, rec)| (key.clone(), weight(rec)))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+        std::mem::drop(profiler);
+        // the first step is right away, with nothing to summarize yet.
+        let Some((allocated, at)) = previous.replace((totals.allocated_bytes, elapsed)) else {
+            return;
+        };
+        let since = elapsed.saturating_sub(at).as_secs_f64();
+        let rate = if since > 0.0 {
+            (totals.allocated_bytes - allocated) as f64 / since
+        } else {
+            0.0
+        };
+        let top = Profiler::untracked(|| {
+            top.into_iter()
+                .map(|(key, bytes)| {
+                    let frames: pprof::Frames = key.frames.into();
+                    let leaf = crate::callsite::leaf(&frames).map(|symbol| symbol.name());
+                    format!("{} {}", leaf.as_deref().unwrap_or("?"), format_bytes(bytes))
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+        tracing::info!(
+            target: "heappy",
+            alloc_bytes_per_sec = rate,
+            in_use_bytes = totals.in_use_bytes(),
+            "heap: {}/s allocated, {} in use, top: {}",
+            format_bytes(rate as i64),
+            format_bytes(totals.in_use_bytes()),
+            top
+        );
+    })
+}
+
+// What's still in use with the `measure_free` feature, what was allocated without.
+fn weight(rec: &MemProfileRecord) -> i64 {
+    #[cfg(feature = "measure_free")]
+    return rec.in_use_bytes();
+    #[cfg(not(feature = "measure_free"))]
+    return rec.alloc_bytes;
+}
+
+fn format_bytes(value: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut scaled = value.abs() as f64;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    let sign = if value < 0 { "-" } else { "" };
+    if unit == 0 {
+        format!("{}{}{}", sign, scaled, UNITS[unit])
+    } else {
+        format!("{}{:.2}{}", sign, scaled, UNITS[unit])
+    }
+}
@@ -0,0 +1,413 @@
+//! Symbolizes profiles written by [`UnsymbolizedHeapReport`](crate::UnsymbolizedHeapReport) after the fact, using the
+//! (possibly stripped) binaries and their separate debuginfo.
+//!
+//! For each mapping the debuginfo is looked up in every search path by build-id (`.build-id/ab/cdef….debug`, the
+//! layout of `/usr/lib/debug`), then by name (`<name>.debug`, `<name>`, the `<name>.dSYM` bundles of macOS) and finally
+//! through the binary's `.gnu_debuglink`, and with [`debuginfod`](Symbolizer::debuginfod) fetched from the debuginfod
+//! servers by build-id if it's in none of them. The binary itself is looked up at its recorded path, then by name in
+//! the search paths. The split DWARF of the binaries built with `-C split-debuginfo=packed` (or `unpacked`) is read
+//! from the `<name>.dwp` package next to them (or from the `.dwo` files where they were compiled).
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::protos;
+use object::{Object, ObjectSection, ObjectSegment};
+
+pub struct Symbolizer {
+    search_paths: Vec<PathBuf>,
+    debuginfod: bool,
+}
+
+/// What [`Symbolizer::symbolize`] managed to do, by mapping filename.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub symbolized: Vec<String>,
+    pub missing: Vec<String>,
+}
+
+impl Default for Symbolizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Symbolizer {
+    /// A symbolizer searching `/usr/lib/debug`.
+    pub fn new() -> Self {
+        Self {
+            search_paths: vec!["/usr/lib/debug".into()],
+            debuginfod: false,
+        }
+    }
+
+    /// Adds a directory to look for binaries and debuginfo files in.
+    pub fn search_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(path.into());
+        self
+    }
+
+    /// Fetches the debuginfo found in none of the search paths from the debuginfod servers of `DEBUGINFOD_URLS`
+    /// (separated by spaces) by build-id, with the `curl` command, off by default. The files are cached like elfutils'
+    /// client caches them, in `$DEBUGINFOD_CACHE_PATH`, `$XDG_CACHE_HOME/debuginfod_client` or
+    /// `~/.cache/debuginfod_client`.
+    pub fn debuginfod(mut self, enabled: bool) -> Self {
+        self.debuginfod = enabled;
+        self
+    }
+
+    /// Fills in the functions and lines of all the locations of `profile` that don't have any yet.
+    pub fn symbolize(&self, profile: &mut protos::Profile) -> Summary {
+        let mut strings: HashMap<String, i64> = profile
+            .string_table
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.clone(), i as i64))
+            .collect();
+        let mut functions: HashMap<(String, String), u64> = HashMap::new();
+        let mut next_function_id = profile.function.iter().map(|f| f.id).max().unwrap_or(0) + 1;
+        let mut summary = Summary::default();
+
+        for mapping in profile.mapping.iter_mut() {
+            let string = |idx: i64| profile.string_table[idx as usize].clone();
+            let filename = string(mapping.filename);
+            let build_id = string(mapping.build_id);
+            let Some(files) = self.find_files(&filename, &build_id) else {
+                summary.missing.push(filename);
+                continue;
+            };
+            let Some(resolver) = Resolver::new(&files) else {
+                summary.missing.push(filename);
+                continue;
+            };
+
+            let mut resolved = 0;
+            for location in profile.location.iter_mut() {
+                if location.mapping_id != mapping.id || !location.line.is_empty() {
+                    continue;
+                }
+                let offset = location.address - mapping.memory_start + mapping.file_offset;
+                for frame in resolver.resolve(offset) {
+                    let mut intern = |s: &str| {
+                        *strings.entry(s.to_owned()).or_insert_with(|| {
+                            profile.string_table.push(s.to_owned());
+                            profile.string_table.len() as i64 - 1
+                        })
+                    };
+                    let function_id = *functions
+                        .entry((frame.system_name.clone(), frame.filename.clone()))
+                        .or_insert_with(|| {
+                            let id = next_function_id;
+                            next_function_id += 1;
+                            profile.function.push(protos::Function {
+                                id,
+                                name: intern(&frame.name),
+                                system_name: intern(&frame.system_name),
+                                filename: intern(&frame.filename),
+                                ..protos::Function::default()
+                            });
+                            id
+                        });
+                    location.line.push(protos::Line {
+                        function_id,
+                        line: frame.line as i64,
+                    });
+                }
+                resolved += !location.line.is_empty() as usize;
+            }
+            // e.g. a stripped binary without its debuginfo around.
+            if resolved == 0 {
+                summary.missing.push(filename);
+                continue;
+            }
+
+            mapping.has_functions = true;
+            mapping.has_filenames = resolver.has_dwarf();
+            mapping.has_line_numbers = resolver.has_dwarf();
+            mapping.has_inline_frames = resolver.has_dwarf();
+            summary.symbolized.push(filename);
+        }
+        summary
+    }
+
+    fn find_files(&self, filename: &str, build_id: &str) -> Option<Files> {
+        let path = Path::new(filename);
+        let name = path.file_name()?;
+
+        let binary = std::iter::once(path.to_path_buf())
+            .chain(self.search_paths.iter().map(|dir| dir.join(name)))
+            .find_map(|path| Some((read_matching(&path, build_id)?, path)));
+        let (binary, binary_path) = binary.unzip();
+
+        let mut candidates = vec![];
+        for dir in &self.search_paths {
+            if build_id.len() > 2 {
+                candidates.push(
+                    dir.join(".build-id")
+                        .join(&build_id[..2])
+                        .join(format!("{}.debug", &build_id[2..])),
+                );
+            }
+            candidates.push(dir.join(format!("{}.debug", name.to_string_lossy())));
+            candidates.push(dir.join(name));
+            candidates.push(dsym(dir, name));
+        }
+        candidates.push(dsym(path.parent().unwrap_or(Path::new("/")), name));
+        if let Some(link) = binary.as_deref().and_then(debuglink) {
+            let dir = path.parent().unwrap_or(Path::new("/"));
+            candidates.push(dir.join(&link));
+            candidates.push(dir.join(".debug").join(&link));
+            candidates.extend(self.search_paths.iter().map(|d| d.join(&link)));
+        }
+        let debug = candidates
+            .iter()
+            .filter_map(|path| read_matching(path, build_id))
+            .find(|data| has_dwarf(data))
+            .or_else(|| {
+                self.debuginfod
+                    .then(|| debuginfod(build_id))
+                    .flatten()
+                    .filter(|data| has_dwarf(data))
+            });
+
+        if binary.is_none() && debug.is_none() {
+            return None;
+        }
+        Some(Files {
+            binary,
+            debug,
+            binary_path,
+        })
+    }
+}
+
+struct Files {
+    binary: Option<Vec<u8>>,
+    debug: Option<Vec<u8>>,
+    // where the binary is, the DWARF package of its split debuginfo next to it.
+    binary_path: Option<PathBuf>,
+}
+
+type Reader = gimli::EndianRcSlice<gimli::RunTimeEndian>;
+
+type SplitDwarfLoader = addr2line::builtin_split_dwarf_loader::SplitDwarfLoader<
+    Reader,
+    fn(Cow<'_, [u8]>, gimli::RunTimeEndian) -> Reader,
+>;
+
+struct Frame {
+    name: String,
+    system_name: String,
+    filename: String,
+    line: u32,
+}
+
+struct Resolver<'a> {
+    // used to map file offsets to addresses, the binary if we have it.
+    segments: Vec<(u64, u64, u64)>,
+    symbols: object::SymbolMap<object::SymbolMapName<'a>>,
+    context: Option<addr2line::Context<Reader>>,
+    split: RefCell<SplitDwarfLoader>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(files: &'a Files) -> Option<Self> {
+        let binary = files
+            .binary
+            .as_deref()
+            .and_then(|d| object::File::parse(d).ok());
+        let debug = files
+            .debug
+            .as_deref()
+            .and_then(|d| object::File::parse(d).ok());
+        let layout = binary.as_ref().or(debug.as_ref())?;
+        let segments = layout
+            .segments()
+            .map(|seg| {
+                let (offset, size) = seg.file_range();
+                (offset, size, seg.address())
+            })
+            .collect();
+
+        // a stripped binary has no symbols left, the debuginfo file has them.
+        let symbols = [debug.as_ref(), binary.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|f| f.symbol_map())
+            .find(|map| !map.symbols().is_empty())
+            .unwrap_or_else(|| layout.symbol_map());
+        let dwarf = debug.as_ref().or(binary
+            .as_ref()
+            .filter(|f| section_has_data(f, ".debug_info")));
+        let context = dwarf.and_then(|f| addr2line::Context::new(f).ok());
+        // without a binary, no package: not the current executable's, which the loader defaults to.
+        let binary_path = Some(files.binary_path.clone().unwrap_or_default());
+        let split = SplitDwarfLoader::new(load_section, binary_path);
+
+        Some(Self {
+            segments,
+            symbols,
+            context,
+            split: RefCell::new(split),
+        })
+    }
+
+    fn has_dwarf(&self) -> bool {
+        self.context.is_some()
+    }
+
+    // Resolves an offset in the mapped file, innermost inlined function first.
+    fn resolve(&self, offset: u64) -> Vec<Frame> {
+        let Some(addr) = self
+            .segments
+            .iter()
+            .find(|(start, size, _)| *start <= offset && offset < start + size)
+            .map(|(start, _, vaddr)| offset - start + vaddr)
+        else {
+            return vec![];
+        };
+        let symbol = self.symbols.get(addr).map(|s| s.name());
+
+        let mut frames = vec![];
+        if let Some(context) = &self.context {
+            let lookup = self.split.borrow_mut().run(context.find_frames(addr));
+            if let Ok(mut iter) = lookup {
+                while let Ok(Some(frame)) = iter.next() {
+                    let system_name = frame
+                        .function
+                        .as_ref()
+                        .and_then(|f| f.raw_name().ok().map(|n| n.into_owned()))
+                        .or_else(|| symbol.map(str::to_owned))
+                        .unwrap_or_default();
+                    let name = frame
+                        .function
+                        .as_ref()
+                        .and_then(|f| f.demangle().ok().map(|n| n.into_owned()))
+                        .unwrap_or_else(|| demangle(&system_name));
+                    frames.push(Frame {
+                        name,
+                        system_name,
+                        filename: frame
+                            .location
+                            .as_ref()
+                            .and_then(|l| l.file)
+                            .unwrap_or_default()
+                            .to_owned(),
+                        line: frame.location.as_ref().and_then(|l| l.line).unwrap_or(0),
+                    });
+                }
+            }
+        }
+        if frames.is_empty() {
+            if let Some(symbol) = symbol {
+                frames.push(Frame {
+                    name: demangle(symbol),
+                    system_name: symbol.to_owned(),
+                    filename: String::new(),
+                    line: 0,
+                });
+            }
+        }
+        frames
+    }
+}
+
+fn load_section(data: Cow<'_, [u8]>, endian: gimli::RunTimeEndian) -> Reader {
+    gimli::EndianRcSlice::new(Rc::from(&*data), endian)
+}
+
+// Where a `.dSYM` bundle in `dir` keeps the DWARF of the binary `name`.
+fn dsym(dir: &Path, name: &std::ffi::OsStr) -> PathBuf {
+    let mut bundle = name.to_os_string();
+    bundle.push(".dSYM");
+    dir.join(bundle).join("Contents/Resources/DWARF").join(name)
+}
+
+// The debuginfo of `build_id` from the cache of debuginfod files, fetched from the servers of `DEBUGINFOD_URLS` into
+// it if it's not there yet.
+fn debuginfod(build_id: &str) -> Option<Vec<u8>> {
+    if build_id.is_empty() || !build_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let env = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+    let cache = env("DEBUGINFOD_CACHE_PATH")
+        .map(PathBuf::from)
+        .or_else(|| env("XDG_CACHE_HOME").map(|dir| Path::new(&dir).join("debuginfod_client")))
+        .or_else(|| env("HOME").map(|home| Path::new(&home).join(".cache/debuginfod_client")))?;
+    let path = cache.join(build_id).join("debuginfo");
+    if let Some(data) = read_matching(&path, build_id) {
+        return Some(data);
+    }
+    std::fs::create_dir_all(path.parent()?).ok()?;
+    // renamed once complete, so that an interrupted download isn't taken for the file.
+    let partial = path.with_extension(format!("{}.partial", std::process::id()));
+    let urls = std::env::var("DEBUGINFOD_URLS").unwrap_or_default();
+    for url in urls.split_whitespace() {
+        let fetched = std::process::Command::new("curl")
+            .args([
+                "--fail",
+                "--silent",
+                "--location",
+                "--max-time",
+                "120",
+                "--output",
+            ])
+            .arg(&partial)
+            .arg(format!(
+                "{}/buildid/{}/debuginfo",
+                url.trim_end_matches('/'),
+                build_id
+            ))
+            .status()
+            .map_or(false, |status| status.success());
+        if fetched && read_matching(&partial, build_id).is_some() {
+            std::fs::rename(&partial, &path).ok()?;
+            return read_matching(&path, build_id);
+        }
+    }
+    let _ = std::fs::remove_file(&partial);
+    let _ = std::fs::remove_dir(path.parent()?);
+    None
+}
+
+fn demangle(name: &str) -> String {
+    addr2line::demangle_auto(name.into(), None).into_owned()
+}
+
+// Reads an object file, if it exists and has the expected build-id (when we know which one to expect).
+fn read_matching(path: &Path, build_id: &str) -> Option<Vec<u8>> {
+    let data = std::fs::read(path).ok()?;
+    let file = object::File::parse(&*data).ok()?;
+    if !build_id.is_empty() {
+        // the UUID of Mach-O files, which is what the mappings of macOS record.
+        let id = match file.build_id().ok()? {
+            Some(id) => id.to_vec(),
+            None => file.mach_uuid().ok()??.to_vec(),
+        };
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        if id != build_id {
+            return None;
+        }
+    }
+    std::mem::drop(file);
+    Some(data)
+}
+
+fn debuglink(data: &[u8]) -> Option<PathBuf> {
+    let file = object::File::parse(data).ok()?;
+    let (link, _crc) = file.gnu_debuglink().ok()??;
+    Some(PathBuf::from(String::from_utf8_lossy(link).into_owned()))
+}
+
+fn has_dwarf(data: &[u8]) -> bool {
+    object::File::parse(data).map_or(false, |f| section_has_data(&f, ".debug_info"))
+}
+
+// Sections stripped out into a debuginfo file are still there, but as NOBITS.
+fn section_has_data(file: &object::File, name: &str) -> bool {
+    file.section_by_name(name)
+        .map_or(false, |s| s.file_range().is_some())
+}
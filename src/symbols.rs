@@ -0,0 +1,173 @@
+//! The symbols of the frames of the running session, resolved ahead of the reports on a background thread, see
+//! [`HeapProfilerGuardBuilder::symbolize_in_background`](crate::HeapProfilerGuardBuilder::symbolize_in_background):
+//! the reports look the frames up, and only resolve the ones that are new since the last pass.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::profiler::{is_hidden, Profiler, HEAP_PROFILER_STATE};
+use crate::unwinder::StackFrame;
+
+// The frames resolved between two looks at whether the session stopped.
+const BATCH: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref SYMBOLS: spin::RwLock<HashMap<StackFrame, Vec<Symbol>>> = Default::default();
+}
+
+// A `pprof::Symbol`, whose address is a pointer, which can't be shared between threads.
+#[derive(Clone)]
+struct Symbol {
+    name: Option<Vec<u8>>,
+    addr: Option<usize>,
+    lineno: Option<u32>,
+    filename: Option<PathBuf>,
+}
+
+impl From<&pprof::Symbol> for Symbol {
+    fn from(symbol: &pprof::Symbol) -> Self {
+        Self {
+            name: symbol.name.clone(),
+            addr: symbol.addr.map(|addr| addr as usize),
+            lineno: symbol.lineno,
+            filename: symbol.filename.clone(),
+        }
+    }
+}
+
+impl From<&Symbol> for pprof::Symbol {
+    fn from(symbol: &Symbol) -> Self {
+        Self {
+            name: symbol.name.clone(),
+            addr: symbol.addr.map(|addr| addr as *mut std::ffi::c_void),
+            lineno: symbol.lineno,
+            filename: symbol.filename.clone(),
+        }
+    }
+}
+
+/// The thread resolving the symbols, stopped when dropped.
+pub(crate) struct Symbolizer {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Symbolizer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            // at most a batch away, and done before the next session clears the symbols.
+            let _ = thread.join();
+        }
+    }
+}
+
+// Resolves the new frames of the session every `interval`, until dropped.
+pub(crate) fn spawn(interval: Duration) -> Option<Symbolizer> {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread = thread::Builder::new()
+        .name("heappy-symbolizer".to_string())
+        .spawn({
+            let stopped = Arc::clone(&stopped);
+            move || {
+                // behind the application's threads: on linux the nice value is the thread's own.
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                unsafe {
+                    libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+                }
+                // the symbols aren't the session's.
+                Profiler::untracked(|| {
+                    while !stopped.load(Ordering::SeqCst) {
+                        step(&stopped);
+                        thread::park_timeout(interval);
+                    }
+                });
+            }
+        })
+        .ok()?;
+    Some(Symbolizer {
+        stopped,
+        thread: Some(thread),
+    })
+}
+
+/// Forgets the symbols of the previous session, whose libraries may have been unloaded since.
+pub(crate) fn clear() {
+    let symbols = std::mem::take(&mut *SYMBOLS.write());
+    // freed outside of the lock.
+    std::mem::drop(symbols);
+}
+
+fn step(stopped: &AtomicBool) {
+    let profiler = HEAP_PROFILER_STATE.read().unwrap();
+    let known = SYMBOLS.read();
+    let new: HashSet<StackFrame> = profiler
+        .collector
+        .iter()
+        .flat_map(|(key, _)| key.frames())
+        .filter(|frame| frame.ip != 0 && !known.contains_key(frame))
+        .copied()
+        .collect();
+    std::mem::drop(known);
+    // resolved without holding up the flushes.
+    std::mem::drop(profiler);
+    let new: Vec<_> = new.into_iter().collect();
+    for batch in new.chunks(BATCH) {
+        if stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let resolved: Vec<_> = batch
+            .iter()
+            .map(|frame| (*frame, resolve(frame).iter().map(Symbol::from).collect()))
+            .collect();
+        SYMBOLS.write().extend(resolved);
+    }
+}
+
+/// The symbols of `frame`, the innermost of its inlined functions first, without the hidden ones: resolved by the
+/// background thread if it got to them, right away otherwise.
+pub(crate) fn symbols(frame: &StackFrame) -> Vec<pprof::Symbol> {
+    if let Some(symbols) = SYMBOLS.read().get(frame) {
+        return symbols.iter().map(pprof::Symbol::from).collect();
+    }
+    resolve(frame)
+}
+
+fn resolve(frame: &StackFrame) -> Vec<pprof::Symbol> {
+    let mut symbols = Vec::new();
+    let mut resolved = false;
+    backtrace::resolve(frame.ip as *mut std::ffi::c_void, |symbol| {
+        if let Some(name) = symbol.name() {
+            resolved = true;
+            if !is_hidden(&format!("{:#}", name)) {
+                let mut symbol: pprof::Symbol = symbol.into();
+                // not every symbolizer has it, the one of the physical function is good enough to locate the code.
+                symbol.addr = symbol.addr.or(Some(frame.key() as *mut std::ffi::c_void));
+                symbols.push(symbol);
+            }
+        }
+    });
+    // the return address is right past the call, which can be the last instruction of the function.
+    let executable = (!resolved)
+        .then(|| crate::executable::resolve(frame.ip.saturating_sub(1)))
+        .flatten();
+    if let Some((name, addr)) = executable {
+        if !is_hidden(&format!(
+            "{:#}",
+            backtrace::SymbolName::new(name.as_bytes())
+        )) {
+            symbols.push(pprof::Symbol {
+                name: Some(name.as_bytes().to_vec()),
+                addr: Some(addr as *mut std::ffi::c_void),
+                lineno: None,
+                filename: None,
+            });
+        }
+    }
+    symbols
+}
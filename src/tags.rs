@@ -0,0 +1,106 @@
+//! Tags, the labels of allocator-adjacent code: a tag is a static string rather than a set of labels, so that
+//! tagging doesn't allocate, and the code of an arena or a cache can tell which of its allocations matter:
+//!
+//! ```ignore
+//! let _tag = heappy::tag_scope("cache_entry");
+//! heappy::tag_next_allocation("arena_chunk");
+//! let chunk = Vec::with_capacity(CHUNK_SIZE);
+//! ```
+//!
+//! The samples taken in a tag's scope have it as their `tag` label, the innermost one if they're nested. The tag of
+//! [`tag_next_allocation`] is only the next allocation's (on the thread, that the session sees): it's the label of
+//! the allocation's sample if it's sampled, in place of the scope's, and forgotten otherwise.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::labels::Labels;
+
+/// The label of the tag.
+pub const TAG_LABEL: &str = "tag";
+
+thread_local! {
+    // The tag in scope on the thread, and the one of its next allocation.
+    static SCOPE: Cell<Option<&'static str>> = Cell::new(None);
+    static NEXT: Cell<Option<&'static str>> = Cell::new(None);
+}
+
+/// RAII structure that restores the previous tag when dropped. See [`tag_scope`].
+#[must_use = "the tag is only in scope until the guard is dropped"]
+pub struct TagGuard {
+    prev: Option<&'static str>,
+    // the guard restores a thread local, it must be dropped on the thread that created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl TagGuard {
+    fn enter(tag: &'static str) -> Self {
+        let prev = SCOPE
+            .try_with(|scope| scope.replace(Some(tag)))
+            .ok()
+            .flatten();
+        TagGuard {
+            prev,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        let _ = SCOPE.try_with(|scope| scope.set(self.prev));
+    }
+}
+
+/// Tags the samples taken on the current thread with `tag` until the returned guard is dropped.
+///
+/// Don't hold the guard across an `.await`, use [`tagged`] for async code instead.
+pub fn tag_scope(tag: &'static str) -> TagGuard {
+    TagGuard::enter(tag)
+}
+
+/// Tags the next allocation of the current thread with `tag`, if it's sampled.
+pub fn tag_next_allocation(tag: &'static str) {
+    let _ = NEXT.try_with(|next| next.set(Some(tag)));
+}
+
+/// Wraps a future so that it's in the scope of `tag` whenever it is polled.
+pub fn tagged<F: Future>(tag: &'static str, future: F) -> Tagged<F> {
+    Tagged { tag, future }
+}
+
+pin_project_lite::pin_project! {
+    /// Future returned by [`tagged`].
+    pub struct Tagged<F> {
+        tag: &'static str,
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F: Future> Future for Tagged<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = TagGuard::enter(this.tag);
+        this.future.poll(cx)
+    }
+}
+
+// The tag of the allocation being tracked, forgotten by the thread. Called from the allocation hooks, so it must not
+// panic if the thread local is being torn down.
+pub(crate) fn take_next() -> Option<&'static str> {
+    NEXT.try_with(Cell::take).ok().flatten()
+}
+
+// `labels` with the tag in scope, if there's one.
+pub(crate) fn labels(labels: Labels) -> Labels {
+    match SCOPE.try_with(Cell::get).ok().flatten() {
+        Some(tag) => labels.with(TAG_LABEL, tag),
+        None => labels,
+    }
+}
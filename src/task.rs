@@ -0,0 +1,330 @@
+//! The background work of a session (the watchers, the warm-up and baseline timers) and the lock that keeps sessions
+//! from overlapping: spawned on the [`Runtime`](crate::Runtime) of the session with the `async` feature, on plain
+//! threads without it.
+
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+pub(crate) use self::executor::*;
+
+#[cfg(not(feature = "async"))]
+pub(crate) use self::threads::*;
+
+/// Held by the running session. Unlike a `MutexGuard` the guard can be sent to other threads, with the session.
+#[derive(Default)]
+pub(crate) struct Exclusive {
+    held: Mutex<bool>,
+    released: Condvar,
+}
+
+pub(crate) struct ExclusiveGuard(&'static Exclusive);
+
+impl Exclusive {
+    /// Blocks until the previous session ends.
+    pub(crate) fn lock(&'static self) -> ExclusiveGuard {
+        let mut held = self.held.lock().unwrap();
+        while *held {
+            held = self.released.wait(held).unwrap();
+        }
+        *held = true;
+        ExclusiveGuard(self)
+    }
+}
+
+impl Drop for ExclusiveGuard {
+    fn drop(&mut self) {
+        *self.0.held.lock().unwrap() = false;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(feature = "async")]
+mod executor {
+    use std::future::Future;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Poll, Waker};
+
+    use super::*;
+    use crate::runtime::Runtime;
+
+    lazy_static::lazy_static! {
+        // The runtime of the current (or last) session.
+        static ref RUNTIME: spin::RwLock<Option<Arc<dyn Runtime>>> = spin::RwLock::new(None);
+    }
+
+    pub(crate) fn set_runtime(runtime: Arc<dyn Runtime>) {
+        // the previous one is dropped outside of the lock.
+        let previous = RUNTIME.write().replace(runtime);
+        std::mem::drop(previous);
+    }
+
+    fn runtime() -> Arc<dyn Runtime> {
+        let runtime = RUNTIME.read().clone();
+        runtime.expect("no runtime: the session hasn't been built")
+    }
+
+    // A value sent once from one task to another, or never if the sender is dropped.
+    struct Shared<T> {
+        value: Option<T>,
+        done: bool,
+        waker: Option<Waker>,
+    }
+
+    struct Sender<T>(Arc<Mutex<Shared<T>>>);
+
+    struct Receiver<T>(Arc<Mutex<Shared<T>>>);
+
+    fn oneshot<T>() -> (Sender<T>, Receiver<T>) {
+        let shared = Arc::new(Mutex::new(Shared {
+            value: None,
+            done: false,
+            waker: None,
+        }));
+        (Sender(Arc::clone(&shared)), Receiver(shared))
+    }
+
+    impl<T> Sender<T> {
+        fn send(self, value: T) {
+            self.0.lock().unwrap().value = Some(value);
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            let mut shared = self.0.lock().unwrap();
+            shared.done = true;
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl<T> Receiver<T> {
+        fn is_done(&self) -> bool {
+            self.0.lock().unwrap().done
+        }
+
+        async fn recv(self) -> Option<T> {
+            std::future::poll_fn(|cx| {
+                let mut shared = self.0.lock().unwrap();
+                if shared.done {
+                    return Poll::Ready(shared.value.take());
+                }
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+            .await
+        }
+    }
+
+    #[derive(Default)]
+    struct Abort {
+        aborted: AtomicBool,
+        waker: Mutex<Option<Waker>>,
+    }
+
+    pub(crate) struct Task<T> {
+        result: Receiver<T>,
+        abort: Arc<Abort>,
+    }
+
+    impl<T> Task<T> {
+        pub(crate) fn abort(&self) {
+            self.abort.aborted.store(true, Ordering::SeqCst);
+            if let Some(waker) = self.abort.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+
+        pub(crate) fn is_finished(&self) -> bool {
+            self.result.is_done()
+        }
+
+        /// The result of the finished task, none before.
+        pub(crate) fn join(self) -> Option<T> {
+            self.result.0.lock().unwrap().value.take()
+        }
+    }
+
+    // Spawns the future `f` makes on the runtime, and gives it a sleep that returns false once the task is aborted.
+    fn spawn<T, F>(f: impl FnOnce(Arc<dyn Runtime>, Arc<Abort>) -> F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = Option<T>> + Send + 'static,
+    {
+        let runtime = runtime();
+        let abort = Arc::new(Abort::default());
+        let (sender, result) = oneshot();
+        let future = f(Arc::clone(&runtime), Arc::clone(&abort));
+        runtime.spawn(Box::pin(async move {
+            if let Some(value) = future.await {
+                sender.send(value);
+            }
+        }));
+        Task { result, abort }
+    }
+
+    // Sleeps for `duration`, false if aborted meanwhile.
+    async fn sleep(runtime: &dyn Runtime, duration: Duration, abort: &Abort) -> bool {
+        let mut sleep = runtime.sleep(duration);
+        std::future::poll_fn(|cx| {
+            // registered before checking, so that an abort in between still wakes the task.
+            *abort.waker.lock().unwrap() = Some(cx.waker().clone());
+            if abort.aborted.load(Ordering::SeqCst) {
+                return Poll::Ready(false);
+            }
+            sleep.as_mut().poll(cx).map(|()| true)
+        })
+        .await
+    }
+
+    // Runs the blocking `f` on the runtime; none if the runtime drops it, e.g. shutting down.
+    async fn run_blocking<T: Send + 'static>(
+        runtime: &dyn Runtime,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Option<T> {
+        let (sender, result) = oneshot();
+        runtime.spawn_blocking(Box::new(move || {
+            sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        }));
+        match result.recv().await? {
+            Ok(value) => Some(value),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Runs `step` right away, then every `interval` until aborted.
+    pub(crate) fn every(interval: Duration, mut step: impl FnMut() + Send + 'static) -> Task<()> {
+        spawn(move |runtime, abort| async move {
+            loop {
+                // the steps take the profiler's locks and symbolize, keep them off the executor.
+                step = run_blocking(&*runtime, move || {
+                    step();
+                    step
+                })
+                .await?;
+                if !sleep(&*runtime, interval, &abort).await {
+                    return None;
+                }
+            }
+        })
+    }
+
+    /// Runs `f` once `delay` has passed, unless aborted before.
+    pub(crate) fn after<T: Send + 'static>(
+        delay: Duration,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Task<T> {
+        spawn(move |runtime, abort| async move {
+            if !sleep(&*runtime, delay, &abort).await {
+                return None;
+            }
+            run_blocking(&*runtime, f).await
+        })
+    }
+
+    /// Waits for `duration` on the runtime of the session.
+    pub(crate) async fn delay(duration: Duration) {
+        runtime().sleep(duration).await
+    }
+
+    /// Runs the blocking `f` on the runtime of the session.
+    pub(crate) async fn unblock<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+        unblock_on(&*runtime(), f).await
+    }
+
+    /// Runs the blocking `f` on `runtime`.
+    pub(crate) async fn unblock_on<T: Send + 'static>(
+        runtime: &dyn Runtime,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> T {
+        run_blocking(runtime, f)
+            .await
+            .expect("the runtime dropped a blocking task of the heap profiler")
+    }
+}
+
+#[cfg(not(feature = "async"))]
+mod threads {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+
+    use super::*;
+
+    pub(crate) struct Task<T> {
+        thread: JoinHandle<Option<T>>,
+        aborted: Arc<AtomicBool>,
+    }
+
+    impl<T> Task<T> {
+        pub(crate) fn abort(&self) {
+            self.aborted.store(true, Ordering::SeqCst);
+            self.thread.thread().unpark();
+        }
+
+        pub(crate) fn is_finished(&self) -> bool {
+            self.thread.is_finished()
+        }
+
+        pub(crate) fn join(self) -> Option<T> {
+            self.thread.join().ok().flatten()
+        }
+    }
+
+    // Runs `f` on a new thread, which it passes a sleep that returns false once the task is aborted.
+    fn spawn<T: Send + 'static>(
+        f: impl FnOnce(&dyn Fn(Duration) -> bool) -> Option<T> + Send + 'static,
+    ) -> Task<T> {
+        let aborted = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("heappy".to_string())
+            .spawn({
+                let aborted = Arc::clone(&aborted);
+                move || {
+                    f(&|duration| {
+                        // none for `Duration::MAX`, which only ends with the session.
+                        let deadline = Instant::now().checked_add(duration);
+                        loop {
+                            if aborted.load(Ordering::SeqCst) {
+                                return false;
+                            }
+                            let Some(deadline) = deadline else {
+                                thread::park();
+                                continue;
+                            };
+                            let now = Instant::now();
+                            if now >= deadline {
+                                return true;
+                            }
+                            thread::park_timeout(deadline - now);
+                        }
+                    })
+                }
+            })
+            .expect("failed to spawn a heappy thread");
+        Task { thread, aborted }
+    }
+
+    /// Runs `step` right away, then every `interval` until aborted.
+    pub(crate) fn every(interval: Duration, mut step: impl FnMut() + Send + 'static) -> Task<()> {
+        spawn(move |sleep| loop {
+            step();
+            if !sleep(interval) {
+                return None;
+            }
+        })
+    }
+
+    /// Runs `f` once `delay` has passed, unless aborted before.
+    pub(crate) fn after<T: Send + 'static>(
+        delay: Duration,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Task<T> {
+        spawn(move |sleep| sleep(delay).then(f))
+    }
+}
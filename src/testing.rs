@@ -0,0 +1,404 @@
+//! Helpers for tests keeping hot paths free of heap allocations, or within a budget, and making assertions about what
+//! a function allocates.
+//!
+//! ```ignore
+//! #[test]
+//! fn lookup_does_not_allocate() {
+//!     heappy::dummy_force_link();
+//!     let table = Table::with_capacity(1024);
+//!     heappy::testing::assert_no_alloc(|| table.lookup(42));
+//!     heappy::assert_allocates_at_most!(bytes: 4096, objects: 10, {
+//!         table.insert(42, "answer");
+//!     });
+//! }
+//! ```
+//!
+//! Every allocation is recorded, as with a period of 1, by the `enable_heap_profiler` hooks or by a
+//! [`ProfilingAllocator`](crate::ProfilingAllocator); no session is needed. Only the calling thread is checked.
+//!
+//! [`capture`] runs a [deterministic](crate::HeapProfilerGuardBuilder::deterministic) session instead, and keeps
+//! only the samples of the function it's given:
+//!
+//! ```ignore
+//! let report = heappy::testing::capture(1, || parse(input));
+//! assert_eq!(report.stacks.len(), 1);
+//! assert!(report.allocated_by("my_crate::parse") <= 4096);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::labels::Labels;
+use crate::profiler::{HeapProfilerGuardBuilder, HeapReport, Profiler, StackKey, MAX_DEPTH};
+
+// The label telling the samples of a capture apart from the ones of other threads.
+const CAPTURE_LABEL: &str = "heappy.capture";
+
+// How many stacks the panics list.
+const MAX_STACKS: usize = 20;
+
+// Threads recording their allocations, so that the hooks don't need to look at their thread locals otherwise.
+static RECORDING: AtomicUsize = AtomicUsize::new(0);
+static CAPTURES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // The recordings the thread is in, the innermost last.
+    static RECORDINGS: RefCell<Vec<Recording>> = RefCell::new(vec![]);
+}
+
+// The bytes and objects allocated, by stack or only in total.
+enum Recording {
+    Stacks(HashMap<StackKey<MAX_DEPTH>, (u64, u64)>),
+    Totals(u64, u64),
+}
+
+impl Recording {
+    // What an inner recording saw is the outer one's too.
+    fn merge(&mut self, inner: &Recording) {
+        match (self, inner) {
+            (Recording::Stacks(outer), Recording::Stacks(inner)) => {
+                for (key, (bytes, objects)) in inner {
+                    let total = outer.entry(key.clone()).or_default();
+                    total.0 += bytes;
+                    total.1 += objects;
+                }
+            }
+            (Recording::Totals(bytes, objects), inner) => {
+                let (b, o) = inner.totals();
+                *bytes += b;
+                *objects += o;
+            }
+            // the stacks are gone, there's nothing to attribute the totals to.
+            (Recording::Stacks(_), Recording::Totals(..)) => {}
+        }
+    }
+
+    fn totals(&self) -> (u64, u64) {
+        match self {
+            Recording::Stacks(stacks) => stacks
+                .values()
+                .fold((0, 0), |(b, o), (bytes, objects)| (b + bytes, o + objects)),
+            Recording::Totals(bytes, objects) => (*bytes, *objects),
+        }
+    }
+}
+
+/// What a closure allocated on the heap, see [`allocations`].
+#[derive(Clone, Debug, Default)]
+pub struct Allocations {
+    /// The bytes asked for, a reallocation counting its new size.
+    pub bytes: u64,
+    pub objects: u64,
+    /// Where, the most bytes first.
+    pub stacks: Vec<StackAllocations>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StackAllocations {
+    pub frames: pprof::Frames,
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+impl fmt::Display for Allocations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes in {} allocations", self.bytes, self.objects)?;
+        for stack in self.stacks.iter().take(MAX_STACKS) {
+            write!(
+                f,
+                "\n  {} bytes in {} allocations at {}",
+                stack.bytes,
+                stack.objects,
+                crate::profiler::stack_names(&stack.frames)
+            )?;
+        }
+        if self.stacks.len() > MAX_STACKS {
+            write!(f, "\n  and {} more stacks", self.stacks.len() - MAX_STACKS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `f`, recording all its allocations with their stacks.
+pub fn allocations<R>(f: impl FnOnce() -> R) -> (R, Allocations) {
+    let (result, recording) = record(Recording::Stacks(HashMap::new()), f);
+    let Recording::Stacks(stacks) = recording else {
+        unreachable!()
+    };
+    let allocations = untracked(|| {
+        let mut stacks: Vec<_> = stacks
+            .into_iter()
+            .map(|(key, (bytes, objects))| StackAllocations {
+                frames: key.frames.into(),
+                bytes,
+                objects,
+            })
+            .collect();
+        stacks.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.objects.cmp(&a.objects)));
+        Allocations {
+            bytes: stacks.iter().map(|stack| stack.bytes).sum(),
+            objects: stacks.iter().map(|stack| stack.objects).sum(),
+            stacks,
+        }
+    });
+    (result, allocations)
+}
+
+// Runs `f`, only counting the bytes and objects it allocates: cheap enough to leave its timing alone.
+pub(crate) fn count<R>(f: impl FnOnce() -> R) -> (R, u64, u64) {
+    let (result, recording) = record(Recording::Totals(0, 0), f);
+    let (bytes, objects) = recording.totals();
+    (result, bytes, objects)
+}
+
+fn record<R>(recording: Recording, f: impl FnOnce() -> R) -> (R, Recording) {
+    struct Leave;
+
+    impl Drop for Leave {
+        fn drop(&mut self) {
+            RECORDING.fetch_sub(1, Ordering::Relaxed);
+            // f panicked, nothing's going to look at the recording.
+            if std::thread::panicking() {
+                untracked(|| RECORDINGS.with(|recordings| recordings.borrow_mut().pop()));
+            }
+        }
+    }
+
+    untracked(|| RECORDINGS.with(|recordings| recordings.borrow_mut().push(recording)));
+    RECORDING.fetch_add(1, Ordering::Relaxed);
+    let leave = Leave;
+    let result = f();
+    drop(leave);
+    let recording = untracked(|| {
+        RECORDINGS.with(|recordings| {
+            let mut recordings = recordings.borrow_mut();
+            let recording = recordings.pop().expect("recording");
+            if let Some(outer) = recordings.last_mut() {
+                outer.merge(&recording);
+            }
+            recording
+        })
+    });
+    (result, recording)
+}
+
+/// Runs `f` and panics, with the stacks of the allocations, if it allocated on the heap.
+///
+/// Panicking from the allocator itself isn't possible, so the panic comes once `f` has returned.
+#[track_caller]
+pub fn assert_no_alloc<R>(f: impl FnOnce() -> R) -> R {
+    let (result, allocations) = allocations(f);
+    if allocations.objects > 0 {
+        panic!("heap allocations inside assert_no_alloc: {}", allocations);
+    }
+    result
+}
+
+/// Runs `f` and panics, with the stacks of the allocations, if it allocated more than `bytes` or `objects`, see
+/// [`assert_allocates_at_most`](crate::assert_allocates_at_most).
+#[track_caller]
+pub fn assert_allocates_at_most<R>(bytes: u64, objects: u64, f: impl FnOnce() -> R) -> R {
+    let (result, allocations) = allocations(f);
+    if allocations.bytes > bytes || allocations.objects > objects {
+        // only the limits that were given.
+        let budget: Vec<_> = [(bytes, "bytes"), (objects, "allocations")]
+            .iter()
+            .filter(|(limit, _)| *limit != u64::MAX)
+            .map(|(limit, unit)| format!("{} {}", limit, unit))
+            .collect();
+        panic!(
+            "allocated more than {}: {}",
+            budget.join(" or "),
+            allocations
+        );
+    }
+    result
+}
+
+/// Fails if the block allocates more than the given bytes and/or objects, listing the allocations by stack.
+///
+/// ```ignore
+/// heappy::assert_allocates_at_most!(bytes: 4096, objects: 10, {
+///     parser.parse(input)
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_allocates_at_most {
+    (bytes: $bytes:expr, objects: $objects:expr, $body:block) => {
+        $crate::testing::assert_allocates_at_most($bytes, $objects, || $body)
+    };
+    (bytes: $bytes:expr, $body:block) => {
+        $crate::testing::assert_allocates_at_most($bytes, u64::MAX, || $body)
+    };
+    (objects: $objects:expr, $body:block) => {
+        $crate::testing::assert_allocates_at_most(u64::MAX, $objects, || $body)
+    };
+}
+
+/// What [`capture`] sampled.
+#[derive(Clone, Debug, Default)]
+pub struct TestReport {
+    pub period: usize,
+    pub allocated_bytes: i64,
+    pub allocated_objects: i64,
+    /// What's still allocated, with the `measure_free` feature, and the same as `allocated_bytes` otherwise.
+    pub in_use_bytes: i64,
+    /// The most allocated bytes first.
+    pub stacks: Vec<TestStack>,
+}
+
+/// A stack of a [`TestReport`].
+#[derive(Clone, Debug)]
+pub struct TestStack {
+    /// Function names from the root, like in [`HeapReport::write_folded`].
+    pub functions: Vec<String>,
+    /// The labels in scope, but the one of the capture.
+    pub labels: Labels,
+    pub allocated_bytes: i64,
+    /// What was asked for, which doesn't depend on the allocator unlike `allocated_bytes`.
+    pub requested_bytes: i64,
+    pub allocated_objects: i64,
+    pub in_use_bytes: i64,
+}
+
+impl TestReport {
+    fn new(report: &HeapReport, period: usize, capture: &str) -> Self {
+        let mut stacks: Vec<_> = report
+            .records()
+            .filter(|(_, labels, _)| labels.get(CAPTURE_LABEL) == Some(capture))
+            .map(|(frames, labels, rec)| TestStack {
+                functions: crate::flamechart::stack(frames),
+                labels: labels
+                    .iter()
+                    .filter(|(key, _)| *key != CAPTURE_LABEL)
+                    .collect(),
+                allocated_bytes: rec.allocated.granted_bytes,
+                requested_bytes: rec.allocated.requested_bytes,
+                allocated_objects: rec.allocated.objects,
+                #[cfg(feature = "measure_free")]
+                in_use_bytes: rec.in_use_bytes(),
+                #[cfg(not(feature = "measure_free"))]
+                in_use_bytes: rec.alloc_bytes,
+            })
+            .collect();
+        stacks.sort_by(|a, b| {
+            (b.allocated_bytes.cmp(&a.allocated_bytes)).then_with(|| a.functions.cmp(&b.functions))
+        });
+        Self {
+            period,
+            allocated_bytes: stacks.iter().map(|stack| stack.allocated_bytes).sum(),
+            allocated_objects: stacks.iter().map(|stack| stack.allocated_objects).sum(),
+            in_use_bytes: stacks.iter().map(|stack| stack.in_use_bytes).sum(),
+            stacks,
+        }
+    }
+
+    /// The `n` stacks that allocated the most.
+    pub fn top(&self, n: usize) -> &[TestStack] {
+        &self.stacks[..n.min(self.stacks.len())]
+    }
+
+    /// The stacks like [`HeapReport::write_normalized`] writes them, e.g. for `insta::assert_snapshot!`.
+    pub fn normalized(&self) -> String {
+        let mut out = vec![];
+        let stacks = self.stacks.iter().map(|stack| {
+            let functions = stack.functions.clone();
+            (functions, stack.requested_bytes, stack.allocated_objects)
+        });
+        // writing to a Vec doesn't fail.
+        let _ = crate::normalize::write(&mut out, stacks);
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    /// The bytes allocated by the stacks going through a function whose name contains `function`.
+    pub fn allocated_by(&self, function: &str) -> i64 {
+        self.stacks
+            .iter()
+            .filter(|stack| stack.functions.iter().any(|name| name.contains(function)))
+            .map(|stack| stack.allocated_bytes)
+            .sum()
+    }
+}
+
+/// Profiles `f` in a deterministic session sampling every `period` bytes, in memory. Only the allocations of the
+/// calling thread are kept, not the ones of the threads `f` spawns nor of other tests running meanwhile; a session
+/// already running is waited for.
+///
+/// Panics if the session can't be started.
+#[cfg(not(feature = "async"))]
+pub fn capture(period: usize, f: impl FnOnce()) -> TestReport {
+    let (labels, capture) = capture_labels();
+    let guard = capture_session(period)
+        .build()
+        .expect("cannot start a heap profiling session");
+    {
+        let _scope = crate::label_scope(labels);
+        f();
+    }
+    TestReport::new(&guard.report(), period, &capture)
+}
+
+/// Profiles `f` in a deterministic session sampling every `period` bytes, in memory. Only the allocations made while
+/// polling `f` are kept, not the ones of the tasks it spawns nor of other tests running meanwhile; a session already
+/// running is waited for.
+///
+/// Panics if the session can't be started.
+#[cfg(feature = "async")]
+pub async fn capture(period: usize, f: impl std::future::Future<Output = ()>) -> TestReport {
+    let (labels, capture) = capture_labels();
+    let guard = capture_session(period)
+        .build()
+        .await
+        .expect("cannot start a heap profiling session");
+    crate::labeled(labels, f).await;
+    TestReport::new(&guard.report().await, period, &capture)
+}
+
+fn capture_labels() -> (Labels, String) {
+    let capture = CAPTURES.fetch_add(1, Ordering::Relaxed).to_string();
+    (Labels::new().with(CAPTURE_LABEL, capture.clone()), capture)
+}
+
+fn capture_session(period: usize) -> HeapProfilerGuardBuilder {
+    HeapProfilerGuardBuilder::default()
+        .period(period)
+        .deterministic(true)
+}
+
+// Runs `f` without recording its allocations.
+pub(crate) fn untracked<R>(f: impl FnOnce() -> R) -> R {
+    let mut f = Some(f);
+    // inside the profiler already, its allocations aren't recorded either.
+    Profiler::untracked(|| f.take().unwrap()()).unwrap_or_else(|| f.take().unwrap()())
+}
+
+// Called by the hooks for each allocation (not for the frees) of `size` bytes.
+pub(crate) fn allocating(size: usize) {
+    #[cfg(feature = "tracing_layer")]
+    crate::layer::allocating(size);
+    if RECORDING.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    // the profiler's own allocations don't count.
+    Profiler::untracked(|| {
+        // the thread locals are gone once the thread is exiting.
+        let _ = RECORDINGS.try_with(|recordings| {
+            let mut recordings = recordings.borrow_mut();
+            match recordings.last_mut() {
+                Some(Recording::Stacks(stacks)) => {
+                    // SAFETY: called from the hooks, on the allocating thread.
+                    let total = stacks.entry(unsafe { StackKey::capture() }).or_default();
+                    total.0 += size as u64;
+                    total.1 += 1;
+                }
+                Some(Recording::Totals(bytes, objects)) => {
+                    *bytes += size as u64;
+                    *objects += 1;
+                }
+                None => {}
+            }
+        });
+    });
+}
@@ -0,0 +1,74 @@
+//! The distributed trace of each sample as labels, see
+//! [`HeapProfilerGuardBuilder::trace_context`](crate::HeapProfilerGuardBuilder::trace_context), so that a spike in
+//! a profile leads back to the requests that caused it (`pprof -tagfocus trace_id=...`).
+
+use std::sync::Arc;
+
+use crate::labels::Labels;
+
+/// The label of the trace id, 32 hex digits.
+pub const TRACE_ID_LABEL: &str = "trace_id";
+/// The label of the span id, 16 hex digits.
+pub const SPAN_ID_LABEL: &str = "span_id";
+
+/// Where the current thread is in a distributed trace, with the widths of the W3C trace context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceIds {
+    pub trace_id: u128,
+    /// Left out, the samples of a trace are aggregated over its spans.
+    pub span_id: Option<u64>,
+}
+
+/// Tells the trace of the allocating thread, e.g. with `tracing-opentelemetry`:
+///
+/// ```ignore
+/// use opentelemetry::trace::TraceContextExt;
+/// use tracing_opentelemetry::OpenTelemetrySpanExt;
+///
+/// let guard = HeapProfilerGuardBuilder::default()
+///     .trace_context(|| {
+///         let context = tracing::Span::current().context();
+///         let span = context.span();
+///         let span = span.span_context();
+///         span.is_valid().then(|| heappy::TraceIds {
+///             trace_id: u128::from_be_bytes(span.trace_id().to_bytes()),
+///             span_id: Some(u64::from_be_bytes(span.span_id().to_bytes())),
+///         })
+///     })
+///     .build()?;
+/// ```
+///
+/// The labels of a sample are part of its stack, so each trace (each span, with their ids) makes new stacks: profiles
+/// get as large as the number of traces sampled, which is best kept to short sessions or a low sampling rate.
+pub trait TraceContext: Send + Sync {
+    /// The trace the current thread is in, if any.
+    ///
+    /// Called by the allocating thread for each sample, with the profiler's own allocations disabled: it can allocate,
+    /// but nothing it does is recorded. It may run within the code it asks, e.g. while `tracing` itself allocates.
+    fn current(&self) -> Option<TraceIds>;
+}
+
+impl<F: Fn() -> Option<TraceIds> + Send + Sync> TraceContext for F {
+    fn current(&self) -> Option<TraceIds> {
+        self()
+    }
+}
+
+lazy_static::lazy_static! {
+    pub(crate) static ref TRACE_CONTEXT: spin::RwLock<Option<Arc<dyn TraceContext>>> = Default::default();
+}
+
+// `labels` with those of the current trace, if asked for.
+pub(crate) fn labels(labels: Labels) -> Labels {
+    let Some(context) = TRACE_CONTEXT.read().clone() else {
+        return labels;
+    };
+    let Some(ids) = context.current() else {
+        return labels;
+    };
+    let labels = labels.with(TRACE_ID_LABEL, format!("{:032x}", ids.trace_id));
+    match ids.span_id {
+        Some(span_id) => labels.with(SPAN_ID_LABEL, format!("{:016x}", span_id)),
+        None => labels,
+    }
+}
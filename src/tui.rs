@@ -0,0 +1,295 @@
+//! A live terminal view of the running heap profiler, like `htop` for the heap.
+//!
+//! It runs in the profiled process and takes over its terminal, so it's meant for load tests and local debugging:
+//!
+//! ```ignore
+//! let _guard = heappy::HeapProfilerGuard::new(4096).await?;
+//! heappy::tui::spawn(Duration::from_secs(1));
+//! ```
+//!
+//! Keys: `q`/`Esc` quit, `↑`/`↓` select a stack, `s` toggles sorting between in-use and allocated bytes.
+
+use std::io;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table, TableState};
+
+use crate::collector::MemProfileRecord;
+use crate::profiler::{HeapTotals, LiveSnapshot};
+
+const TOP_STACKS: usize = 50;
+
+// Frames of the allocator and of the profiler itself, skipped when looking for the allocation site.
+const ALLOCATOR_FRAMES: &[&str] = &[
+    "backtrace::",
+    "heappy::",
+    "alloc::",
+    "<alloc::",
+    "core::",
+    "std::alloc::",
+    "__rust",
+    "__rdl_",
+    "malloc",
+    "calloc",
+    "realloc",
+    "aligned_alloc",
+    "posix_memalign",
+];
+
+/// Runs the viewer on the current thread until a quit key is pressed, refreshing every `refresh`.
+///
+/// It blocks, call it from a dedicated thread (see [`spawn`]) rather than from async code.
+pub fn run(refresh: Duration) -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    let res = App::new(refresh).run(&mut terminal);
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    res
+}
+
+/// Runs the viewer on a background thread.
+pub fn spawn(refresh: Duration) -> JoinHandle<io::Result<()>> {
+    std::thread::Builder::new()
+        .name("heappy-tui".to_string())
+        .spawn(move || run(refresh))
+        .expect("failed to spawn the heappy-tui thread")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortBy {
+    InUse,
+    Allocated,
+}
+
+impl SortBy {
+    fn weight(self) -> fn(&MemProfileRecord) -> i64 {
+        match self {
+            #[cfg(feature = "measure_free")]
+            SortBy::InUse => |rec| rec.in_use_bytes(),
+            #[cfg(not(feature = "measure_free"))]
+            SortBy::InUse => |rec| rec.alloc_bytes,
+            SortBy::Allocated => |rec| rec.allocated.granted_bytes,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            SortBy::InUse => "in use",
+            SortBy::Allocated => "allocated",
+        }
+    }
+}
+
+struct App {
+    refresh: Duration,
+    sort_by: SortBy,
+    snapshot: LiveSnapshot,
+    previous: Option<(Instant, HeapTotals)>,
+    // allocated bytes and objects per second since the previous refresh.
+    rate: (f64, f64),
+    table: TableState,
+}
+
+impl App {
+    fn new(refresh: Duration) -> Self {
+        Self {
+            refresh,
+            sort_by: SortBy::InUse,
+            snapshot: LiveSnapshot::take(TOP_STACKS, SortBy::InUse.weight()),
+            previous: None,
+            rate: (0.0, 0.0),
+            table: TableState::default().with_selected(Some(0)),
+        }
+    }
+
+    fn update(&mut self) {
+        self.snapshot = LiveSnapshot::take(TOP_STACKS, self.sort_by.weight());
+        let now = Instant::now();
+        let totals = self.snapshot.totals;
+        if let Some((then, previous)) = self.previous {
+            let elapsed = now.duration_since(then).as_secs_f64();
+            // a new session resets the totals.
+            if elapsed > 0.0 && totals.allocated_bytes >= previous.allocated_bytes {
+                self.rate = (
+                    (totals.allocated_bytes - previous.allocated_bytes) as f64 / elapsed,
+                    (totals.allocated_objects - previous.allocated_objects) as f64 / elapsed,
+                );
+            }
+        }
+        self.previous = Some((now, totals));
+        if self.snapshot.top.is_empty() {
+            self.table.select(Some(0));
+        } else if self.table.selected().unwrap_or(0) >= self.snapshot.top.len() {
+            self.table.select(Some(self.snapshot.top.len() - 1));
+        }
+    }
+
+    fn run<B: Backend>(mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        let mut last_update = Instant::now();
+        loop {
+            terminal.draw(|f| self.draw(f))?;
+
+            let timeout = self.refresh.saturating_sub(last_update.elapsed());
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    let selected = self.table.selected().unwrap_or(0);
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Up | KeyCode::Char('k') => {
+                            self.table.select(Some(selected.saturating_sub(1)))
+                        }
+                        KeyCode::Down | KeyCode::Char('j') => self.table.select(Some(
+                            (selected + 1).min(self.snapshot.top.len().saturating_sub(1)),
+                        )),
+                        KeyCode::Char('s') => {
+                            self.sort_by = match self.sort_by {
+                                SortBy::InUse => SortBy::Allocated,
+                                SortBy::Allocated => SortBy::InUse,
+                            };
+                            self.update();
+                            last_update = Instant::now();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if last_update.elapsed() >= self.refresh {
+                self.update();
+                last_update = Instant::now();
+            }
+        }
+    }
+
+    fn draw(&mut self, f: &mut Frame) {
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(4),
+                Constraint::Min(5),
+                Constraint::Length(12),
+            ])
+            .split(f.size());
+
+        let totals = self.snapshot.totals;
+        let status = if self.snapshot.enabled {
+            "profiling"
+        } else {
+            "not profiling"
+        };
+        let header = vec![
+            Line::from(format!(
+                "{}  in use: {} ({} objects)  stacks: {}",
+                status,
+                format_bytes(totals.in_use_bytes() as f64),
+                totals.in_use_objects(),
+                self.snapshot.stacks,
+            )),
+            Line::from(format!(
+                "allocated: {} ({} objects)  rate: {}/s ({:.0} objects/s)",
+                format_bytes(totals.allocated_bytes as f64),
+                totals.allocated_objects,
+                format_bytes(self.rate.0),
+                self.rate.1,
+            )),
+        ];
+        f.render_widget(
+            Paragraph::new(header).block(Block::default().borders(Borders::ALL).title("heappy")),
+            layout[0],
+        );
+
+        let rows = self.snapshot.top.iter().map(|(frames, labels, rec)| {
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            Row::new(vec![
+                format_bytes((self.sort_by.weight())(rec) as f64),
+                rec.alloc_objects.to_string(),
+                allocation_site(frames),
+                labels.join(","),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(12),
+                Constraint::Length(10),
+                Constraint::Percentage(60),
+                Constraint::Percentage(40),
+            ],
+        )
+        .header(
+            Row::new(vec![
+                self.sort_by.title(),
+                "samples",
+                "allocation site",
+                "labels",
+            ])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("top stacks by {} bytes", self.sort_by.title())),
+        );
+        f.render_stateful_widget(table, layout[1], &mut self.table);
+
+        let stack: Vec<Line> = self
+            .table
+            .selected()
+            .and_then(|idx| self.snapshot.top.get(idx))
+            .map(|(frames, _, _)| {
+                frames
+                    .frames
+                    .iter()
+                    .flatten()
+                    .map(|symbol| Line::from(symbol.name()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        f.render_widget(
+            Paragraph::new(stack).block(Block::default().borders(Borders::ALL).title("stack")),
+            layout[2],
+        );
+    }
+}
+
+// The innermost frame that doesn't belong to the allocator or to the profiler.
+fn allocation_site(frames: &pprof::Frames) -> String {
+    frames
+        .frames
+        .iter()
+        .flatten()
+        .map(|symbol| symbol.name())
+        .find(|name| {
+            !ALLOCATOR_FRAMES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn format_bytes(value: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut scaled = value;
+    let mut unit = 0;
+    while scaled.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", scaled, UNITS[unit])
+}
@@ -0,0 +1,147 @@
+//! Best-effort attribution of samples to the type that was allocated, guessed from the standard library frames right
+//! above the allocator (e.g. `alloc::raw_vec::RawVec<u8>` or `alloc::string::String::push`).
+//!
+//! The generic arguments are only known when the symbols have them: with the legacy mangling scheme most frames only
+//! tell the container (`Vec`), the v0 scheme (`-C symbol-mangling-version=v0`) also tells the element type.
+
+// Path prefixes of the containers, and how to call them.
+const CONTAINERS: &[(&str, &str)] = &[
+    ("alloc::raw_vec::RawVec", "Vec"),
+    ("alloc::vec::Vec", "Vec"),
+    ("alloc::string::String", "String"),
+    ("alloc::string::ToString", "String"),
+    ("alloc::str::", "String"),
+    ("alloc::fmt::format", "String"),
+    ("alloc::ffi::c_str::CString", "CString"),
+    ("alloc::boxed::Box", "Box"),
+    ("alloc::sync::Arc", "Arc"),
+    ("alloc::rc::Rc", "Rc"),
+    ("alloc::collections::vec_deque::VecDeque", "VecDeque"),
+    ("alloc::collections::btree::map::BTreeMap", "BTreeMap"),
+    ("alloc::collections::btree::set::BTreeSet", "BTreeSet"),
+    ("alloc::collections::binary_heap::BinaryHeap", "BinaryHeap"),
+    ("std::collections::hash::map::HashMap", "HashMap"),
+    ("std::collections::hash::set::HashSet", "HashSet"),
+    ("hashbrown::map::HashMap", "HashMap"),
+    ("hashbrown::set::HashSet", "HashSet"),
+    ("hashbrown::raw::RawTable", "HashMap"),
+];
+
+// Containers that allocate through another one, (outer, inner): the outer frame tells more.
+const WRAPPERS: &[(&str, &str)] = &[
+    ("String", "Vec"),
+    ("CString", "Vec"),
+    ("VecDeque", "Vec"),
+    ("BinaryHeap", "Vec"),
+    ("HashSet", "HashMap"),
+    ("BTreeSet", "BTreeMap"),
+];
+
+// Frames that may sit between the allocation and the container that asked for it.
+const LIBRARY_FRAMES: &[&str] = &[
+    "alloc::",
+    "core::",
+    "std::",
+    "hashbrown::",
+    "heappy::",
+    "backtrace::",
+    "malloc",
+    "calloc",
+    "realloc",
+    "__rust",
+    "__rdl_",
+    "_rjem_",
+];
+
+/// The type allocated by a (symbolized) stack, if the frames give it away.
+///
+/// Walks outwards from the allocator through the standard library frames and stops at the first container found, unless
+/// the next ones tell more: e.g. `String::push` wins over the `Vec` it's made of, but a `Vec` collected from an
+/// iterator doesn't win over the allocations of its elements.
+pub fn allocated_type(frames: &pprof::Frames) -> Option<String> {
+    let mut found: Option<(&str, Option<String>)> = None;
+    for name in frames.frames.iter().flatten().map(|symbol| symbol.name()) {
+        // trait impls, e.g. `<u8 as alloc::vec::spec_from_elem::SpecFromElem>::from_elem`.
+        let (self_type, path) = match name.strip_prefix('<').and_then(|n| n.split_once(" as ")) {
+            Some((self_type, path)) => (Some(self_type), path),
+            None => (None, name.trim_start_matches('<')),
+        };
+        let from_elem = self_type
+            .filter(|t| path.starts_with("alloc::vec::spec_from_elem::") && is_concrete(t))
+            .map(|t| ("Vec", Some(t.to_string())));
+        let matched = from_elem.or_else(|| {
+            self_type.into_iter().chain([path]).find_map(|target| {
+                let (prefix, container) = CONTAINERS.iter().find(|(p, _)| target.starts_with(p))?;
+                let rest = &target[prefix.len()..];
+                // the table holds `(K, V)` tuples.
+                let args = if prefix.ends_with("RawTable") {
+                    generic_args(rest)
+                        .map(|args| args.trim_matches(|c| c == '(' || c == ')').to_string())
+                } else {
+                    generic_args(rest)
+                };
+                Some((*container, args))
+            })
+        });
+        if let Some((container, args)) = matched {
+            match &found {
+                None => found = Some((container, args)),
+                Some((inner, inner_args)) if *inner == container => {
+                    if inner_args.is_none() && args.is_some() {
+                        found = Some((container, args));
+                    }
+                }
+                Some((inner, _)) if WRAPPERS.contains(&(container, *inner)) => {
+                    found = Some((container, args))
+                }
+                Some(_) => break,
+            }
+            continue;
+        }
+        if !LIBRARY_FRAMES.iter().any(|prefix| path.starts_with(prefix)) {
+            break;
+        }
+    }
+    found.map(|(container, args)| match args {
+        Some(args) => format!("{}<{}>", container, args),
+        None => container.to_string(),
+    })
+}
+
+// The concrete generic arguments at the start of `rest` (e.g. `<u8, alloc::alloc::Global>::push`), without the
+// allocator and the hasher.
+fn generic_args(rest: &str) -> Option<String> {
+    let inner = rest.strip_prefix('<')?;
+    let mut depth = 0;
+    let mut args = vec![];
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '>' if depth == 0 => {
+                args.push(inner[start..i].trim());
+                break;
+            }
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let args: Vec<_> = args
+        .into_iter()
+        .filter(|arg| !arg.ends_with("::Global") && !arg.ends_with("::RandomState"))
+        .collect();
+    if args.is_empty() || !args.iter().all(|arg| is_concrete(arg)) {
+        return None;
+    }
+    Some(args.join(", "))
+}
+
+// Legacy symbols keep the names of the type parameters, e.g. `RawVec<T,A>`.
+fn is_concrete(ty: &str) -> bool {
+    !(ty.len() <= 2 && ty.chars().all(|c| c.is_ascii_uppercase()))
+}
@@ -0,0 +1,212 @@
+//! The ways of walking the stack of an allocation, see
+//! [`HeapProfilerGuardBuilder::unwinder`](crate::HeapProfilerGuardBuilder::unwinder). Each one trades between what
+//! it needs from the binaries and how much a sample costs:
+//!
+//! - [`Backtrace`], the default, unwinds with the DWARF unwind tables through `backtrace-rs`: it works with any
+//!   binary, but it's the slowest.
+//! - [`FramePointers`] follows the chain of frame pointers, which is much cheaper, but everything on the stack must
+//!   keep them (`-C force-frame-pointers=yes`, and C libraries built with `-fno-omit-frame-pointer`): the walk stops
+//!   at the first function that doesn't.
+//! - [`LibUnwind`] (`libunwind` feature, Linux) uses the caching unwinder of libunwind.
+//!
+//! Samples are told apart by their functions with [`Backtrace`], and by their return addresses with the others,
+//! which don't know where the functions start: the same stack can show up as a few ones calling from different
+//! lines. [`HeapProfilerGuardBuilder::key_by_ip`](crate::HeapProfilerGuardBuilder::key_by_ip) tells them apart by
+//! their return addresses with [`Backtrace`] too.
+
+use std::sync::Arc;
+
+/// A frame of the stack being walked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StackFrame {
+    /// The address the frame returns to.
+    pub ip: usize,
+    /// The start of the function, 0 if the unwinder can't tell.
+    pub function: usize,
+}
+
+impl StackFrame {
+    // What samples are aggregated by.
+    pub(crate) fn key(&self) -> usize {
+        if self.function != 0 {
+            self.function
+        } else {
+            self.ip
+        }
+    }
+
+    // The frame as it's kept in a stack: without its function when the stacks are told apart by their return
+    // addresses, see HeapProfilerGuardBuilder::key_by_ip.
+    pub(crate) fn keyed(&self, by_ip: bool) -> Self {
+        if by_ip {
+            StackFrame {
+                ip: self.ip,
+                function: 0,
+            }
+        } else {
+            *self
+        }
+    }
+}
+
+/// Walks the stack of the current thread, from within the allocator.
+pub trait Unwinder: Send + Sync {
+    /// Calls `frame` for each of the `max` innermost frames of the stack (or fewer), innermost first, until it returns
+    /// false.
+    ///
+    /// # Safety
+    ///
+    /// Called with the profiler's own allocations disabled, by the thread that's allocating: it can allocate, but
+    /// nothing it does is recorded.
+    unsafe fn trace(&self, max: usize, frame: &mut dyn FnMut(&StackFrame) -> bool);
+}
+
+lazy_static::lazy_static! {
+    pub(crate) static ref UNWINDER: spin::RwLock<Arc<dyn Unwinder>> = spin::RwLock::new(Arc::new(Backtrace));
+}
+
+/// Unwinds with `backtrace-rs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Backtrace;
+
+impl Unwinder for Backtrace {
+    unsafe fn trace(&self, max: usize, frame: &mut dyn FnMut(&StackFrame) -> bool) {
+        let mut left = max;
+        backtrace::trace_unsynchronized(|f| {
+            left = left.saturating_sub(1);
+            frame(&f.into()) && left > 0
+        });
+    }
+}
+
+impl From<&backtrace::Frame> for StackFrame {
+    fn from(frame: &backtrace::Frame) -> Self {
+        Self {
+            ip: frame.ip() as usize,
+            function: frame.symbol_address() as usize,
+        }
+    }
+}
+
+/// Follows the frame pointers.
+#[cfg(all(
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FramePointers;
+
+#[cfg(all(
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+))]
+impl Unwinder for FramePointers {
+    #[inline(never)]
+    unsafe fn trace(&self, max: usize, frame: &mut dyn FnMut(&StackFrame) -> bool) {
+        let mut fp: usize;
+        #[cfg(target_arch = "x86_64")]
+        std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack));
+        #[cfg(target_arch = "aarch64")]
+        std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+
+        // a function without a frame pointer leaves anything in the register: only read within the stack.
+        let (low, high) = stack_bounds();
+        // the previous frame pointer, then the return address.
+        let mut left = max;
+        while left > 0
+            && fp >= low
+            && fp.saturating_add(16) <= high
+            && fp % std::mem::align_of::<usize>() == 0
+        {
+            let next = *(fp as *const usize);
+            let ip = *((fp + 8) as *const usize);
+            if ip == 0 || !frame(&StackFrame { ip, function: 0 }) {
+                break;
+            }
+            left -= 1;
+            // the stack grows down, callers are higher up.
+            if next <= fp {
+                break;
+            }
+            fp = next;
+        }
+    }
+}
+
+// The range of the stack of the current thread, cached per thread.
+#[cfg(all(
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+))]
+fn stack_bounds() -> (usize, usize) {
+    use std::cell::Cell;
+
+    thread_local!(static BOUNDS: Cell<(usize, usize)> = Cell::new((0, 0)));
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let current = || unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return (0, 0);
+        }
+        let (mut addr, mut size) = (std::ptr::null_mut(), 0);
+        let res = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        if res != 0 {
+            return (0, 0);
+        }
+        (addr as usize, addr as usize + size)
+    };
+    #[cfg(target_os = "macos")]
+    let current = || unsafe {
+        let thread = libc::pthread_self();
+        let high = libc::pthread_get_stackaddr_np(thread) as usize;
+        (high - libc::pthread_get_stacksize_np(thread), high)
+    };
+    BOUNDS
+        .try_with(|bounds| {
+            if bounds.get() == (0, 0) {
+                bounds.set(current());
+            }
+            bounds.get()
+        })
+        .unwrap_or((0, 0))
+}
+
+/// Unwinds with libunwind's `unw_backtrace`.
+#[cfg(all(feature = "libunwind", target_os = "linux"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LibUnwind;
+
+#[cfg(all(feature = "libunwind", target_os = "linux"))]
+#[link(name = "unwind")]
+extern "C" {
+    fn unw_backtrace(buffer: *mut *mut libc::c_void, size: libc::c_int) -> libc::c_int;
+}
+
+#[cfg(all(feature = "libunwind", target_os = "linux"))]
+impl Unwinder for LibUnwind {
+    unsafe fn trace(&self, max: usize, frame: &mut dyn FnMut(&StackFrame) -> bool) {
+        use crate::profiler::{MAX_DEPTH, MAX_OWN_FRAMES, MAX_SPILLED};
+
+        // the deepest stacks the sessions keep, and the profiler's own frames: deeper ones are rare enough to allocate.
+        const INLINE: usize = MAX_DEPTH + MAX_SPILLED + MAX_OWN_FRAMES;
+        let mut inline = [std::ptr::null_mut(); INLINE];
+        let mut allocated = vec![];
+        let ips = if max <= INLINE {
+            &mut inline[..max]
+        } else {
+            allocated.resize(max.min(libc::c_int::MAX as usize), std::ptr::null_mut());
+            &mut allocated[..]
+        };
+        let len = unw_backtrace(ips.as_mut_ptr(), ips.len() as libc::c_int);
+        for &ip in &ips[..len.max(0) as usize] {
+            if !frame(&StackFrame {
+                ip: ip as usize,
+                function: 0,
+            }) {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! The watchdog of the collector thread, the one the hooks hand their samples over to: gone (of a panic) or stuck
+//! flushing, it would leave the samples queued, for the backpressure policy to drop or flush in place, and the
+//! reports misleadingly small. The watchdog checks on it every second, and starts another one when it's gone, at most
+//! 3 times a session. A sample that panics while it's flushed doesn't take the thread down: the rest of its batch is
+//! dropped. A thread that has been flushing the same batch for 10s is left to it: it holds the lock of the session's
+//! state, which another one would wait for. Either way, the reports tell, see
+//! [`HeapReport::collector_incidents`](crate::HeapReport::collector_incidents).
+
+use std::any::Any;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+// how long a batch can take to flush before the thread is taken for stuck.
+const STALLED: Duration = Duration::from_secs(10);
+const MAX_RESTARTS: usize = 3;
+
+static INCIDENTS: spin::Mutex<Vec<CollectorIncident>> = spin::Mutex::new(Vec::new());
+
+/// Something that went wrong with the collector thread of a session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CollectorIncident {
+    /// When it happened, since the collector thread of the session started.
+    pub elapsed: Duration,
+    pub kind: IncidentKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IncidentKind {
+    /// The thread panicked, with the panic's message: flushing a sample, the rest of whose batch was dropped, or
+    /// elsewhere, and the watchdog started another one.
+    Panicked(String),
+    /// The thread had been flushing a batch for that long (once per batch), holding up the samples queued after it.
+    Stalled(Duration),
+}
+
+impl fmt::Display for CollectorIncident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "collector_incident elapsed_ms={} ",
+            self.elapsed.as_millis()
+        )?;
+        match &self.kind {
+            IncidentKind::Panicked(message) => write!(f, "panicked: {}", message),
+            IncidentKind::Stalled(stalled) => write!(f, "stalled_ms={}", stalled.as_millis()),
+        }
+    }
+}
+
+// What the collector threads of a session and their watchdog share.
+pub(crate) struct Heartbeat {
+    origin: Instant,
+    // when the thread started flushing its batch, in ns since `origin` plus one, 0 while it's waiting for one.
+    busy_since: AtomicU64,
+}
+
+impl Heartbeat {
+    pub(crate) fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            busy_since: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn busy(&self) {
+        let since = self.origin.elapsed().as_nanos() as u64 + 1;
+        self.busy_since.store(since, Ordering::Relaxed);
+    }
+
+    pub(crate) fn idle(&self) {
+        self.busy_since.store(0, Ordering::Relaxed);
+    }
+
+    // How long the thread has been flushing the batch it started at the first value, if it's too long.
+    fn stalled(&self) -> Option<(u64, Duration)> {
+        let busy_since = self.busy_since.load(Ordering::Relaxed);
+        let since = busy_since.checked_sub(1)?;
+        let stalled = self
+            .origin
+            .elapsed()
+            .saturating_sub(Duration::from_nanos(since));
+        (stalled >= STALLED).then_some((busy_since, stalled))
+    }
+
+    pub(crate) fn record(&self, kind: IncidentKind) {
+        INCIDENTS.lock().push(CollectorIncident {
+            elapsed: self.origin.elapsed(),
+            kind,
+        });
+    }
+}
+
+// Forgets the incidents of the previous session.
+pub(crate) fn start() {
+    INCIDENTS.lock().clear();
+}
+
+pub(crate) fn incidents() -> Vec<CollectorIncident> {
+    INCIDENTS.lock().clone()
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "(no message)".to_string(),
+        },
+    }
+}
+
+// Watches `thread` until `stopped` says the session stopped, starting another one with `spawn` when it's gone, then
+// waits for the one running to flush what's left.
+pub(crate) fn watch(
+    heartbeat: &Heartbeat,
+    thread: JoinHandle<()>,
+    stopped: Receiver<()>,
+    spawn: impl Fn() -> Option<JoinHandle<()>>,
+) {
+    let mut thread = Some(thread);
+    let mut restarts = 0;
+    // the batch the last stall was recorded for.
+    let mut stalled_batch = None;
+    loop {
+        let stopping = !matches!(
+            stopped.recv_timeout(INTERVAL),
+            Err(RecvTimeoutError::Timeout)
+        );
+        let replace = match thread.take() {
+            // the last restart couldn't start one.
+            None => true,
+            // it only ends by itself once the session stopped and the queue is empty.
+            Some(finished) if finished.is_finished() => match finished.join() {
+                Ok(()) => false,
+                Err(panic) => {
+                    heartbeat.record(IncidentKind::Panicked(panic_message(&*panic)));
+                    true
+                }
+            },
+            Some(running) => {
+                if let Some((batch, stalled)) = heartbeat.stalled() {
+                    if stalled_batch != Some(batch) {
+                        stalled_batch = Some(batch);
+                        heartbeat.record(IncidentKind::Stalled(stalled));
+                    }
+                }
+                thread = Some(running);
+                false
+            }
+        };
+        if replace && restarts < MAX_RESTARTS {
+            restarts += 1;
+            thread = spawn();
+        }
+        if stopping {
+            break;
+        }
+    }
+    if let Some(thread) = thread {
+        if let Err(panic) = thread.join() {
+            heartbeat.record(IncidentKind::Panicked(panic_message(&*panic)));
+        }
+    }
+}
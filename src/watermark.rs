@@ -0,0 +1,62 @@
+//! Snapshots of the heap at each new peak, capturing what it was made of every time it grew past its previous
+//! maximum without continuously dumping reports.
+//!
+//! ```ignore
+//! let _guard = heappy::HeapProfilerGuardBuilder::default()
+//!     .period(4096)
+//!     .write_peaks(heappy::Watermarks::default(), "/tmp/heap-peaks", 5)
+//!     .build()
+//!     .await?;
+//! ```
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::dumps::DumpFiles;
+use crate::profiler::{HeapReport, HEAP_PROFILER_STATE};
+use crate::task::{self, Task};
+
+pub(crate) type PeakCallback = Arc<dyn Fn(HeapReport) + Send + Sync>;
+
+/// When to take a snapshot: the in-use bytes of the session (see
+/// [`HeapTotals::in_use_bytes`](crate::HeapTotals::in_use_bytes)) are checked every `interval`, and a new peak is one
+/// that exceeds the previous one by more than `min_growth`.
+#[derive(Clone, Debug)]
+pub struct Watermarks {
+    pub interval: Duration,
+    /// Relative to the previous peak (`0.1` for 10%), so that a slowly creeping heap doesn't snapshot on every check.
+    pub min_growth: f64,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            min_growth: 0.1,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum PeakSink {
+    Callback(PeakCallback),
+    Files(DumpFiles),
+}
+
+// Checks the running session every `watermarks.interval` until aborted.
+pub(crate) fn spawn(watermarks: Watermarks, sink: PeakSink) -> Task<()> {
+    let mut peak = 0;
+    task::every(watermarks.interval, move || {
+        let in_use = HEAP_PROFILER_STATE.read().unwrap().totals().in_use_bytes();
+        if in_use <= 0 || (in_use as f64) <= peak as f64 * (1.0 + watermarks.min_growth) {
+            return;
+        }
+        peak = in_use;
+        let report = HeapReport::snapshot();
+
+        match &sink {
+            PeakSink::Callback(callback) => callback(report),
+            PeakSink::Files(files) => files.write(&report, "peak"),
+        }
+    })
+}
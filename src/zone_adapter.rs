@@ -0,0 +1,67 @@
+//! The system allocator of macOS, through the malloc zone API. The hooks only replace `malloc` and co. for the
+//! executable itself (the system libraries keep binding to theirs), and pointers cross over both ways (e.g.
+//! `realpath` allocates, the caller frees): both sides must allocate from the same heap.
+
+use libc::{c_int, c_void, malloc_zone_t, size_t};
+
+extern "C" {
+    fn malloc_zone_memalign(
+        zone: *mut malloc_zone_t,
+        alignment: size_t,
+        size: size_t,
+    ) -> *mut c_void;
+}
+
+// The zone that owns `ptr`, which isn't necessarily the default one.
+unsafe fn zone(ptr: *const c_void) -> *mut malloc_zone_t {
+    let zone = libc::malloc_zone_from_ptr(ptr);
+    if zone.is_null() {
+        libc::malloc_default_zone()
+    } else {
+        zone
+    }
+}
+
+pub unsafe fn sys_malloc(size: size_t) -> *mut c_void {
+    libc::malloc_zone_malloc(libc::malloc_default_zone(), size)
+}
+
+pub unsafe fn sys_calloc(number: size_t, size: size_t) -> *mut c_void {
+    libc::malloc_zone_calloc(libc::malloc_default_zone(), number, size)
+}
+
+pub unsafe fn sys_free(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        libc::malloc_zone_free(zone(ptr), ptr)
+    }
+}
+
+pub unsafe fn sys_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return sys_malloc(size);
+    }
+    libc::malloc_zone_realloc(zone(ptr), ptr, size)
+}
+
+pub unsafe fn sys_malloc_usable_size(ptr: *const c_void) -> size_t {
+    // 0 for null and for pointers of no zone.
+    libc::malloc_size(ptr)
+}
+
+pub unsafe fn sys_posix_memalign(ptr: *mut *mut c_void, alignment: size_t, size: size_t) -> c_int {
+    if !alignment.is_power_of_two() || alignment % std::mem::size_of::<*mut c_void>() != 0 {
+        return libc::EINVAL;
+    }
+    let res = malloc_zone_memalign(libc::malloc_default_zone(), alignment, size);
+    if res.is_null() {
+        return libc::ENOMEM;
+    }
+    *ptr = res;
+    0
+}
+
+pub unsafe fn sys_aligned_alloc(alignment: size_t, size: size_t) -> *mut c_void {
+    // the zones want at least the alignment of a pointer, which malloc has anyway.
+    let alignment = alignment.max(std::mem::size_of::<*mut c_void>());
+    malloc_zone_memalign(libc::malloc_default_zone(), alignment, size)
+}
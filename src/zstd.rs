@@ -0,0 +1,971 @@
+// A small Zstandard (RFC 8878) encoder, there being no zstd crate that doesn't build the C library: LZ77 with hash
+// chains and lazy matching over a 512 KiB window, Huffman coded literals and FSE coded sequences. That leaves
+// compression on the table compared to the reference encoder, but the frames are standard and any zstd decoder reads
+// them.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const WINDOW_LOG: u32 = 19;
+const WINDOW: usize = 1 << WINDOW_LOG;
+const MAX_BLOCK: usize = 128 << 10;
+const HASH_LOG: u32 = 16;
+const SEARCH_DEPTH: usize = 16;
+const MIN_MATCH: usize = 4;
+const MAX_CODE_BITS: u32 = 11;
+const WEIGHTS_LOG: u32 = 6;
+
+const RAW_BLOCK: u32 = 0;
+const RLE_BLOCK: u32 = 1;
+const COMPRESSED_BLOCK: u32 = 2;
+
+const RAW_LITERALS: usize = 0;
+const RLE_LITERALS: usize = 1;
+const COMPRESSED_LITERALS: usize = 2;
+
+// The predefined distributions of the literal lengths, match lengths and offsets codes, with their accuracy logs.
+const LL_NORM: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const ML_NORM: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OF_NORM: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+// The smallest literal and match length of each code, and its extra bits.
+const LL_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64,
+    0x80, 0x100, 0x200, 0x400, 0x800, 0x1000, 0x2000, 0x4000, 0x8000, 0x10000,
+];
+const LL_BITS: [u32; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+const ML_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31, 32, 33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 0x83, 0x103, 0x203,
+    0x403, 0x803, 0x1003, 0x2003, 0x4003, 0x8003, 0x10003,
+];
+const ML_BITS: [u32; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+
+/// Compresses what's written to `W` into a single Zstandard frame, written out by [`finish`](Self::finish) or, best
+/// effort, when dropped.
+pub(crate) struct Encoder<W: Write> {
+    writer: Option<W>,
+    // what was written before the current block, within the window, then the current block.
+    history: Vec<u8>,
+    // the position of history[0] in the whole input.
+    base: usize,
+    // where the current block starts in `history`.
+    block: usize,
+    // by hash of 4 bytes, the last position they were seen at plus one, truncated to 32 bits like all positions.
+    table: Vec<u32>,
+    // by position within the window, the previous position with the same hash.
+    chain: Vec<u32>,
+    // the decoder's repeat offsets, as of the last compressed block.
+    reps: [u32; 3],
+    header_written: bool,
+    ll: Fse,
+    ml: Fse,
+    of: Fse,
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        Self {
+            writer: Some(writer),
+            history: Vec::with_capacity(MAX_BLOCK),
+            base: 0,
+            block: 0,
+            table: vec![0; 1 << HASH_LOG],
+            chain: vec![0; WINDOW],
+            reps: [1, 4, 8],
+            header_written: false,
+            ll: Fse::new(&LL_NORM, 6),
+            ml: Fse::new(&ML_NORM, 6),
+            of: Fse::new(&OF_NORM, 5),
+        }
+    }
+
+    /// Writes the last block and returns the writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let result = self.end();
+        let writer = self.writer.take().expect("finished twice");
+        result.map(|()| writer)
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        while self.history.len() - self.block > MAX_BLOCK {
+            self.compress_block(false)?;
+        }
+        self.compress_block(true)?;
+        self.writer.as_mut().expect("finished twice").flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        self.header_written = true;
+        let writer = self.writer.as_mut().expect("finished twice");
+        writer.write_all(&MAGIC)?;
+        // no content size, checksum nor dictionary; then the window descriptor.
+        writer.write_all(&[0, ((WINDOW_LOG - 10) << 3) as u8])
+    }
+
+    // Compresses the next block, of at most MAX_BLOCK bytes.
+    fn compress_block(&mut self, last: bool) -> io::Result<()> {
+        self.write_header()?;
+        let end = self.history.len().min(self.block + MAX_BLOCK);
+        let input = &self.history[self.block..end];
+        let mut out = vec![];
+        let kind = if !input.is_empty() && input.iter().all(|&b| b == input[0]) {
+            out.push(input[0]);
+            RLE_BLOCK
+        } else {
+            let mut reps = self.reps;
+            match self.compressed(end, &mut reps) {
+                Some(compressed) if compressed.len() < end - self.block => {
+                    out = compressed;
+                    self.reps = reps;
+                    COMPRESSED_BLOCK
+                }
+                _ => {
+                    out.extend_from_slice(&self.history[self.block..end]);
+                    RAW_BLOCK
+                }
+            }
+        };
+        let size = if kind == RLE_BLOCK {
+            end - self.block
+        } else {
+            out.len()
+        };
+        let header = u32::from(last) | kind << 1 | (size as u32) << 3;
+        let writer = self.writer.as_mut().expect("finished twice");
+        writer.write_all(&header.to_le_bytes()[..3])?;
+        writer.write_all(&out)?;
+
+        self.block = end;
+        // keep a window of what came before the next block.
+        if self.block > WINDOW + MAX_BLOCK {
+            let drained = self.block - WINDOW;
+            self.history.drain(..drained);
+            self.base += drained;
+            self.block -= drained;
+        }
+        Ok(())
+    }
+
+    // The literals and sequences sections of history[block..end], none if it doesn't fit a block.
+    fn compressed(&mut self, end: usize, reps: &mut [u32; 3]) -> Option<Vec<u8>> {
+        let mut literals = vec![];
+        let mut sequences = vec![];
+        let (mut i, mut anchor) = (self.block, self.block);
+        while i + MIN_MATCH <= end {
+            let found = self.find_match(i, end, reps[0]);
+            self.insert(i);
+            let Some(mut best) = found else {
+                i += 1;
+                continue;
+            };
+            // lazily, for a longer match one byte further.
+            while i + 1 + MIN_MATCH <= end {
+                match self.find_match(i + 1, end, reps[0]) {
+                    Some(next) if next.1 > best.1 => {
+                        i += 1;
+                        self.insert(i);
+                        best = next;
+                    }
+                    _ => break,
+                }
+            }
+            let (distance, len) = best;
+            literals.extend_from_slice(&self.history[anchor..i]);
+            sequences.push(Sequence {
+                literals: (i - anchor) as u32,
+                offset: offset_value(distance as u32, i > anchor, reps),
+                len: len as u32,
+            });
+            for j in i + 1..(i + len).min(end + 1 - MIN_MATCH) {
+                self.insert(j);
+            }
+            i += len;
+            anchor = i;
+        }
+        literals.extend_from_slice(&self.history[anchor..end]);
+
+        let mut out = vec![];
+        encode_literals(&literals, &mut out);
+
+        let count = sequences.len();
+        match count {
+            0..=127 => out.push(count as u8),
+            128..=0x7eff => out.extend_from_slice(&[(count >> 8) as u8 + 0x80, count as u8]),
+            _ => {
+                out.push(0xff);
+                out.extend_from_slice(&((count - 0x7f00) as u16).to_le_bytes());
+            }
+        }
+        if count > 0 {
+            let codes: Vec<_> = sequences.iter().map(Sequence::codes).collect();
+            let ll = fitted_table(codes.iter().map(|c| c.ll), &LL_NORM, 6, 9);
+            let of = fitted_table(codes.iter().map(|c| c.of), &OF_NORM, 5, 8);
+            let ml = fitted_table(codes.iter().map(|c| c.ml), &ML_NORM, 6, 9);
+            // the predefined distributions (0) or the fitted ones (2), described after the modes.
+            let mode = |table: &Option<(Fse, Vec<u8>)>| if table.is_some() { 2 } else { 0 };
+            out.push(mode(&ll) << 6 | mode(&of) << 4 | mode(&ml) << 2);
+            for (_, description) in [&ll, &of, &ml].into_iter().flatten() {
+                out.extend_from_slice(description);
+            }
+            encode_sequences(
+                &sequences,
+                &codes,
+                [
+                    ll.as_ref().map_or(&self.ll, |(fse, _)| fse),
+                    of.as_ref().map_or(&self.of, |(fse, _)| fse),
+                    ml.as_ref().map_or(&self.ml, |(fse, _)| fse),
+                ],
+                &mut out,
+            );
+        }
+        (out.len() <= MAX_BLOCK).then_some(out)
+    }
+
+    // The longest match for history[i..end] within the window, as its distance and length, trying the last offset
+    // first, then the positions with the same hash.
+    fn find_match(&self, i: usize, end: usize, rep: u32) -> Option<(usize, usize)> {
+        let length = |distance: usize| {
+            let c = i - distance;
+            (0..end - i)
+                .take_while(|&k| self.history[c + k] == self.history[i + k])
+                .count()
+        };
+        let mut best = (0, 0);
+        if rep as usize <= i {
+            best = (rep as usize, length(rep as usize));
+        }
+        let position = (self.base + i) as u32;
+        let mut candidate = self.table[hash(&self.history[i..i + MIN_MATCH])];
+        for _ in 0..SEARCH_DEPTH {
+            let distance = position.wrapping_add(1).wrapping_sub(candidate) as usize;
+            if candidate == 0
+                || distance == 0
+                || distance > WINDOW
+                || distance > i
+                || best.1 == end - i
+            {
+                break;
+            }
+            // only a match longer than the best one is worth measuring.
+            if self.history[i - distance + best.1] == self.history[i + best.1] {
+                let len = length(distance);
+                if len > best.1 {
+                    best = (distance, len);
+                }
+            }
+            candidate = self.chain[(self.base + i - distance) & (WINDOW - 1)];
+        }
+        (best.1 >= MIN_MATCH).then_some(best)
+    }
+
+    fn insert(&mut self, i: usize) {
+        let slot = hash(&self.history[i..i + MIN_MATCH]);
+        let position = self.base + i;
+        self.chain[position & (WINDOW - 1)] = self.table[slot];
+        self.table[slot] = (position as u32).wrapping_add(1);
+    }
+}
+
+// The sequences, with the literal lengths, offsets and match lengths tables, backwards for the decoder to read them
+// forwards.
+fn encode_sequences(
+    sequences: &[Sequence],
+    codes: &[Codes],
+    [ll_fse, of_fse, ml_fse]: [&Fse; 3],
+    out: &mut Vec<u8>,
+) {
+    let mut bits = BitWriter::new(out);
+    let (last, last_codes) = (&sequences[sequences.len() - 1], codes[codes.len() - 1]);
+    let mut ml = ml_fse.init(last_codes.ml);
+    let mut of = of_fse.init(last_codes.of);
+    let mut ll = ll_fse.init(last_codes.ll);
+    last.extra_bits(last_codes, &mut bits);
+    for (sequence, &codes) in sequences.iter().zip(codes).rev().skip(1) {
+        of_fse.encode(&mut of, codes.of, &mut bits);
+        ml_fse.encode(&mut ml, codes.ml, &mut bits);
+        ll_fse.encode(&mut ll, codes.ll, &mut bits);
+        sequence.extra_bits(codes, &mut bits);
+    }
+    bits.add(u64::from(ml), ml_fse.log);
+    bits.add(u64::from(of), of_fse.log);
+    bits.add(u64::from(ll), ll_fse.log);
+    bits.close();
+}
+
+// A distribution fitted to the codes, with its description, if it saves more than the description costs over the
+// predefined one.
+fn fitted_table(
+    codes: impl Iterator<Item = usize>,
+    predefined: &[i16],
+    predefined_log: u32,
+    max_log: u32,
+) -> Option<(Fse, Vec<u8>)> {
+    let mut counts = vec![0u32; predefined.len()];
+    for code in codes {
+        counts[code] += 1;
+    }
+    let total: u32 = counts.iter().sum();
+    let distinct = counts.iter().filter(|&&c| c > 0).count();
+    if distinct < 2 {
+        return None;
+    }
+    let bits = |n: usize| usize::BITS - n.leading_zeros();
+    let log = bits(total as usize).clamp(5, max_log).max(bits(distinct));
+    let norm = normalize(&counts, log);
+    // the bits the codes take with a distribution, less those of the extra bits which don't change.
+    let cost = |norm: &[i16], log: u32| -> f64 {
+        counts
+            .iter()
+            .zip(norm)
+            .filter(|(&c, _)| c > 0)
+            .map(|(&c, &n)| f64::from(c) * (f64::from(log) - f64::from(n.max(1)).log2()))
+            .sum()
+    };
+    let mut description = vec![];
+    write_distribution(&norm, log, &mut description);
+    let fitted = cost(&norm, log) + 8.0 * description.len() as f64;
+    (fitted < cost(predefined, predefined_log)).then(|| (Fse::new(&norm, log), description))
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.history.extend_from_slice(buf);
+        // one block is always kept back, as it might be the last.
+        while self.history.len() - self.block > MAX_BLOCK {
+            self.compress_block(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().expect("finished").flush()
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.end();
+        }
+    }
+}
+
+// The literals section: Huffman coded if that's smaller, raw otherwise.
+fn encode_literals(literals: &[u8], out: &mut Vec<u8>) {
+    let n = literals.len();
+    if n > 1 && literals.iter().all(|&b| b == literals[0]) {
+        literals_header(RLE_LITERALS, n, out);
+        out.push(literals[0]);
+        return;
+    }
+    let raw = match n {
+        0..=31 => 1,
+        32..=4095 => 2,
+        _ => 3,
+    } + n;
+    match huffman_literals(literals) {
+        Some(huffman) if huffman.len() < raw => out.extend_from_slice(&huffman),
+        _ => {
+            literals_header(RAW_LITERALS, n, out);
+            out.extend_from_slice(literals);
+        }
+    }
+}
+
+// The 1, 2 or 3 byte header of raw and run length encoded literals.
+fn literals_header(kind: usize, n: usize, out: &mut Vec<u8>) {
+    match n {
+        0..=31 => out.push((kind | n << 3) as u8),
+        32..=4095 => {
+            out.extend_from_slice(&[(kind | 1 << 2 | (n & 0xf) << 4) as u8, (n >> 4) as u8])
+        }
+        _ => out.extend_from_slice(&[
+            (kind | 3 << 2 | (n & 0xf) << 4) as u8,
+            (n >> 4) as u8,
+            (n >> 12) as u8,
+        ]),
+    }
+}
+
+// The Huffman coded literals section, in one stream up to 1023 literals and four above, if it fits the format.
+fn huffman_literals(literals: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = [0u32; 256];
+    for &b in literals {
+        counts[b as usize] += 1;
+    }
+    let lengths = code_lengths(&counts)?;
+    let max_bits = *lengths.iter().max()?;
+
+    // the weights of all the symbols up to the last one, whose weight the decoder infers.
+    let last = lengths.iter().rposition(|&len| len > 0)?;
+    let weights: Vec<u8> = lengths[..last]
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                0
+            } else {
+                (max_bits + 1 - len) as u8
+            }
+        })
+        .collect();
+    let mut section = vec![];
+    if weights.len() <= 128 {
+        section.push(127 + weights.len() as u8);
+        section.extend(
+            weights
+                .chunks(2)
+                .map(|pair| pair[0] << 4 | pair.get(1).unwrap_or(&0)),
+        );
+    } else {
+        let compressed = compress_weights(&weights).filter(|weights| weights.len() < 128)?;
+        section.push(compressed.len() as u8);
+        section.extend_from_slice(&compressed);
+    }
+
+    // canonical codes: by length, the longest first, then by symbol.
+    let mut per_length = vec![0u32; max_bits as usize + 1];
+    for &len in &lengths {
+        per_length[len as usize] += 1;
+    }
+    let mut next = vec![0u32; max_bits as usize + 1];
+    let mut code = 0;
+    for len in (1..=max_bits as usize).rev() {
+        next[len] = code;
+        code = (code + per_length[len]) >> 1;
+    }
+    let codes: Vec<_> = lengths
+        .iter()
+        .map(|&len| {
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            (code, len)
+        })
+        .collect();
+
+    let streams: Vec<_> = literals
+        .chunks(if literals.len() <= 1023 {
+            1023
+        } else {
+            (literals.len() + 3) / 4
+        })
+        .map(|segment| huffman_stream(segment, &codes))
+        .collect();
+    if streams.len() == 4 {
+        for stream in &streams[..3] {
+            section.extend_from_slice(&u16::try_from(stream.len()).ok()?.to_le_bytes());
+        }
+    }
+    for stream in &streams {
+        section.extend_from_slice(stream);
+    }
+
+    let (regenerated, compressed) = (literals.len(), section.len());
+    let (format, size_bits, header_len) = match (streams.len(), regenerated.max(compressed)) {
+        (1, 0..=1023) => (0, 10, 3),
+        (1, _) => return None,
+        (_, 0..=1023) => (1, 10, 3),
+        (_, 0..=16383) => (2, 14, 4),
+        (_, 0..=262143) => (3, 18, 5),
+        _ => return None,
+    };
+    let header = COMPRESSED_LITERALS as u64
+        | format << 2
+        | (regenerated as u64) << 4
+        | (compressed as u64) << (4 + size_bits);
+    let mut out = header.to_le_bytes()[..header_len].to_vec();
+    out.extend_from_slice(&section);
+    Some(out)
+}
+
+// Huffman code lengths of at most MAX_CODE_BITS, for the symbols with a count, if there are at least two.
+fn code_lengths(counts: &[u32; 256]) -> Option<Vec<u32>> {
+    let symbols: Vec<usize> = (0..256).filter(|&s| counts[s] > 0).collect();
+    if symbols.len() < 2 {
+        return None;
+    }
+    let mut heap: BinaryHeap<_> = symbols
+        .iter()
+        .enumerate()
+        .map(|(node, &s)| Reverse((u64::from(counts[s]), node)))
+        .collect();
+    // the leaves are the first nodes, and parents always come after their children.
+    let mut parents = vec![0; 2 * symbols.len() - 1];
+    let mut node = symbols.len();
+    while let (Some(Reverse((a, left))), Some(Reverse((b, right)))) = (heap.pop(), heap.pop()) {
+        parents[left] = node;
+        parents[right] = node;
+        heap.push(Reverse((a + b, node)));
+        node += 1;
+    }
+    let mut depths = vec![0u32; parents.len()];
+    for node in (0..parents.len() - 1).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+    let mut lengths = vec![0; 256];
+    for (node, &s) in symbols.iter().enumerate() {
+        lengths[s] = depths[node].min(MAX_CODE_BITS);
+    }
+
+    // capping the lengths overflows the code: lengthen the rarest of the longest codes that can be, then shorten the
+    // most frequent of the longest ones while there's room left, to get a complete code again.
+    let target = 1u32 << MAX_CODE_BITS;
+    let mut total: u32 = symbols
+        .iter()
+        .map(|&s| 1 << (MAX_CODE_BITS - lengths[s]))
+        .sum();
+    while total > target {
+        let &s = symbols
+            .iter()
+            .filter(|&&s| lengths[s] < MAX_CODE_BITS)
+            .max_by_key(|&&s| (lengths[s], Reverse(counts[s])))?;
+        lengths[s] += 1;
+        total -= 1 << (MAX_CODE_BITS - lengths[s]);
+    }
+    while total < target {
+        let &s = symbols.iter().max_by_key(|&&s| (lengths[s], counts[s]))?;
+        total += 1 << (MAX_CODE_BITS - lengths[s]);
+        lengths[s] -= 1;
+    }
+    Some(lengths)
+}
+
+// One Huffman stream, written backwards for the decoder to read the literals forwards.
+fn huffman_stream(literals: &[u8], codes: &[(u32, u32)]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bits = BitWriter::new(&mut out);
+    for &b in literals.iter().rev() {
+        let (code, len) = codes[b as usize];
+        bits.add(u64::from(code), len);
+    }
+    bits.close();
+    out
+}
+
+// The Huffman weights, FSE coded with two interleaved states, if there are at least two different ones.
+fn compress_weights(weights: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = vec![0u32; *weights.iter().max()? as usize + 1];
+    for &w in weights {
+        counts[w as usize] += 1;
+    }
+    if counts.iter().any(|&c| c as usize == weights.len()) {
+        return None;
+    }
+    let norm = normalize(&counts, WEIGHTS_LOG);
+    let mut out = vec![];
+    write_distribution(&norm, WEIGHTS_LOG, &mut out);
+
+    let fse = Fse::new(&norm, WEIGHTS_LOG);
+    let mut bits = BitWriter::new(&mut out);
+    // the first state codes the even weights, the second the odd ones.
+    let n = weights.len();
+    let mut states = [0; 2];
+    states[(n - 1) % 2] = fse.init(weights[n - 1] as usize);
+    states[(n - 2) % 2] = fse.init(weights[n - 2] as usize);
+    for i in (0..n - 2).rev() {
+        fse.encode(&mut states[i % 2], weights[i] as usize, &mut bits);
+    }
+    bits.add(u64::from(states[1]), WEIGHTS_LOG);
+    bits.add(u64::from(states[0]), WEIGHTS_LOG);
+    bits.close();
+    Some(out)
+}
+
+// Scales counts to add up to 1 << log, leaving every symbol seen at least 1.
+fn normalize(counts: &[u32], log: u32) -> Vec<i16> {
+    let size = 1i32 << log;
+    let total = u64::from(counts.iter().sum::<u32>());
+    let mut norm: Vec<i16> = counts
+        .iter()
+        .map(|&c| match c {
+            0 => 0,
+            _ => ((u64::from(c) * size as u64 + total / 2) / total).max(1) as i16,
+        })
+        .collect();
+    let mut sum: i32 = norm.iter().map(|&n| i32::from(n)).sum();
+    while sum != size {
+        let s = (0..norm.len())
+            .filter(|&s| sum < size && counts[s] > 0 || norm[s] > 1)
+            .max_by_key(|&s| norm[s])
+            .unwrap_or(0);
+        let delta = if sum < size { 1 } else { -1 };
+        norm[s] += delta;
+        sum += i32::from(delta);
+    }
+    norm
+}
+
+// An FSE table description, written forwards: the accuracy log, then the counts plus one in as few bits as the
+// remaining total allows, with the runs of zero counts after a zero count in 2 bit repeat flags.
+fn write_distribution(norm: &[i16], log: u32, out: &mut Vec<u8>) {
+    let mut bits = BitWriter::new(out);
+    bits.add(u64::from(log - 5), 4);
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut nb_bits = log + 1;
+    let (mut symbol, mut previous_zero) = (0, false);
+    while symbol < norm.len() && remaining > 1 {
+        if previous_zero {
+            let mut start = symbol;
+            while norm[symbol] == 0 {
+                symbol += 1;
+            }
+            while symbol >= start + 24 {
+                start += 24;
+                bits.add(0xffff, 16);
+            }
+            while symbol >= start + 3 {
+                start += 3;
+                bits.add(3, 2);
+            }
+            bits.add((symbol - start) as u64, 2);
+        }
+        let count = i32::from(norm[symbol]);
+        symbol += 1;
+        let max = 2 * threshold - 1 - remaining;
+        remaining -= count.abs();
+        let mut value = count + 1;
+        if value >= threshold {
+            value += max;
+        }
+        bits.add(value as u64, nb_bits - u32::from(value < max));
+        previous_zero = value == 1;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    bits.pad();
+}
+
+// The offset value coding a match distance, using and updating the repeat offsets like the decoder: with literals
+// before the match, 1 to 3 are the repeat offsets, without them 1 and 2 are the second and third ones and 3 is the
+// first one minus one.
+fn offset_value(distance: u32, literals: bool, reps: &mut [u32; 3]) -> u32 {
+    let repeat = if literals {
+        reps.iter().position(|&rep| rep == distance)
+    } else if distance == reps[1] {
+        Some(1)
+    } else if distance == reps[2] {
+        Some(2)
+    } else {
+        None
+    };
+    match repeat {
+        Some(0) => 1,
+        Some(n) => {
+            reps[..=n].rotate_right(1);
+            n as u32 + u32::from(literals)
+        }
+        None if !literals && distance + 1 == reps[0] => {
+            reps.rotate_right(1);
+            reps[0] = distance;
+            3
+        }
+        None => {
+            reps.rotate_right(1);
+            reps[0] = distance;
+            distance + 3
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+struct Sequence {
+    literals: u32,
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Codes {
+    ll: usize,
+    ml: usize,
+    of: usize,
+}
+
+impl Sequence {
+    fn codes(&self) -> Codes {
+        let code = |base: &[u32], v: u32| base.iter().rposition(|&b| b <= v).unwrap_or(0);
+        Codes {
+            ll: code(&LL_BASE, self.literals),
+            ml: code(&ML_BASE, self.len),
+            of: (31 - self.offset.leading_zeros()) as usize,
+        }
+    }
+
+    fn extra_bits(&self, codes: Codes, bits: &mut BitWriter) {
+        bits.add(
+            u64::from(self.literals - LL_BASE[codes.ll]),
+            LL_BITS[codes.ll],
+        );
+        bits.add(u64::from(self.len - ML_BASE[codes.ml]), ML_BITS[codes.ml]);
+        bits.add(u64::from(self.offset - (1 << codes.of)), codes.of as u32);
+    }
+}
+
+// Bits written from the least significant, as the decoder reads the stream backwards from its last bit.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    fn add(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc |= (value & ((1 << bits) - 1)) << self.len;
+        self.len += bits;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    // The end mark, then the last byte.
+    fn close(mut self) {
+        self.add(1, 1);
+        self.pad();
+    }
+
+    // The last byte, zero padded.
+    fn pad(self) {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+    }
+}
+
+// An FSE (tANS) encoding table, built the way the decoders build theirs from the same distribution.
+struct Fse {
+    log: u32,
+    // the next state, by cumulated count of the symbol plus the shifted state.
+    states: Vec<u16>,
+    // per symbol, to find the number of bits to write and the next state.
+    delta_bits: Vec<u32>,
+    delta_state: Vec<i32>,
+}
+
+impl Fse {
+    fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1usize << log;
+        let mut symbols = vec![0usize; size];
+        let mut cumul = vec![0usize; norm.len() + 1];
+        // the symbols with less than one count (-1) take the last cells of the table.
+        let mut high = size - 1;
+        for (s, &n) in norm.iter().enumerate() {
+            if n == -1 {
+                cumul[s + 1] = cumul[s] + 1;
+                symbols[high] = s;
+                high = high.wrapping_sub(1);
+            } else {
+                cumul[s + 1] = cumul[s] + n as usize;
+            }
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (s, &n) in norm.iter().enumerate() {
+            for _ in 0..n.max(0) {
+                symbols[position] = s;
+                position = (position + step) & (size - 1);
+                while position > high {
+                    position = (position + step) & (size - 1);
+                }
+            }
+        }
+        debug_assert_eq!(position, 0);
+
+        let mut states = vec![0u16; size];
+        let mut next = cumul.clone();
+        for (u, &s) in symbols.iter().enumerate() {
+            states[next[s]] = (size + u) as u16;
+            next[s] += 1;
+        }
+
+        let mut delta_bits = vec![0; norm.len()];
+        let mut delta_state = vec![0; norm.len()];
+        let mut total: i32 = 0;
+        for (s, &n) in norm.iter().enumerate() {
+            match n {
+                0 => delta_bits[s] = ((log + 1) << 16) - (1 << log),
+                -1 | 1 => {
+                    delta_bits[s] = (log << 16) - (1 << log);
+                    delta_state[s] = total - 1;
+                    total += 1;
+                }
+                _ => {
+                    let n = n as u32;
+                    let max_bits = log - (31 - (n - 1).leading_zeros());
+                    delta_bits[s] = (max_bits << 16) - (n << max_bits);
+                    delta_state[s] = total - n as i32;
+                    total += n as i32;
+                }
+            }
+        }
+        Self {
+            log,
+            states,
+            delta_bits,
+            delta_state,
+        }
+    }
+
+    // The state after the first symbol encoded, i.e. the last one decoded.
+    fn init(&self, symbol: usize) -> u32 {
+        let bits = (self.delta_bits[symbol] + (1 << 15)) >> 16;
+        let value = (bits << 16).wrapping_sub(self.delta_bits[symbol]);
+        self.next(value >> bits, symbol)
+    }
+
+    fn encode(&self, state: &mut u32, symbol: usize, out: &mut BitWriter) {
+        let bits = (*state + self.delta_bits[symbol]) >> 16;
+        out.add(u64::from(*state), bits);
+        *state = self.next(*state >> bits, symbol);
+    }
+
+    fn next(&self, shifted: u32, symbol: usize) -> u32 {
+        u32::from(self.states[(shifted as i32 + self.delta_state[symbol]) as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(vec![]);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(mut frame: &[u8]) -> Vec<u8> {
+        let mut decoded = vec![];
+        ruzstd::StreamingDecoder::new(&mut frame)
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let frame = compress(data);
+        assert_eq!(frame[..4], MAGIC);
+        assert!(decompress(&frame) == data, "{} bytes", data.len());
+        frame
+    }
+
+    // Bytes that don't compress, of a xorshift.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    // Bytes that do, like the text of the profiles: lines of a few words, in some order.
+    fn text(len: usize) -> Vec<u8> {
+        let words = [
+            "alloc",
+            "vec",
+            "tokio::runtime",
+            "main",
+            "<T as Clone>::clone",
+            "hash",
+        ];
+        let mut text = vec![];
+        let mut noise = noise(len).into_iter();
+        while text.len() < len {
+            let word = words[noise.next().unwrap_or(0) as usize % words.len()];
+            text.extend_from_slice(word.as_bytes());
+            text.push(if noise.next().unwrap_or(0) % 8 == 0 {
+                b'\n'
+            } else {
+                b';'
+            });
+        }
+        text.truncate(len);
+        text
+    }
+
+    #[test]
+    fn empty() {
+        round_trip(b"");
+    }
+
+    #[test]
+    fn one_byte() {
+        round_trip(b"x");
+    }
+
+    #[test]
+    fn rle() {
+        let frame = round_trip(&[7; 3 * MAX_BLOCK + 5]);
+        assert!(frame.len() < 64, "{} bytes", frame.len());
+    }
+
+    #[test]
+    fn incompressible() {
+        let data = noise(2 * MAX_BLOCK + 1);
+        let frame = round_trip(&data);
+        // raw blocks: only the headers added.
+        assert!(frame.len() < data.len() + 32, "{} bytes", frame.len());
+    }
+
+    #[test]
+    fn multi_block() {
+        // past the window too.
+        let data = text(WINDOW + 3 * MAX_BLOCK);
+        let frame = round_trip(&data);
+        assert!(frame.len() < data.len() / 3, "{} bytes", frame.len());
+    }
+
+    #[test]
+    fn small_writes() {
+        let data = text(MAX_BLOCK + 1000);
+        let mut encoder = Encoder::new(vec![]);
+        for chunk in data.chunks(777) {
+            encoder.write_all(chunk).unwrap();
+        }
+        assert!(decompress(&encoder.finish().unwrap()) == data);
+    }
+}